@@ -0,0 +1,41 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Identifies this build of pgextkit (compiler, target, profile, features and time) for
+/// the build fingerprint of extensions' `Magic`
+///
+/// The time is taken from `SOURCE_DATE_EPOCH` when set, so that reproducible builds get the
+/// same identifier.
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = Command::new(rustc)
+        .arg("-V")
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_default();
+    let mut features = std::env::vars()
+        .filter_map(|(name, _)| name.strip_prefix("CARGO_FEATURE_").map(String::from))
+        .collect::<Vec<_>>();
+    features.sort();
+    let built_at = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse::<u128>().ok())
+        .map(|secs| secs * 1_000_000_000)
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_nanos())
+                .unwrap_or_default()
+        });
+    println!(
+        "cargo:rustc-env=PGEXTKIT_BUILD_ID={};{};{};{};{}",
+        rustc_version,
+        std::env::var("TARGET").unwrap_or_default(),
+        std::env::var("PROFILE").unwrap_or_default(),
+        features.join(","),
+        built_at
+    );
+}
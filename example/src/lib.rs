@@ -1,6 +1,8 @@
 use pgextkit::prelude::*;
+use once_cell::sync::OnceCell;
 use pgx::bgworkers::{BackgroundWorker, BackgroundWorkerBuilder};
 use pgx::prelude::*;
+use pgx::{GucContext, GucSetting};
 
 use std::fmt::Write;
 use std::pin::Pin;
@@ -9,6 +11,9 @@ use std::time::Duration;
 
 pgx::pg_module_magic!();
 pgextkit::pgextkit_magic!();
+pgextkit::pgextkit_manifest!(pgextkit::Manifest::new()
+    .with_worker_count(1)
+    .with_services(b"example.math\0"));
 
 extension_sql!(
     r#"
@@ -18,9 +23,71 @@ extension_sql!(
     finalize
 );
 
+type Text = heapless::String<96>;
+type EchoChannel = SharedChannel<Text, Text, 16>;
+
+/// Service other extensions can look up as `example.math`
+#[repr(C)]
+pub struct MathService {
+    pub add: extern "C" fn(i64, i64) -> i64,
+}
+
+unsafe impl ServiceVtable for MathService {}
+
+extern "C" fn add(a: i64, b: i64) -> i64 {
+    a.wrapping_add(b)
+}
+
+#[no_mangle]
+pub static EXAMPLE_MATH_SERVICE: MathService = MathService { add };
+
+static INTERVAL: OnceCell<&'static GucSetting<i32>> = OnceCell::new();
+
+pgextkit::shmem_safe! {
+    #[derive(Clone, Copy)]
+    struct WorkerArgs {
+        /// Database to connect to when it's not supplied by pgextkit
+        database: SharedStr<64>,
+        /// Log the current value every `log_every` wake ups
+        log_every: u32,
+        logger: Logger,
+        /// Name of the `interval` setting, see [`Handle::guc_name`](pgextkit::Handle::guc_name)
+        interval_setting: SharedStr<64>,
+    }
+}
+
+/// Wake up interval of the worker, from the `interval` setting
+///
+/// Workers of an extension loaded once the server is running don't run `pgextkit_init`,
+/// where the setting is defined, but its value is known nonetheless.
+fn interval(args: &WorkerArgs) -> Duration {
+    let name = std::ffi::CString::new(args.interval_setting.as_str()).expect("CString::new failed");
+    let value = unsafe { pg_sys::GetConfigOption(name.as_ptr(), true, false) };
+    let seconds = if value.is_null() {
+        None
+    } else {
+        unsafe { std::ffi::CStr::from_ptr(value) }
+            .to_str()
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+    };
+    Duration::from_secs(seconds.unwrap_or(10).clamp(1, 3600))
+}
+
 #[no_mangle]
 fn pgextkit_init(handle: *mut pgextkit::Handle) {
     let handle = unsafe { &mut *handle } as &mut pgextkit::Handle;
+    INTERVAL.get_or_init(|| {
+        handle.define_int_guc(
+            "interval",
+            "Worker wake up interval (in seconds)",
+            "Worker wake up interval (in seconds)",
+            10,
+            1,
+            3600,
+            GucContext::Sighup,
+        )
+    });
     let worker = BackgroundWorkerBuilder::new("example ({{DATABASE}})")
         .set_library(&handle.library_name())
         .enable_shmem_access(None)
@@ -29,19 +96,51 @@ fn pgextkit_init(handle: *mut pgextkit::Handle) {
     handle.allocate_shmem_for(
         "LOCK",
         DatabaseLocal::<_, 8>::new(|| {
-            PgDynamicLwLock::<heapless::String<96>>::new("A", "Test".into())
+            handle.new_lwlock::<heapless::String<96>>("A", "Test".into())
         }),
     );
     handle.allocate_shmem_for("LATCH", DatabaseLocal::<_, 8>::new(SharedLatch::new));
-    handle.register_bgworker(&worker);
+    handle.allocate_shmem_for(
+        "ECHO",
+        DatabaseLocal::<_, 8>::new(|| EchoChannel::new("example_echo")),
+    );
+    handle.register_service::<MathService>("example.math", "EXAMPLE_MATH_SERVICE");
+    handle.register_bgworker_with_arg(
+        &worker,
+        WorkerArgs {
+            database: "postgres".try_into().expect("database name"),
+            log_every: 1,
+            logger: handle.logger(),
+            interval_setting: handle
+                .guc_name("interval")
+                .as_str()
+                .try_into()
+                .expect("setting name"),
+        },
+    );
+}
+
+/// Gets an entry allocated by `pgextkit_init`, raising a descriptive error if it's missing
+fn entry<T: Unpin + SyncMut>(name: &str) -> Pin<&'static mut T> {
+    SharedDictionary::default()
+        .get_mut_or_err(name)
+        .unwrap_or_else(|err| pgx::error!("{}", err))
 }
 
 #[no_mangle]
 fn pgextkit_deinit() {
     let dict = SharedDictionary::default();
-    let lock: Pin<&mut DatabaseLocal<PgDynamicLwLock<heapless::String<96>>>> =
-        dict.get_mut("LOCK").unwrap();
-    let latch: Pin<&mut DatabaseLocal<SharedLatch>> = dict.get_mut("LATCH").unwrap();
+    // Entries are missing if initialization failed part-way, there's no worker to stop then
+    let (lock, latch) = match (
+        dict.get_mut_or_err::<DatabaseLocal<PgDynamicLwLock<Text>>>("LOCK"),
+        dict.get_mut_or_err::<DatabaseLocal<SharedLatch>>("LATCH"),
+    ) {
+        (Ok(lock), Ok(latch)) => (lock, latch),
+        (Err(err), _) | (_, Err(err)) => {
+            pgx::warning!("Can't ask the example worker to exit: {}", err);
+            return;
+        }
+    };
     let mut latch = latch.for_my_database();
 
     let mut lock = lock.for_my_database();
@@ -53,37 +152,53 @@ fn pgextkit_deinit() {
 
 #[no_mangle]
 #[pg_guard]
-extern "C" fn worker(_arg: pg_sys::Datum) {
-    let dbinfo = BackgroundWorker::get_extra().split('@').collect::<Vec<_>>();
-    assert!(dbinfo.len() == 2);
-    let username = dbinfo[0];
-    let database = dbinfo[1];
-    BackgroundWorker::connect_worker_to_spi(Some(database), Some(username));
-
-    pgx::log!("Starting worker on {} (user: {})", database, username);
-    let dict = SharedDictionary::default();
-    let lock: Pin<&mut DatabaseLocal<PgDynamicLwLock<heapless::String<96>>>> =
-        dict.get_mut("LOCK").unwrap();
-    let latch: Pin<&mut DatabaseLocal<SharedLatch>> = dict.get_mut("LATCH").unwrap();
+extern "C" fn worker(arg: pg_sys::Datum) {
+    let args = pgextkit::bgworker_arg::<WorkerArgs>(arg).expect("worker arguments");
+    let context = WorkerContext::from_extra();
+    let (username, database) = match &context {
+        Some(context) => (Some(context.username.as_str()), context.database.as_str()),
+        None => (None, args.database.as_str()),
+    };
+    BackgroundWorker::connect_worker_to_spi(Some(database), username);
+
+    let logger = &args.logger;
+    logger.log(format!(
+        "Starting worker on {} (user: {:?})",
+        database, username
+    ));
+    let lock: Pin<&mut DatabaseLocal<PgDynamicLwLock<Text>>> = entry("LOCK");
+    let latch: Pin<&mut DatabaseLocal<SharedLatch>> = entry("LATCH");
     let mut latch = latch.for_my_database();
 
     let latch = latch.own().unwrap();
     let mut lock = lock.for_my_database();
+    let echo: Pin<&mut DatabaseLocal<EchoChannel>> = entry("ECHO");
+    let mut echo = echo.for_my_database();
+    let echo_latch = echo.latch().own().unwrap();
 
-    latch.attach_signal_handlers(SignalWakeFlags::SIGTERM);
+    // Re-reads the configuration file, for `interval` to be changed with a reload
+    latch.attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
 
+    let mut iteration = 0u32;
     loop {
-        {
-            let guard = lock.share();
-            let s = guard.as_str();
-            if s == "EXIT" {
-                drop(guard);
-                lock.exclusive().clear();
-                break;
+        if lock.compare_and_set("EXIT", "") {
+            break;
+        }
+        if iteration % args.log_every.max(1) == 0 {
+            logger.log(format!("({}) {}", database, lock.share().as_str()));
+        }
+        while let Some((id, msg)) = echo.recv() {
+            let mut reply = Text::new();
+            for c in msg.chars().flat_map(char::to_uppercase) {
+                if reply.push(c).is_err() {
+                    break;
+                }
             }
-            pgx::log!("({}) {}", database, s);
+            echo.reply(id, reply);
         }
-        latch.wait(Some(Duration::from_secs(10)));
+        pgextkit::heartbeat::beat();
+        iteration = iteration.wrapping_add(1);
+        OwnedLatch::wait_any(&[&latch, &echo_latch], Some(interval(&args)));
         if latch.signal_received(SignalWakeFlags::SIGTERM) {
             break;
         }
@@ -92,10 +207,8 @@ extern "C" fn worker(_arg: pg_sys::Datum) {
 
 #[pg_extern]
 fn hello_example(val: &str) {
-    let dict = SharedDictionary::default();
-    let lock: Pin<&mut DatabaseLocal<PgDynamicLwLock<heapless::String<96>>>> =
-        dict.get_mut("LOCK").unwrap();
-    let latch: Pin<&mut DatabaseLocal<SharedLatch>> = dict.get_mut("LATCH").unwrap();
+    let lock: Pin<&mut DatabaseLocal<PgDynamicLwLock<Text>>> = entry("LOCK");
+    let latch: Pin<&mut DatabaseLocal<SharedLatch>> = entry("LATCH");
     let mut latch = latch.for_my_database();
 
     let mut lock = lock.for_my_database();
@@ -105,6 +218,21 @@ fn hello_example(val: &str) {
     latch.set_and_wake_up();
 }
 
+/// Asks the worker to echo `val` back in upper case
+#[pg_extern]
+fn echo_example(val: &str) -> Option<String> {
+    let echo: Pin<&mut DatabaseLocal<EchoChannel>> = entry("ECHO");
+    echo.for_my_database()
+        .send_and_wait(Text::from(val), Duration::from_secs(5))
+        .map(|reply| reply.to_string())
+}
+
+/// Adds the numbers through the `example.math` service, as another extension would
+#[pg_extern]
+fn add_example(a: i64, b: i64) -> Option<i64> {
+    lookup_service::<MathService>("example.math").map(|service| (service.add)(a, b))
+}
+
 #[cfg(any(test, feature = "pg_test"))]
 #[pg_schema]
 mod tests {
@@ -112,7 +240,20 @@ mod tests {
 
     #[pg_test]
     fn test_hello_example() {
-        assert_eq!("Hello, example", crate::hello_example());
+        crate::hello_example("Hello, example");
+        assert_eq!(
+            Spi::get_one::<String>("SELECT pgextkit.shared_dictionary_get_text('LOCK')").as_deref(),
+            Some("Hello, example")
+        );
+    }
+
+    #[pg_test]
+    fn test_manifest_read_by_pgextkit() {
+        let (worker_count, services) = Spi::get_two::<i32, Vec<String>>(
+            "SELECT worker_count, services FROM pgextkit.loaded_extensions() WHERE name = 'example'",
+        );
+        assert_eq!(worker_count, Some(1));
+        assert_eq!(services, Some(vec!["example.math".to_string()]));
     }
 }
 
@@ -123,7 +264,7 @@ pub mod pg_test {
     }
 
     pub fn postgresql_conf_options() -> Vec<&'static str> {
-        // return any postgresql.conf settings that are required for your tests
-        vec![]
+        // pgextkit loads the extension, which it can only do when preloaded
+        vec!["shared_preload_libraries = 'pgextkit'"]
     }
 }
@@ -0,0 +1,83 @@
+use crate::types::{ShmemSafe, SyncMut};
+use std::cell::UnsafeCell;
+use std::mem::{align_of, size_of, MaybeUninit};
+use std::pin::Pin;
+#[cfg(not(feature = "extension"))]
+use std::ptr::addr_of_mut;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Bump allocator over a fixed region of shared memory
+///
+/// Useful for carving out many small objects without a dictionary entry for each of them.
+/// Objects can't be freed individually, only the whole arena can be reset.
+///
+/// Allocated with [`Handle::allocate_shmem_arena`](crate::Handle) and retrieved from the
+/// [`SharedDictionary`](crate::shmem::SharedDictionary).
+pub struct SharedArena<const N: usize> {
+    offset: AtomicUsize,
+    data: UnsafeCell<[MaybeUninit<u8>; N]>,
+}
+
+unsafe impl<const N: usize> Sync for SharedArena<N> {}
+unsafe impl<const N: usize> SyncMut for SharedArena<N> {}
+unsafe impl<const N: usize> ShmemSafe for SharedArena<N> {}
+
+impl<const N: usize> SharedArena<N> {
+    /// Initializes the arena in place, leaving its data uninitialized
+    ///
+    /// # Safety
+    ///
+    /// `mem` must be valid for writes of `SharedArena<N>`
+    #[cfg(not(feature = "extension"))]
+    pub(crate) unsafe fn init(mem: *mut Self) {
+        addr_of_mut!((*mem).offset).write(AtomicUsize::new(0));
+    }
+
+    /// Moves `val` into the arena, returns `None` if there is not enough space left
+    pub fn alloc<T: Unpin>(&self, val: T) -> Option<Pin<&'static mut T>> {
+        let base = self.data.get() as usize;
+        let mut offset = self.offset.load(Ordering::Acquire);
+        let start = loop {
+            let start =
+                (base + offset + align_of::<T>() - 1) / align_of::<T>() * align_of::<T>() - base;
+            let end = start.checked_add(size_of::<T>())?;
+            if end > N {
+                return None;
+            }
+            match self.offset.compare_exchange_weak(
+                offset,
+                end,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break start,
+                Err(current) => offset = current,
+            }
+        };
+        let ptr = (base + start) as *mut T;
+        unsafe {
+            ptr.write(val);
+            Some(Pin::new(&mut *ptr))
+        }
+    }
+
+    /// Number of bytes allocated so far
+    pub fn used(&self) -> usize {
+        self.offset.load(Ordering::Acquire)
+    }
+
+    /// Total size of the arena
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Makes the whole arena available again
+    ///
+    /// # Safety
+    ///
+    /// Objects allocated before the reset must no longer be used (by any backend),
+    /// as they will be overwritten by subsequent allocations. They are not dropped.
+    pub unsafe fn reset(&self) {
+        self.offset.store(0, Ordering::Release);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::spinlock::SharedSpinLock;
+use crate::types::{ShmemSafe, SyncMut};
+use pgx::check_for_interrupts;
+use pgx::pg_sys;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Maximum number of participants of a barrier
+pub const MAX_BARRIER_PARTICIPANTS: usize = 64;
+
+struct State {
+    /// Participants that arrived in the current generation
+    arrived: usize,
+    /// Latches of the participants waiting for the others
+    waiting: heapless::Vec<*mut pg_sys::Latch, MAX_BARRIER_PARTICIPANTS>,
+}
+
+/// Barrier in shared memory that backends wait at until all participants arrived
+///
+/// Once the last participant arrives, all of them are released and the barrier can be
+/// used again, for example to go through the phases of work shared by several workers.
+pub struct SharedBarrier {
+    state: SharedSpinLock<State>,
+    /// Incremented every time the participants are released
+    generation: AtomicU64,
+}
+
+unsafe impl SyncMut for SharedBarrier {}
+unsafe impl ShmemSafe for SharedBarrier {}
+
+/// Withdraws the arrival if waiting is interrupted, so that the barrier isn't released
+/// early because of a participant that is gone
+struct Arrival<'a> {
+    barrier: &'a SharedBarrier,
+    generation: u64,
+    latch: *mut pg_sys::Latch,
+}
+
+impl Drop for Arrival<'_> {
+    fn drop(&mut self) {
+        let mut state = self.barrier.state.lock();
+        if self.barrier.generation.load(Ordering::SeqCst) == self.generation {
+            state.arrived -= 1;
+            state.waiting.retain(|latch| *latch != self.latch);
+        }
+    }
+}
+
+impl SharedBarrier {
+    pub fn new() -> Self {
+        Self {
+            state: SharedSpinLock::new(State {
+                arrived: 0,
+                waiting: heapless::Vec::new(),
+            }),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Waits until `n` participants (including this one) arrived
+    ///
+    /// Returns `true` in the participant that arrived last. Interrupts are checked while
+    /// waiting, so the query can be cancelled (or the worker terminated), in which case its
+    /// arrival is withdrawn.
+    pub fn wait(&self, n: usize) -> bool {
+        assert!(
+            (1..=MAX_BARRIER_PARTICIPANTS).contains(&n),
+            "barriers have 1 to {} participants",
+            MAX_BARRIER_PARTICIPANTS
+        );
+        let my_latch = unsafe { pg_sys::MyLatch };
+        let arrival = {
+            let mut state = self.state.lock();
+            state.arrived += 1;
+            if state.arrived >= n {
+                state.arrived = 0;
+                self.generation.fetch_add(1, Ordering::SeqCst);
+                let waiting = std::mem::take(&mut state.waiting);
+                drop(state);
+                for latch in waiting {
+                    unsafe { pg_sys::SetLatch(latch) }
+                }
+                return true;
+            }
+            // At most `n - 1` participants are waiting, so there's room
+            let _ = state.waiting.push(my_latch);
+            Arrival {
+                barrier: self,
+                generation: self.generation.load(Ordering::SeqCst),
+                latch: my_latch,
+            }
+        };
+
+        while self.generation.load(Ordering::SeqCst) == arrival.generation {
+            let rc = unsafe {
+                let rc = pg_sys::WaitLatch(
+                    my_latch,
+                    (pg_sys::WL_LATCH_SET | pg_sys::WL_POSTMASTER_DEATH) as _,
+                    0,
+                    pg_sys::PG_WAIT_EXTENSION,
+                );
+                pg_sys::ResetLatch(my_latch);
+                rc
+            };
+            check_for_interrupts!();
+            if rc as u32 & pg_sys::WL_POSTMASTER_DEATH != 0 {
+                pgx::error!("postmaster died while waiting at a barrier");
+            }
+        }
+        false
+    }
+}
+
+impl Default for SharedBarrier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
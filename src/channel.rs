@@ -0,0 +1,148 @@
+use crate::latch::{SharedLatch, MAX_WAIT_MS};
+use crate::lwlock::PgDynamicLwLock;
+use crate::types::{ShmemSafe, SyncMut};
+use pgx::check_for_interrupts;
+use pgx::pg_sys;
+use std::time::{Duration, Instant};
+
+/// Identifier of a request sent through [`SharedChannel`]
+pub type RequestId = u64;
+
+/// Request that is waiting for a reply
+struct Waiting<R> {
+    id: RequestId,
+    /// Latch of the sending backend
+    latch: *mut pg_sys::Latch,
+    reply: Option<R>,
+}
+
+struct State<T, R, const CAP: usize> {
+    next_id: RequestId,
+    requests: heapless::Deque<(RequestId, T), CAP>,
+    waiting: heapless::Vec<Waiting<R>, CAP>,
+}
+
+/// Channel for handing requests over to a background worker and waiting for its replies
+///
+/// Any backend can send a request, which is received by the backend that owns the channel's
+/// latch (see [`SharedChannel::latch`]). At most `CAP` requests can be in flight at a time.
+pub struct SharedChannel<T, R, const CAP: usize> {
+    state: PgDynamicLwLock<State<T, R, CAP>>,
+    latch: SharedLatch,
+}
+
+unsafe impl<T: ShmemSafe, R: ShmemSafe, const CAP: usize> SyncMut for SharedChannel<T, R, CAP> {}
+unsafe impl<T: ShmemSafe, R: ShmemSafe, const CAP: usize> ShmemSafe for SharedChannel<T, R, CAP> {}
+
+impl<T, R, const CAP: usize> SharedChannel<T, R, CAP> {
+    pub fn new(name: &str) -> Self {
+        Self {
+            state: PgDynamicLwLock::new(
+                name,
+                State {
+                    next_id: 0,
+                    requests: heapless::Deque::new(),
+                    waiting: heapless::Vec::new(),
+                },
+            ),
+            latch: SharedLatch::new(),
+        }
+    }
+
+    /// Latch that is set when a request is sent, to be owned by the receiving backend
+    pub fn latch(&mut self) -> &mut SharedLatch {
+        &mut self.latch
+    }
+
+    /// Sends the request and waits for the reply for up to `timeout`
+    ///
+    /// Returns `None` if the channel is full or if no reply arrived in time.
+    pub fn send_and_wait(&mut self, msg: T, timeout: Duration) -> Option<R> {
+        let deadline = Instant::now().checked_add(timeout);
+        let id = {
+            let mut state = self.state.exclusive();
+            let id = state.next_id;
+            let waiting = Waiting {
+                id,
+                latch: unsafe { pg_sys::MyLatch },
+                reply: None,
+            };
+            if state.waiting.push(waiting).is_err() {
+                return None;
+            }
+            if state.requests.push_back((id, msg)).is_err() {
+                state.waiting.pop();
+                return None;
+            }
+            state.next_id = id.wrapping_add(1);
+            id
+        };
+        self.latch.set_and_wake_up();
+
+        loop {
+            {
+                let mut state = self.state.exclusive();
+                if let Some(index) = state.waiting.iter().position(|w| w.id == id) {
+                    if state.waiting[index].reply.is_some() {
+                        return state.waiting.swap_remove(index).reply;
+                    }
+                }
+            }
+
+            let remaining = deadline.map_or(Duration::MAX, |deadline| {
+                deadline.saturating_duration_since(Instant::now())
+            });
+            if remaining == Duration::ZERO {
+                break;
+            }
+            let rc = unsafe {
+                let rc = pg_sys::WaitLatch(
+                    pg_sys::MyLatch,
+                    (pg_sys::WL_LATCH_SET | pg_sys::WL_TIMEOUT | pg_sys::WL_POSTMASTER_DEATH) as _,
+                    remaining.as_millis().min(MAX_WAIT_MS) as _,
+                    pg_sys::PG_WAIT_EXTENSION,
+                );
+                pg_sys::ResetLatch(pg_sys::MyLatch);
+                rc
+            };
+            check_for_interrupts!();
+            if rc as u32 & pg_sys::WL_POSTMASTER_DEATH != 0 {
+                break;
+            }
+        }
+
+        // Give up on the request, whether or not it has been received
+        let mut state = self.state.exclusive();
+        if let Some(index) = state.waiting.iter().position(|w| w.id == id) {
+            let waiting = state.waiting.swap_remove(index);
+            if waiting.reply.is_some() {
+                return waiting.reply;
+            }
+        }
+        let pending = state.requests.len();
+        for _ in 0..pending {
+            if let Some(request) = state.requests.pop_front() {
+                if request.0 != id {
+                    let _ = state.requests.push_back(request);
+                }
+            }
+        }
+        None
+    }
+
+    /// Takes the next request off the channel
+    pub fn recv(&mut self) -> Option<(RequestId, T)> {
+        self.state.exclusive().requests.pop_front()
+    }
+
+    /// Replies to the request and wakes up its sender
+    ///
+    /// The reply is discarded if the sender is no longer waiting for it.
+    pub fn reply(&mut self, id: RequestId, reply: R) {
+        let mut state = self.state.exclusive();
+        if let Some(waiting) = state.waiting.iter_mut().find(|w| w.id == id) {
+            waiting.reply = Some(reply);
+            unsafe { pg_sys::SetLatch(waiting.latch) }
+        }
+    }
+}
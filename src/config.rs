@@ -0,0 +1,123 @@
+use crate::lwlock::PgDynamicLwLock;
+use crate::types::{ShmemSafe, SyncMut};
+use pgx::check_for_interrupts;
+use pgx::pg_sys;
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+/// How long a writer sleeps while all previous versions are still being read
+const WRITER_BACKOFF_US: std::ffi::c_long = 100;
+
+struct Slot<T> {
+    /// Readers currently referencing the value
+    readers: AtomicU32,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// Read-mostly value in shared memory, such as configuration, that readers access without
+/// taking a lock
+///
+/// Writers publish a new version into one of `VERSIONS` slots, which readers switch to
+/// atomically. A slot is reused for a later version once no reader references it anymore,
+/// so a writer has to wait if readers keep all previous versions busy. Writers are
+/// serialized by an LWLock.
+pub struct SharedConfig<T, const VERSIONS: usize = 4> {
+    slots: [Slot<T>; VERSIONS],
+    /// Slot of the current version
+    current: AtomicUsize,
+    /// Incremented every time a version is published
+    version: AtomicU64,
+    writer: PgDynamicLwLock<()>,
+}
+
+unsafe impl<T: ShmemSafe, const VERSIONS: usize> SyncMut for SharedConfig<T, VERSIONS> {}
+unsafe impl<T: ShmemSafe, const VERSIONS: usize> ShmemSafe for SharedConfig<T, VERSIONS> {}
+
+impl<T, const VERSIONS: usize> SharedConfig<T, VERSIONS> {
+    pub fn new(name: &str, value: T) -> Self {
+        assert!(VERSIONS >= 2, "SharedConfig needs at least two versions");
+        let mut value = Some(value);
+        let slots = std::array::from_fn(|_| Slot {
+            readers: AtomicU32::new(0),
+            value: UnsafeCell::new(match value.take() {
+                Some(value) => MaybeUninit::new(value),
+                None => MaybeUninit::uninit(),
+            }),
+        });
+        Self {
+            slots,
+            current: AtomicUsize::new(0),
+            version: AtomicU64::new(0),
+            writer: PgDynamicLwLock::new(name, ()),
+        }
+    }
+
+    /// References the current version, which stays valid until the guard is dropped
+    /// even if a new one is published in the meantime
+    pub fn read(&self) -> SharedConfigGuard<T, VERSIONS> {
+        loop {
+            let current = self.current.load(Ordering::SeqCst);
+            let slot = &self.slots[current];
+            slot.readers.fetch_add(1, Ordering::SeqCst);
+            // The slot may have been replaced (and be rewritten) before it was referenced
+            if self.current.load(Ordering::SeqCst) == current {
+                return SharedConfigGuard { slot };
+            }
+            slot.readers.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Number of versions published since the value was created, to cheaply tell whether
+    /// it changed
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    /// Publishes a new version, waiting for a slot no reader references
+    pub fn publish(&mut self, value: T) {
+        let _writer = self.writer.exclusive();
+        let current = self.current.load(Ordering::SeqCst);
+        let next = loop {
+            let free = (1..VERSIONS)
+                .map(|i| (current + i) % VERSIONS)
+                .find(|&i| self.slots[i].readers.load(Ordering::SeqCst) == 0);
+            if let Some(free) = free {
+                break free;
+            }
+            unsafe { pg_sys::pg_usleep(WRITER_BACKOFF_US) };
+            check_for_interrupts!();
+        };
+        let slot = &self.slots[next];
+        unsafe {
+            let old = &mut *slot.value.get();
+            // Slots that never held a value are never referenced, so they are filled in order
+            if next as u64 <= self.version.load(Ordering::SeqCst) {
+                old.assume_init_drop();
+            }
+            old.write(value);
+        }
+        self.current.store(next, Ordering::SeqCst);
+        self.version.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Reference to a version of a [`SharedConfig`]
+pub struct SharedConfigGuard<'a, T, const VERSIONS: usize> {
+    slot: &'a Slot<T>,
+}
+
+impl<T, const VERSIONS: usize> Deref for SharedConfigGuard<'_, T, VERSIONS> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { (*self.slot.value.get()).assume_init_ref() }
+    }
+}
+
+impl<T, const VERSIONS: usize> Drop for SharedConfigGuard<'_, T, VERSIONS> {
+    fn drop(&mut self) {
+        self.slot.readers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -1,39 +1,220 @@
-use crate::types::SyncMut;
-use heapless::{Entry, FnvIndexMap, Vec};
+use crate::types::{ShmemSafe, SyncMut};
+use cstr_core::cstr;
+use heapless::{Deque, FnvIndexMap, Vec};
 use pgx::{pg_sys, pg_sys::Oid};
 use pin_project::pin_project;
+use std::mem::size_of;
 use std::pin::Pin;
 
 #[pin_project]
 pub struct DatabaseLocal<T: Unpin, const N: usize = 8> {
+    /// Slots in the order databases claimed them, `None` until constructed
     #[pin]
-    inner: Vec<T, N>,
+    inner: Vec<Option<T>, N>,
     counter: usize,
     mapping: FnvIndexMap<Oid, usize, N>,
+    /// Slots given up by dropped databases, see [`forget_database`]
+    released: Vec<usize, N>,
 }
 
 impl<T: Unpin, const N: usize> DatabaseLocal<T, N> {
     pub fn new<F: Fn() -> T>(f: F) -> Self {
-        let inner = (0..N).into_iter().map(|_| f()).collect::<Vec<_, N>>();
+        let inner = (0..N).into_iter().map(|_| Some(f())).collect::<Vec<_, N>>();
         Self {
             inner,
             counter: 0,
             mapping: FnvIndexMap::new(),
+            released: Vec::new(),
         }
     }
+
+    /// Like [`DatabaseLocal::new`], for state whose construction can fail, returning the
+    /// first error
+    ///
+    /// All `N` slots are constructed upfront, see [`DatabaseLocal::lazy`] to only construct
+    /// the slots of the databases that use them.
+    pub fn try_new<E, F: Fn() -> Result<T, E>>(f: F) -> Result<Self, E> {
+        let inner = (0..N)
+            .map(|_| f().map(Some))
+            .collect::<Result<Vec<_, N>, E>>()?;
+        Ok(Self {
+            inner,
+            counter: 0,
+            mapping: FnvIndexMap::new(),
+            released: Vec::new(),
+        })
+    }
+
+    /// Creates it without constructing any slot, each is constructed by
+    /// [`DatabaseLocal::try_for_my_database`] when a database first uses it
+    ///
+    /// The constructor is passed on every use rather than kept: it would have to be kept
+    /// in shared memory, where its code may be mapped at a different address in every
+    /// backend.
+    pub fn lazy() -> Self {
+        Self {
+            inner: (0..N).map(|_| None).collect(),
+            counter: 0,
+            mapping: FnvIndexMap::new(),
+            released: Vec::new(),
+        }
+    }
+
+    /// Panics if the slot of the current database isn't constructed yet, see
+    /// [`DatabaseLocal::try_for_my_database`]
     pub fn for_my_database(self: Pin<&mut Self>) -> Pin<&mut T> {
+        match self.try_for_my_database(|| Err(())) {
+            Ok(value) => value,
+            Err(()) => panic!("the slot of database {} isn't constructed yet", unsafe {
+                pg_sys::MyDatabaseId
+            }),
+        }
+    }
+
+    /// Like [`DatabaseLocal::for_my_database`], constructing the slot with `f` if the
+    /// current database is the first to use it
+    ///
+    /// If `f` fails, the database doesn't claim a slot, so `f` is called again next time.
+    ///
+    /// Once all slots are claimed, the slots of dropped databases are handed over to the
+    /// databases claiming one. They're constructed anew with `f`, or left as the dropped
+    /// database left them if it fails (as with [`DatabaseLocal::for_my_database`]).
+    pub fn try_for_my_database<E, F: FnOnce() -> Result<T, E>>(
+        self: Pin<&mut Self>,
+        f: F,
+    ) -> Result<Pin<&mut T>, E> {
         let this = self.project();
-        use pg_sys::MyDatabaseId;
-        match this.mapping.entry(unsafe { MyDatabaseId }) {
-            Entry::Vacant(entry) => {
-                let _ = entry.insert(*this.counter);
-                let result = Pin::new(this.inner.get_mut().get_mut(*this.counter).unwrap());
-                *this.counter += 1;
-                result
+        let database = unsafe { pg_sys::MyDatabaseId };
+        let inner = this.inner.get_mut();
+        if let Some(index) = this.mapping.get(&database).copied() {
+            let slot = &mut inner[index];
+            if slot.is_none() {
+                *slot = Some(f()?);
+            }
+            return Ok(Pin::new(slot.as_mut().unwrap()));
+        }
+
+        if *this.counter < N {
+            let index = *this.counter;
+            let slot = &mut inner[index];
+            if slot.is_none() {
+                *slot = Some(f()?);
             }
-            Entry::Occupied(entry) => Pin::new(this.inner.get_mut().get_mut(*entry.get()).unwrap()),
+            let _ = this.mapping.insert(database, index);
+            *this.counter += 1;
+            return Ok(Pin::new(slot.as_mut().unwrap()));
+        }
+
+        // Every slot was claimed, take back the ones of dropped databases
+        let dropped = this
+            .mapping
+            .iter()
+            .filter(|(oid, _)| is_dropped(**oid))
+            .map(|(oid, index)| (*oid, *index))
+            .collect::<Vec<_, N>>();
+        for (oid, index) in dropped {
+            this.mapping.remove(&oid);
+            let _ = this.released.push(index);
+        }
+        let index = match this.released.last() {
+            Some(index) => *index,
+            None => panic!("all {} slots are claimed by other databases", N),
+        };
+        let slot = &mut inner[index];
+        match f() {
+            Ok(value) => *slot = Some(value),
+            Err(err) if slot.is_none() => return Err(err),
+            Err(_) => {}
         }
+        this.released.pop();
+        let _ = this.mapping.insert(database, index);
+        Ok(Pin::new(slot.as_mut().unwrap()))
+    }
+
+    /// Index of the slot the current database is mapped to, if any
+    ///
+    /// Unlike [`DatabaseLocal::for_my_database`], this never assigns a slot.
+    pub fn slot_index(self: Pin<&Self>) -> Option<usize> {
+        self.get_ref()
+            .mapping
+            .get(unsafe { &pg_sys::MyDatabaseId })
+            .copied()
+    }
+
+    /// Slot at `index`, as given by [`DatabaseLocal::slot_index`], if it's constructed
+    pub fn slot(self: Pin<&Self>, index: usize) -> Option<Pin<&T>> {
+        self.get_ref()
+            .inner
+            .get(index)
+            .and_then(|slot| slot.as_ref())
+            .map(Pin::new)
+    }
+}
+
+unsafe impl<T: Unpin + ShmemSafe, const N: usize> SyncMut for DatabaseLocal<T, N> {}
+unsafe impl<T: Unpin + ShmemSafe, const N: usize> ShmemSafe for DatabaseLocal<T, N> {}
+
+/// How many dropped databases are remembered until their slots are taken back
+const MAX_DROPPED: usize = 64;
+
+type Dropped = Deque<Oid, MAX_DROPPED>;
+
+fn dropped() -> *mut Dropped {
+    let addin_shmem_init_lock: *mut pg_sys::LWLock =
+        unsafe { &mut (*pg_sys::MainLWLockArray.add(21)).lock };
+    unsafe {
+        pg_sys::LWLockAcquire(addin_shmem_init_lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+        let mut found = false;
+        let ptr = pg_sys::ShmemInitStruct(
+            cstr!("pgextkit_dropped_databases").as_ptr(),
+            size_of::<Dropped>(),
+            &mut found,
+        ) as *mut Dropped;
+        if !found {
+            ptr.write(Deque::new());
+        }
+        pg_sys::LWLockRelease(addin_shmem_init_lock);
+        ptr
     }
 }
 
-unsafe impl<T: Unpin, const N: usize> SyncMut for DatabaseLocal<T, N> {}
+fn with_dropped<R, F: FnOnce(&mut Dropped) -> R>(mode: pg_sys::LWLockMode, f: F) -> R {
+    let dropped = dropped();
+    let lock = unsafe {
+        &mut (*pg_sys::GetNamedLWLockTranche(cstr!("pgextkit_dropped_databases").as_ptr())).lock
+    };
+    unsafe {
+        pg_sys::LWLockAcquire(lock, mode);
+    }
+    let result = f(unsafe { &mut *dropped });
+    unsafe {
+        pg_sys::LWLockRelease(lock);
+    }
+    result
+}
+
+fn is_dropped(database: Oid) -> bool {
+    with_dropped(pg_sys::LWLockMode_LW_SHARED, |dropped| {
+        dropped.iter().any(|oid| *oid == database)
+    })
+}
+
+/// Lets other databases claim the slots the dropped `database` had in every
+/// [`DatabaseLocal`]
+///
+/// Slots are only taken back once all of them are claimed, by then the oldest of the
+/// databases dropped may have been forgotten.
+#[cfg(feature = "extension")]
+pub(crate) fn forget_database(database: Oid) {
+    with_dropped(pg_sys::LWLockMode_LW_EXCLUSIVE, |dropped| {
+        if dropped.is_full() {
+            dropped.pop_front();
+        }
+        let _ = dropped.push_back(database);
+    });
+}
+
+#[cfg(feature = "extension")]
+pub(crate) fn size() -> usize {
+    size_of::<Dropped>()
+}
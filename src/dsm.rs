@@ -0,0 +1,66 @@
+use pgx::pg_sys;
+
+/// Dynamic shared memory segment
+///
+/// Unlike memory allocated with `Handle::allocate_shmem`, segments can be created at any
+/// time after startup. Other backends attach to a segment with its [`DsmSegment::handle`],
+/// which can be shared through a [`SharedDictionary`](crate::shmem::SharedDictionary) entry.
+///
+/// The segment stays mapped until it is detached or the backend exits, and is freed
+/// once the last backend detaches from it.
+pub struct DsmSegment {
+    seg: *mut pg_sys::dsm_segment,
+}
+
+unsafe impl Send for DsmSegment {}
+unsafe impl Sync for DsmSegment {}
+
+impl DsmSegment {
+    /// Creates a segment of `size` bytes
+    pub fn create(size: usize) -> Self {
+        if unsafe { !pg_sys::IsUnderPostmaster } {
+            pgx::error!("dynamic shared memory can only be allocated after startup");
+        }
+        let seg = unsafe { pg_sys::dsm_create(size, 0) };
+        // Keep the mapping beyond the current resource owner
+        unsafe { pg_sys::dsm_pin_mapping(seg) }
+        Self { seg }
+    }
+
+    /// Attaches to the segment, returns `None` if it no longer exists
+    pub fn attach(handle: pg_sys::dsm_handle) -> Option<Self> {
+        let seg = unsafe {
+            match pg_sys::dsm_find_mapping(handle) {
+                seg if seg.is_null() => pg_sys::dsm_attach(handle),
+                seg => seg,
+            }
+        };
+        if seg.is_null() {
+            return None;
+        }
+        unsafe { pg_sys::dsm_pin_mapping(seg) }
+        Some(Self { seg })
+    }
+
+    /// Handle other backends can attach to this segment with
+    pub fn handle(&self) -> pg_sys::dsm_handle {
+        unsafe { pg_sys::dsm_segment_handle(self.seg) }
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        unsafe { pg_sys::dsm_segment_address(self.seg) as *mut u8 }
+    }
+
+    pub fn len(&self) -> usize {
+        unsafe { pg_sys::dsm_segment_map_length(self.seg) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Unmaps the segment from this backend, freeing it if no other backend is attached
+    pub fn detach(self) {
+        unsafe { pg_sys::dsm_detach(self.seg) }
+    }
+}
@@ -0,0 +1,130 @@
+//! Shared memory allocated by extensions loaded once the server is running
+//!
+//! Unlike memory allocated while preloading, it comes from pgextkit's arena or overflow
+//! pool, so it is given back when the extension is unloaded.
+use crate::ext::deallocate;
+use crate::ext::usage::ShmemUsage;
+use crate::ext::{shmem_struct, with_named_lock};
+use crate::shmem::{SharedDictionary, TruncatingFrom};
+use cstr_core::cstr;
+use pgx::pg_sys;
+use std::alloc::Layout;
+
+const MAX_ALLOCATIONS: usize = 4096;
+
+#[derive(Clone)]
+struct Allocation {
+    extension: heapless::String<64>,
+    start: usize,
+    size: usize,
+    align: usize,
+    /// Whether it was allocated from the overflow pool
+    overflow: bool,
+}
+
+type List = heapless::Vec<Allocation, MAX_ALLOCATIONS>;
+
+/// Allocations of every loaded extension
+pub(crate) struct Allocations {
+    list: *mut List,
+}
+
+impl Default for Allocations {
+    fn default() -> Self {
+        Self {
+            list: unsafe { shmem_struct(cstr!("pgextkit_allocations"), heapless::Vec::new) },
+        }
+    }
+}
+
+impl Allocations {
+    fn with_lock<R, F: FnOnce(&mut List) -> R>(&self, f: F) -> R {
+        with_named_lock(
+            cstr!("pgextkit_allocations"),
+            pg_sys::LWLockMode_LW_EXCLUSIVE,
+            || f(unsafe { &mut *self.list }),
+        )
+    }
+
+    pub(crate) fn record(&mut self, extension: &str, ptr: *mut u8, layout: Layout, overflow: bool) {
+        let allocation = Allocation {
+            extension: heapless::String::truncating_from(extension),
+            start: ptr as usize,
+            size: layout.size(),
+            align: layout.align(),
+            overflow,
+        };
+        if self.with_lock(|list| list.push(allocation)).is_err() {
+            pgx::warning!(
+                "Too many allocations to track, {} bytes allocated by {} won't be freed when it's unloaded",
+                layout.size(),
+                extension
+            );
+        }
+    }
+
+    /// Frees everything the extension allocated
+    ///
+    /// Dictionary entries pointing into the memory are removed, so they aren't found once
+    /// the memory is handed out again.
+    pub(crate) fn free_all(&mut self, extension: &str) {
+        let extension = heapless::String::<64>::truncating_from(extension);
+        let freed = self.with_lock(|list| {
+            let mut freed = vec![];
+            let mut i = 0;
+            while i < list.len() {
+                if list[i].extension == extension {
+                    freed.push(list.swap_remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+            freed
+        });
+        for allocation in freed {
+            let layout = Layout::from_size_align(allocation.size, allocation.align)
+                .expect("recorded layout");
+            free(
+                &extension,
+                allocation.start as *mut u8,
+                layout,
+                allocation.overflow,
+            );
+        }
+    }
+
+    /// Frees a single allocation of the extension, such as one of an extension whose
+    /// loading failed
+    pub(crate) fn free_one(
+        &mut self,
+        extension: &str,
+        ptr: *mut u8,
+        layout: Layout,
+        overflow: bool,
+    ) {
+        self.with_lock(|list| {
+            if let Some(index) = list
+                .iter()
+                .position(|allocation| allocation.start == ptr as usize)
+            {
+                list.swap_remove(index);
+            }
+        });
+        free(extension, ptr, layout, overflow);
+    }
+
+    pub(crate) fn size() -> usize {
+        std::mem::size_of::<List>()
+    }
+}
+
+fn free(extension: &str, ptr: *mut u8, layout: Layout, overflow: bool) {
+    // The dictionary must not point to memory that can be handed out again
+    SharedDictionary::default().remove_within(ptr, layout.size());
+    if overflow {
+        crate::overflow::release(ptr, layout.size());
+    } else {
+        unsafe { deallocate(ptr, layout) };
+    }
+    ShmemUsage::default().release(extension, layout.pad_to_align().size(), overflow);
+}
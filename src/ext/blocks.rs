@@ -0,0 +1,111 @@
+use crate::ext::{shmem_struct, with_named_lock};
+use cstr_core::cstr;
+use pgx::pg_sys;
+
+const MAX_BLOCKS: usize = 4096;
+
+#[derive(Clone, Copy)]
+struct Block {
+    start: usize,
+    size: usize,
+}
+
+struct Blocks {
+    /// Start and size of the arena
+    arena: (usize, usize),
+    /// Blocks allocated from the arena, ordered by address
+    blocks: heapless::Vec<Block, MAX_BLOCKS>,
+    /// Set once a block couldn't be recorded, the free blocks are unknown from then on
+    untracked: bool,
+}
+
+/// Blocks allocated from pgextkit's arena, to tell how fragmented it is without
+/// querying the allocator
+pub(crate) struct ArenaBlocks {
+    blocks: *mut Blocks,
+}
+
+impl Default for ArenaBlocks {
+    fn default() -> Self {
+        Self {
+            blocks: unsafe {
+                shmem_struct(cstr!("pgextkit_arena_blocks"), || Blocks {
+                    arena: (0, 0),
+                    blocks: heapless::Vec::new(),
+                    untracked: false,
+                })
+            },
+        }
+    }
+}
+
+impl ArenaBlocks {
+    fn with_lock<R, F: FnOnce(&mut Blocks) -> R>(&self, mode: pg_sys::LWLockMode, f: F) -> R {
+        with_named_lock(cstr!("pgextkit_arena_blocks"), mode, || {
+            f(unsafe { &mut *self.blocks })
+        })
+    }
+
+    /// Records where the arena is, once it's created
+    pub(crate) fn set_arena(&mut self, start: usize, size: usize) {
+        self.with_lock(pg_sys::LWLockMode_LW_EXCLUSIVE, |blocks| {
+            blocks.arena = (start, size);
+            blocks.blocks.clear();
+            blocks.untracked = false;
+        })
+    }
+
+    pub(crate) fn record(&mut self, ptr: *mut u8, size: usize) {
+        let block = Block {
+            start: ptr as usize,
+            size,
+        };
+        self.with_lock(pg_sys::LWLockMode_LW_EXCLUSIVE, |blocks| {
+            let index = blocks
+                .blocks
+                .partition_point(|other| other.start < block.start);
+            if blocks.blocks.push(block).is_err() {
+                blocks.untracked = true;
+                return;
+            }
+            blocks.blocks[index..].rotate_right(1);
+        })
+    }
+
+    pub(crate) fn forget(&mut self, ptr: *mut u8) {
+        self.with_lock(pg_sys::LWLockMode_LW_EXCLUSIVE, |blocks| {
+            if let Ok(index) = blocks
+                .blocks
+                .binary_search_by_key(&(ptr as usize), |block| block.start)
+            {
+                blocks.blocks[index..].rotate_left(1);
+                blocks.blocks.pop();
+            }
+        })
+    }
+
+    /// Largest range of the arena between allocated blocks, `None` if not every block
+    /// could be recorded
+    ///
+    /// The allocator keeps some bookkeeping next to each block, so this is an upper bound
+    /// of the largest allocation that can succeed.
+    pub(crate) fn largest_free(&self) -> Option<usize> {
+        self.with_lock(pg_sys::LWLockMode_LW_SHARED, |blocks| {
+            if blocks.untracked {
+                return None;
+            }
+            let (start, size) = blocks.arena;
+            let mut largest = 0;
+            let mut free_from = start;
+            for block in &blocks.blocks {
+                largest = largest.max(block.start.saturating_sub(free_from));
+                free_from = free_from.max(block.start + block.size);
+            }
+            Some(largest.max((start + size).saturating_sub(free_from)))
+        })
+    }
+
+    pub(crate) fn size() -> usize {
+        std::mem::size_of::<Blocks>()
+    }
+}
@@ -0,0 +1,58 @@
+use crate::ext::{shmem_struct, with_named_lock};
+use crate::shmem::TruncatingFrom;
+use cstr_core::cstr;
+use heapless::FnvIndexSet;
+use pgx::pg_sys;
+
+const MAX_DISABLED_WORKERS: usize = 1024;
+
+type Set = FnvIndexSet<(pg_sys::Oid, heapless::String<64>), MAX_DISABLED_WORKERS>;
+
+/// Extensions whose background workers are disabled in a particular database
+pub(crate) struct DisabledWorkers {
+    set: *mut Set,
+}
+
+impl Default for DisabledWorkers {
+    fn default() -> Self {
+        Self {
+            set: unsafe { shmem_struct(cstr!("pgextkit_disabled_workers"), FnvIndexSet::new) },
+        }
+    }
+}
+
+impl DisabledWorkers {
+    fn with_lock<R, F: FnOnce(&mut Set) -> R>(&self, mode: pg_sys::LWLockMode, f: F) -> R {
+        with_named_lock(cstr!("pgextkit_disabled_workers"), mode, || {
+            f(unsafe { &mut *self.set })
+        })
+    }
+
+    pub(crate) fn is_disabled(&self, database: pg_sys::Oid, extension: &str) -> bool {
+        let key = (database, heapless::String::truncating_from(extension));
+        self.with_lock(pg_sys::LWLockMode_LW_SHARED, |set| set.contains(&key))
+    }
+
+    /// Enables or disables the extension's workers in the database, returns `false`
+    /// if they already were
+    pub(crate) fn set_enabled(
+        &mut self,
+        database: pg_sys::Oid,
+        extension: &str,
+        enabled: bool,
+    ) -> Result<bool, anyhow::Error> {
+        let key = (database, heapless::String::truncating_from(extension));
+        self.with_lock(pg_sys::LWLockMode_LW_EXCLUSIVE, |set| {
+            if enabled {
+                Ok(set.remove(&key))
+            } else {
+                set.insert(key)
+                    .map_err(|_| anyhow::Error::msg("too many disabled workers"))
+            }
+        })
+    }
+
+    pub(crate) fn size() -> usize {
+        std::mem::size_of::<Set>()
+    }
+}
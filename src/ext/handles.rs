@@ -0,0 +1,277 @@
+use crate::ext::{shmem_struct, with_named_lock};
+use crate::shmem::TruncatingFrom;
+use cstr_core::cstr;
+use heapless::FnvIndexMap;
+use pgx::{check_for_interrupts, pg_sys};
+use std::time::{Duration, Instant};
+
+const MAX_EXTENSIONS: usize = 128;
+const MAX_WORKERS_PER_EXTENSION: usize = 32;
+
+/// How often startup or shutdown of a worker is checked for
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Copy of Postgres' `BackgroundWorkerHandle`, which is opaque in its headers
+///
+/// Unlike the workers' PIDs, it remains valid in any backend and after the worker is gone.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct WorkerHandle {
+    slot: std::ffi::c_int,
+    generation: u64,
+}
+
+impl WorkerHandle {
+    /// Copies the handle returned by `RegisterDynamicBackgroundWorker`
+    pub(crate) unsafe fn from_raw(handle: *const pg_sys::BackgroundWorkerHandle) -> Self {
+        *(handle as *const Self)
+    }
+
+    fn as_ptr(&self) -> *mut pg_sys::BackgroundWorkerHandle {
+        // Postgres doesn't modify the handle, it takes a mutable pointer nonetheless
+        self as *const Self as *mut _
+    }
+
+    /// PID of the worker if it's running
+    pub(crate) fn pid(&self) -> Option<pg_sys::pid_t> {
+        let mut pid = 0;
+        match unsafe { pg_sys::GetBackgroundWorkerPid(self.as_ptr(), &mut pid) } {
+            pg_sys::BgwHandleStatus_BGWH_STARTED => Some(pid),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        let mut pid = 0;
+        unsafe {
+            pg_sys::GetBackgroundWorkerPid(self.as_ptr(), &mut pid)
+                == pg_sys::BgwHandleStatus_BGWH_STOPPED
+        }
+    }
+
+    /// Waits for the worker to start, returns its PID or `None` if it stopped or
+    /// didn't start within `timeout`
+    ///
+    /// `cancelled` is checked between polls, so that the waiting backend can still shut down
+    /// (or react to postmaster death) while the worker is slow to start.
+    pub(crate) fn wait_for_startup<F: Fn() -> bool>(
+        &self,
+        timeout: Duration,
+        cancelled: F,
+    ) -> Option<pg_sys::pid_t> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut pid = 0;
+            match unsafe { pg_sys::GetBackgroundWorkerPid(self.as_ptr(), &mut pid) } {
+                pg_sys::BgwHandleStatus_BGWH_STARTED => return Some(pid),
+                pg_sys::BgwHandleStatus_BGWH_NOT_YET_STARTED => {}
+                _ => return None,
+            }
+            if Instant::now() >= deadline || cancelled() {
+                return None;
+            }
+            let rc = unsafe {
+                let rc = pg_sys::WaitLatch(
+                    pg_sys::MyLatch,
+                    (pg_sys::WL_LATCH_SET | pg_sys::WL_TIMEOUT | pg_sys::WL_POSTMASTER_DEATH) as _,
+                    POLL_INTERVAL.as_millis() as _,
+                    pg_sys::PG_WAIT_EXTENSION,
+                );
+                pg_sys::ResetLatch(pg_sys::MyLatch);
+                rc
+            };
+            check_for_interrupts!();
+            if rc as u32 & pg_sys::WL_POSTMASTER_DEATH != 0 {
+                return None;
+            }
+        }
+    }
+
+    /// Waits for all workers of the extension to run and to have reported a heartbeat
+    /// (see [`crate::heartbeat::beat`]), returns `false` if they didn't within `timeout`
+    ///
+    /// Stopped workers aren't waited for. An extension without any running worker isn't
+    /// ready, as its workers may not be registered yet.
+    pub(crate) fn wait_for_ready(extension: &str, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let beating = crate::heartbeat::beating_pids();
+            let mut running = WorkerHandles::default()
+                .of(extension)
+                .into_iter()
+                .filter(|(_, handle)| !handle.is_stopped())
+                .peekable();
+            let any_running = running.peek().is_some();
+            if any_running
+                && running
+                    .all(|(_, handle)| handle.pid().map_or(false, |pid| beating.contains(&pid)))
+            {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            unsafe {
+                pg_sys::WaitLatch(
+                    pg_sys::MyLatch,
+                    (pg_sys::WL_LATCH_SET | pg_sys::WL_TIMEOUT | pg_sys::WL_POSTMASTER_DEATH) as _,
+                    POLL_INTERVAL.as_millis() as _,
+                    pg_sys::PG_WAIT_EXTENSION,
+                );
+                pg_sys::ResetLatch(pg_sys::MyLatch);
+            }
+            check_for_interrupts!();
+        }
+    }
+
+    /// Waits for the worker to stop, returns `false` if it didn't within `timeout`
+    pub(crate) fn wait_for_shutdown(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !self.is_stopped() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            unsafe {
+                pg_sys::WaitLatch(
+                    pg_sys::MyLatch,
+                    (pg_sys::WL_LATCH_SET | pg_sys::WL_TIMEOUT | pg_sys::WL_POSTMASTER_DEATH) as _,
+                    POLL_INTERVAL.as_millis() as _,
+                    pg_sys::PG_WAIT_EXTENSION,
+                );
+                pg_sys::ResetLatch(pg_sys::MyLatch);
+            }
+            check_for_interrupts!();
+        }
+        true
+    }
+
+    pub(crate) fn terminate(&self) {
+        unsafe { pg_sys::TerminateBackgroundWorker(self.as_ptr()) }
+    }
+}
+
+type Map = FnvIndexMap<
+    heapless::String<64>,
+    heapless::Vec<(pg_sys::Oid, WorkerHandle), MAX_WORKERS_PER_EXTENSION>,
+    MAX_EXTENSIONS,
+>;
+
+/// Handles of the dynamic background workers started on behalf of each extension
+pub(crate) struct WorkerHandles {
+    map: *mut Map,
+}
+
+impl Default for WorkerHandles {
+    fn default() -> Self {
+        Self {
+            map: unsafe { shmem_struct(cstr!("pgextkit_worker_handles"), FnvIndexMap::new) },
+        }
+    }
+}
+
+impl WorkerHandles {
+    fn with_lock<R, F: FnOnce(&mut Map) -> R>(&self, f: F) -> R {
+        with_named_lock(
+            cstr!("pgextkit_worker_handles"),
+            pg_sys::LWLockMode_LW_EXCLUSIVE,
+            || f(unsafe { &mut *self.map }),
+        )
+    }
+
+    /// Remembers the worker as belonging to the extension and connected to the database
+    pub(crate) fn record(
+        &mut self,
+        extension: &str,
+        database: pg_sys::Oid,
+        handle: *mut pg_sys::BackgroundWorkerHandle,
+    ) {
+        self.restore(extension, database, unsafe {
+            WorkerHandle::from_raw(handle)
+        });
+    }
+
+    /// Puts back a worker returned by [`WorkerHandles::take`]
+    pub(crate) fn restore(&mut self, extension: &str, database: pg_sys::Oid, handle: WorkerHandle) {
+        let recorded = self.with_lock(|map| {
+            let extension = heapless::String::truncating_from(extension);
+            if !map.contains_key(&extension)
+                && map.insert(extension.clone(), heapless::Vec::new()).is_err()
+            {
+                return false;
+            }
+            let handles = map.get_mut(&extension).expect("just inserted");
+            // Forget workers that are gone to make room
+            handles.retain(|(_, handle)| !handle.is_stopped());
+            handles.push((database, handle)).is_ok()
+        });
+        if !recorded {
+            pgx::warning!(
+                "Too many background workers started by {}, it won't be waited for on unload",
+                extension
+            );
+        }
+    }
+
+    /// Returns the extension's workers, along with their databases
+    pub(crate) fn of(&self, extension: &str) -> Vec<(pg_sys::Oid, WorkerHandle)> {
+        self.with_lock(|map| {
+            map.get(&heapless::String::truncating_from(extension))
+                .map(|handles| handles.iter().copied().collect())
+                .unwrap_or_default()
+        })
+    }
+
+    /// Forgets and returns the extension's workers, along with their databases
+    pub(crate) fn take(&mut self, extension: &str) -> Vec<(pg_sys::Oid, WorkerHandle)> {
+        self.with_lock(|map| {
+            map.remove(&heapless::String::truncating_from(extension))
+                .map(|handles| handles.into_iter().collect())
+                .unwrap_or_default()
+        })
+    }
+
+    /// Forgets and returns the extension's workers connected to the database
+    pub(crate) fn take_in_database(
+        &mut self,
+        extension: &str,
+        database: pg_sys::Oid,
+    ) -> Vec<WorkerHandle> {
+        self.with_lock(|map| {
+            let mut result = vec![];
+            if let Some(handles) = map.get_mut(&heapless::String::truncating_from(extension)) {
+                handles.retain(|(database_, handle)| {
+                    if *database_ == database {
+                        result.push(*handle);
+                        false
+                    } else {
+                        true
+                    }
+                });
+            }
+            result
+        })
+    }
+
+    /// Forgets and returns the workers of all extensions connected to the database, along
+    /// with their extensions
+    pub(crate) fn take_database(&mut self, database: pg_sys::Oid) -> Vec<(String, WorkerHandle)> {
+        self.with_lock(|map| {
+            let mut result = vec![];
+            for (extension, handles) in map.iter_mut() {
+                handles.retain(|(database_, handle)| {
+                    if *database_ == database {
+                        result.push((extension.to_string(), *handle));
+                        false
+                    } else {
+                        true
+                    }
+                });
+            }
+            result
+        })
+    }
+
+    pub(crate) fn size() -> usize {
+        std::mem::size_of::<Map>()
+    }
+}
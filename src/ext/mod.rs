@@ -1,45 +1,66 @@
-use super::Magic;
-use crate::shmem::SharedDictionary;
-use crate::{Handle, VERSION};
+use super::{Magic, Manifest};
+use crate::db::DatabaseLocal;
+use crate::lwlock::PgDynamicLwLock;
+use crate::shmem::{
+    SharedDictionary, DEFAULT_MAX_ATTACHMENTS, DEFAULT_OVERFLOW_ENTRIES, DICTIONARY_PARTITIONS,
+};
+use crate::{
+    Handle, NewDatabaseCallback, RestartPolicy, ABI_FINGERPRINT, PG_MAJOR_VERSION, VERSION,
+};
+use allocations::Allocations;
+use blocks::ArenaBlocks;
 use cstr_core::{cstr, CStr, CString};
+use disabled::DisabledWorkers;
 use good_memory_allocator::SpinLockedAllocator;
+use handles::{WorkerHandle, WorkerHandles};
 use pgx::bgworkers::BackgroundWorkerBuilder;
 use pgx::pg_sys::{AccessShareLock, ExtensionRelationId, ScanDirection_ForwardScanDirection};
 use pgx::prelude::*;
-use pgx::{pg_sys, FromDatum, GucContext, GucRegistry, GucSetting, IntoDatum};
-use std::collections::HashMap;
+use pgx::{
+    ereport, pg_sys, FromDatum, GucContext, GucRegistry, GucSetting, IntoDatum, PgLogLevel,
+    PgSqlErrorCode, PgTryBuilder, PostgresGucEnum,
+};
+use registry::{LoadedExtension, Registry};
+use restarts::RestartTracker;
+use rollback::StagedLoad;
+use std::collections::{HashMap, HashSet};
 use std::convert::AsRef;
 use std::fs::{DirEntry, File};
 use std::io::{BufRead, BufReader};
 use std::mem::size_of;
 use std::num::NonZeroUsize;
+use std::panic::AssertUnwindSafe;
 use std::path::{Path, PathBuf};
 use std::ptr::null_mut;
 use std::time::Duration;
+use usage::ShmemUsage;
 
-mod workers;
+pub(crate) mod allocations;
+pub(crate) mod blocks;
+pub(crate) mod disabled;
+pub(crate) mod handles;
+pub(crate) mod registry;
+pub(crate) mod restarts;
+pub(crate) mod rollback;
+pub(crate) mod usage;
+pub(crate) mod workers;
 
 pgx::pg_module_magic!();
 
 // Ensure pgexkit is preloaded, otherwise it's not very useful
+// (loading the library outside of `shared_preload_libraries` fails in `_PG_init`)
 extension_sql!(
     r#"
-DO $$
-DECLARE
-s TEXT;
-BEGIN
-SELECT name FROM pg_settings WHERE name = 'shared_preload_libraries' AND setting LIKE '%pgextkit%' INTO s;
-IF count(s) = 0
-  THEN RAISE EXCEPTION 'postgresql.conf must contain pgexitkit.so in shared_preload_libraries';
-END IF;
-END $$;
+SELECT pgextkit.ensure_preloaded();
 "#,
     name = "config_check",
-    bootstrap
+    requires = [ensure_preloaded]
 );
 
 static mut ALLOC_CALLBACKS: Vec<(
-    extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_void),
+    String,
+    std::ffi::c_int,
+    extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_void, bool),
     usize,
     *const std::ffi::c_void,
 )> = vec![];
@@ -51,24 +72,226 @@ static mut SHMEM_SIZE: usize = 0;
 static SHMEM_SIZE_SETTING: GucSetting<Option<&str>> =
     GucSetting::<Option<&str>>::new(Some("16 MiB"));
 
-static mut BACKGROUND_WORKERS: Vec<(String, String, Box<pg_sys::BackgroundWorker>)> = vec![];
+// Shared memory allocated once the server is running comes from two tiers: the arena of
+// `pgextkit.shmem_size`, set aside at startup, and once it's full, the overflow pool of DSM
+// segments (see `crate::overflow`). The pool is created on demand, up to
+// `pgextkit.overflow_shmem_size`, which can be raised with a reload as long as it stays
+// within the address space reserved at startup (`pgextkit.max_overflow_shmem_size`).
+
+static MAX_OVERFLOW_SHMEM_SIZE_SETTING: GucSetting<Option<&str>> =
+    GucSetting::<Option<&str>>::new(Some("0"));
+
+static OVERFLOW_SHMEM_SIZE_SETTING: GucSetting<Option<&str>> =
+    GucSetting::<Option<&str>>::new(Some("0"));
+
+static MAX_DICTIONARY_ENTRIES_SETTING: GucSetting<i32> =
+    GucSetting::<i32>::new(DEFAULT_MAX_ATTACHMENTS as i32);
+
+static DICTIONARY_OVERFLOW_ENTRIES_SETTING: GucSetting<i32> =
+    GucSetting::<i32>::new(DEFAULT_OVERFLOW_ENTRIES as i32);
+
+static MASTER_POLL_INTERVAL_SETTING: GucSetting<i32> = GucSetting::<i32>::new(10_000);
+
+static WORKER_STARTUP_TIMEOUT_SETTING: GucSetting<i32> = GucSetting::<i32>::new(10_000);
+
+static DATABASE_WORKER_MAX_RESTARTS_SETTING: GucSetting<i32> = GucSetting::<i32>::new(5);
+
+static LOCK_STATS_SETTING: GucSetting<bool> = GucSetting::<bool>::new(false);
+
+/// Level of the messages about preparing, loading and unloading extensions
+#[derive(PostgresGucEnum, Clone, Copy, PartialEq, Eq, Debug)]
+enum LogLevel {
+    Debug2,
+    Debug1,
+    Log,
+}
+
+impl LogLevel {
+    fn as_pg(self) -> PgLogLevel {
+        match self {
+            LogLevel::Debug2 => PgLogLevel::DEBUG2,
+            LogLevel::Debug1 => PgLogLevel::DEBUG1,
+            LogLevel::Log => PgLogLevel::LOG,
+        }
+    }
+}
+
+static LOG_LEVEL_SETTING: GucSetting<LogLevel> = GucSetting::new(LogLevel::Debug1);
+
+/// Reports progress of loading or unloading an extension at the level of
+/// `pgextkit.log_level`, warnings and errors are always reported
+fn log_progress<S: AsRef<str>>(message: S) {
+    ereport!(
+        LOG_LEVEL_SETTING.get().as_pg(),
+        PgSqlErrorCode::ERRCODE_SUCCESSFUL_COMPLETION,
+        message.as_ref()
+    );
+}
+
+static mut BACKGROUND_WORKERS: Vec<(
+    String,
+    String,
+    Box<pg_sys::BackgroundWorker>,
+    Option<RestartPolicy>,
+)> = vec![];
+
+static mut SHMEM_QUOTAS: Vec<(String, usize)> = vec![];
+
+/// Number of times this process allocated shared memory or registered workers on behalf
+/// of an extension, used to tell whether an extension needs deinitializing
+static mut ACQUIRED_RESOURCES: usize = 0;
+
+static mut NEW_DATABASE_CALLBACKS: Vec<(String, NewDatabaseCallback)> = vec![];
+
+static mut LWLOCK_TRANCHES: Vec<(*const std::ffi::c_char, std::ffi::c_int)> = vec![];
+
+static mut PRELOADED_EXTENSIONS: Vec<(String, String)> = vec![];
+
+static mut PRELOADED: bool = false;
+
+static mut BUILD_FINGERPRINTS: Vec<(PathBuf, u64)> = vec![];
 
 /// Initialization (happens when pgextkit is being preloaded)
 #[pg_guard]
 pub extern "C" fn _PG_init() {
+    check_preloaded();
+    unsafe {
+        PRELOADED = true;
+    }
+
+    GucRegistry::define_string_guc(
+        "pgextkit.shmem_size",
+        "Shared memory size for pgextkit extensions",
+        "Shared memory size for pgextkit extensions",
+        &SHMEM_SIZE_SETTING,
+        GucContext::Postmaster,
+    );
+    GucRegistry::define_string_guc(
+        "pgextkit.max_overflow_shmem_size",
+        "Address space reserved for pgextkit's overflow shared memory",
+        "Address space reserved at startup for the shared memory pgextkit extensions allocate once pgextkit.shmem_size is used up, pgextkit.overflow_shmem_size can be raised up to it (memory is only allocated as needed)",
+        &MAX_OVERFLOW_SHMEM_SIZE_SETTING,
+        GucContext::Postmaster,
+    );
+    GucRegistry::define_string_guc(
+        "pgextkit.overflow_shmem_size",
+        "Shared memory pgextkit extensions can allocate once pgextkit.shmem_size is used up",
+        "Shared memory pgextkit extensions can allocate once pgextkit.shmem_size is used up, at most pgextkit.max_overflow_shmem_size (can be changed with a reload)",
+        &OVERFLOW_SHMEM_SIZE_SETTING,
+        GucContext::Sighup,
+    );
+
+    GucRegistry::define_int_guc(
+        "pgextkit.max_dictionary_entries",
+        "Maximum number of entries in pgextkit's shared dictionary",
+        "Maximum number of entries in pgextkit's shared dictionary (rounded up to a power of two)",
+        &MAX_DICTIONARY_ENTRIES_SETTING,
+        16,
+        1 << 24,
+        GucContext::Postmaster,
+    );
+    GucRegistry::define_int_guc(
+        "pgextkit.dictionary_overflow_entries",
+        "Maximum number of entries spilling over once pgextkit's shared dictionary is full",
+        "Maximum number of entries of the overflow table used once pgextkit's shared dictionary is full (rounded up to a power of two)",
+        &DICTIONARY_OVERFLOW_ENTRIES_SETTING,
+        16,
+        1 << 20,
+        GucContext::Postmaster,
+    );
+    GucRegistry::define_int_guc(
+        "pgextkit.master_poll_interval",
+        "Interval at which pgextkit checks for new databases (in milliseconds)",
+        "Interval at which pgextkit's master worker checks for new or dropped databases (in milliseconds)",
+        &MASTER_POLL_INTERVAL_SETTING,
+        100,
+        3_600_000,
+        GucContext::Postmaster,
+    );
+
+    GucRegistry::define_int_guc(
+        "pgextkit.worker_startup_timeout",
+        "Time pgextkit waits for a database worker to start (in milliseconds)",
+        "Time pgextkit's master worker waits for a database worker to start before retrying later (in milliseconds)",
+        &WORKER_STARTUP_TIMEOUT_SETTING,
+        100,
+        3_600_000,
+        GucContext::Postmaster,
+    );
+
+    GucRegistry::define_int_guc(
+        "pgextkit.database_worker_max_restarts",
+        "Restarts after which pgextkit gives up on a crashing database worker (0 to never give up)",
+        "Number of times a database worker may restart within 10 minutes before pgextkit stops it and records an error in pgextkit.worker_errors() (0 to never give up)",
+        &DATABASE_WORKER_MAX_RESTARTS_SETTING,
+        0,
+        1000,
+        GucContext::Postmaster,
+    );
+
+    GucRegistry::define_bool_guc(
+        "pgextkit.lock_stats",
+        "Records time spent waiting for extensions' LWLocks",
+        "Records how long backends wait to acquire PgDynamicLwLocks, shown by pgextkit.lock_wait_stats()",
+        &LOCK_STATS_SETTING,
+        GucContext::Suset,
+    );
+
+    GucRegistry::define_enum_guc(
+        "pgextkit.log_level",
+        "Level of pgextkit's messages about loading extensions",
+        "Level of the messages pgextkit emits for every extension it prepares, loads or unloads (debug2, debug1 or log), warnings and errors are always logged",
+        &LOG_LEVEL_SETTING,
+        GucContext::Sighup,
+    );
+
+    pgx::log!(
+        "pgextkit: Initializing shared dictionary with {} entries",
+        SharedDictionary::max_entries()
+    );
+
+    let shmem_size = parse_size::parse_size(
+        SHMEM_SIZE_SETTING
+            .get()
+            .unwrap_or_else(|| "16MiB".to_string()),
+    )
+    .unwrap_or_else(|e| {
+        pgx::warning!(
+            "Invalid pgextkit.shmem_size setting ({}), setting it to its default (16MB)",
+            e
+        );
+        16 * 1024 * 1024
+    });
+    pgx::log!("pgextkit: Initializing with {} shmem", shmem_size);
+    unsafe {
+        SHMEM_SIZE = shmem_size as usize;
+    }
+    let max_overflow_shmem_size = setting_size(MAX_OVERFLOW_SHMEM_SIZE_SETTING.get())
+        .unwrap_or_else(|| {
+            pgx::warning!("Invalid pgextkit.max_overflow_shmem_size setting, not reserving any");
+            0
+        });
+    crate::overflow::reserve(max_overflow_shmem_size);
+
     // At this point, we don't know which extensions are installed, so we find all of them that
     // conform to pgexkit signature and load them speculatively.
     // At a later point, a background worker will be started and it will proceed with further initialization
     // if warranted.
 
-    for (name, version, path) in extkit_extensions() {
-        pgx::log!(
+    for ControlFile {
+        name,
+        version,
+        path,
+        ..
+    } in extkit_extensions()
+    {
+        log_progress(format!(
             "Preparing {}--{} at {}",
             name,
             version,
             path.to_string_lossy()
-        );
-        match unsafe { libloading::Library::new(path.as_os_str()) } {
+        ));
+        match open_library(&path) {
             Err(err) => {
                 pgx::warning!("Couldn't load {}: {}", path.to_string_lossy(), err);
             }
@@ -87,52 +310,34 @@ pub extern "C" fn _PG_init() {
                     }
                     Ok(init) => {
                         let handle = Handle::make_static(
-                            name,
-                            version,
+                            name.clone(),
+                            version.clone(),
                             path.file_stem()
                                 .expect("filename")
                                 .to_str()
                                 .expect("string"),
                         );
                         unsafe {
+                            let acquired = ACQUIRED_RESOURCES;
                             init(&handle);
+                            check_deinit(&lib, &name, ACQUIRED_RESOURCES > acquired);
+                            PRELOADED_EXTENSIONS.push((name, version));
                         }
-                        pgx::log!("Loaded pgextkit library {}", path.to_string_lossy());
+                        log_progress(format!(
+                            "Loaded pgextkit library {}",
+                            path.to_string_lossy()
+                        ));
                     }
                 }
             }
         }
     }
+    // Libraries of skipped extensions are no longer needed
+    unsafe { PROBED_LIBRARIES.clear() };
 
-    GucRegistry::define_string_guc(
-        "pgextkit.shmem_size",
-        "Shared memory size for pgextkit extensions",
-        "Shared memory size for pgextkit extensions",
-        &SHMEM_SIZE_SETTING,
-        GucContext::Postmaster,
-    );
-
-    let shmem_size = parse_size::parse_size(
-        SHMEM_SIZE_SETTING
-            .get()
-            .unwrap_or_else(|| "16MiB".to_string()),
-    )
-    .unwrap_or_else(|e| {
-        pgx::warning!(
-            "Invalid pgextkit.shmem_size setting ({}), setting it to its default (16MB)",
-            e
-        );
-        16 * 1024 * 1024
-    });
-    pgx::log!("pgextkit: Initializing with {} shmem", shmem_size);
-    unsafe {
-        SHMEM_SIZE = shmem_size as usize;
-    }
     #[cfg(not(feature = "pg15"))]
     unsafe {
-        pg_sys::RequestAddinShmemSpace(shmem_size as usize);
-        pg_sys::RequestAddinShmemSpace(SharedDictionary::size());
-        pg_sys::RequestNamedLWLockTranche(cstr!("pgextkit_shared_dictionary").as_ptr(), 1);
+        request_shmem();
     }
 
     unsafe {
@@ -146,13 +351,14 @@ pub extern "C" fn _PG_init() {
                 if let Some(i) = PREV_SHMEM_REQUEST_HOOK {
                     i();
                 }
-                pg_sys::RequestAddinShmemSpace(SHMEM_SIZE);
-                pg_sys::RequestAddinShmemSpace(SharedDictionary::size());
-                pg_sys::RequestNamedLWLockTranche(cstr!("pgextkit_shared_dictionary").as_ptr(), 1);
+                request_shmem();
 
-                for (_cb, size, _payload) in ALLOC_CALLBACKS.iter() {
+                for (_name, _priority, _cb, size, _payload) in ALLOC_CALLBACKS.iter() {
                     pg_sys::RequestAddinShmemSpace(*size);
                 }
+                for (tranche, count) in LWLOCK_TRANCHES.iter() {
+                    pg_sys::RequestNamedLWLockTranche(*tranche, *count);
+                }
             }
         }
 
@@ -168,6 +374,18 @@ pub extern "C" fn _PG_init() {
 
             // Ensure shared dictionary exists
             let _ = SharedDictionary::default();
+
+            let mut usage = ShmemUsage::default();
+            for (name, quota) in SHMEM_QUOTAS.drain(..) {
+                usage.set_quota(&name, quota);
+            }
+
+            let mut registry = Registry::default();
+            for (name, version) in PRELOADED_EXTENSIONS.drain(..) {
+                if let Err(err) = registry.claim(&name, &version, None) {
+                    pgx::warning!("Can't register {}--{}: {}", name, version, err);
+                }
+            }
             let shm_name = cstr!("pgextkit_shmem");
             let addin_shmem_init_lock: *mut pg_sys::LWLock =
                 &mut (*pg_sys::MainLWLockArray.add(21)).lock;
@@ -179,25 +397,28 @@ pub extern "C" fn _PG_init() {
 
             pg_sys::LWLockRelease(addin_shmem_init_lock);
 
+            if allocated_shmem == 0 {
+                ereport!(
+                    PgLogLevel::FATAL,
+                    PgSqlErrorCode::ERRCODE_OUT_OF_MEMORY,
+                    format!(
+                        "pgextkit couldn't allocate {} bytes of shared memory for extensions",
+                        SHMEM_SIZE
+                    )
+                    .as_str()
+                );
+            }
             if !ALLOCATOR.was_initialized() {
                 ALLOCATOR.init(allocated_shmem, SHMEM_SIZE);
+                ArenaBlocks::default().set_arena(allocated_shmem, SHMEM_SIZE);
             }
 
-            for (cb, size, payload) in ALLOC_CALLBACKS.drain(..) {
-                let shm_name = CString::new(uuid::Uuid::new_v4().to_string())
-                    .expect("can't create allocation name");
-                pg_sys::LWLockAcquire(addin_shmem_init_lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
-
-                let mut found = false;
-                let shmem = pg_sys::ShmemInitStruct(shm_name.into_raw(), size, &mut found);
-
-                pg_sys::LWLockRelease(addin_shmem_init_lock);
-
-                cb(shmem, payload);
-            }
+            init_allocations();
         }
     }
 
+    workers::install_database_hooks();
+
     BackgroundWorkerBuilder::new("pgextkit_master")
         .set_function("master_worker")
         .set_library("pgextkit")
@@ -208,14 +429,255 @@ pub extern "C" fn _PG_init() {
         .load();
 }
 
+fn check_preloaded() {
+    if unsafe { PRELOADED || pg_sys::process_shared_preload_libraries_in_progress } {
+        return;
+    }
+    not_preloaded();
+}
+
+/// Raises the error explaining how to preload pgextkit
+pub(crate) fn not_preloaded() {
+    let current = unsafe {
+        let value = pg_sys::GetConfigOption(
+            cstr!("shared_preload_libraries").as_ptr(),
+            true,
+            false,
+        );
+        if value.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr(value).to_string_lossy().to_string()
+        }
+    };
+    ereport!(
+        PgLogLevel::ERROR,
+        PgSqlErrorCode::ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE,
+        "pgextkit must be loaded via shared_preload_libraries",
+        format!(
+            "Add 'pgextkit' to shared_preload_libraries in postgresql.conf and restart the server (current value: '{}')",
+            current
+        )
+        .as_str()
+    );
+}
+
+#[pg_extern]
+fn ensure_preloaded() {
+    check_preloaded();
+}
+
+/// Finds or creates a named shared memory structure, initializing it with `init` when created
+unsafe fn shmem_struct<T, F: FnOnce() -> T>(name: &CStr, init: F) -> *mut T {
+    let addin_shmem_init_lock: *mut pg_sys::LWLock =
+        &mut (*pg_sys::MainLWLockArray.add(21)).lock;
+    pg_sys::LWLockAcquire(addin_shmem_init_lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+
+    let mut found = false;
+    let ptr = pg_sys::ShmemInitStruct(name.as_ptr(), size_of::<T>(), &mut found) as *mut T;
+    if !found {
+        ptr.write(init());
+    }
+
+    pg_sys::LWLockRelease(addin_shmem_init_lock);
+    ptr
+}
+
+/// Makes the allocations requested while preloading, higher priorities first and in
+/// registration order otherwise
+pub(crate) unsafe fn init_allocations() {
+    ALLOC_CALLBACKS.sort_by_key(|(_name, priority, ..)| std::cmp::Reverse(*priority));
+    for (name, _priority, cb, size, payload) in ALLOC_CALLBACKS.drain(..) {
+        let shm_name =
+            CString::new(uuid::Uuid::new_v4().to_string()).expect("can't create allocation name");
+        init_allocation(&shm_name, &name, size, cb, payload);
+    }
+}
+
+/// Allocates (or attaches to) the structure named `shm_name` for the extension `name` and
+/// calls `cb` with it, telling whether it already existed
+pub(crate) unsafe fn init_allocation(
+    shm_name: &CStr,
+    name: &str,
+    size: usize,
+    cb: extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_void, bool),
+    payload: *const std::ffi::c_void,
+) {
+    let addin_shmem_init_lock: *mut pg_sys::LWLock = &mut (*pg_sys::MainLWLockArray.add(21)).lock;
+    pg_sys::LWLockAcquire(addin_shmem_init_lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+
+    let mut found = false;
+    let shmem = pg_sys::ShmemInitStruct(shm_name.as_ptr(), size, &mut found);
+
+    pg_sys::LWLockRelease(addin_shmem_init_lock);
+
+    // Running the remaining callbacks against missing memory would corrupt it
+    if shmem.is_null() {
+        ereport!(
+            PgLogLevel::FATAL,
+            PgSqlErrorCode::ERRCODE_OUT_OF_MEMORY,
+            format!(
+                "pgextkit couldn't allocate {} bytes of shared memory for {}",
+                size, name
+            )
+            .as_str()
+        );
+    }
+
+    cb(shmem, payload, found);
+}
+
+/// Bytes of the overflow pool that can be allocated, as configured by
+/// `pgextkit.overflow_shmem_size`
+fn overflow_limit() -> usize {
+    setting_size(OVERFLOW_SHMEM_SIZE_SETTING.get()).unwrap_or(0)
+}
+
+/// Parses a size setting such as `pgextkit.overflow_shmem_size`
+fn setting_size(value: Option<String>) -> Option<usize> {
+    match value {
+        None => Some(0),
+        Some(value) => parse_size::parse_size(value).ok().map(|size| size as usize),
+    }
+}
+
+/// Runs `f` while holding the lock of the named LWLock tranche
+fn with_named_lock<R, F: FnOnce() -> R>(tranche: &CStr, mode: pg_sys::LWLockMode, f: F) -> R {
+    let lock = unsafe { &mut (*pg_sys::GetNamedLWLockTranche(tranche.as_ptr())).lock };
+    unsafe {
+        pg_sys::LWLockAcquire(lock, mode);
+    }
+    let result = f();
+    unsafe {
+        pg_sys::LWLockRelease(lock);
+    }
+    result
+}
+
+/// Requests shared memory and LWLock tranches used by pgextkit itself
+unsafe fn request_shmem() {
+    pg_sys::RequestAddinShmemSpace(SHMEM_SIZE);
+    pg_sys::RequestAddinShmemSpace(crate::overflow::size());
+    pg_sys::RequestNamedLWLockTranche(cstr!("pgextkit_overflow_pool").as_ptr(), 1);
+    pg_sys::RequestAddinShmemSpace(SharedDictionary::size());
+    pg_sys::RequestNamedLWLockTranche(
+        cstr!("pgextkit_shared_dictionary").as_ptr(),
+        DICTIONARY_PARTITIONS as _,
+    );
+    pg_sys::RequestAddinShmemSpace(ShmemUsage::size());
+    pg_sys::RequestNamedLWLockTranche(cstr!("pgextkit_shmem_usage").as_ptr(), 1);
+    pg_sys::RequestAddinShmemSpace(ArenaBlocks::size());
+    pg_sys::RequestNamedLWLockTranche(cstr!("pgextkit_arena_blocks").as_ptr(), 1);
+    pg_sys::RequestAddinShmemSpace(Allocations::size());
+    pg_sys::RequestNamedLWLockTranche(cstr!("pgextkit_allocations").as_ptr(), 1);
+    pg_sys::RequestAddinShmemSpace(size_of::<workers::MasterState>());
+    pg_sys::RequestNamedLWLockTranche(cstr!("pgextkit_master").as_ptr(), 1);
+    pg_sys::RequestAddinShmemSpace(RestartTracker::size());
+    pg_sys::RequestNamedLWLockTranche(cstr!("pgextkit_restarts").as_ptr(), 1);
+    pg_sys::RequestAddinShmemSpace(Registry::size());
+    pg_sys::RequestNamedLWLockTranche(cstr!("pgextkit_registry").as_ptr(), 1);
+    pg_sys::RequestAddinShmemSpace(WorkerHandles::size());
+    pg_sys::RequestNamedLWLockTranche(cstr!("pgextkit_worker_handles").as_ptr(), 1);
+    pg_sys::RequestAddinShmemSpace(DisabledWorkers::size());
+    pg_sys::RequestNamedLWLockTranche(cstr!("pgextkit_disabled_workers").as_ptr(), 1);
+    pg_sys::RequestAddinShmemSpace(crate::heartbeat::size());
+    pg_sys::RequestNamedLWLockTranche(cstr!("pgextkit_heartbeats").as_ptr(), 1);
+    pg_sys::RequestAddinShmemSpace(crate::worker_errors::size());
+    pg_sys::RequestNamedLWLockTranche(cstr!("pgextkit_worker_errors").as_ptr(), 1);
+    pg_sys::RequestAddinShmemSpace(crate::lock_stats::size());
+    pg_sys::RequestNamedLWLockTranche(cstr!("pgextkit_lock_stats").as_ptr(), 1);
+    pg_sys::RequestAddinShmemSpace(crate::db::size());
+    pg_sys::RequestNamedLWLockTranche(cstr!("pgextkit_dropped_databases").as_ptr(), 1);
+}
+
 fn substitute_libdir(s: &str) -> String {
     let pkglib = unsafe { CStr::from_ptr(pg_sys::pkglib_path.as_ptr()) }.to_string_lossy();
     let pkglib_str = pkglib.as_ref();
     s.replace("$libdir", pkglib_str)
 }
 
-fn has_magic(path: &PathBuf) -> Result<bool, anyhow::Error> {
-    let lib = unsafe { libloading::Library::new(path)? };
+const DLSUFFIX: &str = ".so";
+
+/// Resolves library name the same way PostgreSQL does: names without a directory
+/// are searched for in `dynamic_library_path`, others get `$libdir` substituted
+fn expand_dynamic_library_name(name: &str) -> PathBuf {
+    if !name.contains('/') {
+        if let Some(path) = find_in_dynamic_libpath(name)
+            .or_else(|| find_in_dynamic_libpath(format!("{}{}", name, DLSUFFIX).as_str()))
+        {
+            return path;
+        }
+    } else {
+        let path = PathBuf::from(substitute_libdir(name));
+        if path.is_file() {
+            return path;
+        }
+    }
+    PathBuf::from(format!("{}{}", substitute_libdir(name), DLSUFFIX))
+}
+
+fn find_in_dynamic_libpath(name: &str) -> Option<PathBuf> {
+    let dynamic_library_path = unsafe {
+        let value = pg_sys::GetConfigOption(cstr!("dynamic_library_path").as_ptr(), true, false);
+        if value.is_null() {
+            "$libdir".to_string()
+        } else {
+            CStr::from_ptr(value).to_string_lossy().to_string()
+        }
+    };
+    dynamic_library_path
+        .split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Path::new(&substitute_libdir(dir)).join(name))
+        .find(|path| path.is_file())
+}
+
+/// Libraries kept open after `has_magic` found their magic, so that loading them right
+/// after doesn't open them again
+static mut PROBED_LIBRARIES: Vec<(PathBuf, libloading::Library)> = vec![];
+
+/// Takes the library opened by `has_magic`, if any
+fn take_probed_library(path: &Path) -> Option<libloading::Library> {
+    let probed = unsafe { &mut PROBED_LIBRARIES };
+    let index = probed.iter().position(|(path_, _)| path_ == path)?;
+    Some(probed.swap_remove(index).1)
+}
+
+/// Number of libraries kept open by `has_magic`, for tests to check they don't pile up
+#[cfg(all(feature = "extension", any(test, feature = "pg_test")))]
+pub(crate) fn probed_libraries() -> usize {
+    unsafe { PROBED_LIBRARIES.len() }
+}
+
+/// Opens the library, reusing the handle `has_magic` opened it with
+fn open_library(path: &Path) -> Result<libloading::Library, libloading::Error> {
+    match take_probed_library(path) {
+        Some(lib) => Ok(lib),
+        None => unsafe { libloading::Library::new(path) },
+    }
+}
+
+fn has_magic(path: &Path) -> Result<bool, anyhow::Error> {
+    let lib = open_library(path)?;
+    let has_magic = check_magic(path, &lib)?;
+    if has_magic {
+        unsafe { PROBED_LIBRARIES.push((path.to_path_buf(), lib)) };
+    }
+    Ok(has_magic)
+}
+
+/// Like [`has_magic`], warning about libraries that can't be validated
+fn magic_or_warn(path: &Path) -> bool {
+    match has_magic(path) {
+        Ok(has_magic) => has_magic,
+        Err(err) => {
+            pgx::warning!("Can't validate {}: {:#}", path.to_string_lossy(), err);
+            false
+        }
+    }
+}
+
+fn check_magic(path: &Path, lib: &libloading::Library) -> Result<bool, anyhow::Error> {
     let magic = unsafe {
         lib.get::<unsafe extern "C" fn() -> *const Magic>(
             cstr!("pgextkit_magic").to_bytes_with_nul(),
@@ -227,22 +689,241 @@ fn has_magic(path: &PathBuf) -> Result<bool, anyhow::Error> {
         .and_then(|magic_func| {
             let magic: &'static Magic = unsafe { &*magic_func() };
             if magic.magic_size == size_of::<Magic>() && magic.version == VERSION {
-                Some(())
+                Some(magic)
             } else {
                 None
             }
         })
+        .map(|magic| {
+            // `pg_sys` layouts differ between major versions
+            if magic.pg_major_version != PG_MAJOR_VERSION {
+                return Err(anyhow::Error::msg(format!(
+                    "{} was built for PostgreSQL {}, but the server is PostgreSQL {}",
+                    path.to_string_lossy(),
+                    magic.pg_major_version,
+                    PG_MAJOR_VERSION
+                )));
+            }
+            check_fingerprints(path, magic)?;
+            if let Some(manifest) = manifest(lib) {
+                if !manifest.supports_pg_version(pg_sys::PG_VERSION_NUM) {
+                    return Err(anyhow::Error::msg(format!(
+                        "{} doesn't support PostgreSQL {}",
+                        path.to_string_lossy(),
+                        pg_sys::PG_VERSION_NUM
+                    )));
+                }
+            }
+            Ok(())
+        })
+        .transpose()?
         .is_some())
 }
 
-fn extkit_extensions() -> impl IntoIterator<Item = (String, String, PathBuf)> {
-    control_files()
-        .filter_map(|e| parse_control_file(&e).ok())
+/// Manifest exported by the library, if any
+fn manifest(lib: &libloading::Library) -> Option<&'static Manifest> {
+    let manifest = unsafe {
+        lib.get::<unsafe extern "C" fn() -> *const Manifest>(
+            cstr!("pgextkit_manifest").to_bytes_with_nul(),
+        )
+    }
+    .ok()?;
+    let manifest: &'static Manifest = unsafe { &*manifest() };
+    // Manifests only grow, older ones lack the fields added since
+    (manifest.manifest_size >= size_of::<Manifest>()).then_some(manifest)
+}
+
+/// Refuses extensions built against a different pgextkit, warns about those rebuilt since
+/// they were loaded
+pub(crate) fn check_fingerprints(path: &Path, magic: &Magic) -> Result<(), anyhow::Error> {
+    // Layouts shared with the extension may differ
+    if magic.abi_fingerprint != ABI_FINGERPRINT {
+        return Err(anyhow::Error::msg(format!(
+            "{} was built against a different pgextkit (fingerprint {:016x}, expected {:016x})",
+            path.to_string_lossy(),
+            magic.abi_fingerprint,
+            ABI_FINGERPRINT
+        )));
+    }
+    let fingerprints = unsafe { &mut BUILD_FINGERPRINTS };
+    match fingerprints
+        .iter_mut()
+        .find(|(path_, _)| path_.as_path() == path)
+    {
+        Some((_, fingerprint)) if *fingerprint != magic.build_fingerprint => {
+            pgx::warning!(
+                "{} has changed since it was loaded (fingerprint {:016x}, loaded {:016x})",
+                path.to_string_lossy(),
+                magic.build_fingerprint,
+                fingerprint
+            );
+            *fingerprint = magic.build_fingerprint;
+        }
+        Some(_) => {}
+        None => fingerprints.push((path.to_path_buf(), magic.build_fingerprint)),
+    }
+    Ok(())
+}
+
+fn extkit_extensions() -> Vec<ControlFile> {
+    let extensions = control_files()
+        .filter_map(|entry| match parse_control_file(&entry) {
+            Ok(control_file) => Some(control_file),
+            Err(err) => {
+                pgx::warning!(
+                    "Can't parse control file {}: {:#}",
+                    entry.path().to_string_lossy(),
+                    err
+                );
+                None
+            }
+        })
+        // pgextkit itself only exports a magic function in tests
+        .filter(|control_file| control_file.name != "pgextkit")
         // Check for magic function
-        .filter(|(_, _, ref path)| match has_magic(path) {
+        .filter(|control_file| match has_magic(&control_file.path) {
             Ok(has_magic) => has_magic,
-            Err(_err) => false,
+            Err(err) => {
+                pgx::warning!(
+                    "Skipping {}--{}: {}",
+                    control_file.name,
+                    control_file.version,
+                    err
+                );
+                false
+            }
+        })
+        .collect::<Vec<_>>();
+    order_by_dependencies(preferred_versions(extensions))
+}
+
+/// Keeps a single version of every extension, preferring more specific versions
+pub(crate) fn preferred_versions(mut extensions: Vec<ControlFile>) -> Vec<ControlFile> {
+    // Group by name, preferring more specific versions
+    extensions.sort_by(|x, y| {
+        x.name
+            .cmp(&y.name)
+            .then_with(|| more_specific_first(&x.version, &y.version))
+    });
+
+    let mut result: Vec<ControlFile> = vec![];
+    for control_file in extensions {
+        match result.last() {
+            Some(last) if last.name == control_file.name => {
+                pgx::warning!(
+                    "Skipping {}--{} at {} as version {} is preferred",
+                    control_file.name,
+                    control_file.version,
+                    control_file.path.to_string_lossy(),
+                    last.version
+                );
+            }
+            _ => result.push(control_file),
+        }
+    }
+    result
+}
+
+/// Orders extensions so that every extension comes after the extensions it requires,
+/// skipping those with missing or circular dependencies
+fn order_by_dependencies(extensions: Vec<ControlFile>) -> Vec<ControlFile> {
+    let available = control_files()
+        .filter_map(|entry| {
+            entry
+                .path()
+                .file_stem()
+                .and_then(|stem| stem.to_string_lossy().split("--").next().map(String::from))
         })
+        .collect::<HashSet<_>>();
+    order_by_dependencies_among(extensions, &available)
+}
+
+/// Like [`order_by_dependencies`], with the names of the `available` extensions
+pub(crate) fn order_by_dependencies_among(
+    extensions: Vec<ControlFile>,
+    available: &HashSet<String>,
+) -> Vec<ControlFile> {
+    let mut skipped = HashSet::new();
+    let mut ordered: Vec<ControlFile> = vec![];
+    let mut pending = extensions;
+    while !pending.is_empty() {
+        let pending_names = pending
+            .iter()
+            .map(|extension| extension.name.clone())
+            .collect::<HashSet<_>>();
+        let mut blocked = vec![];
+        let mut progress = false;
+        for extension in pending {
+            if let Some(missing) = extension
+                .requires
+                .iter()
+                .find(|required| !available.contains(*required) || skipped.contains(*required))
+            {
+                pgx::warning!(
+                    "Skipping {}--{}: required extension {} is not available",
+                    extension.name,
+                    extension.version,
+                    missing
+                );
+                skipped.insert(extension.name.clone());
+                progress = true;
+            } else if extension
+                .requires
+                .iter()
+                .any(|required| pending_names.contains(required))
+            {
+                blocked.push(extension);
+            } else {
+                ordered.push(extension);
+                progress = true;
+            }
+        }
+        if !progress {
+            for extension in &blocked {
+                pgx::warning!(
+                    "Skipping {}--{}: circular dependency between {}",
+                    extension.name,
+                    extension.version,
+                    blocked
+                        .iter()
+                        .map(|extension| extension.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+            break;
+        }
+        pending = blocked;
+    }
+    ordered
+}
+
+/// Orders more specific (longer) names first
+fn more_specific_first(x: &str, y: &str) -> std::cmp::Ordering {
+    x.len().cmp(&y.len()).then_with(|| x.cmp(y)).reverse()
+}
+
+/// Compares versions numerically when both look like (partial) semantic versions,
+/// so that `1.0` matches `1.0.0`, and as strings otherwise
+pub(crate) fn versions_match(x: &str, y: &str) -> bool {
+    match (parse_version(x), parse_version(y)) {
+        (Some(x), Some(y)) => x == y,
+        _ => x == y,
+    }
+}
+
+/// Parses `major[.minor[.patch]][-prerelease][+build]`, ignoring build metadata
+fn parse_version(version: &str) -> Option<([u64; 3], Option<&str>)> {
+    let version = version.split('+').next()?;
+    let (core, prerelease) = match version.split_once('-') {
+        Some((core, prerelease)) => (core, Some(prerelease)),
+        None => (version, None),
+    };
+    let mut numbers = [0; 3];
+    for (i, part) in core.split('.').enumerate() {
+        *numbers.get_mut(i)? = part.parse().ok()?;
+    }
+    Some((numbers, prerelease))
 }
 
 fn control_files() -> impl Iterator<Item = DirEntry> {
@@ -259,11 +940,39 @@ fn control_files() -> impl Iterator<Item = DirEntry> {
     };
 
     dir.push("extension");
+    control_files_in(dir)
+}
+
+/// Control files in `dir`, warning about those that can't be read
+pub(crate) fn control_files_in(dir: PathBuf) -> impl Iterator<Item = DirEntry> {
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => Some(entries),
+        Err(err) => {
+            pgx::warning!(
+                "Can't read extension directory {}: {}",
+                dir.to_string_lossy(),
+                err
+            );
+            None
+        }
+    };
 
-    std::fs::read_dir(dir).ok().into_iter().flat_map(|dir| {
-        dir.into_iter()
+    entries.into_iter().flat_map(move |entries| {
+        let dir = dir.clone();
+        entries
+            .into_iter()
             // Get a valid entry
-            .filter_map(Result::ok)
+            .filter_map(move |entry| match entry {
+                Ok(entry) => Some(entry),
+                Err(err) => {
+                    pgx::warning!(
+                        "Can't read an entry of extension directory {}: {}",
+                        dir.to_string_lossy(),
+                        err
+                    );
+                    None
+                }
+            })
             // Filter for .control files
             .filter_map(|entry| {
                 if let Some(true) = entry
@@ -279,8 +988,18 @@ fn control_files() -> impl Iterator<Item = DirEntry> {
     })
 }
 
-fn parse_control_file(entry: &DirEntry) -> Result<(String, String, PathBuf), anyhow::Error> {
-    let entry_path = entry.path();
+/// Extension's control file
+pub(crate) struct ControlFile {
+    pub(crate) name: String,
+    pub(crate) version: String,
+    /// Path to the extension's library
+    pub(crate) path: PathBuf,
+    /// Extensions this extension requires
+    pub(crate) requires: Vec<String>,
+}
+
+pub(crate) fn parse_control_file(entry: &DirEntry) -> Result<ControlFile, anyhow::Error> {
+    let entry_path = entry.path();
 
     let f = File::open(&entry_path)?;
     let reader = BufReader::new(f);
@@ -289,14 +1008,11 @@ fn parse_control_file(entry: &DirEntry) -> Result<(String, String, PathBuf), any
     for line in reader.lines() {
         let line = line?.split('#').next().unwrap_or("").to_string();
 
-        if let &[k, v] = line
-            .split('=')
-            .map(str::trim)
-            .collect::<Vec<_>>()
-            .as_slice()
-        {
+        // Values (like `comment`) may contain `=` themselves
+        if let Some((k, v)) = line.split_once('=') {
+            let v = v.trim();
             config.insert(
-                k.to_string(),
+                k.trim().to_string(),
                 v.trim_start_matches('\'')
                     .trim_end_matches('\'')
                     .to_string(),
@@ -332,21 +1048,44 @@ fn parse_control_file(entry: &DirEntry) -> Result<(String, String, PathBuf), any
         }
     };
 
-    let mut path = substitute_libdir(
-        config
-            .get("module_pathname")
-            .ok_or_else(|| anyhow::Error::msg("module_pathname not found in control file"))?
-            .as_str(),
-    );
-    path.push_str(".so");
+    // Like Postgres, assume the library is named after the extension if not specified
+    let path = match config.get("module_pathname") {
+        Some(module_pathname) => expand_dynamic_library_name(module_pathname),
+        None => expand_dynamic_library_name(&format!("$libdir/{}", name)),
+    };
+
+    let requires = config
+        .get("requires")
+        .map(|requires| {
+            requires
+                .split(',')
+                .map(str::trim)
+                .filter(|required| !required.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
 
-    Ok((name, version, PathBuf::from(path)))
+    Ok(ControlFile {
+        name,
+        version,
+        path,
+        requires,
+    })
 }
 
-fn find_matching_control_file(
+pub(crate) fn find_matching_control_file(
     extname: &str,
     version: Option<&str>,
-) -> Result<(String, String, PathBuf), anyhow::Error> {
+) -> Result<ControlFile, anyhow::Error> {
+    // Prefer the version that is installed in the current database
+    if version.is_none() {
+        if let Some(installed) = installed_version(extname) {
+            if let Ok(control_file) = find_matching_control_file(extname, Some(&installed)) {
+                return Ok(control_file);
+            }
+        }
+    }
     let mut matching = control_files()
         // Filter for matching extension
         .filter_map(|entry| {
@@ -355,7 +1094,7 @@ fn find_matching_control_file(
                 if s == extname {
                     true
                 } else if let Some(version) = version {
-                    s.split("--").collect::<Vec<_>>().as_slice() == [extname, version]
+                    matches!(s.split("--").collect::<Vec<_>>().as_slice(), &[name, version_] if name == extname && versions_match(version_, version))
                 } else {
                     matches!(s.split("--").collect::<Vec<_>>().as_slice(), &[name, ..] if name == extname)
                 }
@@ -368,11 +1107,10 @@ fn find_matching_control_file(
         .collect::<Vec<_>>();
     // Sort by length (more specific versions will be earlier)
     matching.sort_by(|x, y| {
-        x.file_name()
-            .to_string_lossy()
-            .len()
-            .cmp(&y.file_name().to_string_lossy().len())
-            .reverse()
+        more_specific_first(
+            x.file_name().to_string_lossy().as_ref(),
+            y.file_name().to_string_lossy().as_ref(),
+        )
     });
 
     if let Some(matching_control_file) = matching.first() {
@@ -382,122 +1120,470 @@ fn find_matching_control_file(
     }
 }
 
+/// Outcome of `load` and `unload`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Loaded,
+    AlreadyLoaded,
+    Unloaded,
+    NotFound,
+    Incompatible,
+    /// Some of the extension's workers are still running
+    InUse,
+    /// The extension's `pgextkit_can_unload` refused to be unloaded
+    Busy,
+}
+
+impl Status {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Status::Loaded => "loaded",
+            Status::AlreadyLoaded => "already_loaded",
+            Status::Unloaded => "unloaded",
+            Status::NotFound => "not_found",
+            Status::Incompatible => "incompatible",
+            Status::InUse => "in_use",
+            Status::Busy => "busy",
+        }
+    }
+}
+
 #[pg_extern]
-fn load(extname: &str, version: default!(Option<&str>, NULL)) {
-    if let Ok((name, version, path)) = find_matching_control_file(extname, version) {
-        let handle = Handle::make_dynamic(
-            name,
-            version,
-            Path::new(&path)
-                .file_stem()
-                .expect("filename")
-                .to_str()
-                .expect("string"),
+fn load(extname: &str, version: default!(Option<&str>, NULL)) -> &'static str {
+    load_extension(extname, version).as_str()
+}
+
+fn load_extension(extname: &str, version: Option<&str>) -> Status {
+    let ControlFile {
+        name,
+        version,
+        path,
+        ..
+    } = match find_matching_control_file(extname, version) {
+        Ok(control_file) => control_file,
+        Err(_err) => return Status::NotFound,
+    };
+    let library_name = path
+        .file_stem()
+        .expect("filename")
+        .to_str()
+        .expect("string")
+        .to_string();
+    load_library(name, version, &path, &library_name, false)
+}
+
+/// Loads a library that isn't necessarily installed, for trying out a fresh build
+///
+/// It's registered as `name` and `version`, its workers are started with the library's
+/// full path as their library name. It is unloaded with `unload` by that name, like
+/// installed extensions.
+#[pg_extern]
+fn load_from_path(path: &str, name: &str, version: &str) -> &'static str {
+    if !unsafe { pg_sys::superuser() } {
+        ereport!(
+            PgLogLevel::ERROR,
+            PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+            "must be superuser to load a library from an arbitrary path"
         );
+    }
+    let path = PathBuf::from(path);
+    if !path.is_absolute() {
+        pgx::error!("{} is not an absolute path", path.to_string_lossy());
+    }
+    if !path.is_file() {
+        return Status::NotFound.as_str();
+    }
+    let library_name = path.to_string_lossy().to_string();
+    load_library(
+        name.to_string(),
+        version.to_string(),
+        &path,
+        &library_name,
+        true,
+    )
+    .as_str()
+}
 
-        if has_magic(&path).expect("error while validating extension") {
-            match unsafe { libloading::Library::new(&path) } {
-                Err(err) => {
-                    pgx::error!("Couldn't load {}: {}", path.to_string_lossy(), err);
+/// Loads the library at `path` as `name` and `version`, `library_name` is what Postgres
+/// loads it by (in background workers or to look up functions)
+///
+/// Libraries loaded `from_path` are registered along with their path, as they have no
+/// control file to find them by when they are unloaded.
+fn load_library(
+    name: String,
+    version: String,
+    path: &Path,
+    library_name: &str,
+    from_path: bool,
+) -> Status {
+    let mut registry = Registry::default();
+    if registry.contains(&name, &version) {
+        return Status::AlreadyLoaded;
+    }
+    if !magic_or_warn(path) {
+        return Status::Incompatible;
+    }
+    let handle = Handle::make_dynamic(name.clone(), version.clone(), library_name);
+    // Claim the extension before initializing it so that concurrent loads don't initialize it twice
+    match registry.claim(&name, &version, from_path.then_some(library_name)) {
+        Ok(true) => {}
+        Ok(false) => return Status::AlreadyLoaded,
+        Err(err) => pgx::error!("Can't load {}--{}: {}", name, version, err),
+    }
+    // Loading the extension again gives its quarantined workers another chance
+    RestartTracker::default().clear(&name);
+    match open_library(path) {
+        Err(err) => {
+            registry.remove(&name, &version);
+            pgx::error!("Couldn't load {}: {}", path.to_string_lossy(), err);
+        }
+        Ok(lib) => {
+            let init = unsafe {
+                lib.get::<unsafe extern "C" fn(handle: *const Handle)>(
+                    cstr!("pgextkit_init").to_bytes_with_nul(),
+                )
+            };
+            match init {
+                Err(_err) => {
+                    registry.remove(&name, &version);
+                    pgx::warning!(
+                        "Can't find pgxextkit_init in {}, skipping loading",
+                        path.to_string_lossy()
+                    );
+                    Status::Incompatible
                 }
-                Ok(lib) => {
-                    let init = unsafe {
-                        lib.get::<unsafe extern "C" fn(handle: *const Handle)>(
-                            cstr!("pgextkit_init").to_bytes_with_nul(),
-                        )
-                    };
-                    match init {
-                        Err(_err) => {
-                            pgx::warning!(
-                                "Can't find pgxextkit_init in {}, skipping loading",
-                                path.to_string_lossy()
-                            );
-                        }
-                        Ok(init) => {
-                            unsafe {
-                                init(&handle);
-                            }
-                            pgx::log!("Loaded pgextkit library {}", path.to_string_lossy());
-                        }
-                    }
+                Ok(init) => {
+                    // Allocations and workers of a failed load are undone as the error
+                    // unwinds, the claim is released before re-raising it
+                    PgTryBuilder::new(AssertUnwindSafe(|| unsafe {
+                        let staged = StagedLoad::begin(&name);
+                        let acquired = ACQUIRED_RESOURCES;
+                        init(&handle);
+                        staged.commit();
+                        check_deinit(&lib, &name, ACQUIRED_RESOURCES > acquired);
+                    }))
+                    .catch_others(|err| {
+                        registry.remove(&name, &version);
+                        err.rethrow()
+                    })
+                    .execute();
+                    log_progress(format!(
+                        "Loaded pgextkit library {}",
+                        path.to_string_lossy()
+                    ));
+                    Status::Loaded
                 }
             }
         }
-    } else {
-        pgx::error!("Can't find matching control file");
     }
 }
 
+/// Calls the `pgextkit_migrate_shmem` function of the library (if it has one) to migrate
+/// shared memory registered under `name` by another version of the extension
+///
+/// Returns `true` if it was migrated in place, `false` if it must be reinitialized.
+extern "C" fn migrate_shmem(
+    library: *const std::ffi::c_char,
+    name: *const std::ffi::c_char,
+    old_version: *const std::ffi::c_char,
+    ptr: *mut std::ffi::c_void,
+) -> bool {
+    type Migrate = unsafe extern "C" fn(
+        name: *const std::ffi::c_char,
+        old_version: *const std::ffi::c_char,
+        ptr: *mut std::ffi::c_void,
+    ) -> bool;
+    let migrate = unsafe {
+        pg_sys::load_external_function(
+            library,
+            cstr!("pgextkit_migrate_shmem").as_ptr(),
+            false,
+            null_mut(),
+        )
+    };
+    match migrate {
+        Some(migrate) => unsafe {
+            std::mem::transmute::<_, Migrate>(migrate)(name, old_version, ptr)
+        },
+        None => false,
+    }
+}
+
+/// Advises about extensions that allocated shared memory or registered workers
+/// but won't be able to release them when unloaded
+pub(crate) fn check_deinit(lib: &libloading::Library, name: &str, acquired_resources: bool) {
+    let has_deinit = unsafe {
+        lib.get::<unsafe extern "C" fn()>(cstr!("pgextkit_deinit").to_bytes_with_nul())
+            .is_ok()
+    };
+    if acquired_resources && !has_deinit {
+        pgx::log!(
+            "{} allocates shared memory or registers background workers but has no pgextkit_deinit, they won't be cleaned up on unload",
+            name
+        );
+    }
+}
+
+/// Unloads the extension, stopping its background workers
+///
+/// The library can't be truly unmapped while Postgres itself has it loaded (for example, to
+/// call one of its functions). If its workers don't stop, `in_use` is returned and the
+/// extension remains loaded.
+///
+/// Extensions holding resources that can't be released on demand can export
+/// `extern "C" fn pgextkit_can_unload() -> bool`, which is called first: if it returns
+/// `false`, `busy` is returned and the extension is left alone. It must not have side
+/// effects, as it may be called any number of times.
 #[pg_extern]
-fn unload(extname: &str, version: default!(Option<&str>, NULL)) {
-    let version = match version {
-        None => {
-            if let Some((_, version, _)) = get_extensions()
-                .into_iter()
-                .find(|(name, _, _)| name == extname)
-            {
-                version
-            } else {
-                pgx::error!("{} extension not found", extname);
-            }
+fn unload(extname: &str, version: default!(Option<&str>, NULL)) -> &'static str {
+    unload_extension(extname, version).as_str()
+}
+
+fn unload_extension(extname: &str, version: Option<&str>) -> Status {
+    // Libraries loaded from a path aren't installed, the registry knows them
+    if let Some((version, _path)) = Registry::default().loaded_from_path(extname, version) {
+        return deinit_extension(extname, &version);
+    }
+    let installed = get_extensions()
+        .into_iter()
+        .find(|(name, version_, _username)| {
+            name == extname
+                && version
+                    .map(|version| versions_match(version, version_))
+                    .unwrap_or(true)
+        });
+    let version = match installed {
+        Some((_, version, _)) => version,
+        None => return Status::NotFound,
+    };
+    deinit_extension(extname, &version)
+}
+
+/// Unloads every loaded extension, extensions requiring others first
+#[pg_extern]
+fn unload_all() -> TableIterator<
+    'static,
+    (
+        name!(name, String),
+        name!(version, String),
+        name!(status, &'static str),
+    ),
+> {
+    let mut result = vec![];
+    for extension in unload_order(Registry::default().entries()) {
+        let status = deinit_extension(&extension.name, &extension.version);
+        result.push((
+            extension.name.to_string(),
+            extension.version.to_string(),
+            status.as_str(),
+        ));
+    }
+    TableIterator::new(result.into_iter())
+}
+
+/// Swaps loaded extensions whose installed version changed (for example, with
+/// `ALTER EXTENSION ... UPDATE`) to the installed version
+///
+/// Only the current database's catalog is consulted, so extensions that aren't installed in
+/// it are left alone. Extensions already in sync are skipped, so it can be called repeatedly.
+#[pg_extern]
+fn refresh() -> TableIterator<
+    'static,
+    (
+        name!(name, String),
+        name!(loaded_version, String),
+        name!(installed_version, String),
+        name!(status, &'static str),
+    ),
+> {
+    let installed = get_extensions();
+    let mut result = vec![];
+    for extension in unload_order(Registry::default().entries()) {
+        let installed_version = match installed
+            .iter()
+            .find(|(name, _, _)| name == extension.name.as_str())
+        {
+            Some((_, version, _)) if !versions_match(version, &extension.version) => version,
+            _ => continue,
+        };
+        let status = match deinit_extension(&extension.name, &extension.version) {
+            Status::Unloaded => load_extension(&extension.name, Some(installed_version)),
+            status => status,
+        };
+        result.push((
+            extension.name.to_string(),
+            extension.version.to_string(),
+            installed_version.clone(),
+            status.as_str(),
+        ));
+    }
+    TableIterator::new(result.into_iter())
+}
+
+/// Orders loaded extensions so that every extension comes before the extensions it
+/// requires, and otherwise most recently loaded first
+///
+/// Extensions can be loaded in any order after startup, so the registry's order alone
+/// doesn't guarantee that.
+fn unload_order(loaded: Vec<LoadedExtension>) -> Vec<LoadedExtension> {
+    let mut pending = loaded
+        .into_iter()
+        .map(|extension| {
+            let requires = find_matching_control_file(&extension.name, Some(&extension.version))
+                .map(|control_file| control_file.requires)
+                .unwrap_or_default();
+            (extension, requires)
+        })
+        .collect::<Vec<_>>();
+    let mut ordered = vec![];
+    while !pending.is_empty() {
+        // Most recently loaded extension no other pending extension requires
+        let next = pending
+            .iter()
+            .rposition(|(extension, _)| {
+                !pending.iter().any(|(_, requires)| {
+                    requires
+                        .iter()
+                        .any(|required| required.as_str() == extension.name.as_str())
+                })
+            })
+            // Circular requirements, fall back to the load order
+            .unwrap_or(pending.len() - 1);
+        ordered.push(pending.remove(next).0);
+    }
+    ordered
+}
+
+/// How long to wait for an extension's workers to stop when it is unloaded
+const WORKER_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Calls extension's deinitialization function, waits for its workers to stop
+/// and removes it from the registry
+fn deinit_extension(extname: &str, version: &str) -> Status {
+    let (path, version) = match Registry::default().loaded_from_path(extname, Some(version)) {
+        Some((version, path)) => (PathBuf::from(path), version),
+        None => match find_matching_control_file(extname, Some(version)) {
+            Ok(ControlFile { path, version, .. }) => (path, version),
+            Err(_err) => return Status::NotFound,
+        },
+    };
+    // Opened once and checked directly, `has_magic` would keep it among the probed libraries
+    let lib = match open_library(&path)
+        .map_err(anyhow::Error::from)
+        .and_then(|lib| Ok((check_magic(&path, &lib)?, lib)))
+    {
+        Ok((true, lib)) => lib,
+        Ok((false, _lib)) => return Status::Incompatible,
+        Err(err) => {
+            pgx::warning!("Can't validate {}: {:#}", path.to_string_lossy(), err);
+            return Status::Incompatible;
         }
-        Some(version) => {
-            if get_extensions()
-                .iter()
-                .any(|(name_, version_, _username)| name_ == extname && version_ == version)
-            {
-                version.to_string()
-            } else {
-                pgx::error!("{} extension at version {} not found", extname, version);
+    };
+    let can_unload = unsafe {
+        lib.get::<unsafe extern "C" fn() -> bool>(cstr!("pgextkit_can_unload").to_bytes_with_nul())
+    };
+    if let Ok(can_unload) = can_unload {
+        if !unsafe { can_unload() } {
+            return Status::Busy;
+        }
+    }
+    let deinit =
+        unsafe { lib.get::<unsafe extern "C" fn()>(cstr!("pgextkit_deinit").to_bytes_with_nul()) };
+    match deinit {
+        Err(_err) => {
+            // No deinitialization required
+        }
+        Ok(deinit) => {
+            unsafe {
+                deinit();
             }
+            log_progress(format!(
+                "Unloaded pgextkit library {}",
+                path.to_string_lossy()
+            ));
         }
-    };
-    if let Ok((_name, _version, path)) = find_matching_control_file(extname, Some(&version)) {
-        if has_magic(&path).expect("error while validating extension") {
-            match unsafe { libloading::Library::new(&path) } {
-                Err(err) => {
-                    pgx::error!("Couldn't load {}: {}", path.to_string_lossy(), err);
-                }
-                Ok(lib) => {
-                    let deinit = unsafe {
-                        lib.get::<unsafe extern "C" fn()>(
-                            cstr!("pgextkit_deinit").to_bytes_with_nul(),
-                        )
-                    };
-                    match deinit {
-                        Err(_err) => {
-                            // No deinitialization required
-                        }
-                        Ok(deinit) => {
-                            unsafe {
-                                deinit();
-                            }
-                            pgx::log!("Unloaded pgextkit library {}", path.to_string_lossy());
-                        }
-                    }
-                }
+    }
+    // Give the workers a chance to wind down before forcing them to
+    let mut handles = WorkerHandles::default();
+    let mut in_use = false;
+    for (database, worker) in handles.take(extname) {
+        if !worker.wait_for_shutdown(WORKER_SHUTDOWN_TIMEOUT) {
+            pgx::warning!(
+                "Background worker of {} didn't stop within {}s, terminating it",
+                extname,
+                WORKER_SHUTDOWN_TIMEOUT.as_secs()
+            );
+            worker.terminate();
+            if !worker.wait_for_shutdown(WORKER_SHUTDOWN_TIMEOUT) {
+                handles.restore(extname, database, worker);
+                in_use = true;
             }
         }
-    } else {
-        pgx::error!("Can't find matching control file");
     }
+    if in_use {
+        // The worker may still be running the library's code, so it must stay mapped
+        std::mem::forget(lib);
+        return Status::InUse;
+    }
+    // Nothing of the extension runs anymore, so nothing uses its shared memory either
+    Allocations::default().free_all(extname);
+    // The registry has the version of the control file, which may be spelled differently
+    Registry::default().remove(extname, &version);
+    Status::Unloaded
 }
 
 mod static_handle {
-    use crate::ext::{ALLOC_CALLBACKS, BACKGROUND_WORKERS};
-    use crate::Handle;
+    use crate::ext::{
+        ACQUIRED_RESOURCES, ALLOC_CALLBACKS, BACKGROUND_WORKERS, LWLOCK_TRANCHES,
+        NEW_DATABASE_CALLBACKS, SHMEM_QUOTAS, SHMEM_SIZE,
+    };
+    use crate::{Handle, NewDatabaseCallback, RestartPolicy};
     use pgx::pg_sys;
 
     pub(crate) extern "C" fn allocate_shmem(
-        _handle: *const Handle,
+        handle: *const Handle,
+        size: usize,
+        cb: extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_void, bool),
+        payload: *const std::ffi::c_void,
+    ) {
+        allocate_shmem_aligned(handle, size, std::mem::size_of::<usize>(), cb, payload)
+    }
+
+    /// `ShmemInitStruct` aligns every allocation to `PG_CACHE_LINE_SIZE`
+    const SHMEM_ALIGNMENT: usize = 128;
+
+    pub(crate) extern "C" fn allocate_shmem_aligned(
+        handle: *const Handle,
+        size: usize,
+        align: usize,
+        cb: extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_void, bool),
+        payload: *const std::ffi::c_void,
+    ) {
+        allocate_shmem_prioritized(handle, size, align, 0, cb, payload)
+    }
+
+    pub(crate) extern "C" fn allocate_shmem_prioritized(
+        handle: *const Handle,
         size: usize,
-        cb: extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_void),
+        align: usize,
+        priority: std::ffi::c_int,
+        cb: extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_void, bool),
         payload: *const std::ffi::c_void,
     ) {
         unsafe {
+            let handle = &*handle;
+            if align > SHMEM_ALIGNMENT {
+                pgx::error!(
+                    "{} requested shared memory aligned to {} bytes, at most {} is supported",
+                    handle.name,
+                    align,
+                    SHMEM_ALIGNMENT
+                );
+            }
             #[cfg(not(feature = "pg15"))]
             pg_sys::RequestAddinShmemSpace(size);
-            ALLOC_CALLBACKS.push((cb, size, payload));
+            ALLOC_CALLBACKS.push((handle.name.to_string(), priority, cb, size, payload));
+            ACQUIRED_RESOURCES += 1;
         }
     }
 
@@ -511,38 +1597,190 @@ mod static_handle {
                 handle.name.to_string(),
                 handle.version.to_string(),
                 Box::new(*bgw),
+                None,
+            ));
+            ACQUIRED_RESOURCES += 1;
+        }
+    }
+
+    pub(crate) extern "C" fn register_bgworker_with_policy(
+        handle: *const Handle,
+        bgw: *mut pg_sys::BackgroundWorker,
+        policy: *const RestartPolicy,
+    ) {
+        unsafe {
+            let handle = &*handle;
+            BACKGROUND_WORKERS.push((
+                handle.name.to_string(),
+                handle.version.to_string(),
+                Box::new(*bgw),
+                Some(*policy),
             ));
+            ACQUIRED_RESOURCES += 1;
+        }
+    }
+
+    pub(crate) extern "C" fn request_shmem_quota(handle: *const Handle, size: usize) {
+        unsafe {
+            let handle = &*handle;
+            SHMEM_QUOTAS.push((handle.name.to_string(), size));
+        }
+    }
+
+    pub(crate) extern "C" fn request_lwlocks(
+        _handle: *const Handle,
+        tranche: *const std::ffi::c_char,
+        count: std::ffi::c_int,
+    ) {
+        unsafe {
+            #[cfg(not(feature = "pg15"))]
+            pg_sys::RequestNamedLWLockTranche(tranche, count);
+            LWLOCK_TRANCHES.push((tranche, count));
+            ACQUIRED_RESOURCES += 1;
+        }
+    }
+
+    pub(crate) extern "C" fn on_new_database(handle: *const Handle, callback: NewDatabaseCallback) {
+        unsafe {
+            let handle = &*handle;
+            NEW_DATABASE_CALLBACKS.push((handle.name.to_string(), callback));
+        }
+    }
+
+    /// Nothing is allocated from the arena until the server has started
+    pub(crate) extern "C" fn shmem_stats(
+        _handle: *const Handle,
+        total: *mut usize,
+        free: *mut usize,
+    ) {
+        unsafe {
+            *total = SHMEM_SIZE;
+            *free = SHMEM_SIZE;
+        }
+    }
+
+    /// Postmaster starts the worker directly, bypassing `database_worker`
+    pub(crate) extern "C" fn register_global_bgworker(
+        _handle: *const Handle,
+        bgw: *mut pg_sys::BackgroundWorker,
+    ) {
+        unsafe {
+            (*bgw).bgw_extra = [0; 128];
+            pg_sys::RegisterBackgroundWorker(bgw);
+            ACQUIRED_RESOURCES += 1;
         }
     }
 }
 
 mod dynamic_handle {
-    use crate::ext::ALLOCATOR;
+    use crate::ext::handles::WorkerHandle;
+    use crate::ext::rollback::{self, Step};
+    use crate::ext::{
+        overflow_limit, workers, Allocations, DisabledWorkers, RestartTracker, ShmemUsage,
+        WorkerHandles, ACQUIRED_RESOURCES, ALLOCATOR, SHMEM_SIZE,
+    };
     use crate::types::{RpgffiChar128, RpgffiChar96};
-    use crate::Handle;
-    use pgx::{direct_function_call, pg_sys, FromDatum};
+    use crate::worker::WorkerContext;
+    use crate::{Handle, NewDatabaseCallback, RestartPolicy};
+    use pgx::{direct_function_call, pg_guard, pg_sys, FromDatum};
     use std::alloc::{GlobalAlloc, Layout};
     use std::ffi::CStr;
 
+    #[pg_guard]
     pub(crate) extern "C" fn allocate_shmem(
-        _handle: *const Handle,
+        handle: *const Handle,
         size: usize,
-        cb: extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_void),
+        cb: extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_void, bool),
         payload: *const std::ffi::c_void,
     ) {
-        let alloc = unsafe {
-            ALLOCATOR.alloc(
-                Layout::from_size_align(size, std::mem::size_of::<usize>())
-                    .expect("Invalid layout"),
-            )
+        allocate_shmem_aligned(handle, size, std::mem::size_of::<usize>(), cb, payload)
+    }
+
+    #[pg_guard]
+    pub(crate) extern "C" fn allocate_shmem_aligned(
+        handle: *const Handle,
+        size: usize,
+        align: usize,
+        cb: extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_void, bool),
+        payload: *const std::ffi::c_void,
+    ) {
+        allocate_shmem_prioritized(handle, size, align, 0, cb, payload)
+    }
+
+    /// Allocations are made right away once the server is running, so the priority
+    /// doesn't matter
+    #[pg_guard]
+    pub(crate) extern "C" fn allocate_shmem_prioritized(
+        handle: *const Handle,
+        size: usize,
+        align: usize,
+        _priority: std::ffi::c_int,
+        cb: extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_void, bool),
+        payload: *const std::ffi::c_void,
+    ) {
+        let handle = unsafe { &*handle };
+        let layout = Layout::from_size_align(size, align.max(std::mem::size_of::<usize>()))
+            .expect("Invalid layout");
+        let footprint = layout.pad_to_align().size();
+        let mut usage = ShmemUsage::default();
+        if let Err(err) = usage.allocate(&handle.name, footprint) {
+            pgx::error!("{}", err);
+        }
+        let (alloc, overflow) = match unsafe { ALLOCATOR.alloc(layout) } {
+            alloc if alloc.is_null() => (crate::overflow::allocate(layout, overflow_limit()), true),
+            alloc => (alloc, false),
         };
-        cb(alloc as *mut _, payload);
+        if alloc.is_null() {
+            usage.release(&handle.name, footprint, false);
+            pgx::error!(
+                "pgextkit is out of shared memory ({} bytes requested by {}, {} of {} bytes of the arena in use), consider raising pgextkit.shmem_size or pgextkit.overflow_shmem_size",
+                size,
+                handle.name,
+                usage.arena_used(),
+                unsafe { SHMEM_SIZE }
+            );
+        }
+        if overflow {
+            usage.allocated_overflow(&handle.name, footprint);
+        }
+        if !overflow {
+            ArenaBlocks::default().record(alloc, size);
+        }
+        Allocations::default().record(&handle.name, alloc, layout, overflow);
+        unsafe { ACQUIRED_RESOURCES += 1 };
+        rollback::stage(Step::Allocation {
+            ptr: alloc,
+            layout,
+            overflow,
+        });
+        // The allocator always hands out fresh memory
+        cb(alloc as *mut _, payload, false);
     }
 
     pub(crate) extern "C" fn register_bgworker(
-        _handle: *const Handle,
+        handle: *const Handle,
+        bgw: *mut pg_sys::BackgroundWorker,
+    ) {
+        register(unsafe { &*handle }, bgw, None)
+    }
+
+    pub(crate) extern "C" fn register_bgworker_with_policy(
+        handle: *const Handle,
+        bgw: *mut pg_sys::BackgroundWorker,
+        policy: *const RestartPolicy,
+    ) {
+        register(unsafe { &*handle }, bgw, Some(unsafe { &*policy }))
+    }
+
+    fn register(
+        handle: &Handle,
         bgw: *mut pg_sys::BackgroundWorker,
+        policy: Option<&RestartPolicy>,
     ) {
+        if DisabledWorkers::default().is_disabled(unsafe { pg_sys::MyDatabaseId }, &handle.name) {
+            pgx::debug1!("Workers of {} are disabled in this database", handle.name);
+            return;
+        }
         unsafe {
             let database: &CStr = FromDatum::from_polymorphic_datum(
                 direct_function_call(pg_sys::current_database, vec![]).unwrap(),
@@ -552,31 +1790,141 @@ mod dynamic_handle {
             .unwrap();
             let username = CStr::from_ptr(pg_sys::GetUserNameFromId(pg_sys::GetUserId(), false));
             (*bgw).bgw_name = RpgffiChar96::from(
-                CStr::from_ptr((*bgw).bgw_name.as_ptr())
-                    .to_string_lossy()
-                    .replace("{{DATABASE}}", database.to_string_lossy().as_ref())
-                    .as_str(),
-            )
-            .0;
-            (*bgw).bgw_extra = RpgffiChar128::from(
-                format!(
-                    "{}@{}",
+                workers::expand_worker_name(
+                    CStr::from_ptr((*bgw).bgw_name.as_ptr())
+                        .to_string_lossy()
+                        .as_ref(),
+                    database.to_string_lossy().as_ref(),
                     username.to_string_lossy().as_ref(),
-                    database.to_string_lossy().as_ref()
+                    &handle.name,
+                    &handle.version,
                 )
                 .as_str(),
             )
             .0;
-            pg_sys::RegisterDynamicBackgroundWorker(bgw, std::ptr::null_mut());
+            (*bgw).bgw_extra = RpgffiChar128::from(
+                WorkerContext::new(database.to_string_lossy(), username.to_string_lossy())
+                    .encode()
+                    .as_str(),
+            )
+            .0;
+            if policy.is_some() {
+                let worker = CStr::from_ptr((*bgw).bgw_name.as_ptr()).to_string_lossy();
+                if !RestartTracker::default().admit(
+                    &handle.name,
+                    pg_sys::MyDatabaseId,
+                    worker.as_ref(),
+                ) {
+                    return;
+                }
+                workers::notify_master(&mut *bgw);
+            }
+            let mut worker_handle = std::ptr::null_mut();
+            if pg_sys::RegisterDynamicBackgroundWorker(bgw, &mut worker_handle) {
+                WorkerHandles::default().record(&handle.name, pg_sys::MyDatabaseId, worker_handle);
+                if let Some(policy) = policy {
+                    RestartTracker::default().track(
+                        &handle.name,
+                        pg_sys::MyDatabaseId,
+                        &*bgw,
+                        WorkerHandle::from_raw(worker_handle),
+                        policy,
+                    );
+                }
+                rollback::stage(Step::Worker {
+                    database: pg_sys::MyDatabaseId,
+                    handle: WorkerHandle::from_raw(worker_handle),
+                });
+                ACQUIRED_RESOURCES += 1;
+            }
+        }
+    }
+
+    pub(crate) extern "C" fn request_shmem_quota(handle: *const Handle, size: usize) {
+        let handle = unsafe { &*handle };
+        ShmemUsage::default().set_quota(&handle.name, size);
+    }
+
+    pub(crate) extern "C" fn request_lwlocks(
+        handle: *const Handle,
+        _tranche: *const std::ffi::c_char,
+        _count: std::ffi::c_int,
+    ) {
+        let handle = unsafe { &*handle };
+        pgx::error!(
+            "{} can only request LWLocks when preloaded via shared_preload_libraries",
+            handle.name
+        );
+    }
+
+    pub(crate) extern "C" fn on_new_database(
+        handle: *const Handle,
+        _callback: NewDatabaseCallback,
+    ) {
+        let handle = unsafe { &*handle };
+        pgx::error!(
+            "{} can only watch for new databases when preloaded via shared_preload_libraries",
+            handle.name
+        );
+    }
+
+    pub(crate) extern "C" fn shmem_stats(
+        _handle: *const Handle,
+        total: *mut usize,
+        free: *mut usize,
+    ) {
+        // Only the arena, the overflow pool is a fallback with a size of its own
+        let used = ShmemUsage::default().arena_used();
+        unsafe {
+            *total = SHMEM_SIZE;
+            *free = SHMEM_SIZE.saturating_sub(used);
+        }
+    }
+
+    /// Starts the worker unless it is already running, whichever database the
+    /// extension is loaded from
+    pub(crate) extern "C" fn register_global_bgworker(
+        handle: *const Handle,
+        bgw: *mut pg_sys::BackgroundWorker,
+    ) {
+        let handle = unsafe { &*handle };
+        let mut handles = WorkerHandles::default();
+        let running = handles.take_in_database(&handle.name, pg_sys::InvalidOid);
+        if running.iter().any(|worker| !worker.is_stopped()) {
+            for worker in running {
+                handles.restore(&handle.name, pg_sys::InvalidOid, worker);
+            }
+            return;
+        }
+        unsafe {
+            (*bgw).bgw_extra = [0; 128];
+            let mut worker_handle = std::ptr::null_mut();
+            if pg_sys::RegisterDynamicBackgroundWorker(bgw, &mut worker_handle) {
+                handles.record(&handle.name, pg_sys::InvalidOid, worker_handle);
+                rollback::stage(Step::Worker {
+                    database: pg_sys::InvalidOid,
+                    handle: WorkerHandle::from_raw(worker_handle),
+                });
+                ACQUIRED_RESOURCES += 1;
+            }
         }
     }
 }
 impl Handle {
-    fn make_static(name: String, version: String, library_name: &str) -> Self {
+    pub(crate) fn make_static(name: String, version: String, library_name: &str) -> Self {
         use static_handle::*;
         Self {
             allocate_shmem,
+            allocate_shmem_aligned,
+            allocate_shmem_prioritized,
             register_bgworker,
+            register_bgworker_with_policy,
+            request_shmem_quota,
+            register_global_bgworker,
+            request_lwlocks,
+            on_new_database,
+            shmem_stats,
+            migrate_shmem,
             library_name: Box::leak(
                 CString::new(library_name)
                     .expect("CString::new failed")
@@ -588,11 +1936,20 @@ impl Handle {
         }
     }
 
-    fn make_dynamic(name: String, version: String, library_name: &str) -> Self {
+    pub(crate) fn make_dynamic(name: String, version: String, library_name: &str) -> Self {
         use dynamic_handle::*;
         Self {
             allocate_shmem,
+            allocate_shmem_aligned,
+            allocate_shmem_prioritized,
             register_bgworker,
+            register_bgworker_with_policy,
+            request_shmem_quota,
+            register_global_bgworker,
+            request_lwlocks,
+            on_new_database,
+            shmem_stats,
+            migrate_shmem,
             library_name: Box::leak(
                 CString::new(library_name)
                     .expect("CString::new failed")
@@ -605,7 +1962,36 @@ impl Handle {
     }
 }
 
-fn get_extensions() -> Vec<(String, String, String)> {
+/// Version of the extension installed in the current database, if any
+///
+/// The catalog can only be read within a transaction, `None` is returned outside of one.
+fn installed_version(extname: &str) -> Option<String> {
+    if !unsafe { pg_sys::IsTransactionState() } {
+        return None;
+    }
+    get_extensions()
+        .into_iter()
+        .find(|(name, _, _)| name == extname)
+        .map(|(_, version, _)| version)
+}
+
+/// Ends a scan started with `table_beginscan_catalog`
+///
+/// `table_endscan` is inline in Postgres' headers, so this dispatches to the table's
+/// access method the same way. Catalogs are heap tables, so if the access method is
+/// missing, the heap ends the scan.
+pub(crate) unsafe fn end_catalog_scan(scan: pg_sys::TableScanDesc) {
+    let tableam = match (*scan).rs_rd.as_ref() {
+        Some(rel) => rel.rd_tableam,
+        None => std::ptr::null(),
+    };
+    match tableam.as_ref().and_then(|tableam| tableam.scan_end) {
+        Some(end) => end(scan),
+        None => pg_sys::heap_endscan(scan),
+    }
+}
+
+pub(crate) fn get_extensions() -> Vec<(String, String, String)> {
     unsafe {
         let mut result = vec![];
         {
@@ -635,9 +2021,7 @@ fn get_extensions() -> Vec<(String, String, String)> {
 
                 result.push((name, version, user_name));
             }
-            if let Some(end) = (*(*(*scan).rs_rd).rd_tableam).scan_end {
-                end(scan);
-            }
+            end_catalog_scan(scan);
             pg_sys::table_close(rel, AccessShareLock as _);
         }
         result
@@ -645,13 +2029,324 @@ fn get_extensions() -> Vec<(String, String, String)> {
 }
 
 #[pg_extern]
-fn shared_dictionary_entries(
-) -> TableIterator<'static, (name!(name, String), name!(type_name, String))> {
+fn shared_dictionary_entries() -> TableIterator<
+    'static,
+    (
+        name!(name, String),
+        name!(type_name, String),
+        name!(size, i64),
+    ),
+> {
+    let mut entries = vec![];
+    SharedDictionary::default().for_each(|name, type_name, size| {
+        entries.push((name.to_string(), type_name.to_string(), size as i64))
+    });
+    TableIterator::new(entries.into_iter())
+}
+
+/// Reads text-like entries of the shared dictionary
+#[pg_extern]
+fn shared_dictionary_get_text(name: &str) -> Option<String> {
+    type Text = heapless::String<96>;
+    let dict = SharedDictionary::default();
+    if !dict.is_of_type::<Text>(name)? {
+        if dict.is_of_type::<PgDynamicLwLock<Text>>(name)? {
+            return dict
+                .get::<PgDynamicLwLock<Text>>(name)
+                .map(|lock| lock.share().to_string());
+        }
+        if dict.is_of_type::<DatabaseLocal<PgDynamicLwLock<Text>>>(name)? {
+            // Reading must not claim a slot for the current database
+            let local = dict.get::<DatabaseLocal<PgDynamicLwLock<Text>>>(name)?;
+            let index = local.slot_index()?;
+            return local.slot(index).map(|lock| lock.share().to_string());
+        }
+        pgx::error!(
+            "{} is of type {}, which can't be read as text",
+            name,
+            dict.type_name(name).unwrap_or_default()
+        );
+    }
+    dict.get::<Text>(name).map(|text| text.to_string())
+}
+
+#[pg_extern]
+fn shmem_usage() -> TableIterator<
+    'static,
+    (
+        name!(extension, String),
+        name!(quota, Option<i64>),
+        name!(used, i64),
+        name!(overflow_used, i64),
+    ),
+> {
     TableIterator::new(
-        SharedDictionary::default()
+        ShmemUsage::default()
             .entries()
-            .map(|(name, type_name)| (name.to_string(), type_name.to_string()))
-            .collect::<Vec<_>>()
-            .into_iter(),
+            .into_iter()
+            .map(|(name, usage)| {
+                (
+                    name,
+                    usage.quota.map(|q| q as i64),
+                    usage.used as i64,
+                    usage.overflow as i64,
+                )
+            }),
     )
 }
+
+/// Version of the extension interface supported by this build of pgextkit, along with the
+/// size of the [`Magic`] extensions must export to be loaded
+///
+/// It doesn't touch shared memory, so it works even if pgextkit isn't fully set up.
+#[pg_extern]
+fn pgextkit_version() -> TableIterator<'static, (name!(host_version, i32), name!(magic_size, i32))>
+{
+    TableIterator::new(std::iter::once((VERSION as i32, size_of::<Magic>() as i32)))
+}
+
+/// Gives back a block allocated from the arena
+pub(crate) unsafe fn deallocate(ptr: *mut u8, layout: std::alloc::Layout) {
+    use std::alloc::GlobalAlloc;
+    ArenaBlocks::default().forget(ptr);
+    ALLOCATOR.dealloc(ptr, layout);
+}
+
+/// Total, used and free bytes of the shared memory arena extensions allocate from after
+/// startup, along with the largest range between allocated blocks
+///
+/// Once it falls well below the free amount, the arena is fragmented. Allocations that
+/// didn't fit in the arena and were made from the overflow pool are reported on their own.
+#[pg_extern]
+fn shmem_allocator_stats() -> TableIterator<
+    'static,
+    (
+        name!(total, i64),
+        name!(used, i64),
+        name!(free, i64),
+        name!(largest_free, Option<i64>),
+        name!(overflow_used, i64),
+    ),
+> {
+    let usage = ShmemUsage::default();
+    let total = unsafe { SHMEM_SIZE };
+    let used = usage.arena_used();
+    let free = total.saturating_sub(used);
+    TableIterator::new(std::iter::once((
+        total as i64,
+        used as i64,
+        free as i64,
+        ArenaBlocks::default()
+            .largest_free()
+            .map(|largest| largest.min(free) as i64),
+        usage.overflow_used() as i64,
+    )))
+}
+
+#[pg_extern]
+fn shared_dictionary_stats() -> TableIterator<'static, (name!(entries, i64), name!(capacity, i64))>
+{
+    TableIterator::new(std::iter::once((
+        SharedDictionary::default().len() as i64,
+        SharedDictionary::max_entries() as i64,
+    )))
+}
+
+/// Enables or disables background workers of the extension in the current database
+///
+/// Workers are stopped when disabled. When enabled again, workers registered while
+/// preloading the extension are started right away, others once the extension is loaded again.
+///
+/// Like `pg_terminate_backend`, it requires the privileges of `pg_signal_backend`.
+#[pg_extern]
+pub(crate) fn set_worker_enabled(extname: &str, enabled: bool) {
+    let action = if enabled { "enable" } else { "disable" };
+    let allowed = unsafe {
+        pg_sys::superuser()
+            || pg_sys::has_privs_of_role(
+                pg_sys::GetUserId(),
+                pg_sys::get_role_oid(cstr!("pg_signal_backend").as_ptr(), false),
+            )
+    };
+    if !allowed {
+        ereport!(
+            PgLogLevel::ERROR,
+            PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+            format!(
+                "must have the privileges of pg_signal_backend to {} workers of {}",
+                action, extname
+            )
+        );
+    }
+    let database = unsafe { pg_sys::MyDatabaseId };
+    let changed = match DisabledWorkers::default().set_enabled(database, extname, enabled) {
+        Ok(changed) => changed,
+        Err(err) => pgx::error!("Can't {} workers of {}: {}", action, extname, err),
+    };
+    if !changed {
+        return;
+    }
+    if enabled {
+        let database_name = unsafe { CStr::from_ptr(pg_sys::get_database_name(database)) };
+        workers::start_workers(
+            &database_name.to_string_lossy(),
+            get_extensions()
+                .into_iter()
+                .filter(|(name, _, _)| name == extname)
+                .collect(),
+        );
+    } else {
+        for worker in WorkerHandles::default().take_in_database(extname, database) {
+            worker.terminate();
+        }
+    }
+}
+
+/// Progress reported by background workers through `pgextkit::heartbeat::beat`
+#[pg_extern]
+fn worker_heartbeats() -> TableIterator<
+    'static,
+    (
+        name!(name, String),
+        name!(last_tick, i64),
+        name!(seconds_since, f64),
+    ),
+> {
+    let now = unsafe { pg_sys::GetCurrentTimestamp() };
+    TableIterator::new(crate::heartbeat::entries().into_iter().map(
+        move |(name, tick, last_update)| {
+            (name, tick as i64, (now - last_update) as f64 / 1_000_000.0)
+        },
+    ))
+}
+
+/// Waits for `PgDynamicLwLock`s recorded while `pgextkit.lock_stats` is on, by lock name
+///
+/// Acquisitions that didn't have to wait aren't counted.
+#[pg_extern]
+fn lock_wait_stats() -> TableIterator<
+    'static,
+    (
+        name!(lock_name, String),
+        name!(count, i64),
+        name!(total_wait_us, i64),
+        name!(max_wait_us, i64),
+    ),
+> {
+    TableIterator::new(crate::lock_stats::entries().into_iter().map(
+        |(name, count, total_wait_us, max_wait_us)| {
+            (name, count as i64, total_wait_us as i64, max_wait_us as i64)
+        },
+    ))
+}
+
+/// Last errors recorded by background workers through `pgextkit::worker_errors::record`,
+/// and by pgextkit when it fails to start them
+#[pg_extern]
+fn worker_errors() -> TableIterator<
+    'static,
+    (
+        name!(extension, String),
+        name!(database, String),
+        name!(message, String),
+        name!(when, Option<pgx::TimestampWithTimeZone>),
+    ),
+> {
+    TableIterator::new(crate::worker_errors::entries().into_iter().map(
+        |(extension, database, message, when)| {
+            let when =
+                unsafe { pgx::TimestampWithTimeZone::from_datum(pg_sys::Datum::from(when), false) };
+            (extension, database, message, when)
+        },
+    ))
+}
+
+/// Waits for up to `timeout` milliseconds for the workers of the extension to be running
+/// and to report a heartbeat, returns whether they did
+///
+/// Only the workers registered so far are waited for: workers of databases whose pgextkit
+/// worker hasn't started yet are not known about.
+#[pg_extern]
+fn wait_workers_ready(extname: &str, timeout: default!(i32, 10000)) -> bool {
+    WorkerHandle::wait_for_ready(extname, Duration::from_millis(timeout.max(0) as u64))
+}
+
+/// Databases the master worker has started database workers for
+#[pg_extern]
+fn active_database_workers() -> SetOfIterator<'static, String> {
+    SetOfIterator::new(workers::MasterState::get().databases().into_iter())
+}
+
+#[pg_extern]
+fn loaded_extensions() -> TableIterator<
+    'static,
+    (
+        name!(name, String),
+        name!(version, String),
+        name!(worker_count, Option<i32>),
+        name!(services, Option<Vec<String>>),
+    ),
+> {
+    TableIterator::new(Registry::default().entries().into_iter().map(|extension| {
+        // Libraries of loaded extensions are already mapped, so this is cheap
+        let manifest = find_matching_control_file(&extension.name, Some(&extension.version))
+            .ok()
+            .and_then(|control_file| unsafe { libloading::Library::new(&control_file.path) }.ok())
+            .and_then(|lib| {
+                manifest(&lib)
+                    .map(|manifest| (manifest.worker_count() as i32, manifest.service_names()))
+            });
+        (
+            extension.name.to_string(),
+            extension.version.to_string(),
+            manifest.as_ref().map(|(worker_count, _)| *worker_count),
+            manifest.map(|(_, services)| services),
+        )
+    }))
+}
+
+/// Control files pgextkit considers while preloading, with the reason those that would be
+/// skipped are
+#[pg_extern]
+fn discoverable_extensions() -> TableIterator<
+    'static,
+    (
+        name!(name, String),
+        name!(version, Option<String>),
+        name!(module_path, Option<String>),
+        name!(has_magic, Option<bool>),
+        name!(error, Option<String>),
+    ),
+> {
+    let extensions = control_files()
+        .map(|entry| match parse_control_file(&entry) {
+            Ok(control_file) => {
+                let (has_magic, error) = match has_magic(&control_file.path) {
+                    Ok(has_magic) => (Some(has_magic), None),
+                    Err(err) => (None, Some(format!("{:#}", err))),
+                };
+                (
+                    control_file.name,
+                    Some(control_file.version),
+                    Some(control_file.path.to_string_lossy().to_string()),
+                    has_magic,
+                    error,
+                )
+            }
+            Err(err) => (
+                entry
+                    .path()
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                None,
+                None,
+                None,
+                Some(format!("{:#}", err)),
+            ),
+        })
+        .collect::<Vec<_>>();
+    // Only probed for diagnostics, they aren't about to be loaded
+    unsafe { PROBED_LIBRARIES.clear() };
+    TableIterator::new(extensions.into_iter())
+}
@@ -0,0 +1,129 @@
+use crate::ext::{shmem_struct, with_named_lock};
+use crate::shmem::TruncatingFrom;
+use cstr_core::cstr;
+use pgx::pg_sys;
+
+const MAX_LOADED_EXTENSIONS: usize = 128;
+
+#[derive(Clone)]
+pub(crate) struct LoadedExtension {
+    pub(crate) name: heapless::String<64>,
+    pub(crate) version: heapless::String<64>,
+    /// Library of an extension loaded with `load_from_path`, others are found through
+    /// their control files
+    pub(crate) path: Option<heapless::String<1024>>,
+}
+
+impl LoadedExtension {
+    fn is(&self, name: &str, version: &str) -> bool {
+        self.name == heapless::String::<64>::truncating_from(name)
+            && self.version == heapless::String::<64>::truncating_from(version)
+    }
+}
+
+/// Extensions are kept in the order they were loaded in
+type List = heapless::Vec<LoadedExtension, MAX_LOADED_EXTENSIONS>;
+
+/// Registry of extensions loaded through pgextkit
+pub(crate) struct Registry {
+    list: *mut List,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            list: unsafe { shmem_struct(cstr!("pgextkit_registry"), List::new) },
+        }
+    }
+}
+
+impl Registry {
+    fn with_lock<R, F: FnOnce(&mut List) -> R>(&self, mode: pg_sys::LWLockMode, f: F) -> R {
+        with_named_lock(cstr!("pgextkit_registry"), mode, || {
+            f(unsafe { &mut *self.list })
+        })
+    }
+
+    pub(crate) fn contains(&self, name: &str, version: &str) -> bool {
+        self.with_lock(pg_sys::LWLockMode_LW_SHARED, |list| {
+            list.iter().any(|extension| extension.is(name, version))
+        })
+    }
+
+    /// Records the extension as loaded, returns `false` if it has already been loaded
+    ///
+    /// `path` is the library of an extension that isn't loaded through its control file.
+    pub(crate) fn claim(
+        &mut self,
+        name: &str,
+        version: &str,
+        path: Option<&str>,
+    ) -> Result<bool, anyhow::Error> {
+        let path = match path {
+            Some(path) => {
+                let mut library = heapless::String::new();
+                library
+                    .push_str(path)
+                    .map_err(|_| anyhow::Error::msg("library path too long"))?;
+                Some(library)
+            }
+            None => None,
+        };
+        self.with_lock(pg_sys::LWLockMode_LW_EXCLUSIVE, |list| {
+            if list.iter().any(|extension| extension.is(name, version)) {
+                return Ok(false);
+            }
+            list.push(LoadedExtension {
+                name: heapless::String::truncating_from(name),
+                version: heapless::String::truncating_from(version),
+                path,
+            })
+            .map_err(|_| anyhow::Error::msg("too many extensions loaded"))?;
+            Ok(true)
+        })
+    }
+
+    /// Removes the extension from the registry, returns `false` if it wasn't there
+    pub(crate) fn remove(&mut self, name: &str, version: &str) -> bool {
+        self.with_lock(pg_sys::LWLockMode_LW_EXCLUSIVE, |list| {
+            match list
+                .iter()
+                .position(|extension| extension.is(name, version))
+            {
+                Some(index) => {
+                    list.remove(index);
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+
+    /// Library of the extension if it was loaded with `load_from_path`, in any version
+    /// unless `version` is given
+    pub(crate) fn loaded_from_path(
+        &self,
+        name: &str,
+        version: Option<&str>,
+    ) -> Option<(String, String)> {
+        self.with_lock(pg_sys::LWLockMode_LW_SHARED, |list| {
+            list.iter()
+                .find(|extension| match version {
+                    Some(version) => extension.is(name, version),
+                    None => extension.name == heapless::String::<64>::truncating_from(name),
+                })
+                .and_then(|extension| {
+                    let path = extension.path.as_ref()?;
+                    Some((extension.version.to_string(), path.to_string()))
+                })
+        })
+    }
+
+    pub(crate) fn entries(&self) -> Vec<LoadedExtension> {
+        self.with_lock(pg_sys::LWLockMode_LW_SHARED, |list| list.to_vec())
+    }
+
+    pub(crate) fn size() -> usize {
+        std::mem::size_of::<List>()
+    }
+}
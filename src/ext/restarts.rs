@@ -0,0 +1,219 @@
+use crate::ext::handles::WorkerHandle;
+use crate::ext::workers::RESTART_WINDOW;
+use crate::ext::{shmem_struct, with_named_lock};
+use crate::shmem::TruncatingFrom;
+use crate::RestartPolicy;
+use cstr_core::cstr;
+use heapless::FnvIndexMap;
+use pgx::pg_sys;
+
+const MAX_TRACKED_WORKERS: usize = 1024;
+
+#[derive(Clone, Copy)]
+struct Restarts {
+    handle: WorkerHandle,
+    policy: RestartPolicy,
+    /// `bgw_restart_time` of the worker, in milliseconds
+    restart_time_ms: u64,
+    /// PID the worker was last seen running with, zero if it wasn't seen yet
+    last_pid: pg_sys::pid_t,
+    last_started: pg_sys::TimestampTz,
+    retries: u32,
+    /// When the worker was quarantined, if it is
+    quarantined_at: Option<pg_sys::TimestampTz>,
+}
+
+/// Extension, database and (expanded) name of the worker
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Key {
+    extension: heapless::String<64>,
+    database: pg_sys::Oid,
+    worker: heapless::String<96>,
+}
+
+impl Key {
+    fn new(extension: &str, database: pg_sys::Oid, worker: &str) -> Self {
+        Self {
+            extension: heapless::String::truncating_from(extension),
+            database,
+            worker: heapless::String::truncating_from(worker),
+        }
+    }
+}
+
+type Map = FnvIndexMap<Key, Restarts, MAX_TRACKED_WORKERS>;
+
+/// Tracks restarts of background workers that have a [`RestartPolicy`], by extension,
+/// database and worker name
+///
+/// Postgres restarts crashed workers by itself, so restarts are noticed by
+/// [`RestartTracker::watch`] seeing the PID of a worker change. A restart counts as a
+/// retry when the previous run lasted less than the policy's `min_interval`, and a worker
+/// that retries more than `max_retries` times in a row is stopped and quarantined.
+///
+/// Quarantined workers are admitted again once `RESTART_WINDOW` has passed, or once the
+/// extension is loaded again.
+pub(crate) struct RestartTracker {
+    map: *mut Map,
+}
+
+impl Default for RestartTracker {
+    fn default() -> Self {
+        Self {
+            map: unsafe { shmem_struct(cstr!("pgextkit_restarts"), FnvIndexMap::new) },
+        }
+    }
+}
+
+impl RestartTracker {
+    /// Returns `false` if the `worker` of the extension is quarantined in the database
+    pub(crate) fn admit(&mut self, extension: &str, database: pg_sys::Oid, worker: &str) -> bool {
+        let map = unsafe { &mut *self.map };
+        let now = unsafe { pg_sys::GetCurrentTimestamp() };
+        let quarantine = RESTART_WINDOW.as_micros() as i64;
+        with_named_lock(
+            cstr!("pgextkit_restarts"),
+            pg_sys::LWLockMode_LW_EXCLUSIVE,
+            || match map.get(&Key::new(extension, database, worker)) {
+                Some(Restarts {
+                    quarantined_at: Some(at),
+                    ..
+                }) if now - at < quarantine => false,
+                Some(Restarts {
+                    quarantined_at: Some(_),
+                    ..
+                }) => {
+                    map.remove(&Key::new(extension, database, worker));
+                    true
+                }
+                _ => true,
+            },
+        )
+    }
+
+    /// Starts tracking the restarts of a worker that was just registered with `policy`
+    pub(crate) fn track(
+        &mut self,
+        extension: &str,
+        database: pg_sys::Oid,
+        bgw: &pg_sys::BackgroundWorker,
+        handle: WorkerHandle,
+        policy: &RestartPolicy,
+    ) {
+        let map = unsafe { &mut *self.map };
+        let worker = unsafe { std::ffi::CStr::from_ptr(bgw.bgw_name.as_ptr()) }.to_string_lossy();
+        let restarts = Restarts {
+            handle,
+            policy: *policy,
+            restart_time_ms: bgw.bgw_restart_time.max(0) as u64 * 1000,
+            last_pid: 0,
+            last_started: 0,
+            retries: 0,
+            quarantined_at: None,
+        };
+        let tracked = with_named_lock(
+            cstr!("pgextkit_restarts"),
+            pg_sys::LWLockMode_LW_EXCLUSIVE,
+            || {
+                map.insert(Key::new(extension, database, &worker), restarts)
+                    .is_ok()
+            },
+        );
+        if !tracked {
+            pgx::warning!(
+                "Too many workers with a restart policy, restarts of `{}` are not tracked",
+                worker
+            );
+        }
+    }
+
+    /// Counts the restarts of tracked workers since the last call, stopping and
+    /// quarantining the ones that exceeded their policy
+    ///
+    /// This is called by the master worker, which is notified of the workers' restarts
+    /// unless they have a `bgw_notify_pid` of their own. Otherwise, restarts happening
+    /// between two of its polls are counted as one.
+    pub(crate) fn watch(&mut self) {
+        let map = unsafe { &mut *self.map };
+        let now = unsafe { pg_sys::GetCurrentTimestamp() };
+        let quarantined = with_named_lock(
+            cstr!("pgextkit_restarts"),
+            pg_sys::LWLockMode_LW_EXCLUSIVE,
+            || {
+                // Workers that exited for good aren't restarted anymore
+                let stopped = map
+                    .iter()
+                    .filter(|(_, restarts)| {
+                        restarts.quarantined_at.is_none() && restarts.handle.is_stopped()
+                    })
+                    .map(|(key, _)| key.clone())
+                    .collect::<Vec<_>>();
+                for key in stopped {
+                    map.remove(&key);
+                }
+                let mut quarantined = vec![];
+                for (key, restarts) in map.iter_mut() {
+                    if restarts.quarantined_at.is_some() {
+                        continue;
+                    }
+                    let pid = match restarts.handle.pid() {
+                        Some(pid) if pid != restarts.last_pid => pid,
+                        _ => continue,
+                    };
+                    if restarts.last_pid != 0 {
+                        // The worker was restarted `restart_time` after it exited
+                        let threshold =
+                            (restarts.restart_time_ms + restarts.policy.min_interval_ms) as i64;
+                        if now - restarts.last_started < threshold * 1000 {
+                            restarts.retries += 1;
+                        } else {
+                            restarts.retries = 0;
+                        }
+                    }
+                    restarts.last_pid = pid;
+                    restarts.last_started = now;
+                    if restarts.retries > restarts.policy.max_retries {
+                        restarts.quarantined_at = Some(now);
+                        quarantined.push((key.worker.clone(), *restarts));
+                    }
+                }
+                quarantined
+            },
+        );
+        for (worker, restarts) in quarantined {
+            restarts.handle.terminate();
+            pgx::warning!(
+                "Background worker `{}` restarted more than {} times in a row after running for less than {}ms, quarantining it for {:?}",
+                worker,
+                restarts.policy.max_retries,
+                restarts.policy.min_interval_ms,
+                RESTART_WINDOW
+            );
+        }
+    }
+
+    /// Forgets the restarts of the extension's workers in every database, lifting
+    /// their quarantine
+    pub(crate) fn clear(&mut self, extension: &str) {
+        let map = unsafe { &mut *self.map };
+        let extension = heapless::String::<64>::truncating_from(extension);
+        with_named_lock(
+            cstr!("pgextkit_restarts"),
+            pg_sys::LWLockMode_LW_EXCLUSIVE,
+            || {
+                let keys = map
+                    .keys()
+                    .filter(|key| key.extension == extension)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for key in keys {
+                    map.remove(&key);
+                }
+            },
+        )
+    }
+
+    pub(crate) fn size() -> usize {
+        std::mem::size_of::<Map>()
+    }
+}
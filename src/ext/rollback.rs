@@ -0,0 +1,83 @@
+//! Undoing the effects of an extension whose loading failed part-way
+//!
+//! While an extension is being loaded by `pgextkit.load()`, the dynamic handle stages
+//! every allocation and worker registration here. If its initialization fails, they are
+//! undone so that the extension can be loaded again without leaking shared memory or
+//! leaving its workers running.
+use crate::ext::allocations::Allocations;
+use crate::ext::handles::{WorkerHandle, WorkerHandles};
+use pgx::pg_sys;
+use std::alloc::Layout;
+
+pub(crate) enum Step {
+    Allocation {
+        ptr: *mut u8,
+        layout: Layout,
+        /// Whether it was allocated from the overflow pool
+        overflow: bool,
+    },
+    Worker {
+        database: pg_sys::Oid,
+        handle: WorkerHandle,
+    },
+}
+
+/// Steps taken by the extension being loaded by this backend, if any
+static mut STAGED: Option<Vec<Step>> = None;
+
+/// Records the step if an extension is being loaded
+pub(crate) fn stage(step: Step) {
+    if let Some(steps) = unsafe { STAGED.as_mut() } {
+        steps.push(step);
+    }
+}
+
+/// Load of an extension, undone when dropped unless committed
+///
+/// Postgres errors raised while loading are turned into panics, so the load is undone
+/// while unwinding.
+pub(crate) struct StagedLoad {
+    extension: String,
+}
+
+impl StagedLoad {
+    pub(crate) fn begin(extension: &str) -> Self {
+        unsafe { STAGED = Some(vec![]) };
+        Self {
+            extension: extension.to_string(),
+        }
+    }
+
+    pub(crate) fn commit(self) {
+        unsafe { STAGED = None };
+    }
+}
+
+impl Drop for StagedLoad {
+    fn drop(&mut self) {
+        let steps = match unsafe { STAGED.take() } {
+            Some(steps) => steps,
+            None => return,
+        };
+        let mut allocations = Allocations::default();
+        let mut handles = WorkerHandles::default();
+        for step in steps.into_iter().rev() {
+            match step {
+                Step::Allocation {
+                    ptr,
+                    layout,
+                    overflow,
+                } => allocations.free_one(&self.extension, ptr, layout, overflow),
+                Step::Worker { database, handle } => {
+                    for other in handles.take_in_database(&self.extension, database) {
+                        if other != handle {
+                            handles.restore(&self.extension, database, other);
+                        }
+                    }
+                    handle.terminate();
+                }
+            }
+        }
+        pgx::log!("Undid the partial load of {}", self.extension);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::ext::{shmem_struct, with_named_lock};
+use crate::shmem::TruncatingFrom;
+use cstr_core::cstr;
+use heapless::FnvIndexMap;
+use pgx::pg_sys;
+
+const MAX_EXTENSIONS: usize = 128;
+
+#[derive(Default, Clone, Copy)]
+pub(crate) struct Usage {
+    pub(crate) quota: Option<usize>,
+    /// Bytes allocated from either the arena or the overflow pool, which the quota limits
+    pub(crate) used: usize,
+    /// Part of `used` allocated from the overflow pool
+    pub(crate) overflow: usize,
+}
+
+type Map = FnvIndexMap<heapless::String<64>, Usage, MAX_EXTENSIONS>;
+
+/// Per-extension accounting of shared memory allocated from pgextkit's arena and overflow
+/// pool
+///
+/// Sizes are the footprints of the allocations' layouts, that is, including their padding.
+pub(crate) struct ShmemUsage {
+    map: *mut Map,
+}
+
+impl Default for ShmemUsage {
+    fn default() -> Self {
+        Self {
+            map: unsafe { shmem_struct(cstr!("pgextkit_shmem_usage"), FnvIndexMap::new) },
+        }
+    }
+}
+
+impl ShmemUsage {
+    fn with_lock<R, F: FnOnce(&mut Map) -> R>(&self, mode: pg_sys::LWLockMode, f: F) -> R {
+        with_named_lock(cstr!("pgextkit_shmem_usage"), mode, || {
+            f(unsafe { &mut *self.map })
+        })
+    }
+
+    pub(crate) fn set_quota(&mut self, name: &str, quota: usize) {
+        self.with_lock(pg_sys::LWLockMode_LW_EXCLUSIVE, |map| {
+            let name = heapless::String::truncating_from(name);
+            match map.get_mut(&name) {
+                Some(usage) => usage.quota = Some(quota),
+                None => {
+                    let _ = map.insert(
+                        name,
+                        Usage {
+                            quota: Some(quota),
+                            ..Usage::default()
+                        },
+                    );
+                }
+            }
+        })
+    }
+
+    /// Accounts for `size` bytes allocated by the extension `name`,
+    /// failing if that would exceed its quota
+    pub(crate) fn allocate(&mut self, name: &str, size: usize) -> Result<(), anyhow::Error> {
+        self.with_lock(pg_sys::LWLockMode_LW_EXCLUSIVE, |map| {
+            let name = heapless::String::truncating_from(name);
+            if !map.contains_key(&name) {
+                map.insert(name.clone(), Usage::default()).map_err(|_| {
+                    anyhow::Error::msg("too many extensions to account shared memory for")
+                })?;
+            }
+            let usage = map.get_mut(&name).expect("usage entry");
+            match usage.quota {
+                Some(quota) if usage.used + size > quota => Err(anyhow::Error::msg(format!(
+                    "extension {} exceeded its shared memory quota ({} bytes used, {} bytes requested, {} bytes allowed)",
+                    name, usage.used, size, quota
+                ))),
+                _ => {
+                    usage.used += size;
+                    Ok(())
+                }
+            }
+        })
+    }
+
+    /// Accounts for `size` bytes, already accounted for by [`ShmemUsage::allocate`], as
+    /// allocated from the overflow pool rather than the arena
+    pub(crate) fn allocated_overflow(&mut self, name: &str, size: usize) {
+        self.with_lock(pg_sys::LWLockMode_LW_EXCLUSIVE, |map| {
+            if let Some(usage) = map.get_mut(&heapless::String::truncating_from(name)) {
+                usage.overflow += size;
+            }
+        })
+    }
+
+    /// Reverts accounting of an allocation that didn't succeed or was freed
+    pub(crate) fn release(&mut self, name: &str, size: usize, overflow: bool) {
+        self.with_lock(pg_sys::LWLockMode_LW_EXCLUSIVE, |map| {
+            if let Some(usage) = map.get_mut(&heapless::String::truncating_from(name)) {
+                usage.used = usage.used.saturating_sub(size);
+                if overflow {
+                    usage.overflow = usage.overflow.saturating_sub(size);
+                }
+            }
+        })
+    }
+
+    /// Total amount of memory allocated from the arena across all extensions
+    pub(crate) fn arena_used(&self) -> usize {
+        self.with_lock(pg_sys::LWLockMode_LW_SHARED, |map| {
+            map.values().map(|usage| usage.used - usage.overflow).sum()
+        })
+    }
+
+    /// Total amount of memory allocated from the overflow pool across all extensions
+    pub(crate) fn overflow_used(&self) -> usize {
+        self.with_lock(pg_sys::LWLockMode_LW_SHARED, |map| {
+            map.values().map(|usage| usage.overflow).sum()
+        })
+    }
+
+    pub(crate) fn entries(&self) -> Vec<(String, Usage)> {
+        self.with_lock(pg_sys::LWLockMode_LW_SHARED, |map| {
+            map.iter()
+                .map(|(name, usage)| (name.to_string(), *usage))
+                .collect()
+        })
+    }
+
+    pub(crate) fn size() -> usize {
+        std::mem::size_of::<Map>()
+    }
+}
@@ -1,13 +1,18 @@
 use crate::ext;
-use crate::ext::BACKGROUND_WORKERS;
+use crate::ext::handles::WorkerHandle;
+use crate::ext::{DisabledWorkers, RestartTracker, WorkerHandles, BACKGROUND_WORKERS};
+use crate::shmem::TruncatingFrom;
 use crate::types::{RpgffiChar128, RpgffiChar96};
+use crate::worker::WorkerContext;
+use cstr_core::cstr;
 use pgx::bgworkers::{BackgroundWorker, BackgroundWorkerBuilder, SignalWakeFlags};
-use pgx::cstr_core::CStr;
+use pgx::cstr_core::{CStr, CString};
 use pgx::pg_sys::{AccessShareLock, DatabaseRelationId, ScanDirection_ForwardScanDirection};
 use pgx::{pg_guard, pg_sys, IntoDatum};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ptr::null_mut;
-use std::time::Duration;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::{Duration, Instant};
 
 #[pg_guard]
 #[no_mangle]
@@ -15,47 +20,386 @@ pub extern "C" fn master_worker(_arg: pg_sys::Datum) {
     BackgroundWorker::connect_worker_to_spi(None, None);
     BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
 
-    let mut databases = vec![];
+    MasterState::get()
+        .pid
+        .store(unsafe { pg_sys::MyProcPid }, Ordering::SeqCst);
+
+    let poll_interval = Duration::from_millis(ext::MASTER_POLL_INTERVAL_SETTING.get() as u64);
+    let startup_timeout = Duration::from_millis(ext::WORKER_STARTUP_TIMEOUT_SETTING.get() as u64);
+    let max_restarts = ext::DATABASE_WORKER_MAX_RESTARTS_SETTING.get() as usize;
+
+    let mut databases: HashMap<String, WorkerHandle> = HashMap::new();
+    // Databases whose worker failed to start, along with when to retry and the current backoff
+    let mut retries: HashMap<String, (Instant, Duration)> = HashMap::new();
+    // Databases extensions were notified about
+    let mut known: HashSet<String> = HashSet::new();
+    // Last seen PIDs of database workers, and when they were seen restarting
+    let mut restarts: HashMap<String, (Option<pg_sys::pid_t>, Vec<Instant>)> = HashMap::new();
+    // Databases whose worker kept crashing, it isn't started again
+    let mut quarantined: HashSet<String> = HashSet::new();
+    // OIDs of the databases, they can't be looked up anymore once dropped
+    let mut oids: HashMap<String, pg_sys::Oid> = HashMap::new();
 
     loop {
-        let mut new_dbs = get_new_databases(databases.as_slice());
-        if !new_dbs.is_empty() {
-            for database in &new_dbs {
-                let executor_startup = BackgroundWorkerBuilder::new(
-                    format!("pgexitkit_database: {}", database).as_str(),
-                )
-                .set_function("database_worker")
-                .set_library("pgextkit")
-                .set_argument(0.into_datum())
-                .set_extra(database)
-                .set_restart_time(Some(Duration::from_secs(0)))
-                .enable_spi_access()
-                .enable_shmem_access(None)
-                .set_notify_pid(unsafe { pg_sys::MyProcPid })
-                .load_dynamic()
-                .wait_for_startup();
-                match executor_startup {
-                    Ok(pid) => {
-                        pgx::debug1!("Started pgextkit worker for `{}` (pid {})", database, pid);
-                    }
-                    Err(status) => {
-                        pgx::error!(
-                            "Failed to start pgextkit worker for `{}`: {:?}",
-                            database,
-                            status
-                        );
-                    }
+        let live_databases = get_databases();
+        remember_oids(&mut oids, &live_databases);
+
+        // Database workers have exited already, but what they started is still running
+        remove_dropped(&mut databases, &live_databases);
+        for (database, oid) in remove_dropped(&mut oids, &live_databases) {
+            pgx::debug1!("Stopping extension workers of dropped `{}`", database);
+            forget_database(oid);
+        }
+        retries.retain(|database, _| live_databases.contains(database));
+        known.retain(|database| live_databases.contains(database));
+        restarts.retain(|database, _| databases.contains_key(database));
+        quarantined.retain(|database| live_databases.contains(database));
+
+        if max_restarts > 0 {
+            for database in watch_restarts(&databases, &mut restarts, max_restarts) {
+                if let Some(worker) = databases.remove(&database) {
+                    worker.terminate();
+                }
+                restarts.remove(&database);
+                let message = format!(
+                    "database worker restarted more than {} times within {:?}, not restarting it",
+                    max_restarts, RESTART_WINDOW
+                );
+                pgx::warning!("pgextkit: {} in `{}`", message, database);
+                crate::worker_errors::record_in("pgextkit", &database, &message);
+                quarantined.insert(database);
+            }
+        }
+
+        let new_databases = take_new(&mut known, &live_databases);
+        if !new_databases.is_empty() {
+            notify_new_databases(&new_databases);
+        }
+
+        for database in live_databases {
+            if databases.contains_key(&database) || quarantined.contains(&database) {
+                continue;
+            }
+            if let Some((retry_at, _)) = retries.get(&database) {
+                if Instant::now() < *retry_at {
+                    continue;
+                }
+            }
+            match start_database_worker(&database, startup_timeout) {
+                Ok(worker) => {
+                    retries.remove(&database);
+                    databases.insert(database, worker);
+                }
+                // Shutting down, the remaining databases don't matter anymore
+                Err(_) if BackgroundWorker::sigterm_received() => return,
+                Err(err) => {
+                    let backoff = retries
+                        .get(&database)
+                        .map(|(_, backoff)| (*backoff * 2).min(MAX_RETRY_BACKOFF))
+                        .unwrap_or(MIN_RETRY_BACKOFF);
+                    pgx::warning!(
+                        "Failed to start pgextkit worker for `{}`: {}, retrying in {:?}",
+                        database,
+                        err,
+                        backoff
+                    );
+                    crate::worker_errors::record_in(
+                        "pgextkit",
+                        &database,
+                        &format!("failed to start database worker: {}", err),
+                    );
+                    retries.insert(database, (Instant::now() + backoff, backoff));
                 }
             }
-            databases.append(&mut new_dbs);
         }
-        if !BackgroundWorker::wait_latch(Some(Duration::from_millis(100))) {
+        MasterState::get().set_databases(databases.keys());
+        RestartTracker::default().watch();
+        crate::heartbeat::beat();
+        // Database creation and removal wake us up, so this is merely a safety net
+        if !BackgroundWorker::wait_latch(Some(poll_interval)) {
             break;
         }
     }
 }
 
-fn get_new_databases(existing_databases: &[String]) -> Vec<String> {
+/// Databases of `live` that aren't `known` yet, which are added to it
+pub(crate) fn take_new(known: &mut HashSet<String>, live: &[String]) -> Vec<String> {
+    let new = live
+        .iter()
+        .filter(|database| !known.contains(*database))
+        .cloned()
+        .collect::<Vec<_>>();
+    known.extend(new.iter().cloned());
+    new
+}
+
+/// Removes the databases that aren't `live` anymore, returning them along with their workers
+pub(crate) fn remove_dropped<W>(
+    databases: &mut HashMap<String, W>,
+    live: &[String],
+) -> Vec<(String, W)> {
+    let dropped = databases
+        .keys()
+        .filter(|database| !live.contains(database))
+        .cloned()
+        .collect::<Vec<_>>();
+    dropped
+        .into_iter()
+        .filter_map(|database| databases.remove(&database).map(|worker| (database, worker)))
+        .collect()
+}
+
+/// Looks up the OIDs of the `live` databases that aren't in `oids` yet
+fn remember_oids(oids: &mut HashMap<String, pg_sys::Oid>, live: &[String]) {
+    if live.iter().all(|database| oids.contains_key(database)) {
+        return;
+    }
+    BackgroundWorker::transaction(|| {
+        for database in live {
+            if oids.contains_key(database) {
+                continue;
+            }
+            let name = CString::new(database.as_str()).expect("database name");
+            let oid = unsafe { pg_sys::get_database_oid(name.as_ptr(), true) };
+            // Dropped in the meantime
+            if oid != pg_sys::InvalidOid {
+                oids.insert(database.clone(), oid);
+            }
+        }
+    });
+}
+
+/// Stops the extension workers connected to the dropped database, and lets other
+/// databases have its slots of `DatabaseLocal`
+pub(crate) fn forget_database(database: pg_sys::Oid) {
+    for (extension, worker) in WorkerHandles::default().take_database(database) {
+        pgx::debug1!(
+            "Terminating {} worker of dropped database {}",
+            extension,
+            database
+        );
+        worker.terminate();
+    }
+    crate::db::forget_database(database);
+}
+
+/// Calls the callbacks registered with `Handle::on_new_database` for each of the databases
+fn notify_new_databases(databases: &[String]) {
+    let callbacks = unsafe { &ext::NEW_DATABASE_CALLBACKS };
+    if callbacks.is_empty() {
+        return;
+    }
+    BackgroundWorker::transaction(|| {
+        for database in databases {
+            let name = CString::new(database.as_str()).expect("database name");
+            let oid = unsafe { pg_sys::get_database_oid(name.as_ptr(), true) };
+            // Dropped in the meantime
+            if oid == pg_sys::InvalidOid {
+                continue;
+            }
+            for (extension, callback) in callbacks {
+                pgx::debug1!("Notifying {} of database `{}`", extension, database);
+                callback(oid, name.as_ptr());
+            }
+        }
+    });
+}
+
+/// Window within which database worker restarts are counted
+pub(crate) const RESTART_WINDOW: Duration = Duration::from_secs(600);
+
+/// Counts restarts of database workers (as changes of their PIDs), returns the databases
+/// whose worker restarted more than `max_restarts` times within `RESTART_WINDOW`
+///
+/// Workers are only checked on every iteration of the master, restarts in between are
+/// counted once.
+pub(crate) fn watch_restarts(
+    databases: &HashMap<String, WorkerHandle>,
+    restarts: &mut HashMap<String, (Option<pg_sys::pid_t>, Vec<Instant>)>,
+    max_restarts: usize,
+) -> Vec<String> {
+    let now = Instant::now();
+    let mut crashing = vec![];
+    for (database, worker) in databases {
+        let pid = worker.pid();
+        let (last_pid, restarted_at) = restarts.entry(database.clone()).or_default();
+        if let (Some(last), Some(pid)) = (*last_pid, pid) {
+            if last != pid {
+                restarted_at.push(now);
+            }
+        }
+        if pid.is_some() {
+            *last_pid = pid;
+        }
+        restarted_at.retain(|at| now.duration_since(*at) < RESTART_WINDOW);
+        if restarted_at.len() > max_restarts {
+            crashing.push(database.clone());
+        }
+    }
+    crashing
+}
+
+/// Backoff before retrying to start a database worker that failed to start
+const MIN_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(300);
+
+pub(crate) fn start_database_worker(
+    database: &str,
+    startup_timeout: Duration,
+) -> Result<WorkerHandle, String> {
+    let mut bgw: pg_sys::BackgroundWorker =
+        (&BackgroundWorkerBuilder::new(format!("pgexitkit_database: {}", database).as_str())
+            .set_function("database_worker")
+            .set_library("pgextkit")
+            .set_argument(0.into_datum())
+            .set_extra(database)
+            .set_restart_time(Some(Duration::from_secs(0)))
+            .enable_spi_access()
+            .enable_shmem_access(None)
+            .set_notify_pid(unsafe { pg_sys::MyProcPid }))
+            .into();
+    let mut handle = null_mut();
+    if !unsafe { pg_sys::RegisterDynamicBackgroundWorker(&mut bgw, &mut handle) } {
+        return Err("no background worker slots available".to_string());
+    }
+    let worker = unsafe { WorkerHandle::from_raw(handle) };
+    match worker.wait_for_startup(startup_timeout, BackgroundWorker::sigterm_received) {
+        Some(pid) => {
+            pgx::debug1!("Started pgextkit worker for `{}` (pid {})", database, pid);
+            Ok(worker)
+        }
+        None => {
+            // Don't leave it around if it's merely slow to start
+            worker.terminate();
+            if BackgroundWorker::sigterm_received() {
+                Err("pgextkit is shutting down".to_string())
+            } else {
+                Err(format!("it didn't start within {:?}", startup_timeout))
+            }
+        }
+    }
+}
+
+const MAX_DATABASES: usize = 256;
+
+/// Shared state of the master worker
+pub(crate) struct MasterState {
+    pid: AtomicI32,
+    /// Databases the master worker has started workers for
+    databases: heapless::Vec<heapless::String<64>, MAX_DATABASES>,
+}
+
+impl MasterState {
+    pub(crate) fn get() -> &'static mut Self {
+        unsafe {
+            &mut *ext::shmem_struct(cstr!("pgextkit_master"), || MasterState {
+                pid: AtomicI32::new(0),
+                databases: heapless::Vec::new(),
+            })
+        }
+    }
+
+    fn set_databases<'a, I: Iterator<Item = &'a String>>(&mut self, databases: I) {
+        ext::with_named_lock(
+            cstr!("pgextkit_master"),
+            pg_sys::LWLockMode_LW_EXCLUSIVE,
+            || {
+                self.databases.clear();
+                for database in databases {
+                    if self
+                        .databases
+                        .push(heapless::String::truncating_from(database))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            },
+        )
+    }
+
+    /// Databases the master worker has started workers for
+    pub(crate) fn databases(&self) -> Vec<String> {
+        ext::with_named_lock(
+            cstr!("pgextkit_master"),
+            pg_sys::LWLockMode_LW_SHARED,
+            || {
+                self.databases
+                    .iter()
+                    .map(|database| database.to_string())
+                    .collect()
+            },
+        )
+    }
+
+    /// PID of the master worker, zero if it isn't running yet
+    pub(crate) fn pid(&self) -> pg_sys::pid_t {
+        self.pid.load(Ordering::SeqCst)
+    }
+
+    /// Wakes the master worker up so it rescans databases
+    fn wake_up(&self) {
+        let pid = self.pid.load(Ordering::SeqCst);
+        if pid == 0 {
+            return;
+        }
+        unsafe {
+            let proc = pg_sys::BackendPidGetProc(pid);
+            if !proc.is_null() {
+                pg_sys::SetLatch(&mut (*proc).procLatch);
+            }
+        }
+    }
+}
+
+static mut DATABASES_CHANGED: bool = false;
+
+/// Tracks creation and removal of databases so that the master worker
+/// is woken up as soon as such a transaction commits
+pub(crate) fn install_database_hooks() {
+    static mut PREV_OBJECT_ACCESS_HOOK: pg_sys::object_access_hook_type = None;
+
+    #[pg_guard]
+    unsafe extern "C" fn object_access_hook(
+        access: pg_sys::ObjectAccessType,
+        class_id: pg_sys::Oid,
+        object_id: pg_sys::Oid,
+        sub_id: std::ffi::c_int,
+        arg: *mut std::ffi::c_void,
+    ) {
+        if let Some(hook) = PREV_OBJECT_ACCESS_HOOK {
+            hook(access, class_id, object_id, sub_id, arg);
+        }
+        if class_id == DatabaseRelationId
+            && (access == pg_sys::ObjectAccessType_OAT_POST_CREATE
+                || access == pg_sys::ObjectAccessType_OAT_DROP)
+        {
+            DATABASES_CHANGED = true;
+        }
+    }
+
+    #[pg_guard]
+    unsafe extern "C" fn xact_callback(event: pg_sys::XactEvent, _arg: *mut std::ffi::c_void) {
+        match event {
+            pg_sys::XactEvent_XACT_EVENT_COMMIT if DATABASES_CHANGED => {
+                DATABASES_CHANGED = false;
+                MasterState::get().wake_up();
+            }
+            pg_sys::XactEvent_XACT_EVENT_ABORT => {
+                DATABASES_CHANGED = false;
+            }
+            _ => {}
+        }
+    }
+
+    unsafe {
+        PREV_OBJECT_ACCESS_HOOK = pg_sys::object_access_hook;
+        pg_sys::object_access_hook = Some(object_access_hook);
+        pg_sys::RegisterXactCallback(Some(xact_callback), null_mut());
+    }
+}
+
+pub(crate) fn get_databases() -> Vec<String> {
     BackgroundWorker::transaction(|| unsafe {
         let mut result = vec![];
         {
@@ -73,13 +417,9 @@ fn get_new_databases(existing_databases: &[String]) -> Vec<String> {
 
                 let str = CStr::from_ptr((*class).datname.data.as_ptr());
                 let name: String = str.to_string_lossy().into();
-                if !existing_databases.contains(&name) {
-                    result.push(name);
-                }
-            }
-            if let Some(end) = (*(*(*scan).rs_rd).rd_tableam).scan_end {
-                end(scan);
+                result.push(name);
             }
+            ext::end_catalog_scan(scan);
             pg_sys::table_close(rel, AccessShareLock as _);
         }
         result
@@ -93,27 +433,131 @@ pub extern "C" fn database_worker(_arg: pg_sys::Datum) {
     BackgroundWorker::connect_worker_to_spi(Some(database), None);
     BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
 
-    let extensions = BackgroundWorker::transaction(|| {
-        ext::get_extensions()
-            .into_iter()
-            .map(|(name, version, username)| (name, (version, username)))
-            .collect::<HashMap<_, _>>()
-    });
+    let extensions = BackgroundWorker::transaction(ext::get_extensions);
+    start_workers(database, extensions);
+}
+
+/// Tokens that are left as-is in worker names, so that they're only warned about once
+static mut UNKNOWN_NAME_TOKENS: Vec<String> = vec![];
 
-    for (name, version, bgw) in unsafe { BACKGROUND_WORKERS.iter_mut() } {
+/// Substitutes `{{DATABASE}}`, `{{USER}}`, `{{EXTENSION}}` and `{{VERSION}}` in the name
+/// of a worker
+///
+/// Unknown tokens are left as-is.
+pub(crate) fn expand_worker_name(
+    template: &str,
+    database: &str,
+    username: &str,
+    extension: &str,
+    version: &str,
+) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(len) => start + len + 2,
+            None => break,
+        };
+        result.push_str(&rest[..start]);
+        let token = &rest[start..end];
+        match token {
+            "{{DATABASE}}" => result.push_str(database),
+            "{{USER}}" => result.push_str(username),
+            "{{EXTENSION}}" => result.push_str(extension),
+            "{{VERSION}}" => result.push_str(version),
+            _ => {
+                let warned = unsafe { &mut UNKNOWN_NAME_TOKENS };
+                if !warned.iter().any(|warned| warned == token) {
+                    pgx::warning!(
+                        "Unknown token {} in the name of a worker of {}",
+                        token,
+                        extension
+                    );
+                    warned.push(token.to_string());
+                }
+                result.push_str(token);
+            }
+        }
+        rest = &rest[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Has the master worker notified of the worker's restarts, so it counts them as they
+/// happen, unless the worker notifies another backend already
+pub(crate) fn notify_master(bgw: &mut pg_sys::BackgroundWorker) {
+    if bgw.bgw_notify_pid == 0 {
+        bgw.bgw_notify_pid = MasterState::get().pid();
+    }
+}
+
+/// Starts background workers registered during preloading by the `extensions`
+/// (name, version, owner) installed in the current database
+pub(crate) fn start_workers(database: &str, extensions: Vec<(String, String, String)>) {
+    let extensions = extensions
+        .into_iter()
+        .map(|(name, version, username)| (name, (version, username)))
+        .collect::<HashMap<_, _>>();
+    let disabled = DisabledWorkers::default();
+
+    for (name, version, bgw, policy) in unsafe { BACKGROUND_WORKERS.iter_mut() } {
         if let Some((installed_version, username)) = extensions.get(name) {
             if installed_version == version {
+                if disabled.is_disabled(unsafe { pg_sys::MyDatabaseId }, name) {
+                    pgx::debug1!("Workers of {} are disabled in `{}`", name, database);
+                    continue;
+                }
                 unsafe {
-                    bgw.bgw_extra =
-                        RpgffiChar128::from(format!("{}@{}", username, database).as_str()).0;
-                    (*bgw).bgw_name = RpgffiChar96::from(
-                        CStr::from_ptr((*bgw).bgw_name.as_ptr())
-                            .to_string_lossy()
-                            .replace("{{DATABASE}}", database)
+                    bgw.bgw_extra = RpgffiChar128::from(
+                        WorkerContext::new(database, username.as_str())
+                            .encode()
                             .as_str(),
                     )
                     .0;
-                    pg_sys::RegisterDynamicBackgroundWorker(&mut **bgw, std::ptr::null_mut());
+                    (*bgw).bgw_name = RpgffiChar96::from(
+                        expand_worker_name(
+                            CStr::from_ptr((*bgw).bgw_name.as_ptr())
+                                .to_string_lossy()
+                                .as_ref(),
+                            database,
+                            username,
+                            name,
+                            version,
+                        )
+                        .as_str(),
+                    )
+                    .0;
+                    if policy.is_some() {
+                        let worker = CStr::from_ptr((*bgw).bgw_name.as_ptr()).to_string_lossy();
+                        if !RestartTracker::default().admit(
+                            name,
+                            pg_sys::MyDatabaseId,
+                            worker.as_ref(),
+                        ) {
+                            continue;
+                        }
+                        notify_master(&mut **bgw);
+                    }
+                    let mut handle = null_mut();
+                    if pg_sys::RegisterDynamicBackgroundWorker(&mut **bgw, &mut handle) {
+                        WorkerHandles::default().record(name, pg_sys::MyDatabaseId, handle);
+                        if let Some(policy) = policy {
+                            RestartTracker::default().track(
+                                name,
+                                pg_sys::MyDatabaseId,
+                                &**bgw,
+                                WorkerHandle::from_raw(handle),
+                                policy,
+                            );
+                        }
+                    } else {
+                        crate::worker_errors::record_in(
+                            name,
+                            database,
+                            "no background worker slots available",
+                        );
+                    }
                 }
             }
         }
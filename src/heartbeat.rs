@@ -0,0 +1,134 @@
+//! Liveness reporting of background workers
+//!
+//! Workers call [`beat`] on every iteration of their main loop, and
+//! `pgextkit.worker_heartbeats()` shows when each of them last did so.
+use crate::shmem::{compare, make_hashkey, TruncatingFrom};
+use cstr_core::cstr;
+use pgx::pg_sys;
+use std::ffi::{c_void, CStr};
+use std::mem::size_of;
+
+const MAX_WORKERS: usize = 1024;
+
+type Key = heapless::String<96>;
+
+#[repr(C)]
+struct Entry {
+    // Key must be the first field of the hash table entry
+    name: Key,
+    /// PID of the worker that last reported under the name
+    pid: i32,
+    tick: u64,
+    last_update: pg_sys::TimestampTz,
+}
+
+fn htab() -> *mut pg_sys::HTAB {
+    let addin_shmem_init_lock: *mut pg_sys::LWLock =
+        unsafe { &mut (*pg_sys::MainLWLockArray.add(21)).lock };
+    let mut ctl = unsafe { std::mem::zeroed::<pg_sys::HASHCTL>() };
+    ctl.keysize = size_of::<Key>();
+    ctl.entrysize = size_of::<Entry>();
+    ctl.hash = Some(make_hashkey);
+    ctl.match_ = Some(compare);
+    unsafe {
+        pg_sys::LWLockAcquire(addin_shmem_init_lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+        let htab = pg_sys::ShmemInitHash(
+            cstr!("pgextkit_heartbeats").as_ptr(),
+            MAX_WORKERS as _,
+            MAX_WORKERS as _,
+            &mut ctl,
+            (pg_sys::HASH_ELEM | pg_sys::HASH_FUNCTION | pg_sys::HASH_COMPARE) as _,
+        );
+        pg_sys::LWLockRelease(addin_shmem_init_lock);
+        htab
+    }
+}
+
+fn lock() -> *mut pg_sys::LWLock {
+    unsafe { &mut (*pg_sys::GetNamedLWLockTranche(cstr!("pgextkit_heartbeats").as_ptr())).lock }
+}
+
+/// Records that the current background worker is alive and making progress
+pub fn beat() {
+    let worker = unsafe { pg_sys::MyBgworkerEntry };
+    if worker.is_null() {
+        return;
+    }
+    let name = Key::truncating_from(
+        unsafe { CStr::from_ptr((*worker).bgw_name.as_ptr()) }.to_string_lossy(),
+    );
+    let htab = htab();
+    let lock = lock();
+    unsafe {
+        pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+        let mut found = false;
+        let entry = pg_sys::hash_search(
+            htab,
+            &name as *const _ as *const c_void,
+            pg_sys::HASHACTION_HASH_ENTER_NULL,
+            &mut found,
+        ) as *mut Entry;
+        if !entry.is_null() {
+            if !found {
+                std::ptr::addr_of_mut!((*entry).tick).write(0);
+            }
+            (*entry).pid = pg_sys::MyProcPid;
+            (*entry).tick = (*entry).tick.wrapping_add(1);
+            (*entry).last_update = pg_sys::GetCurrentTimestamp();
+        }
+        pg_sys::LWLockRelease(lock);
+    }
+}
+
+/// Names, ticks and times of the last heartbeat of all workers
+#[cfg(feature = "extension")]
+pub(crate) fn entries() -> Vec<(String, u64, pg_sys::TimestampTz)> {
+    let htab = htab();
+    let lock = lock();
+    let mut result = vec![];
+    unsafe {
+        pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_SHARED);
+        let mut status = std::mem::zeroed::<pg_sys::HASH_SEQ_STATUS>();
+        pg_sys::hash_seq_init(&mut status, htab);
+        loop {
+            let entry = pg_sys::hash_seq_search(&mut status) as *const Entry;
+            if entry.is_null() {
+                break;
+            }
+            result.push((
+                (*entry).name.to_string(),
+                (*entry).tick,
+                (*entry).last_update,
+            ));
+        }
+        pg_sys::LWLockRelease(lock);
+    }
+    result
+}
+
+/// PIDs of the workers that reported a heartbeat
+#[cfg(feature = "extension")]
+pub(crate) fn beating_pids() -> std::collections::HashSet<i32> {
+    let htab = htab();
+    let lock = lock();
+    let mut result = std::collections::HashSet::new();
+    unsafe {
+        pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_SHARED);
+        let mut status = std::mem::zeroed::<pg_sys::HASH_SEQ_STATUS>();
+        pg_sys::hash_seq_init(&mut status, htab);
+        loop {
+            let entry = pg_sys::hash_seq_search(&mut status) as *const Entry;
+            if entry.is_null() {
+                break;
+            }
+            result.insert((*entry).pid);
+        }
+        pg_sys::LWLockRelease(lock);
+    }
+    result
+}
+
+#[cfg(feature = "extension")]
+pub(crate) fn size() -> usize {
+    unsafe { pg_sys::hash_estimate_size(MAX_WORKERS as _, size_of::<Entry>()) }
+}
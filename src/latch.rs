@@ -1,15 +1,24 @@
-use crate::types::SyncMut;
+use crate::types::{ShmemSafe, SyncMut};
 use bitflags::bitflags;
 use once_cell::sync::OnceCell;
 use pgx::check_for_interrupts;
 use pgx::prelude::*;
+use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::future::Future;
 use std::mem::MaybeUninit;
+use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex, Weak};
-use std::time::Duration;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Clone)]
+/// Latch in shared memory that any backend can set to wake up the backend owning it
+///
+/// [`SharedLatch::own`] hands out an [`OwnedLatch`] pointing at this very latch, so it must
+/// stay in place (in shared memory) for as long as it's owned. It can't be cloned: setting
+/// a copy would never wake the owner up.
+#[derive(Debug)]
 pub struct SharedLatch {
     latch: pg_sys::Latch,
 }
@@ -24,11 +33,28 @@ impl SharedLatch {
         Self { latch }
     }
 
+    /// Takes ownership of the latch, returns `None` if another backend owns it
     pub fn own(&mut self) -> Option<OwnedLatch> {
+        if let Some(owner) = self.owner_pid() {
+            pgx::warning!("latch is already owned by backend {}", owner);
+            return None;
+        }
         unsafe { pg_sys::OwnLatch(&mut self.latch) }
         Some(OwnedLatch::new(&mut self.latch as *mut _))
     }
 
+    /// PID of the backend owning the latch
+    pub fn owner_pid(&self) -> Option<i32> {
+        match unsafe { std::ptr::read_volatile(&self.latch.owner_pid) } {
+            0 => None,
+            pid => Some(pid),
+        }
+    }
+
+    /// Sets the latch, waking up the backend waiting on its [`OwnedLatch`]
+    ///
+    /// If nobody owns the latch yet, it stays set and the wait of its future owner
+    /// returns immediately, so the wake up isn't lost.
     pub fn set_and_wake_up(&mut self) {
         #[cfg(feature = "raw-set-latch")]
         extern "C" {
@@ -41,10 +67,14 @@ impl SharedLatch {
 }
 
 unsafe impl SyncMut for SharedLatch {}
+unsafe impl ShmemSafe for SharedLatch {}
 
 pub struct OwnedLatch {
     latch: *mut pg_sys::Latch,
     rc: Arc<LatchPtr>,
+    reload_callbacks: RefCell<Vec<Box<dyn FnMut()>>>,
+    /// Started by the first [`OwnedLatch::set_future`]
+    waker: OnceCell<Arc<LatchWaker>>,
 }
 
 bitflags! {
@@ -55,12 +85,263 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Socket readiness to wait for with [`WaitEvents::socket`]
+    pub struct SocketEvents: u32 {
+        const READABLE = pg_sys::WL_SOCKET_READABLE;
+        const WRITEABLE = pg_sys::WL_SOCKET_WRITEABLE;
+    }
+}
+
+/// Reason for waking up from [`wait_event_set`] (and [`OwnedLatch::wait_any`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeReason {
+    /// Latch at the given index was set
+    Latch(usize),
+    /// Socket became ready for the given events
+    Socket(SocketEvents),
+    Timeout,
+    PostmasterDeath,
+}
+
+/// Frees the wait event set when dropped, even if an interrupt is raised while waiting
+struct WaitEventSet(*mut pg_sys::WaitEventSet);
+
+impl Drop for WaitEventSet {
+    fn drop(&mut self) {
+        unsafe { pg_sys::FreeWaitEventSet(self.0) }
+    }
+}
+
+/// Events to wait for with [`wait_event_set`]
+///
+/// The wait always ends on postmaster death.
+///
+/// ```ignore
+/// let reason = wait_event_set(
+///     WaitEvents::new()
+///         .latch(&latch)
+///         .timeout(Duration::from_micros(500)),
+/// );
+/// ```
+#[derive(Default)]
+pub struct WaitEvents<'a> {
+    latches: Vec<&'a OwnedLatch>,
+    socket: Option<(pg_sys::pgsocket, SocketEvents)>,
+    timeout: Option<Duration>,
+}
+
+impl<'a> WaitEvents<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wakes up when the latch is set
+    ///
+    /// PostgreSQL can only wait on a single latch at a time, so only the first latch added
+    /// wakes the backend immediately, the rest are checked every few milliseconds.
+    pub fn latch(mut self, latch: &'a OwnedLatch) -> Self {
+        self.latches.push(latch);
+        self
+    }
+
+    /// Wakes up when the socket is ready for any of the `events`
+    pub fn socket(mut self, socket: pg_sys::pgsocket, events: SocketEvents) -> Self {
+        self.socket = Some((socket, events));
+        self
+    }
+
+    /// Wakes up once `timeout` elapses
+    ///
+    /// Timeouts longer than PostgreSQL can wait for at once are split into several waits,
+    /// and PostgreSQL waits in whole milliseconds, so sub-millisecond remainders are
+    /// rounded up.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+/// Waits for any of the `events`, returning the first one that happened
+///
+/// A latch that was set is reset before returning. Interrupts are checked, and the
+/// configuration file re-read on SIGHUP, whenever the backend wakes up.
+pub fn wait_event_set(events: WaitEvents) -> WakeReason {
+    // A deadline too far in the future to represent is as good as none
+    let deadline = events
+        .timeout
+        .and_then(|timeout| Instant::now().checked_add(timeout));
+    let set = unsafe {
+        let set = WaitEventSet(pg_sys::CreateWaitEventSet(pg_sys::CurrentMemoryContext, 3));
+        if let Some(latch) = events.latches.first() {
+            pg_sys::AddWaitEventToSet(
+                set.0,
+                pg_sys::WL_LATCH_SET,
+                pg_sys::PGINVALID_SOCKET,
+                latch.latch,
+                std::ptr::null_mut(),
+            );
+        }
+        if let Some((socket, socket_events)) = events.socket {
+            pg_sys::AddWaitEventToSet(
+                set.0,
+                socket_events.bits(),
+                socket,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            );
+        }
+        pg_sys::AddWaitEventToSet(
+            set.0,
+            pg_sys::WL_POSTMASTER_DEATH,
+            pg_sys::PGINVALID_SOCKET,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        );
+        set
+    };
+
+    loop {
+        if let Some(index) = events.latches.iter().position(|latch| latch.is_set()) {
+            events.latches[index].reset();
+            return WakeReason::Latch(index);
+        }
+
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if remaining == Some(Duration::ZERO) {
+            return WakeReason::Timeout;
+        }
+        let wait = match (remaining, events.latches.len() > 1) {
+            (Some(remaining), false) => remaining,
+            (Some(remaining), true) => remaining.min(WAIT_ANY_POLL_INTERVAL),
+            (None, false) => Duration::MAX,
+            (None, true) => WAIT_ANY_POLL_INTERVAL,
+        };
+        let wait_ms = if wait == Duration::MAX {
+            -1
+        } else {
+            ((wait.as_nanos() + 999_999) / 1_000_000).min(MAX_WAIT_MS) as std::ffi::c_long
+        };
+
+        let mut event = unsafe { MaybeUninit::<pg_sys::WaitEvent>::zeroed().assume_init() };
+        let rc = unsafe {
+            pg_sys::WaitEventSetWait(set.0, wait_ms, &mut event, 1, pg_sys::PG_WAIT_EXTENSION)
+        };
+        check_for_interrupts!();
+        if OwnedLatch::reload_config_if_pending() {
+            events
+                .latches
+                .iter()
+                .for_each(|latch| latch.config_reloaded());
+        }
+        if rc > 0 {
+            if event.events & pg_sys::WL_POSTMASTER_DEATH != 0 {
+                return WakeReason::PostmasterDeath;
+            }
+            let ready = SocketEvents::from_bits_truncate(event.events);
+            if !ready.is_empty() {
+                return WakeReason::Socket(ready);
+            }
+        }
+    }
+}
+
+/// Longest timeout PostgreSQL accepts for a single wait
+pub(crate) const MAX_WAIT_MS: u128 = i32::MAX as u128;
+
+/// How often [`wait_event_set`] checks latches other than the first one
+/// (and [`LatchSetFuture`] checks its latch)
+const WAIT_ANY_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Future returned by [`OwnedLatch::set_future`]
+pub struct LatchSetFuture {
+    waker: Arc<LatchWaker>,
+}
+
+impl Future for LatchSetFuture {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.waker.is_set() {
+            return Poll::Ready(());
+        }
+        self.waker.register(cx.waker());
+        Poll::Pending
+    }
+}
+
+#[derive(Default)]
+struct LatchWakerState {
+    /// Tasks waiting for the latch to be set
+    wakers: Vec<Waker>,
+    stopped: bool,
+}
+
+/// Thread checking a latch every few milliseconds for as long as futures wait on it, started
+/// once per [`OwnedLatch`]
+struct LatchWaker {
+    latch: LatchPtr,
+    state: Mutex<LatchWakerState>,
+    changed: Condvar,
+}
+
+impl LatchWaker {
+    fn spawn(latch: *mut pg_sys::Latch) -> Arc<Self> {
+        let waker = Arc::new(Self {
+            latch: LatchPtr(latch),
+            state: Mutex::new(LatchWakerState::default()),
+            changed: Condvar::new(),
+        });
+        let thread_waker = waker.clone();
+        std::thread::spawn(move || thread_waker.run());
+        waker
+    }
+
+    fn is_set(&self) -> bool {
+        unsafe { std::ptr::read_volatile(&(*self.latch.0).is_set) != 0 }
+    }
+
+    fn register(&self, waker: &Waker) {
+        let mut state = self.state.lock().expect("can't lock latch waker");
+        if !state.wakers.iter().any(|other| other.will_wake(waker)) {
+            state.wakers.push(waker.clone());
+        }
+        self.changed.notify_one();
+    }
+
+    fn stop(&self) {
+        self.state.lock().expect("can't lock latch waker").stopped = true;
+        self.changed.notify_one();
+    }
+
+    fn run(&self) {
+        let mut state = self.state.lock().expect("can't lock latch waker");
+        loop {
+            // Idle until a future waits on the latch
+            while state.wakers.is_empty() && !state.stopped {
+                state = self.changed.wait(state).expect("can't lock latch waker");
+            }
+            if state.stopped {
+                return;
+            }
+            drop(state);
+            std::thread::sleep(WAIT_ANY_POLL_INTERVAL);
+            state = self.state.lock().expect("can't lock latch waker");
+            if self.is_set() {
+                state.wakers.drain(..).for_each(Waker::wake);
+            }
+        }
+    }
+}
+
 struct LatchPtr(*mut pg_sys::Latch);
 unsafe impl Send for LatchPtr {}
 unsafe impl Sync for LatchPtr {}
 
 static OWNED_LATCHES: OnceCell<Mutex<Vec<Weak<LatchPtr>>>> = OnceCell::new();
 static SIGNALS: OnceCell<BTreeMap<SignalWakeFlags, AtomicBool>> = OnceCell::new();
+/// Set by the signal handler, the configuration file is re-read outside of it
+static CONFIG_RELOAD_PENDING: AtomicBool = AtomicBool::new(false);
 
 impl OwnedLatch {
     fn new(latch: *mut pg_sys::Latch) -> Self {
@@ -74,48 +355,118 @@ impl OwnedLatch {
         Self {
             latch,
             rc: Arc::new(LatchPtr(latch)),
+            reload_callbacks: RefCell::new(vec![]),
+            waker: OnceCell::new(),
         }
     }
 
-    fn wait_latch(&self, timeout: i64, wakeup_flags: u32) -> i32 {
-        unsafe {
-            let latch = pg_sys::WaitLatch(
-                self.latch,
-                wakeup_flags as _,
-                timeout,
-                pg_sys::PG_WAIT_EXTENSION,
-            );
-            pg_sys::ResetLatch(self.latch);
-            check_for_interrupts!();
+    /// Registers a callback to be called after the configuration file was re-read on SIGHUP
+    ///
+    /// Callbacks are not called from the signal handler, but when the backend wakes up
+    /// from waiting on this latch, so they can safely read GUCs and allocate.
+    pub fn on_config_reload<F: FnMut() + 'static>(&mut self, callback: F) {
+        self.reload_callbacks.get_mut().push(Box::new(callback));
+    }
 
-            latch
+    /// Re-reads the configuration file if SIGHUP was received, returns `true` if it did
+    fn reload_config_if_pending() -> bool {
+        if CONFIG_RELOAD_PENDING.swap(false, Ordering::SeqCst) {
+            unsafe { pg_sys::ProcessConfigFile(pg_sys::GucContext_PGC_SIGHUP) }
+            true
+        } else {
+            false
         }
     }
 
+    fn config_reloaded(&self) {
+        for callback in self.reload_callbacks.borrow_mut().iter_mut() {
+            callback();
+        }
+    }
+
+    /// Waits until the latch is set, or until `timeout` elapses
+    ///
+    /// Timeouts longer than PostgreSQL can wait for at once are split into several waits.
     pub fn wait(&self, timeout: Option<Duration>) {
-        match timeout {
-            Some(t) => self.wait_latch(
-                t.as_millis().try_into().unwrap(),
-                pg_sys::WL_LATCH_SET | pg_sys::WL_TIMEOUT | pg_sys::WL_POSTMASTER_DEATH,
-            ),
-            None => self.wait_latch(0, pg_sys::WL_LATCH_SET | pg_sys::WL_POSTMASTER_DEATH),
-        };
+        let events = WaitEvents::new().latch(self);
+        wait_event_set(match timeout {
+            Some(timeout) => events.timeout(timeout),
+            None => events,
+        });
+    }
+
+    fn is_set(&self) -> bool {
+        unsafe { std::ptr::read_volatile(&(*self.latch).is_set) != 0 }
+    }
+
+    /// Waits until any of the `latches` is set, or until `timeout` elapses
+    ///
+    /// PostgreSQL can only wait on a single latch at a time, so only the first latch wakes
+    /// the backend immediately, the rest are checked every few milliseconds.
+    pub fn wait_any(latches: &[&OwnedLatch], timeout: Option<Duration>) -> WakeReason {
+        assert!(!latches.is_empty(), "no latches to wait on");
+        let events = latches
+            .iter()
+            .fold(WaitEvents::new(), |events, latch| events.latch(latch));
+        wait_event_set(match timeout {
+            Some(timeout) => events.timeout(timeout),
+            None => events,
+        })
     }
 
     pub fn set_and_wake_up(&self) {
         unsafe { pg_sys::SetLatch(self.latch) }
     }
 
+    /// Future that resolves once the latch is set, for use within async runtimes
+    ///
+    /// PostgreSQL doesn't expose a descriptor that could be registered with a reactor (its
+    /// self-pipe or signalfd is private to the backend), so the latch is checked every few
+    /// milliseconds from a helper thread instead, started once per latch and idle while no
+    /// future waits. The latch is not reset by the future,
+    /// call [`OwnedLatch::reset`] once it resolves.
+    ///
+    /// ```ignore
+    /// tokio::select! {
+    ///     _ = latch.set_future() => latch.reset(),
+    ///     response = client.get(url).send() => { /* ... */ }
+    /// }
+    /// ```
+    pub fn set_future(&self) -> LatchSetFuture {
+        LatchSetFuture {
+            waker: self
+                .waker
+                .get_or_init(|| LatchWaker::spawn(self.latch))
+                .clone(),
+        }
+    }
+
+    /// Clears the latch without waiting on it
+    ///
+    /// To not miss a wake up, reset the latch *before* checking for pending work:
+    /// resetting it after would discard a set that happened while the work was
+    /// being checked, and the next wait would sleep despite work being available.
+    pub fn reset(&self) {
+        unsafe { pg_sys::ResetLatch(self.latch) }
+    }
+
     pub fn disown(&self) {
         unsafe { pg_sys::DisownLatch(self.latch) }
     }
 
+    /// Wakes the latch up on the signals in `wake`
+    ///
+    /// It can be called several times to handle more signals, the latch is only
+    /// registered once.
     pub fn attach_signal_handlers(&self, wake: SignalWakeFlags) {
         if let Some(latches) = OWNED_LATCHES.get() {
-            latches
-                .lock()
-                .expect("can't lock latches")
-                .push(Arc::downgrade(&self.rc));
+            let mut latches = latches.lock().expect("can't lock latches");
+            // Forget latches that were dropped
+            latches.retain(|latch| latch.strong_count() > 0);
+            let rc = Arc::downgrade(&self.rc);
+            if !latches.iter().any(|latch| latch.ptr_eq(&rc)) {
+                latches.push(rc);
+            }
         }
         if wake.contains(SignalWakeFlags::SIGHUP) {
             unsafe {
@@ -132,14 +483,27 @@ impl OwnedLatch {
         }
     }
 
+    /// Number of times the latch is registered to be woken up on signals, for tests
+    #[cfg(all(feature = "extension", any(test, feature = "pg_test")))]
+    pub(crate) fn signal_registrations(&self) -> usize {
+        let rc = Arc::downgrade(&self.rc);
+        OWNED_LATCHES.get().map_or(0, |latches| {
+            latches
+                .lock()
+                .expect("can't lock latches")
+                .iter()
+                .filter(|latch| latch.ptr_eq(&rc))
+                .count()
+        })
+    }
+
     extern "C" fn signal_handler(signal: i32) {
         if SignalWakeFlags::from_bits(signal)
             .unwrap_or_else(SignalWakeFlags::empty)
             .contains(SignalWakeFlags::SIGHUP)
         {
-            unsafe {
-                pg_sys::ProcessConfigFile(pg_sys::GucContext_PGC_SIGHUP);
-            }
+            // Re-reading the configuration file is not safe in a signal handler
+            CONFIG_RELOAD_PENDING.store(true, Ordering::SeqCst);
         }
         if let Some(latches) = OWNED_LATCHES.get() {
             for latch in &*latches.lock().expect("can't lock latches") {
@@ -157,6 +521,14 @@ impl OwnedLatch {
         }
     }
 
+    /// Like [`OwnedLatch::signal_received`], but leaves the signal pending
+    pub(crate) fn signal_pending(&self, wake: SignalWakeFlags) -> bool {
+        SIGNALS
+            .get()
+            .and_then(|signals| signals.get(&wake))
+            .map_or(false, |flag| flag.load(Ordering::SeqCst))
+    }
+
     pub fn signal_received(&self, wake: SignalWakeFlags) -> bool {
         if let Some(signals) = SIGNALS.get() {
             if let Some(flag) = signals.get(&wake) {
@@ -169,6 +541,9 @@ impl OwnedLatch {
 
 impl Drop for OwnedLatch {
     fn drop(&mut self) {
+        if let Some(waker) = self.waker.get() {
+            waker.stop();
+        }
         self.disown();
     }
 }
@@ -2,27 +2,51 @@ use pgx::pg_sys;
 
 use std::mem::size_of;
 
-#[cfg(not(feature = "extension"))]
+pub mod arena;
+pub mod barrier;
+pub mod channel;
+pub mod config;
 pub mod db;
+pub mod dsm;
 #[cfg(feature = "extension")]
 mod ext;
+pub mod heartbeat;
 pub mod latch;
-#[cfg(not(feature = "extension"))]
+mod lock_stats;
+pub mod logging;
 pub mod lwlock;
+mod overflow;
+pub mod queue;
+pub mod service;
 pub mod shmem;
+pub mod spinlock;
 
 pub mod types;
+pub mod worker;
+pub mod worker_errors;
 
 #[cfg(not(feature = "extension"))]
 use crate::shmem::SharedDictionary;
+#[cfg(not(feature = "extension"))]
+use crate::types::ShmemSafe;
 
 #[cfg(not(feature = "extension"))]
 pub mod prelude {
+    pub use crate::arena::*;
+    pub use crate::barrier::*;
+    pub use crate::channel::*;
+    pub use crate::config::*;
     pub use crate::db::*;
+    pub use crate::dsm::*;
     pub use crate::latch::*;
+    pub use crate::logging::*;
     pub use crate::lwlock::*;
+    pub use crate::queue::*;
+    pub use crate::service::{lookup_service, ServiceVtable};
     pub use crate::shmem::*;
+    pub use crate::spinlock::*;
     pub use crate::types::*;
+    pub use crate::worker::*;
 }
 
 /// This structure is used to check whether an extension is of compatible version
@@ -30,30 +54,237 @@ pub mod prelude {
 pub struct Magic {
     /// Size of the structure (size_of::<Magic>)
     magic_size: usize,
-    /// Version of pgextkit supported (0)
+    /// Version of pgextkit supported (VERSION)
     version: u8,
+    /// Fingerprint of the pgextkit build (ABI_FINGERPRINT)
+    abi_fingerprint: u64,
+    /// Fingerprint of the extension build
+    build_fingerprint: u64,
+    /// Major version of PostgreSQL the extension was built against
+    pg_major_version: u32,
 }
 
-pub const VERSION: u8 = 0;
+pub const VERSION: u8 = 1;
+
+/// Major version of PostgreSQL this build of pgextkit targets
+pub const PG_MAJOR_VERSION: u32 = pg_sys::PG_VERSION_NUM / 10000;
+
+/// Fingerprint of pgextkit's version and the layouts shared with the extensions
+pub const ABI_FINGERPRINT: u64 = {
+    let hash = fnv1a64_extend(FNV1A64_OFFSET, env!("CARGO_PKG_VERSION").as_bytes());
+    let hash = fnv1a64_extend(hash, &(size_of::<Handle>() as u64).to_le_bytes());
+    fnv1a64_extend(hash, &(size_of::<RestartPolicy>() as u64).to_le_bytes())
+};
+
+/// Identifies this build of pgextkit: compiler, target, profile, features and build time
+pub const BUILD_ID: &str = env!("PGEXTKIT_BUILD_ID");
 
 impl Magic {
     pub const fn new() -> Self {
         Self {
             magic_size: size_of::<Self>(),
             version: VERSION,
+            abi_fingerprint: ABI_FINGERPRINT,
+            build_fingerprint: ABI_FINGERPRINT,
+            pg_major_version: PG_MAJOR_VERSION,
+        }
+    }
+
+    /// Magic of an extension build identified by `build` (such as its name and version)
+    ///
+    /// The fingerprint also covers [`BUILD_ID`], so it changes whenever the extension is
+    /// built with another compiler, target or profile, or once pgextkit is rebuilt.
+    pub const fn for_build(build: &str) -> Self {
+        let fingerprint = fnv1a64_extend(ABI_FINGERPRINT, BUILD_ID.as_bytes());
+        Self {
+            build_fingerprint: fnv1a64_extend(fingerprint, build.as_bytes()),
+            ..Self::new()
+        }
+    }
+
+    /// Also fingerprints `id`, identifying this particular build of the extension
+    pub const fn with_build_id(self, id: &str) -> Self {
+        Self {
+            build_fingerprint: fnv1a64_extend(self.build_fingerprint, id.as_bytes()),
+            ..self
+        }
+    }
+}
+
+/// Optional description of an extension, read by pgextkit before running any of its code
+///
+/// It is exported with [`pgextkit_manifest!`] and its layout is, in order:
+///
+/// | field            | type             | meaning                                                 |
+/// |------------------|------------------|---------------------------------------------------------|
+/// | `manifest_size`  | `usize`          | `size_of::<Manifest>()`, newer fields are appended       |
+/// | `min_pg_version` | `u32`            | lowest `PG_VERSION_NUM` supported, `0` for no bound      |
+/// | `max_pg_version` | `u32`            | highest `PG_VERSION_NUM` supported, `0` for no bound     |
+/// | `worker_count`   | `u32`            | background workers started in each database              |
+/// | `services`       | `*const c_char`  | comma-separated names of provided services, or null      |
+///
+/// Extensions whose manifest rules out the running PostgreSQL version are not loaded.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Manifest {
+    manifest_size: usize,
+    min_pg_version: u32,
+    max_pg_version: u32,
+    worker_count: u32,
+    services: *const std::ffi::c_char,
+}
+
+// The only pointer is to a static string
+unsafe impl Sync for Manifest {}
+
+impl Manifest {
+    pub const fn new() -> Self {
+        Self {
+            manifest_size: size_of::<Self>(),
+            min_pg_version: 0,
+            max_pg_version: 0,
+            worker_count: 0,
+            services: std::ptr::null(),
+        }
+    }
+
+    /// Range of `PG_VERSION_NUM` (such as `150002`) the extension supports, `0` for no bound
+    pub const fn with_pg_versions(self, min: u32, max: u32) -> Self {
+        Self {
+            min_pg_version: min,
+            max_pg_version: max,
+            ..self
+        }
+    }
+
+    pub const fn with_worker_count(self, worker_count: u32) -> Self {
+        Self {
+            worker_count,
+            ..self
+        }
+    }
+
+    /// Comma-separated names of the services the extension registers, NUL-terminated
+    /// (such as `b"example.math\0"`)
+    pub const fn with_services(self, services: &'static [u8]) -> Self {
+        assert!(
+            !services.is_empty() && services[services.len() - 1] == 0,
+            "services must be NUL-terminated"
+        );
+        Self {
+            services: services.as_ptr() as *const std::ffi::c_char,
+            ..self
+        }
+    }
+
+    pub fn supports_pg_version(&self, version_num: u32) -> bool {
+        (self.min_pg_version == 0 || version_num >= self.min_pg_version)
+            && (self.max_pg_version == 0 || version_num <= self.max_pg_version)
+    }
+
+    pub fn service_names(&self) -> Vec<String> {
+        if self.services.is_null() {
+            return vec![];
+        }
+        unsafe { std::ffi::CStr::from_ptr(self.services) }
+            .to_string_lossy()
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    pub fn worker_count(&self) -> u32 {
+        self.worker_count
+    }
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const FNV1A64_OFFSET: u64 = 0xcbf29ce484222325;
+
+const fn fnv1a64_extend(mut hash: u64, bytes: &[u8]) -> u64 {
+    let mut i = 0;
+    while i < bytes.len() {
+        hash = (hash ^ bytes[i] as u64).wrapping_mul(0x100000001b3);
+        i += 1;
+    }
+    hash
+}
+
+/// Restart policy for background workers registered through [`Handle`]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RestartPolicy {
+    /// Restarts of a worker that ran for less than this are counted as retries, and the
+    /// worker isn't restarted sooner than this after exiting
+    pub min_interval_ms: u64,
+    /// Maximum number of consecutive retries before the worker is quarantined
+    pub max_retries: u32,
+}
+
+impl RestartPolicy {
+    pub fn new(min_interval: std::time::Duration, max_retries: u32) -> Self {
+        Self {
+            min_interval_ms: min_interval.as_millis() as u64,
+            max_retries,
         }
     }
 }
 
+/// Called with the OID and name of a database pgextkit discovered
+pub type NewDatabaseCallback = extern "C" fn(database: pg_sys::Oid, name: *const std::ffi::c_char);
+
 #[repr(C)]
 pub struct Handle {
     allocate_shmem: extern "C" fn(
         handle: *const Handle,
         size: usize,
-        cb: extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_void),
+        cb: extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_void, bool),
         payload: *const std::ffi::c_void,
     ),
     register_bgworker: extern "C" fn(handle: *const Handle, bgw: *mut pg_sys::BackgroundWorker),
+    register_bgworker_with_policy: extern "C" fn(
+        handle: *const Handle,
+        bgw: *mut pg_sys::BackgroundWorker,
+        policy: *const RestartPolicy,
+    ),
+    request_shmem_quota: extern "C" fn(handle: *const Handle, size: usize),
+    register_global_bgworker:
+        extern "C" fn(handle: *const Handle, bgw: *mut pg_sys::BackgroundWorker),
+    request_lwlocks: extern "C" fn(
+        handle: *const Handle,
+        tranche: *const std::ffi::c_char,
+        count: std::ffi::c_int,
+    ),
+    on_new_database: extern "C" fn(handle: *const Handle, callback: NewDatabaseCallback),
+    shmem_stats: extern "C" fn(handle: *const Handle, total: *mut usize, free: *mut usize),
+    allocate_shmem_aligned: extern "C" fn(
+        handle: *const Handle,
+        size: usize,
+        align: usize,
+        cb: extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_void, bool),
+        payload: *const std::ffi::c_void,
+    ),
+    allocate_shmem_prioritized: extern "C" fn(
+        handle: *const Handle,
+        size: usize,
+        align: usize,
+        priority: std::ffi::c_int,
+        cb: extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_void, bool),
+        payload: *const std::ffi::c_void,
+    ),
+    migrate_shmem: extern "C" fn(
+        library: *const std::ffi::c_char,
+        name: *const std::ffi::c_char,
+        old_version: *const std::ffi::c_char,
+        ptr: *mut std::ffi::c_void,
+    ) -> bool,
     library_name: *const std::ffi::c_char,
     name: String,
     version: String,
@@ -63,7 +294,7 @@ pub struct Handle {
 extern "C" fn allocate_shmem(
     handle: *const Handle,
     size: usize,
-    cb: extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_void),
+    cb: extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_void, bool),
     payload: *const std::ffi::c_void,
 ) {
     unsafe { ((*handle).allocate_shmem)(handle, size, cb, payload) }
@@ -74,65 +305,4001 @@ extern "C" fn register_bgworker(handle: *const Handle, bgw: *mut pg_sys::Backgro
     unsafe { ((*handle).register_bgworker)(handle, bgw) }
 }
 
+#[no_mangle]
+extern "C" fn register_bgworker_with_policy(
+    handle: *const Handle,
+    bgw: *mut pg_sys::BackgroundWorker,
+    policy: *const RestartPolicy,
+) {
+    unsafe { ((*handle).register_bgworker_with_policy)(handle, bgw, policy) }
+}
+
+#[no_mangle]
+extern "C" fn register_global_bgworker(handle: *const Handle, bgw: *mut pg_sys::BackgroundWorker) {
+    unsafe { ((*handle).register_global_bgworker)(handle, bgw) }
+}
+
+#[no_mangle]
+extern "C" fn request_lwlocks(
+    handle: *const Handle,
+    tranche: *const std::ffi::c_char,
+    count: std::ffi::c_int,
+) {
+    unsafe { ((*handle).request_lwlocks)(handle, tranche, count) }
+}
+
+#[no_mangle]
+extern "C" fn allocate_shmem_aligned(
+    handle: *const Handle,
+    size: usize,
+    align: usize,
+    cb: extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_void, bool),
+    payload: *const std::ffi::c_void,
+) {
+    unsafe { ((*handle).allocate_shmem_aligned)(handle, size, align, cb, payload) }
+}
+
+#[no_mangle]
+extern "C" fn allocate_shmem_prioritized(
+    handle: *const Handle,
+    size: usize,
+    align: usize,
+    priority: std::ffi::c_int,
+    cb: extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_void, bool),
+    payload: *const std::ffi::c_void,
+) {
+    unsafe { ((*handle).allocate_shmem_prioritized)(handle, size, align, priority, cb, payload) }
+}
+
+#[no_mangle]
+extern "C" fn shmem_stats(handle: *const Handle, total: *mut usize, free: *mut usize) {
+    unsafe { ((*handle).shmem_stats)(handle, total, free) }
+}
+
+#[no_mangle]
+extern "C" fn on_new_database(handle: *const Handle, callback: NewDatabaseCallback) {
+    unsafe { ((*handle).on_new_database)(handle, callback) }
+}
+
+#[no_mangle]
+extern "C" fn request_shmem_quota(handle: *const Handle, size: usize) {
+    unsafe { ((*handle).request_shmem_quota)(handle, size) }
+}
+
+impl Handle {
+    /// Creates a lock whose tranche is named after the extension (`<extension>.<name>`),
+    /// so that waits on it can be attributed to the extension in `pg_stat_activity`
+    pub fn new_lwlock<T>(&self, name: &str, data: T) -> crate::lwlock::PgDynamicLwLock<T> {
+        crate::lwlock::PgDynamicLwLock::new(&format!("{}.{}", self.name, name), data)
+    }
+}
+
+#[cfg(not(feature = "extension"))]
+use pgx::{GucContext, GucRegistry, GucSetting};
 #[cfg(not(feature = "extension"))]
 use std::{borrow::Cow, ffi::CStr};
 
 #[cfg(not(feature = "extension"))]
 impl Handle {
-    extern "C" fn call_closure<T, F: FnOnce(*mut T)>(
+    extern "C" fn call_closure<T, F: FnOnce(*mut T, bool)>(
         mem: *mut std::ffi::c_void,
         payload: *const std::ffi::c_void,
+        found: bool,
     ) {
         let mem = unsafe { std::mem::transmute::<_, *mut T>(mem) };
-        unsafe { Box::<F>::from_raw(payload as *mut _)(mem) }
+        unsafe { Box::<F>::from_raw(payload as *mut _)(mem, found) }
     }
 
     pub fn allocate_shmem<T, F: FnOnce(*mut T)>(&self, f: F) {
+        self.allocate_shmem_found(move |mem, _found| f(mem))
+    }
+
+    /// Like [`Handle::allocate_shmem`], but also tells whether the memory
+    /// already existed (and was initialized) before
+    pub fn allocate_shmem_found<T, F: FnOnce(*mut T, bool)>(&self, f: F) {
+        self.allocate_shmem_found_prioritized(0, f)
+    }
+
+    /// Like [`Handle::allocate_shmem`], but `f` is called before the callbacks of all
+    /// allocations (of any extension) with a lower priority, which default to 0
+    ///
+    /// Allocations other extensions' shared memory depends on can use it to be initialized
+    /// first when the server starts. Once it's running, `f` is called right away.
+    pub fn allocate_shmem_with_priority<T, F: FnOnce(*mut T)>(&self, priority: i32, f: F) {
+        self.allocate_shmem_found_prioritized(priority, move |mem, _found| f(mem))
+    }
+
+    fn allocate_shmem_found_prioritized<T, F: FnOnce(*mut T, bool)>(&self, priority: i32, f: F) {
         let ptr = Box::leak(Box::new(f)) as *mut F as *mut _;
-        (self.allocate_shmem)(self, size_of::<T>(), Self::call_closure::<T, F>, ptr)
+        (self.allocate_shmem_prioritized)(
+            self,
+            size_of::<T>(),
+            std::mem::align_of::<T>(),
+            priority,
+            Self::call_closure::<T, F>,
+            ptr,
+        )
+    }
+
+    /// Allocates shared memory initialized with `f` and registers it in the
+    /// [`SharedDictionary`] under `name`
+    ///
+    /// Entries are tagged with the extension's version. If the entry was registered by
+    /// another version (for example, before an upgrade), the extension's
+    /// `pgextkit_migrate_shmem` is called, if it exports one:
+    ///
+    /// ```ignore
+    /// #[no_mangle]
+    /// pub extern "C" fn pgextkit_migrate_shmem(
+    ///     name: *const c_char,
+    ///     old_version: *const c_char,
+    ///     ptr: *mut c_void,
+    /// ) -> bool
+    /// ```
+    ///
+    /// It returns `true` if it migrated the memory at `ptr` in place, which then keeps being
+    /// used. Otherwise (or without such a function), the memory is reinitialized with `f`.
+    pub fn allocate_shmem_with<T: Unpin + ShmemSafe, F: FnOnce() -> T>(&self, name: &str, f: F) {
+        self.allocate_shmem_with_prioritized(name, 0, f)
     }
 
-    pub fn allocate_shmem_with<T: Unpin, F: FnOnce() -> T>(&self, name: &str, f: F) {
+    fn allocate_shmem_with_prioritized<T: Unpin + ShmemSafe, F: FnOnce() -> T>(
+        &self,
+        name: &str,
+        priority: i32,
+        f: F,
+    ) {
         use std::mem::ManuallyDrop;
-        // We need to move this name so it stays allocated
+        // We need to move these so they stay allocated
         let name = String::from(name);
-        self.allocate_shmem(move |mem| unsafe {
-            *mem = ManuallyDrop::new(f());
-            SharedDictionary::default().insert::<T>(name.as_str(), mem as *mut T);
-        });
+        let version = self.version.clone();
+        // Unlike the handle, these outlive the initialization of the extension
+        let library = self.library_name;
+        let migrate = self.migrate_shmem;
+        self.allocate_shmem_found_prioritized(
+            priority,
+            move |mem: *mut ManuallyDrop<T>, found| unsafe {
+                register_versioned(&name, &version, library, migrate, mem as *mut T, found, f);
+            },
+        );
     }
 
-    pub fn allocate_shmem_for<T: Unpin>(&self, name: &str, val: T) {
+    pub fn allocate_shmem_for<T: Unpin + ShmemSafe>(&self, name: &str, val: T) {
         self.allocate_shmem_with(name, move || val)
     }
 
+    /// Like [`Handle::allocate_shmem_for`], with the priority of
+    /// [`Handle::allocate_shmem_with_priority`]
+    pub fn allocate_shmem_for_with_priority<T: Unpin + ShmemSafe>(
+        &self,
+        name: &str,
+        priority: i32,
+        val: T,
+    ) {
+        self.allocate_shmem_with_prioritized(name, priority, move || val)
+    }
+
+    /// Allocates a [`SharedArena`](crate::arena::SharedArena) of `N` bytes under `name`
+    ///
+    /// This takes a single allocation and dictionary entry, however many objects
+    /// are allocated from the arena later.
+    pub fn allocate_shmem_arena<const N: usize>(&self, name: &str) {
+        use crate::arena::SharedArena;
+        let name = String::from(name);
+        self.allocate_shmem_found(move |mem: *mut SharedArena<N>, found| unsafe {
+            if !found {
+                SharedArena::init(mem);
+            }
+            SharedDictionary::default().insert::<SharedArena<N>>(name.as_str(), mem);
+        });
+    }
+
+    /// Allocates a dynamic shared memory segment, only possible once the extension is
+    /// loaded into a running server (not when preloading)
+    pub fn allocate_dsm(&self, size: usize) -> crate::dsm::DsmSegment {
+        crate::dsm::DsmSegment::create(size)
+    }
+
+    /// Registers a background worker started in every database the extension is installed in
+    ///
+    /// `{{DATABASE}}`, `{{USER}}`, `{{EXTENSION}}` and `{{VERSION}}` in the worker's name are
+    /// substituted, for example `"{{EXTENSION}} ({{USER}}@{{DATABASE}})"`.
     pub fn register_bgworker<W: Into<pg_sys::BackgroundWorker>>(&self, worker: W) {
         let mut worker = worker.into();
         (self.register_bgworker)(self, &mut worker);
     }
+
+    /// Registers a background worker with a typed argument
+    ///
+    /// The argument is placed in shared memory and the worker can retrieve it
+    /// by passing its `bgw_main_arg` to [`bgworker_arg`]. It must be `Copy`: it's never
+    /// dropped, and strings can be kept in a [`SharedStr`](crate::types::SharedStr).
+    pub fn register_bgworker_with_arg<
+        W: Into<pg_sys::BackgroundWorker>,
+        T: Copy + Unpin + ShmemSafe,
+    >(
+        &self,
+        worker: W,
+        arg: T,
+    ) {
+        let mut worker = worker.into();
+        let id = fnv1a64_extend(
+            FNV1A64_OFFSET,
+            format!(
+                "{}/{}",
+                self.name,
+                unsafe { CStr::from_ptr(worker.bgw_name.as_ptr()) }.to_string_lossy()
+            )
+            .as_bytes(),
+        );
+        self.allocate_shmem_for(&bgworker_arg_key(id), arg);
+        worker.bgw_main_arg = pg_sys::Datum::from(id as usize);
+        (self.register_bgworker)(self, &mut worker);
+    }
+
+    /// Registers a background worker that gets stopped and quarantined if it keeps
+    /// crashing shortly after being restarted, more often than `policy` allows
+    ///
+    /// The worker is restarted by Postgres as usual, so its `bgw_restart_time` is what
+    /// determines whether it is restarted at all.
+    pub fn register_bgworker_with_policy<W: Into<pg_sys::BackgroundWorker>>(
+        &self,
+        worker: W,
+        policy: RestartPolicy,
+    ) {
+        let mut worker = worker.into();
+        // Let postmaster back off as well when restarting the worker
+        let min_restart_time = ((policy.min_interval_ms + 999) / 1000) as std::ffi::c_int;
+        if worker.bgw_restart_time != pg_sys::BGW_NEVER_RESTART as std::ffi::c_int
+            && worker.bgw_restart_time < min_restart_time
+        {
+            worker.bgw_restart_time = min_restart_time;
+        }
+        (self.register_bgworker_with_policy)(self, &mut worker, &policy);
+    }
+
+    /// Registers a background worker that runs once for the whole cluster
+    ///
+    /// Unlike [`Handle::register_bgworker`], the worker is not started for every database
+    /// the extension is installed in, so it doesn't get a
+    /// [`WorkerContext`](crate::worker::WorkerContext) and `{{DATABASE}}` is not substituted
+    /// in its name. It is up to the worker to connect to a database, if it needs one.
+    pub fn register_global_bgworker<W: Into<pg_sys::BackgroundWorker>>(&self, worker: W) {
+        let mut worker = worker.into();
+        (self.register_global_bgworker)(self, &mut worker);
+    }
+    fn shmem_stats(&self) -> (usize, usize) {
+        let (mut total, mut free) = (0, 0);
+        (self.shmem_stats)(self, &mut total, &mut free);
+        (total, free)
+    }
+
+    /// Size of the arena shared memory allocated after startup comes from
+    /// (`pgextkit.shmem_size`)
+    pub fn total_shmem(&self) -> usize {
+        self.shmem_stats().0
+    }
+
+    /// Space left in the arena, shared by all extensions
+    ///
+    /// The largest single allocation that can succeed may be smaller if the arena is
+    /// fragmented.
+    pub fn free_shmem(&self) -> usize {
+        self.shmem_stats().1
+    }
+
+    /// Limit the amount of shared memory this extension can allocate after startup
+    pub fn request_shmem_quota(&self, size: usize) {
+        (self.request_shmem_quota)(self, size);
+    }
+
+    /// Logger prefixing messages with the extension's name and version
+    pub fn logger(&self) -> crate::logging::Logger {
+        crate::logging::Logger::new(&self.name, &self.version)
+    }
+
+    pub fn log<S: AsRef<str>>(&self, message: S) {
+        self.logger().log(message)
+    }
+
+    pub fn warn<S: AsRef<str>>(&self, message: S) {
+        self.logger().warn(message)
+    }
+
+    pub fn error<S: AsRef<str>>(&self, message: S) -> ! {
+        self.logger().error(message)
+    }
+
     pub fn library_name(&self) -> Cow<str> {
         unsafe { CStr::from_ptr(self.library_name).to_string_lossy() }
     }
+
+    /// Requests `count` locks shared by all backends, only possible when preloading
+    ///
+    /// The tranche is named after the extension (`<extension>.<name>`), and its locks
+    /// can be accessed once the server has started.
+    pub fn request_lwlocks(&self, name: &str, count: usize) -> crate::lwlock::NamedLwLockTranche {
+        let tranche: &'static CStr = Box::leak(
+            std::ffi::CString::new(format!("{}.{}", self.name, name))
+                .expect("CString::new failed")
+                .into_boxed_c_str(),
+        );
+        (self.request_lwlocks)(self, tranche.as_ptr(), count as _);
+        crate::lwlock::NamedLwLockTranche::new(tranche, count)
+    }
+
+    /// Makes the vtable exported by this extension's library as the `#[no_mangle]` static
+    /// `symbol` available to other extensions through
+    /// [`lookup_service`](crate::service::lookup_service)
+    pub fn register_service<V: crate::service::ServiceVtable>(&self, name: &str, symbol: &str) {
+        use crate::service::{service_key, ServiceEntry};
+        self.allocate_shmem_for(
+            &service_key(name),
+            ServiceEntry::<V>::new(&self.library_name(), symbol),
+        );
+    }
+
+    /// Calls `callback` for every database the master worker discovers (including those
+    /// existing at startup), before workers are started in it
+    ///
+    /// The callback runs in the master worker within a transaction, so it can access the
+    /// cluster-wide catalogs. Only possible when preloading.
+    pub fn on_new_database(&self, callback: NewDatabaseCallback) {
+        (self.on_new_database)(self, callback);
+    }
+
+    /// Full name of the extension's GUC (`pgextkit.<extension>.<name>`)
+    pub fn guc_name(&self, name: &str) -> String {
+        format!("pgextkit.{}.{}", self.name, name)
+    }
+
+    pub fn define_string_guc(
+        &self,
+        name: &str,
+        short_description: &str,
+        description: &str,
+        default: Option<&str>,
+        context: GucContext,
+    ) -> &'static GucSetting<Option<&'static str>> {
+        let default = default.map(|s| &*Box::leak(String::from(s).into_boxed_str()));
+        let setting = Box::leak(Box::new(GucSetting::<Option<&'static str>>::new(default)));
+        GucRegistry::define_string_guc(
+            self.guc_name(name).as_str(),
+            short_description,
+            description,
+            setting,
+            context,
+        );
+        setting
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn define_int_guc(
+        &self,
+        name: &str,
+        short_description: &str,
+        description: &str,
+        default: i32,
+        min_value: i32,
+        max_value: i32,
+        context: GucContext,
+    ) -> &'static GucSetting<i32> {
+        let setting = Box::leak(Box::new(GucSetting::<i32>::new(default)));
+        GucRegistry::define_int_guc(
+            self.guc_name(name).as_str(),
+            short_description,
+            description,
+            setting,
+            min_value,
+            max_value,
+            context,
+        );
+        setting
+    }
+
+    pub fn define_bool_guc(
+        &self,
+        name: &str,
+        short_description: &str,
+        description: &str,
+        default: bool,
+        context: GucContext,
+    ) -> &'static GucSetting<bool> {
+        let setting = Box::leak(Box::new(GucSetting::<bool>::new(default)));
+        GucRegistry::define_bool_guc(
+            self.guc_name(name).as_str(),
+            short_description,
+            description,
+            setting,
+            context,
+        );
+        setting
+    }
+}
+
+/// Registers the memory of an entry of [`Handle::allocate_shmem_with`] under `name`,
+/// letting the extension migrate the memory another version of it registered there
+///
+/// Memory that was migrated keeps being used, `mem` is then left uninitialized. Returns
+/// the memory the entry points to.
+#[cfg(any(not(feature = "extension"), test, feature = "pg_test"))]
+unsafe fn register_versioned<T: Unpin + crate::types::ShmemSafe, F: FnOnce() -> T>(
+    name: &str,
+    version: &str,
+    library: *const std::ffi::c_char,
+    migrate: extern "C" fn(
+        library: *const std::ffi::c_char,
+        name: *const std::ffi::c_char,
+        old_version: *const std::ffi::c_char,
+        ptr: *mut std::ffi::c_void,
+    ) -> bool,
+    mem: *mut T,
+    found: bool,
+    f: F,
+) -> *mut T {
+    use std::ffi::CString;
+    let mut dictionary = crate::shmem::SharedDictionary::default();
+    let migrated = match dictionary.find_versioned(name) {
+        // Inserted by another version of the extension, its layout may differ
+        Some((old, old_version)) if !old_version.is_empty() && old_version != version => {
+            let c_name = CString::new(name).expect("CString::new failed");
+            let c_old_version = CString::new(old_version).expect("CString::new failed");
+            migrate(
+                library,
+                c_name.as_ptr(),
+                c_old_version.as_ptr(),
+                old as *mut std::ffi::c_void,
+            )
+            .then_some(old as *mut T)
+        }
+        _ => None,
+    };
+    let value = match migrated {
+        Some(old) => old,
+        None => {
+            // Memory that already existed keeps its state, it only needs to be registered again
+            if !found {
+                mem.write(f());
+            }
+            mem
+        }
+    };
+    dictionary.insert_versioned::<T>(name, value, version);
+    value
+}
+
+#[cfg(not(feature = "extension"))]
+fn bgworker_arg_key(id: u64) -> String {
+    format!("pgextkit.bgw_arg.{:016x}", id)
+}
+
+/// Retrieves the argument of a worker registered with [`Handle::register_bgworker_with_arg`]
+#[cfg(not(feature = "extension"))]
+pub fn bgworker_arg<T: Copy + Unpin + ShmemSafe>(
+    arg: pg_sys::Datum,
+) -> Option<std::pin::Pin<&'static T>> {
+    SharedDictionary::default().get::<T>(&bgworker_arg_key(arg.value() as u64))
 }
 
+/// Exports the extension's [`Magic`]
+///
+/// Its build fingerprint covers the extension's name and version and pgextkit's
+/// [`BUILD_ID`]. Extensions with a build script can tell every build apart by passing
+/// an identifier they generate:
+///
+/// ```ignore
+/// pgextkit::pgextkit_magic!(env!("EXAMPLE_BUILD_ID"));
+/// ```
 #[macro_export]
 macro_rules! pgextkit_magic {
     () => {
+        pgextkit::pgextkit_magic!("");
+    };
+    ($build:expr) => {
         #[no_mangle]
         #[allow(non_snake_case)]
         #[allow(unused)]
         #[link_name = "Pg_magic_func"]
         #[doc(hidden)]
         pub extern "C" fn pgextkit_magic() -> *const pgextkit::Magic {
-            const MAGIC: pgextkit::Magic = pgextkit::Magic::new();
+            const MAGIC: pgextkit::Magic = pgextkit::Magic::for_build(concat!(
+                env!("CARGO_PKG_NAME"),
+                "--",
+                env!("CARGO_PKG_VERSION")
+            ))
+            .with_build_id($build);
             &MAGIC
         }
     };
 }
 
+/// Exports the extension's [`Manifest`]
+///
+/// ```ignore
+/// pgextkit::pgextkit_manifest!(pgextkit::Manifest::new().with_worker_count(1));
+/// ```
+#[macro_export]
+macro_rules! pgextkit_manifest {
+    ($manifest:expr) => {
+        #[no_mangle]
+        #[allow(unused)]
+        #[doc(hidden)]
+        pub extern "C" fn pgextkit_manifest() -> *const pgextkit::Manifest {
+            static MANIFEST: pgextkit::Manifest = $manifest;
+            &MANIFEST
+        }
+    };
+}
+
 #[cfg(all(feature = "extension", any(test, feature = "pg_test")))]
 #[pgx::pg_schema]
-mod tests {}
+mod tests {
+    use crate::ext::handles::WorkerHandle;
+    use crate::ext::rollback::StagedLoad;
+    use crate::latch::SharedLatch;
+    use crate::lwlock::{PgConditionVariable, PgDynamicLwLock};
+    use crate::shmem::SharedDictionary;
+    use crate::types::{ShmemSafe, SyncMut};
+    use crate::Handle;
+    use pgx::bgworkers::BackgroundWorkerBuilder;
+    use pgx::pg_sys::panic::CaughtError;
+    use pgx::prelude::*;
+    use std::ffi::{c_void, CString};
+    use std::mem::{align_of, size_of};
+    use std::panic::AssertUnwindSafe;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Handle of an extension loaded once the server is running, as `pgextkit.load()`
+    /// makes them
+    fn dynamic_handle(name: &str) -> Handle {
+        Handle::make_dynamic(name.to_string(), "1.0".to_string(), "pgextkit")
+    }
+
+    extern "C" fn store_allocation(mem: *mut c_void, payload: *const c_void, _found: bool) {
+        unsafe { *(payload as *mut *mut c_void) = mem }
+    }
+
+    /// Allocates `size` bytes through `handle`, null if that raised an error
+    fn try_allocate(handle: &Handle, size: usize, align: usize) -> *mut c_void {
+        let mut mem = std::ptr::null_mut::<c_void>();
+        let payload = &mut mem as *mut _ as *const c_void;
+        PgTryBuilder::new(AssertUnwindSafe(|| {
+            (handle.allocate_shmem_aligned)(handle, size, align, store_allocation, payload)
+        }))
+        .catch_others(|_| ())
+        .execute();
+        mem
+    }
+
+    /// Allocates `value` in pgextkit's shared memory and registers it under `name`, so that
+    /// other backends (such as the test workers) can find it
+    fn shared<T: Unpin + ShmemSafe>(name: &str, value: T) -> &'static mut T {
+        let handle = dynamic_handle("pgextkit_tests");
+        let mem = try_allocate(&handle, size_of::<T>(), align_of::<T>()) as *mut T;
+        assert!(!mem.is_null(), "can't allocate {}", name);
+        unsafe { mem.write(value) };
+        SharedDictionary::default().insert(name, mem);
+        unsafe { &mut *mem }
+    }
+
+    /// Background worker running `function` of pgextkit's library, which notifies this
+    /// backend when it starts
+    fn test_worker(function: &str, arg: i64) -> pg_sys::BackgroundWorker {
+        (&BackgroundWorkerBuilder::new(function)
+            .set_function(function)
+            .set_library("pgextkit")
+            .set_argument(arg.into_datum())
+            .set_restart_time(None)
+            .enable_spi_access()
+            .enable_shmem_access(None)
+            .set_notify_pid(unsafe { pg_sys::MyProcPid }))
+            .into()
+    }
+
+    /// Starts a background worker running `function`, which pgextkit's library must export
+    /// (as the test workers below do), and waits for it to have started (short-lived
+    /// workers may already be done by then)
+    fn start_worker(function: &str, arg: i64) -> WorkerHandle {
+        let mut bgw = test_worker(function, arg);
+        let mut handle = std::ptr::null_mut();
+        assert!(
+            unsafe { pg_sys::RegisterDynamicBackgroundWorker(&mut bgw, &mut handle) },
+            "no background worker slots available"
+        );
+        let worker = unsafe { WorkerHandle::from_raw(handle) };
+        assert!(
+            worker
+                .wait_for_startup(Duration::from_secs(10), || false)
+                .is_some()
+                || worker.is_stopped(),
+            "{} didn't start",
+            function
+        );
+        worker
+    }
+
+    /// Runs `f`, returning the message and detail of the error it raised, if any
+    fn caught_error<F: FnOnce()>(f: F) -> Option<(String, Option<String>)> {
+        PgTryBuilder::new(AssertUnwindSafe(|| {
+            f();
+            None
+        }))
+        .catch_others(|err| match err {
+            CaughtError::PostgresError(report)
+            | CaughtError::ErrorReport(report)
+            | CaughtError::RustPanic {
+                ereport: report, ..
+            } => Some((
+                report.message().to_string(),
+                report.detail().map(String::from),
+            )),
+        })
+        .execute()
+    }
+
+    /// Creates a directory holding `files` (names and contents), such as control files
+    fn directory_with(files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pgextkit_test_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).expect("can't create directory");
+        for (name, contents) in files {
+            std::fs::write(dir.join(name), contents).expect("can't write file");
+        }
+        dir
+    }
+
+    /// Control files (and scripts) written to the server's extension directory, removed
+    /// when dropped
+    struct InstalledControlFiles(Vec<PathBuf>);
+
+    impl InstalledControlFiles {
+        /// Writes `files` (names and contents) to the extension directory
+        fn new(files: &[(&str, &str)]) -> Self {
+            let mut share = [0 as std::ffi::c_char; pg_sys::MAXPGPATH as usize];
+            unsafe {
+                pg_sys::get_share_path(&pg_sys::my_exec_path as *const _, share.as_mut_ptr())
+            };
+            let dir = Path::new(
+                unsafe { std::ffi::CStr::from_ptr(share.as_ptr()) }
+                    .to_string_lossy()
+                    .as_ref(),
+            )
+            .join("extension");
+            Self(
+                files
+                    .iter()
+                    .map(|(name, contents)| {
+                        let path = dir.join(name);
+                        std::fs::write(&path, contents).expect("can't write control file");
+                        path
+                    })
+                    .collect(),
+            )
+        }
+    }
+
+    impl Drop for InstalledControlFiles {
+        fn drop(&mut self) {
+            for path in &self.0 {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    /// Parses the control files in `dir`
+    fn control_files_in(dir: &Path) -> Vec<crate::ext::ControlFile> {
+        std::fs::read_dir(dir)
+            .expect("can't read directory")
+            .map(|entry| {
+                crate::ext::parse_control_file(&entry.expect("can't read entry"))
+                    .expect("can't parse control file")
+            })
+            .collect()
+    }
+
+    static CAPTURED: std::sync::Mutex<Vec<(i32, String)>> = std::sync::Mutex::new(vec![]);
+
+    #[pg_guard]
+    extern "C" fn capture_message(edata: *mut pg_sys::ErrorData) {
+        let (elevel, message) = unsafe {
+            if (*edata).message.is_null() {
+                return;
+            }
+            ((*edata).elevel, std::ffi::CStr::from_ptr((*edata).message))
+        };
+        CAPTURED
+            .lock()
+            .expect("can't lock captured messages")
+            .push((elevel, message.to_string_lossy().to_string()));
+    }
+
+    /// Runs `f`, returning the messages of level `elevel` it logged
+    fn captured_messages<F: FnOnce()>(elevel: u32, f: F) -> Vec<String> {
+        CAPTURED
+            .lock()
+            .expect("can't lock captured messages")
+            .clear();
+        let previous = unsafe { pg_sys::emit_log_hook };
+        unsafe { pg_sys::emit_log_hook = Some(capture_message) };
+        f();
+        unsafe { pg_sys::emit_log_hook = previous };
+        std::mem::take(&mut *CAPTURED.lock().expect("can't lock captured messages"))
+            .into_iter()
+            .filter(|(level, _)| *level == elevel as i32)
+            .map(|(_, message)| message)
+            .collect()
+    }
+
+    /// Runs `f`, returning the warnings it logged
+    fn captured_warnings<F: FnOnce()>(f: F) -> Vec<String> {
+        captured_messages(pg_sys::WARNING, f)
+    }
+
+    /// Changes a setting as if the configuration file was reloaded
+    fn reload_setting(name: &str, value: &str) {
+        let name = CString::new(name).expect("CString::new failed");
+        let value = CString::new(value).expect("CString::new failed");
+        unsafe {
+            pg_sys::SetConfigOption(
+                name.as_ptr(),
+                value.as_ptr(),
+                pg_sys::GucContext_PGC_SIGHUP,
+                pg_sys::GucSource_PGC_S_FILE,
+            )
+        }
+    }
+
+    #[pg_test]
+    fn test_overflow_shmem_after_reload() {
+        let handle = dynamic_handle("overflow_test");
+        // Undoes the allocations once done, so that other tests have the arena to themselves
+        let _load = StagedLoad::begin("overflow_test");
+        let (mut total, mut free) = (0, 0);
+        (handle.shmem_stats)(&handle, &mut total, &mut free);
+        // More than the whole arena, so it can only come from the overflow pool
+        let size = total + 1;
+        assert!(try_allocate(&handle, size, 8).is_null());
+
+        reload_setting("pgextkit.overflow_shmem_size", "32MB");
+        let stats = || {
+            Spi::get_two::<i64, i64>(
+                "SELECT used, overflow_used FROM pgextkit.shmem_allocator_stats()",
+            )
+        };
+        let (used_before, overflow_before) = stats();
+        let mem = try_allocate(&handle, size, 8);
+        assert!(!mem.is_null());
+        assert!(crate::overflow::contains(mem));
+        // Accounted for at its padded size, apart from the arena
+        let footprint = ((size + 7) / 8 * 8) as i64;
+        assert_eq!(
+            stats(),
+            (used_before, overflow_before.map(|used| used + footprint))
+        );
+        let mut free_after = 0;
+        (handle.shmem_stats)(&handle, &mut total, &mut free_after);
+        assert_eq!(free_after, free);
+        unsafe {
+            std::ptr::write_bytes(mem as *mut u8, 0xa5, size);
+            assert_eq!(*(mem as *const u8).add(size - 1), 0xa5);
+        }
+    }
+
+    struct Flag {
+        lock: PgDynamicLwLock<bool>,
+        cv: PgConditionVariable,
+    }
+
+    unsafe impl SyncMut for Flag {}
+    unsafe impl ShmemSafe for Flag {}
+
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_raise_flag(_arg: pg_sys::Datum) {
+        let flag = SharedDictionary::default()
+            .get_mut::<Flag>("tests.condition_variable")
+            .expect("flag");
+        let flag = std::pin::Pin::into_inner(flag);
+        // Gives the test time to start waiting
+        unsafe { pg_sys::pg_usleep(100_000) };
+        *flag.lock.exclusive() = true;
+        flag.cv.notify_all();
+    }
+
+    #[pg_test]
+    fn test_condition_variable_wait_while() {
+        let Flag { lock, cv } = shared(
+            "tests.condition_variable",
+            Flag {
+                lock: PgDynamicLwLock::new("tests.flag", false),
+                cv: PgConditionVariable::new(),
+            },
+        );
+        start_worker("pgextkit_test_raise_flag", 0);
+        let raised = cv.wait_while(lock.exclusive(), |raised| !*raised);
+        assert!(*raised);
+    }
+
+    #[pg_test]
+    fn test_not_preloaded_error() {
+        let (message, detail) = caught_error(crate::ext::not_preloaded).expect("error");
+        assert_eq!(
+            message,
+            "pgextkit must be loaded via shared_preload_libraries"
+        );
+        let detail = detail.expect("detail");
+        assert!(detail.contains("Add 'pgextkit' to shared_preload_libraries"));
+        assert!(detail.contains("(current value: 'pgextkit')"));
+    }
+
+    #[pg_test]
+    fn test_one_version_per_extension() {
+        let dir = directory_with(&[
+            ("dup--1.0.control", "module_pathname = '$libdir/dup'\n"),
+            ("dup--2.0.control", "module_pathname = '$libdir/dup'\n"),
+            ("other--1.0.control", "module_pathname = '$libdir/other'\n"),
+        ]);
+        let extensions = crate::ext::preferred_versions(control_files_in(&dir));
+        let loaded = extensions
+            .iter()
+            .map(|extension| (extension.name.as_str(), extension.version.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(loaded, vec![("dup", "2.0"), ("other", "1.0")]);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[pg_test]
+    fn test_max_dictionary_entries_setting() {
+        // Set to 10000 for the tests, rounded up to a power of two
+        assert_eq!(
+            Spi::get_one::<i64>("SELECT capacity FROM pgextkit.shared_dictionary_stats()"),
+            Some(16384)
+        );
+    }
+
+    #[pg_test]
+    fn test_workers_crashing_after_restarts_are_quarantined() {
+        use crate::ext::restarts::RestartTracker;
+        let handle = dynamic_handle("restarting");
+        let database = unsafe { pg_sys::MyDatabaseId };
+        let register = |function: &str, name: &str, restart_time, policy| {
+            let mut bgw = test_worker(function, 0);
+            bgw.bgw_name = crate::types::RpgffiChar96::from(name).0;
+            bgw.bgw_restart_time = restart_time;
+            (handle.register_bgworker_with_policy)(&handle, &mut bgw, &policy);
+        };
+        let handles = || {
+            crate::ext::handles::WorkerHandles::default()
+                .of("restarting")
+                .into_iter()
+                .map(|(_, worker)| worker)
+                .collect::<Vec<_>>()
+        };
+
+        // Siblings registered back to back aren't restarts of one another
+        let policy = crate::RestartPolicy::new(Duration::from_secs(60), 2);
+        for i in 0..4 {
+            register(
+                "pgextkit_test_idle",
+                &format!("restarting sibling {}", i),
+                pg_sys::BGW_NEVER_RESTART as _,
+                policy,
+            );
+        }
+        let siblings = handles();
+        assert_eq!(siblings.len(), 4);
+        for sibling in &siblings {
+            assert!(sibling
+                .wait_for_startup(Duration::from_secs(10), || false)
+                .is_some());
+        }
+
+        // A worker crashing right after every restart is stopped after two retries
+        let policy = crate::RestartPolicy::new(Duration::from_secs(1), 2);
+        register("pgextkit_test_crash", "restarting crasher", 1, policy);
+        let crasher = *handles()
+            .iter()
+            .find(|worker| !siblings.contains(worker))
+            .unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(30);
+        let mut pids = std::collections::HashSet::new();
+        while !crasher.is_stopped() {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "crashing worker wasn't quarantined"
+            );
+            pids.extend(crasher.pid());
+            RestartTracker::default().watch();
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        // Started once, then restarted three times
+        assert!(pids.len() >= 4, "{:?}", pids);
+        assert!(!RestartTracker::default().admit("restarting", database, "restarting crasher"));
+        assert!(RestartTracker::default().admit("restarting", database, "restarting sibling 0"));
+        assert!(siblings.iter().all(|sibling| !sibling.is_stopped()));
+
+        // Loading the extension again lifts the quarantine
+        RestartTracker::default().clear("restarting");
+        assert!(RestartTracker::default().admit("restarting", database, "restarting crasher"));
+        for sibling in crate::ext::handles::WorkerHandles::default().take("restarting") {
+            sibling.1.terminate();
+        }
+    }
+
+    #[pg_test]
+    fn test_dictionary_entry_size() {
+        shared("test_entry_size", [0u64; 5]);
+        let (_name, type_name, size) = SharedDictionary::default()
+            .entries()
+            .find(|(name, ..)| name == "test_entry_size")
+            .expect("entry");
+        assert_eq!(type_name, std::any::type_name::<[u64; 5]>());
+        assert_eq!(size, size_of::<[u64; 5]>());
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT size FROM pgextkit.shared_dictionary_entries() WHERE name = 'test_entry_size'"
+            ),
+            Some(size_of::<[u64; 5]>() as i64)
+        );
+    }
+
+    #[pg_test]
+    fn test_abi_fingerprint_mismatch_is_refused() {
+        let path = Path::new("/nonexistent/fingerprinted.so");
+        let magic = crate::Magic::for_build("fingerprinted--1.0");
+        assert!(crate::ext::check_fingerprints(path, &magic).is_ok());
+        // A rebuild only warns
+        let rebuilt = crate::Magic::for_build("fingerprinted--1.0").with_build_id("rebuilt");
+        assert_ne!(rebuilt.build_fingerprint, magic.build_fingerprint);
+        assert!(crate::ext::check_fingerprints(path, &rebuilt).is_ok());
+        let other_pgextkit = crate::Magic {
+            abi_fingerprint: crate::ABI_FINGERPRINT ^ 1,
+            ..crate::Magic::for_build("fingerprinted--1.0")
+        };
+        assert!(crate::ext::check_fingerprints(path, &other_pgextkit).is_err());
+    }
+
+    #[pg_test]
+    fn test_module_on_dynamic_library_path() {
+        let libraries = directory_with(&[("custom_module.so", "")]);
+        let control_files =
+            directory_with(&[("custom--1.0.control", "module_pathname = 'custom_module'\n")]);
+        let path = CString::new(format!("$libdir:{}", libraries.to_string_lossy()))
+            .expect("CString::new failed");
+        unsafe {
+            pg_sys::SetConfigOption(
+                cstr_core::cstr!("dynamic_library_path").as_ptr(),
+                path.as_ptr(),
+                pg_sys::GucContext_PGC_SUSET,
+                pg_sys::GucSource_PGC_S_SESSION,
+            )
+        }
+        let control_file = control_files_in(&control_files)
+            .pop()
+            .expect("control file");
+        assert_eq!(control_file.path, libraries.join("custom_module.so"));
+    }
+
+    /// Makes pgextkit's test build loadable as an extension, whose initialization counts
+    /// how many times it ran in this backend
+    ///
+    /// Loaded as `drain`, it also starts the `pgextkit_test_drain` worker, which
+    /// `pgextkit_deinit` tells to exit.
+    #[no_mangle]
+    pub extern "C" fn pgextkit_magic() -> *const crate::Magic {
+        const MAGIC: crate::Magic = crate::Magic::for_build("pgextkit_tests");
+        &MAGIC
+    }
+
+    static INITIALIZED: AtomicUsize = AtomicUsize::new(0);
+
+    #[no_mangle]
+    pub extern "C" fn pgextkit_init(handle: *const Handle) {
+        INITIALIZED.fetch_add(1, Ordering::SeqCst);
+        let handle = unsafe { &*handle };
+        if handle.name == "drain" {
+            let mut bgw = test_worker("pgextkit_test_drain", 0);
+            (handle.register_bgworker)(handle, &mut bgw);
+        }
+        if handle.name == "stuck" {
+            let mut bgw = test_worker("pgextkit_test_stuck", 0);
+            (handle.register_bgworker)(handle, &mut bgw);
+        }
+        if handle.name == "ready" || handle.name == "vetoed" {
+            let mut bgw = test_worker("pgextkit_test_beating", 0);
+            (handle.register_bgworker)(handle, &mut bgw);
+        }
+        if handle.name == "fails_midway" {
+            let mut mem = std::ptr::null_mut::<c_void>();
+            (handle.allocate_shmem)(
+                handle,
+                size_of::<u64>(),
+                store_allocation,
+                &mut mem as *mut _ as *const c_void,
+            );
+            SharedDictionary::default().insert("tests.fails_midway", mem as *mut u64);
+            let mut bgw = test_worker("pgextkit_test_idle", 0);
+            (handle.register_bgworker)(handle, &mut bgw);
+            pgx::error!("fails_midway failed to initialize");
+        }
+        if handle.name == "churning" {
+            // Room for a single load's allocation
+            (handle.request_shmem_quota)(handle, CHURNING_SIZE + CHURNING_SIZE / 2);
+            let mut mem = std::ptr::null_mut::<c_void>();
+            (handle.allocate_shmem)(
+                handle,
+                CHURNING_SIZE,
+                store_allocation,
+                &mut mem as *mut _ as *const c_void,
+            );
+            SharedDictionary::default().insert("tests.churning", mem as *mut u64);
+        }
+        if handle.name == "shmem_stats" {
+            let (mut total, mut free) = (0, 0);
+            (handle.shmem_stats)(handle, &mut total, &mut free);
+            *SHMEM_STATS_IN_INIT.lock().expect("can't lock shmem stats") = Some((total, free));
+        }
+    }
+
+    /// Bytes the `churning` test extension allocates whenever it's loaded
+    const CHURNING_SIZE: usize = 64 * 1024;
+
+    /// Total and free shared memory `shmem_stats` saw in `pgextkit_init`
+    static SHMEM_STATS_IN_INIT: std::sync::Mutex<Option<(usize, usize)>> =
+        std::sync::Mutex::new(None);
+
+    /// Test extensions named `deinit_order_*` still loaded whenever one of them is
+    /// deinitialized
+    static DEINIT_ORDER: std::sync::Mutex<Vec<Vec<String>>> = std::sync::Mutex::new(vec![]);
+
+    #[no_mangle]
+    pub extern "C" fn pgextkit_deinit() {
+        let loaded = crate::ext::registry::Registry::default()
+            .entries()
+            .into_iter()
+            .map(|extension| extension.name.to_string())
+            .filter(|name| name.starts_with("deinit_order_"))
+            .collect::<Vec<_>>();
+        if !loaded.is_empty() {
+            DEINIT_ORDER
+                .lock()
+                .expect("can't lock deinit order")
+                .push(loaded);
+        }
+        if let Some(drain) = SharedDictionary::default().get_mut::<Drain>("tests.drain") {
+            let drain = std::pin::Pin::into_inner(drain);
+            drain.exit.store(true, Ordering::SeqCst);
+            drain.latch.set_and_wake_up();
+        }
+    }
+
+    struct Drain {
+        exit: std::sync::atomic::AtomicBool,
+        stopped: std::sync::atomic::AtomicBool,
+        latch: SharedLatch,
+    }
+
+    unsafe impl SyncMut for Drain {}
+    unsafe impl ShmemSafe for Drain {}
+
+    /// Takes a while to stop once told to exit
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_drain(_arg: pg_sys::Datum) {
+        let drain = std::pin::Pin::into_inner(
+            SharedDictionary::default()
+                .get_mut::<Drain>("tests.drain")
+                .expect("drain"),
+        );
+        let latch = drain.latch.own().expect("latch");
+        while !drain.exit.load(Ordering::SeqCst) {
+            latch.wait(Some(Duration::from_millis(100)));
+            latch.reset();
+        }
+        std::thread::sleep(Duration::from_millis(500));
+        drain.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Path of `library` in `$libdir`
+    fn pkglib(library: &str) -> String {
+        let pkglib = unsafe { std::ffi::CStr::from_ptr(pg_sys::pkglib_path.as_ptr()) };
+        format!("{}/{}.so", pkglib.to_string_lossy(), library)
+    }
+
+    #[pg_test]
+    fn test_load_statuses() {
+        let load_from_path = |library: &str| {
+            Spi::get_one::<String>(&format!(
+                "SELECT pgextkit.load_from_path('{}', 'status_test', '1.0')",
+                pkglib(library)
+            ))
+        };
+        assert_eq!(load_from_path("pgextkit").as_deref(), Some("loaded"));
+        assert_eq!(
+            load_from_path("pgextkit").as_deref(),
+            Some("already_loaded")
+        );
+        // Only libraries exporting a magic function can be loaded
+        assert_eq!(load_from_path("plpgsql").as_deref(), Some("incompatible"));
+        assert_eq!(load_from_path("nonexistent").as_deref(), Some("not_found"));
+        assert_eq!(
+            Spi::get_one::<String>("SELECT pgextkit.load('nonexistent')").as_deref(),
+            Some("not_found")
+        );
+        assert_eq!(
+            Spi::get_one::<String>("SELECT pgextkit.unload('nonexistent')").as_deref(),
+            Some("not_found")
+        );
+    }
+
+    #[pg_test]
+    fn test_load_twice() {
+        let load = || {
+            Spi::get_one::<String>(&format!(
+                "SELECT pgextkit.load_from_path('{}', 'twice', '1.0')",
+                pkglib("pgextkit")
+            ))
+        };
+        let initialized = INITIALIZED.load(Ordering::SeqCst);
+        assert_eq!(load().as_deref(), Some("loaded"));
+        assert_eq!(load().as_deref(), Some("already_loaded"));
+        assert_eq!(INITIALIZED.load(Ordering::SeqCst), initialized + 1);
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM pgextkit.loaded_extensions() WHERE name = 'twice'"
+            ),
+            Some(1)
+        );
+    }
+
+    #[pg_test]
+    fn test_out_of_shared_memory_error() {
+        let handle = dynamic_handle("oom_test");
+        // More than the arena and the whole overflow pool together
+        let size = 1024 * 1024 * 1024;
+        let mut mem = std::ptr::null_mut::<c_void>();
+        let payload = &mut mem as *mut _ as *const c_void;
+        let (message, _detail) = caught_error(|| {
+            (handle.allocate_shmem_aligned)(&handle, size, 8, store_allocation, payload)
+        })
+        .expect("error");
+        assert!(message.contains("out of shared memory"), "{}", message);
+        assert!(message.contains("pgextkit.shmem_size"), "{}", message);
+        assert!(mem.is_null());
+    }
+
+    #[pg_test]
+    fn test_required_extensions_load_first() {
+        let dir = directory_with(&[
+            ("dependent--1.0.control", "requires = 'required'\n"),
+            ("required--1.0.control", ""),
+        ]);
+        let mut control_files = control_files_in(&dir);
+        // Discovered in the wrong order
+        control_files.sort_by(|x, y| x.name.cmp(&y.name));
+        let available = control_files
+            .iter()
+            .map(|control_file| control_file.name.clone())
+            .collect();
+        let ordered = crate::ext::order_by_dependencies_among(control_files, &available)
+            .into_iter()
+            .map(|control_file| control_file.name)
+            .collect::<Vec<_>>();
+        assert_eq!(ordered, vec!["required", "dependent"]);
+    }
+
+    #[pg_test]
+    fn test_downgrade() {
+        let lock = shared(
+            "tests.downgrade",
+            PgDynamicLwLock::new("tests.downgrade", 1),
+        );
+        let raw = lock.raw();
+        let mut guard = lock.exclusive();
+        *guard = 2;
+        let guard = guard.downgrade();
+        assert_eq!(*guard, 2);
+        unsafe {
+            assert!(pg_sys::LWLockHeldByMeInMode(
+                raw,
+                pg_sys::LWLockMode_LW_SHARED
+            ));
+            assert!(!pg_sys::LWLockHeldByMeInMode(
+                raw,
+                pg_sys::LWLockMode_LW_EXCLUSIVE
+            ));
+        }
+        drop(guard);
+        assert!(!unsafe { pg_sys::LWLockHeldByMe(raw) });
+    }
+
+    #[pg_test]
+    fn test_huge_latch_timeout() {
+        let latch = shared("tests.huge_timeout", SharedLatch::new())
+            .own()
+            .expect("latch");
+        for timeout in [Duration::from_secs(30 * 24 * 3600), Duration::MAX] {
+            // The wait returns right away as the latch is set
+            latch.set_and_wake_up();
+            latch.wait(Some(timeout));
+        }
+    }
+
+    #[pg_test]
+    fn test_slot_index() {
+        let local = shared(
+            "tests.slot_index",
+            crate::db::DatabaseLocal::<u32, 4>::new(|| 0),
+        );
+        assert_eq!(std::pin::Pin::new(&*local).slot_index(), None);
+        *std::pin::Pin::new(&mut *local).for_my_database() = 1;
+        assert_eq!(std::pin::Pin::new(&*local).slot_index(), Some(0));
+    }
+
+    const BENCH_READERS: usize = 4;
+    const BENCH_KEYS: usize = 64;
+    const BENCH_GETS: usize = 100_000;
+
+    struct DictionaryBench {
+        /// Time each reader took for its gets, in microseconds, followed by the times of
+        /// the baseline readers
+        elapsed_us: [AtomicU64; 2 * BENCH_READERS],
+    }
+
+    unsafe impl ShmemSafe for DictionaryBench {}
+
+    /// Does `BENCH_GETS` gets, the readers past `BENCH_READERS` are baseline readers
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_dictionary_reader(arg: pg_sys::Datum) {
+        let reader = arg.value();
+        // Before the dictionary was partitioned, every get took the same lock: baseline
+        // readers take the first partition lock on top of their key's
+        let single_lock = (reader >= BENCH_READERS).then(|| unsafe {
+            &mut (*pg_sys::GetNamedLWLockTranche(
+                cstr_core::cstr!("pgextkit_shared_dictionary").as_ptr(),
+            ))
+            .lock as *mut pg_sys::LWLock
+        });
+        let dict = SharedDictionary::default();
+        let keys = (0..BENCH_KEYS)
+            .map(|key| format!("tests.bench.{}", key))
+            .collect::<Vec<_>>();
+        let started = std::time::Instant::now();
+        for i in 0..BENCH_GETS {
+            if let Some(lock) = single_lock {
+                unsafe { pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_SHARED) };
+            }
+            assert!(dict.get::<u64>(&keys[(reader + i) % BENCH_KEYS]).is_some());
+            if let Some(lock) = single_lock {
+                unsafe { pg_sys::LWLockRelease(lock) };
+            }
+        }
+        let elapsed = started.elapsed();
+        dict.get::<DictionaryBench>("tests.dictionary_bench")
+            .expect("bench")
+            .elapsed_us[reader]
+            .store(elapsed.as_micros().max(1) as u64, Ordering::SeqCst);
+    }
+
+    /// Concurrent gets on different keys, which only contend if they hash to the same
+    /// partition of the dictionary. The throughput is reported as a notice, along with
+    /// the baseline of all gets going through a single lock.
+    #[pg_test]
+    fn test_dictionary_concurrent_gets() {
+        for key in 0..BENCH_KEYS {
+            shared(&format!("tests.bench.{}", key), key as u64);
+        }
+        let bench = shared(
+            "tests.dictionary_bench",
+            DictionaryBench {
+                elapsed_us: Default::default(),
+            },
+        );
+        let run = |first: usize| {
+            let readers = (first..first + BENCH_READERS)
+                .map(|reader| start_worker("pgextkit_test_dictionary_reader", reader as i64))
+                .collect::<Vec<_>>();
+            for reader in &readers {
+                assert!(reader.wait_for_shutdown(Duration::from_secs(60)));
+            }
+            let elapsed_us = bench.elapsed_us[first..first + BENCH_READERS]
+                .iter()
+                .map(|elapsed_us| elapsed_us.load(Ordering::SeqCst))
+                .collect::<Vec<_>>();
+            assert!(elapsed_us.iter().all(|elapsed_us| *elapsed_us > 0));
+            let slowest_us = *elapsed_us.iter().max().expect("readers");
+            let gets_per_s = (BENCH_READERS * BENCH_GETS) as u64 * 1_000_000 / slowest_us;
+            (elapsed_us, gets_per_s)
+        };
+        let (elapsed_us, gets_per_s) = run(0);
+        let (baseline_elapsed_us, baseline_gets_per_s) = run(BENCH_READERS);
+        pgx::notice!(
+            "{} readers did {} gets each in {:?}us, {} gets/s overall \
+             (single lock baseline: {:?}us, {} gets/s)",
+            BENCH_READERS,
+            BENCH_GETS,
+            elapsed_us,
+            gets_per_s,
+            baseline_elapsed_us,
+            baseline_gets_per_s
+        );
+    }
+
+    #[pg_test]
+    fn test_default_module_pathname() {
+        let dir = directory_with(&[(
+            "defaulted--1.0.control",
+            "comment = 'no module_pathname'\nrelocatable = false\nschema = defaulted\n",
+        )]);
+        let control_file = control_files_in(&dir).pop().expect("control file");
+        assert_eq!(control_file.name, "defaulted");
+        assert_eq!(control_file.path, PathBuf::from(pkglib("defaulted")));
+    }
+
+    #[pg_test]
+    fn test_unload_all() {
+        let _control_files = InstalledControlFiles::new(&[
+            (
+                "unload_all_a--1.0.control",
+                "module_pathname = '$libdir/pgextkit'\n",
+            ),
+            (
+                "unload_all_b--1.0.control",
+                "module_pathname = '$libdir/pgextkit'\n",
+            ),
+        ]);
+        for name in ["unload_all_a", "unload_all_b"] {
+            assert_eq!(
+                Spi::get_one::<String>(&format!("SELECT pgextkit.load('{}')", name)).as_deref(),
+                Some("loaded")
+            );
+        }
+        let unloaded = "SELECT count(*) FROM pgextkit.unload_all() \
+            WHERE name LIKE 'unload_all_%' AND status = 'unloaded'";
+        assert_eq!(Spi::get_one::<i64>(unloaded), Some(2));
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM pgextkit.loaded_extensions() WHERE name LIKE 'unload_all_%'"
+            ),
+            Some(0)
+        );
+        // Nothing left to unload
+        assert_eq!(Spi::get_one::<i64>(unloaded), Some(0));
+    }
+
+    #[pg_test]
+    fn test_config_reload_callback() {
+        use crate::latch::SignalWakeFlags;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let mut latch = shared("tests.config_reload", SharedLatch::new())
+            .own()
+            .expect("latch");
+        let observed = Rc::new(RefCell::new(None));
+        latch.on_config_reload({
+            let observed = observed.clone();
+            move || {
+                let work_mem = unsafe {
+                    std::ffi::CStr::from_ptr(pg_sys::GetConfigOption(
+                        cstr_core::cstr!("work_mem").as_ptr(),
+                        false,
+                        false,
+                    ))
+                };
+                *observed.borrow_mut() = Some(work_mem.to_string_lossy().to_string());
+            }
+        });
+        // The backend's own handler is put back afterwards
+        let previous = unsafe { libc::signal(libc::SIGHUP, libc::SIG_IGN) };
+        latch.attach_signal_handlers(SignalWakeFlags::SIGHUP);
+        unsafe {
+            pg_sys::SetConfigOption(
+                cstr_core::cstr!("work_mem").as_ptr(),
+                cstr_core::cstr!("12345kB").as_ptr(),
+                pg_sys::GucContext_PGC_USERSET,
+                pg_sys::GucSource_PGC_S_SESSION,
+            );
+            libc::kill(pg_sys::MyProcPid, libc::SIGHUP);
+        }
+        latch.wait(Some(Duration::from_secs(10)));
+        unsafe { libc::signal(libc::SIGHUP, previous) };
+        assert!(latch.signal_received(SignalWakeFlags::SIGHUP));
+        assert_eq!(observed.borrow().as_deref(), Some("12345kB"));
+    }
+
+    type EchoChannel = crate::channel::SharedChannel<u64, u64, 4>;
+
+    /// Replies to a single request of `tests.channel` with twice its value
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_echo(_arg: pg_sys::Datum) {
+        let channel = std::pin::Pin::into_inner(
+            SharedDictionary::default()
+                .get_mut::<EchoChannel>("tests.channel")
+                .expect("channel"),
+        );
+        let latch = channel.latch().own().expect("latch");
+        for _ in 0..100 {
+            if let Some((id, msg)) = channel.recv() {
+                channel.reply(id, msg * 2);
+                return;
+            }
+            latch.wait(Some(Duration::from_millis(100)));
+        }
+    }
+
+    #[pg_test]
+    fn test_channel_echo() {
+        let channel = shared("tests.channel", EchoChannel::new("tests.channel"));
+        let worker = start_worker("pgextkit_test_echo", 0);
+        assert_eq!(channel.send_and_wait(21, Duration::from_secs(10)), Some(42));
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+    }
+
+    #[pg_test]
+    fn test_invalid_library_is_incompatible() {
+        let dir = directory_with(&[("invalid.so", "not a library")]);
+        assert_eq!(
+            Spi::get_one::<String>(&format!(
+                "SELECT pgextkit.load_from_path('{}', 'invalid', '1.0')",
+                dir.join("invalid.so").to_string_lossy()
+            ))
+            .as_deref(),
+            Some("incompatible")
+        );
+    }
+
+    #[pg_test]
+    fn test_arena() {
+        type Arena = crate::arena::SharedArena<64>;
+        let handle = dynamic_handle("arena_test");
+        let mem = try_allocate(&handle, size_of::<Arena>(), align_of::<Arena>()) as *mut Arena;
+        assert!(!mem.is_null());
+        // A zeroed arena is empty
+        let arena = unsafe {
+            mem.write_bytes(0, 1);
+            &*mem
+        };
+        let a = arena.alloc(1u8).expect("a");
+        let b = arena.alloc(2u64).expect("b");
+        let c = arena.alloc([3u32; 4]).expect("c");
+        assert_eq!((*a, *b, *c), (1, 2, [3; 4]));
+        assert_eq!(&*b as *const u64 as usize % align_of::<u64>(), 0);
+        assert_eq!(arena.used(), 32);
+        // The remaining 32 bytes don't fit another 40
+        assert!(arena.alloc([0u64; 5]).is_none());
+        unsafe { arena.reset() };
+        assert_eq!(arena.used(), 0);
+        assert!(arena.alloc([0u64; 5]).is_some());
+    }
+
+    /// Waits for the lock of `tests.wait_event`
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_wait_for_lock(_arg: pg_sys::Datum) {
+        let lock = SharedDictionary::default()
+            .get::<PgDynamicLwLock<u32>>("tests.wait_event")
+            .expect("lock");
+        let _ = *lock.share();
+    }
+
+    #[pg_test]
+    fn test_lock_wait_event_name() {
+        let handle = dynamic_handle("waiting_ext");
+        let lock = shared("tests.wait_event", handle.new_lwlock("A", 0u32));
+        let guard = lock.exclusive();
+        let worker = start_worker("pgextkit_test_wait_for_lock", 0);
+        let pid = worker.pid().expect("pid");
+        let query = format!(
+            "SELECT wait_event FROM pg_stat_activity WHERE pid = {}",
+            pid
+        );
+        let mut wait_event = None;
+        for _ in 0..100 {
+            // Activity is otherwise only read once per transaction
+            unsafe { pg_sys::pgstat_clear_snapshot() };
+            wait_event = Spi::get_one::<String>(&query);
+            if wait_event.as_deref() == Some("waiting_ext.A") {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        drop(guard);
+        assert_eq!(wait_event.as_deref(), Some("waiting_ext.A"));
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+    }
+
+    #[pg_test]
+    fn test_unload_waits_for_workers() {
+        // Only extensions installed in the database can be unloaded
+        let _control_files = InstalledControlFiles::new(&[
+            (
+                "drain.control",
+                "default_version = '1.0'\nmodule_pathname = '$libdir/pgextkit'\n",
+            ),
+            ("drain--1.0.sql", ""),
+        ]);
+        Spi::run("CREATE EXTENSION drain");
+        let drain = shared(
+            "tests.drain",
+            Drain {
+                exit: Default::default(),
+                stopped: Default::default(),
+                latch: SharedLatch::new(),
+            },
+        );
+        assert_eq!(
+            Spi::get_one::<String>("SELECT pgextkit.load('drain')").as_deref(),
+            Some("loaded")
+        );
+        for _ in 0..100 {
+            if drain.latch.owner_pid().is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert!(drain.latch.owner_pid().is_some(), "worker didn't start");
+        assert_eq!(
+            Spi::get_one::<String>("SELECT pgextkit.unload('drain')").as_deref(),
+            Some("unloaded")
+        );
+        assert!(drain.stopped.load(Ordering::SeqCst));
+    }
+
+    #[pg_test]
+    fn test_update_counter() {
+        shared("tests.counter", PgDynamicLwLock::new("tests.counter", 0u64));
+        let dict = SharedDictionary::default();
+        let increment = |counter: &mut PgDynamicLwLock<u64>| {
+            let mut count = counter.exclusive();
+            *count += 1;
+            *count
+        };
+        assert_eq!(dict.update("tests.counter", increment), Some(1));
+        assert_eq!(dict.update("tests.counter", increment), Some(2));
+        assert_eq!(dict.update("tests.missing_counter", increment), None);
+        assert_eq!(
+            *dict
+                .get::<PgDynamicLwLock<u64>>("tests.counter")
+                .expect("counter")
+                .share(),
+            2
+        );
+    }
+
+    /// Switches the current role, like `SET ROLE`
+    fn set_role(role: &str) {
+        let role = CString::new(role).expect("CString::new failed");
+        unsafe {
+            pg_sys::SetConfigOption(
+                cstr_core::cstr!("role").as_ptr(),
+                role.as_ptr(),
+                pg_sys::GucContext_PGC_SUSET,
+                pg_sys::GucSource_PGC_S_SESSION,
+            )
+        }
+    }
+
+    #[pg_test]
+    fn test_set_worker_enabled() {
+        use crate::ext::disabled::DisabledWorkers;
+        let database = unsafe { pg_sys::MyDatabaseId };
+        crate::ext::set_worker_enabled("toggled", false);
+        assert!(DisabledWorkers::default().is_disabled(database, "toggled"));
+        crate::ext::set_worker_enabled("toggled", true);
+        assert!(!DisabledWorkers::default().is_disabled(database, "toggled"));
+
+        Spi::run("CREATE ROLE pgextkit_unprivileged");
+        set_role("pgextkit_unprivileged");
+        let error = caught_error(|| crate::ext::set_worker_enabled("toggled", false));
+        set_role("none");
+        let (message, _detail) = error.expect("error");
+        assert_eq!(
+            message,
+            "must have the privileges of pg_signal_backend to disable workers of toggled"
+        );
+        assert!(!DisabledWorkers::default().is_disabled(database, "toggled"));
+    }
+
+    #[pg_test]
+    fn test_latch_reset_before_recheck() {
+        use crate::latch::{wait_event_set, WaitEvents, WakeReason};
+        let latch = shared("tests.reset_latch", SharedLatch::new())
+            .own()
+            .expect("latch");
+        let pending = std::cell::Cell::new(0);
+        let produce = || {
+            pending.set(pending.get() + 1);
+            latch.set_and_wake_up();
+        };
+        let wait = || {
+            wait_event_set(
+                WaitEvents::new()
+                    .latch(&latch)
+                    .timeout(Duration::from_millis(50)),
+            )
+        };
+
+        // Work that was already processed doesn't wake the worker up once reset
+        produce();
+        pending.set(0);
+        latch.reset();
+        assert_eq!(wait(), WakeReason::Timeout);
+
+        // Resetting before checking for work: work produced after the check still wakes
+        // the worker up
+        produce();
+        latch.reset();
+        assert_eq!(pending.replace(0), 1);
+        produce();
+        assert_eq!(wait(), WakeReason::Latch(0));
+        assert_eq!(pending.replace(0), 1);
+
+        // Resetting after checking would lose work produced in between
+        assert_eq!(pending.get(), 0);
+        produce();
+        latch.reset();
+        assert_eq!(wait(), WakeReason::Timeout);
+        assert_eq!(pending.get(), 1);
+    }
+
+    #[pg_test]
+    fn test_unreadable_extension_directory() {
+        let dir = std::env::temp_dir().join("pgextkit_test_nonexistent");
+        let mut entries = 0;
+        let warnings = captured_warnings(|| {
+            entries = crate::ext::control_files_in(dir.clone()).count();
+        });
+        assert_eq!(entries, 0);
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(
+            warnings[0].starts_with(&format!(
+                "Can't read extension directory {}:",
+                dir.to_string_lossy()
+            )),
+            "{}",
+            warnings[0]
+        );
+    }
+
+    struct DsmTest {
+        handle: pg_sys::dsm_handle,
+        /// Value the worker read from the segment
+        read: AtomicU64,
+    }
+
+    unsafe impl ShmemSafe for DsmTest {}
+
+    /// Attaches to the segment of `tests.dsm` and reads its first value
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_attach_dsm(_arg: pg_sys::Datum) {
+        let test = SharedDictionary::default()
+            .get::<DsmTest>("tests.dsm")
+            .expect("test");
+        let segment = crate::dsm::DsmSegment::attach(test.handle).expect("segment");
+        let value = unsafe { *(segment.as_ptr() as *const u64) };
+        test.read.store(value, Ordering::SeqCst);
+        segment.detach();
+    }
+
+    #[pg_test]
+    fn test_dsm_attach_from_another_backend() {
+        let segment = crate::dsm::DsmSegment::create(1024);
+        assert!(segment.len() >= 1024);
+        unsafe { *(segment.as_ptr() as *mut u64) = 42 };
+        let test = shared(
+            "tests.dsm",
+            DsmTest {
+                handle: segment.handle(),
+                read: AtomicU64::new(0),
+            },
+        );
+        let worker = start_worker("pgextkit_test_attach_dsm", 0);
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+        assert_eq!(test.read.load(Ordering::SeqCst), 42);
+        segment.detach();
+    }
+
+    #[pg_test]
+    fn test_versions_match() {
+        use crate::ext::versions_match;
+        assert!(versions_match("1.0", "1.0.0"));
+        assert!(versions_match("1", "1.0.0"));
+        assert!(versions_match("1.0.0+build.5", "1.0"));
+        assert!(!versions_match("1.0", "1.0.1"));
+        assert!(versions_match("1.0-beta.1", "1.0.0-beta.1"));
+        assert!(!versions_match("1.0-beta.1", "1.0"));
+        assert!(!versions_match("1.0-beta.1", "1.0-beta.2"));
+        // Versions that don't look like semver only match exactly
+        assert!(versions_match("nightly", "nightly"));
+        assert!(!versions_match("1.0a", "1.0"));
+    }
+
+    #[pg_test]
+    fn test_worker_context_round_trip() {
+        use crate::worker::WorkerContext;
+        for (database, username) in [
+            ("postgres", "postgres"),
+            ("db@name", "user@example.com"),
+            ("", "we:ird@"),
+            ("données", "usér"),
+        ] {
+            let context = WorkerContext::new(database, username);
+            assert_eq!(WorkerContext::decode(&context.encode()), Some(context));
+        }
+        assert_eq!(WorkerContext::decode("user@database"), None);
+        assert_eq!(WorkerContext::decode("10:user"), None);
+    }
+
+    /// Removes the `tests.removed` entry, then raises the flag of `tests.remove`
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_remove(_arg: pg_sys::Datum) {
+        let mut dict = SharedDictionary::default();
+        let entry = dict.get::<u64>("tests.removed").expect("entry");
+        dict.remove_within(&*entry as *const u64 as *const u8, size_of::<u64>());
+        dict.get::<std::sync::atomic::AtomicBool>("tests.remove")
+            .expect("flag")
+            .store(true, Ordering::SeqCst);
+    }
+
+    #[pg_test]
+    fn test_locked_entry_blocks_remove() {
+        shared("tests.removed", 1u64);
+        let removed = shared("tests.remove", std::sync::atomic::AtomicBool::new(false));
+        let dict = SharedDictionary::default();
+        let guard = dict.get_locked::<u64>("tests.removed").expect("entry");
+        let worker = start_worker("pgextkit_test_remove", 0);
+        std::thread::sleep(Duration::from_millis(500));
+        assert!(!removed.load(Ordering::SeqCst));
+        assert_eq!(*guard, 1);
+        drop(guard);
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+        assert!(removed.load(Ordering::SeqCst));
+        assert!(dict.get::<u64>("tests.removed").is_none());
+    }
+
+    /// Runs `sql` with psql, for statements that can't run inside a transaction block
+    fn psql(sql: &str) {
+        let bindir = unsafe { std::ffi::CStr::from_ptr(pg_sys::my_exec_path.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+        let bindir = Path::new(&bindir).parent().expect("bindir");
+        let user = unsafe {
+            std::ffi::CStr::from_ptr(pg_sys::GetUserNameFromId(pg_sys::GetUserId(), false))
+        }
+        .to_string_lossy()
+        .into_owned();
+        let status = std::process::Command::new(bindir.join("psql"))
+            .args(["-h", "localhost", "-d", "postgres", "-U", &user, "-c", sql])
+            .arg("-p")
+            .arg(unsafe { pg_sys::PostPortNumber }.to_string())
+            .status()
+            .expect("can't run psql");
+        assert!(status.success(), "{} failed", sql);
+    }
+
+    #[pg_test]
+    fn test_new_database_gets_a_worker() {
+        psql("CREATE DATABASE pgextkit_test_new_database");
+        let listed = || {
+            Spi::get_one::<bool>(
+                "SELECT 'pgextkit_test_new_database' IN (SELECT pgextkit.active_database_workers())",
+            )
+            .unwrap_or_default()
+        };
+        let appeared = (0..100).any(|_| {
+            listed() || {
+                std::thread::sleep(Duration::from_millis(100));
+                false
+            }
+        });
+        psql("DROP DATABASE pgextkit_test_new_database WITH (FORCE)");
+        assert!(appeared);
+    }
+
+    const HASH_BENCH_KEYS: u32 = 1024;
+    const HASH_BENCH_LOOKUPS: u32 = 1_000_000;
+
+    /// Hashes keys that are formatted integers to the integer
+    unsafe extern "C" fn identity_hash(key: *const c_void, _keysize: pg_sys::Size) -> u32 {
+        let key = &*(key as *const heapless::String<96>);
+        key.parse().unwrap_or_default()
+    }
+
+    /// Lookups of integer keys in a dictionary-like hash table hashed with `hasher`, in
+    /// microseconds
+    fn time_lookups(hasher: crate::shmem::KeyHasher) -> u128 {
+        let mut ctl = unsafe { std::mem::zeroed::<pg_sys::HASHCTL>() };
+        ctl.keysize = size_of::<heapless::String<96>>();
+        ctl.entrysize = size_of::<heapless::String<96>>();
+        ctl.hash = Some(hasher);
+        ctl.match_ = Some(crate::shmem::compare);
+        let htab = unsafe {
+            pg_sys::hash_create(
+                cstr_core::cstr!("pgextkit_hash_bench").as_ptr(),
+                HASH_BENCH_KEYS as _,
+                &mut ctl,
+                (pg_sys::HASH_ELEM | pg_sys::HASH_FUNCTION | pg_sys::HASH_COMPARE) as _,
+            )
+        };
+        let keys = (0..HASH_BENCH_KEYS)
+            .map(|key| heapless::String::<96>::try_from(key.to_string().as_str()).expect("key"))
+            .collect::<Vec<_>>();
+        let search = |key: &heapless::String<96>, action| {
+            let mut found = false;
+            unsafe {
+                pg_sys::hash_search(htab, key as *const _ as *const c_void, action, &mut found);
+            }
+            found
+        };
+        for key in &keys {
+            search(key, pg_sys::HASHACTION_HASH_ENTER);
+        }
+        let started = std::time::Instant::now();
+        for i in 0..HASH_BENCH_LOOKUPS {
+            assert!(search(
+                &keys[(i % HASH_BENCH_KEYS) as usize],
+                pg_sys::HASHACTION_HASH_FIND
+            ));
+        }
+        let elapsed = started.elapsed();
+        unsafe { pg_sys::hash_destroy(htab) };
+        elapsed.as_micros().max(1)
+    }
+
+    /// Compares lookups of integer keys hashed with the default hasher and with an identity
+    /// hash. The shared dictionary itself can't be used, as every backend has to hash its
+    /// keys the same way, so this uses a local table with the dictionary's layout. The
+    /// throughput is reported as a notice.
+    #[pg_test]
+    fn test_key_hasher_bench() {
+        let default_us = time_lookups(crate::shmem::make_hashkey);
+        let identity_us = time_lookups(identity_hash);
+        pgx::notice!(
+            "{} lookups of integer keys: {}us with the default hasher, {}us with an identity hash",
+            HASH_BENCH_LOOKUPS,
+            default_us,
+            identity_us
+        );
+    }
+
+    /// Beats twice, a second apart
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_heartbeat(_arg: pg_sys::Datum) {
+        for _ in 0..2 {
+            crate::heartbeat::beat();
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    #[pg_test]
+    fn test_heartbeat_tick_advances() {
+        let worker = start_worker("pgextkit_test_heartbeat", 0);
+        let tick = || {
+            crate::heartbeat::entries()
+                .into_iter()
+                .find(|(name, _, _)| name == "pgextkit_test_heartbeat")
+                .map(|(_, tick, _)| tick)
+        };
+        let wait_for_tick = |expected| {
+            (0..100).any(|_| {
+                tick() == Some(expected) || {
+                    std::thread::sleep(Duration::from_millis(50));
+                    false
+                }
+            })
+        };
+        assert!(wait_for_tick(1));
+        assert!(wait_for_tick(2));
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+    }
+
+    #[derive(Default)]
+    struct Stuck {
+        started: std::sync::atomic::AtomicBool,
+        release: std::sync::atomic::AtomicBool,
+    }
+
+    unsafe impl ShmemSafe for Stuck {}
+
+    /// Ignores being terminated, only stops once released
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_stuck(_arg: pg_sys::Datum) {
+        unsafe { libc::signal(libc::SIGTERM, libc::SIG_IGN) };
+        let stuck = SharedDictionary::default()
+            .get::<Stuck>("tests.stuck")
+            .expect("stuck");
+        stuck.started.store(true, Ordering::SeqCst);
+        while !stuck.release.load(Ordering::SeqCst) {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    #[pg_test]
+    fn test_unload_refuses_while_worker_runs() {
+        let _control_files = InstalledControlFiles::new(&[
+            (
+                "stuck.control",
+                "default_version = '1.0'\nmodule_pathname = '$libdir/pgextkit'\n",
+            ),
+            ("stuck--1.0.sql", ""),
+        ]);
+        Spi::run("CREATE EXTENSION stuck");
+        let stuck = shared("tests.stuck", Stuck::default());
+        assert_eq!(
+            Spi::get_one::<String>("SELECT pgextkit.load('stuck')").as_deref(),
+            Some("loaded")
+        );
+        for _ in 0..100 {
+            if stuck.started.load(Ordering::SeqCst) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert!(stuck.started.load(Ordering::SeqCst), "worker didn't start");
+        assert_eq!(
+            Spi::get_one::<String>("SELECT pgextkit.unload('stuck')").as_deref(),
+            Some("in_use")
+        );
+        // The worker is still tracked, so unloading succeeds once it stops
+        stuck.release.store(true, Ordering::SeqCst);
+        assert_eq!(
+            Spi::get_one::<String>("SELECT pgextkit.unload('stuck')").as_deref(),
+            Some("unloaded")
+        );
+    }
+
+    const CONTENDERS: usize = 4;
+    const INCREMENTS: u64 = 100_000;
+
+    struct Contended {
+        spinlock: crate::spinlock::SharedSpinLock<u64>,
+        lwlock: PgDynamicLwLock<u64>,
+    }
+
+    unsafe impl SyncMut for Contended {}
+    unsafe impl ShmemSafe for Contended {}
+
+    /// Increments both counters of `tests.contended`
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_contend(_arg: pg_sys::Datum) {
+        let contended = std::pin::Pin::into_inner(
+            SharedDictionary::default()
+                .get_mut::<Contended>("tests.contended")
+                .expect("contended"),
+        );
+        for _ in 0..INCREMENTS {
+            *contended.spinlock.lock() += 1;
+            *contended.lwlock.exclusive() += 1;
+        }
+    }
+
+    #[pg_test]
+    fn test_spinlock_under_contention() {
+        let contended = shared(
+            "tests.contended",
+            Contended {
+                spinlock: crate::spinlock::SharedSpinLock::new(0),
+                lwlock: PgDynamicLwLock::new("tests.contended", 0),
+            },
+        );
+        let workers = (0..CONTENDERS)
+            .map(|_| start_worker("pgextkit_test_contend", 0))
+            .collect::<Vec<_>>();
+        for worker in &workers {
+            assert!(worker.wait_for_shutdown(Duration::from_secs(60)));
+        }
+        // No increment is lost with either lock
+        assert_eq!(*contended.spinlock.lock(), CONTENDERS as u64 * INCREMENTS);
+        assert_eq!(*contended.lwlock.share(), CONTENDERS as u64 * INCREMENTS);
+    }
+
+    static CONSTRUCTED: AtomicUsize = AtomicUsize::new(0);
+
+    /// Initializes the memory unless it already existed, like `Handle::allocate_shmem_with`
+    extern "C" fn construct_unless_found(mem: *mut c_void, _payload: *const c_void, found: bool) {
+        if !found {
+            CONSTRUCTED.fetch_add(1, Ordering::SeqCst);
+            unsafe { (mem as *mut u64).write(1) };
+        }
+    }
+
+    /// Allocating the same structure again, as when reattaching to it, must keep its state
+    #[pg_test]
+    fn test_reattach_constructs_once() {
+        let name = cstr_core::cstr!("pgextkit_tests_reattach");
+        let allocate = || unsafe {
+            crate::ext::init_allocation(
+                name,
+                "pgextkit_tests",
+                size_of::<u64>(),
+                construct_unless_found,
+                std::ptr::null(),
+            )
+        };
+        allocate();
+        let mem = unsafe {
+            let mut found = false;
+            pg_sys::ShmemInitStruct(name.as_ptr(), size_of::<u64>(), &mut found) as *mut u64
+        };
+        unsafe { *mem += 1 };
+        allocate();
+        assert_eq!(CONSTRUCTED.load(Ordering::SeqCst), 1);
+        assert_eq!(unsafe { *mem }, 2);
+    }
+
+    /// Owns the latch of `tests.owned_latch` until told to exit
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_own_latch(_arg: pg_sys::Datum) {
+        let owned = std::pin::Pin::into_inner(
+            SharedDictionary::default()
+                .get_mut::<Drain>("tests.owned_latch")
+                .expect("owned latch"),
+        );
+        let latch = owned.latch.own().expect("latch");
+        while !owned.exit.load(Ordering::SeqCst) {
+            latch.wait(Some(Duration::from_millis(100)));
+            latch.reset();
+        }
+    }
+
+    #[pg_test]
+    fn test_latch_owner_pid() {
+        let owned = shared(
+            "tests.owned_latch",
+            Drain {
+                exit: Default::default(),
+                stopped: Default::default(),
+                latch: SharedLatch::new(),
+            },
+        );
+        assert_eq!(owned.latch.owner_pid(), None);
+        let worker = start_worker("pgextkit_test_own_latch", 0);
+        for _ in 0..100 {
+            if owned.latch.owner_pid().is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(owned.latch.owner_pid(), worker.pid());
+        // Owned by the worker, so this backend can't own it
+        assert!(owned.latch.own().is_none());
+        owned.exit.store(true, Ordering::SeqCst);
+        owned.latch.set_and_wake_up();
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+        assert_eq!(owned.latch.owner_pid(), None);
+    }
+
+    #[derive(Default)]
+    struct GlobalWorker {
+        starts: AtomicUsize,
+        release: std::sync::atomic::AtomicBool,
+    }
+
+    unsafe impl ShmemSafe for GlobalWorker {}
+
+    /// Counts its starts, then runs until released
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_global(_arg: pg_sys::Datum) {
+        let global = SharedDictionary::default()
+            .get::<GlobalWorker>("tests.global")
+            .expect("global");
+        global.starts.fetch_add(1, Ordering::SeqCst);
+        while !global.release.load(Ordering::SeqCst) {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    #[pg_test]
+    fn test_global_worker_starts_once() {
+        let global = shared("tests.global", GlobalWorker::default());
+        let handle = dynamic_handle("global_test");
+        // As if the extension was loaded from three databases
+        for _ in 0..3 {
+            let mut bgw = test_worker("pgextkit_test_global", 0);
+            (handle.register_global_bgworker)(&handle, &mut bgw);
+        }
+        let workers = crate::ext::handles::WorkerHandles::default().of("global_test");
+        assert_eq!(workers.len(), 1);
+        let (database, worker) = &workers[0];
+        assert_eq!(*database, pg_sys::InvalidOid);
+        assert!(worker
+            .wait_for_startup(Duration::from_secs(10), || false)
+            .is_some());
+        std::thread::sleep(Duration::from_millis(500));
+        assert_eq!(global.starts.load(Ordering::SeqCst), 1);
+        global.release.store(true, Ordering::SeqCst);
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+        crate::ext::handles::WorkerHandles::default().take("global_test");
+    }
+
+    #[pg_test]
+    fn test_logger_prefixes_extension() {
+        let logger = crate::logging::Logger::new("logged", "1.2");
+        let warnings = captured_warnings(|| logger.warn("something happened"));
+        assert_eq!(
+            warnings,
+            vec!["[logged 1.2] something happened".to_string()]
+        );
+    }
+
+    /// A database worker that doesn't start in time is reported as an error the master
+    /// worker retries later, rather than raised
+    #[pg_test]
+    fn test_database_worker_startup_timeout() {
+        let started =
+            crate::ext::workers::start_database_worker("pgextkit_test_slow", Duration::ZERO);
+        let err = started.err().expect("worker started without waiting");
+        assert!(err.contains("didn't start within"), "{}", err);
+    }
+
+    const WALK_ENTRIES: usize = 1000;
+
+    /// Inserts `tests.walk.0`, `tests.walk.1`, ... in order, all pointing to `tests.walk`
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_insert_entries(_arg: pg_sys::Datum) {
+        let mut dict = SharedDictionary::default();
+        let target = std::pin::Pin::into_inner(dict.get_mut::<u64>("tests.walk").expect("target"));
+        for i in 0..WALK_ENTRIES {
+            dict.insert(&format!("tests.walk.{}", i), target as *mut u64);
+        }
+    }
+
+    #[pg_test]
+    fn test_for_each_while_inserting() {
+        let target = shared("tests.walk", 0u64);
+        let worker = start_worker("pgextkit_test_insert_entries", 0);
+        let dict = SharedDictionary::default();
+        let mut seen = 0;
+        while seen < WALK_ENTRIES {
+            let mut indices = vec![];
+            dict.for_each(|name, type_name, size| {
+                if let Some(index) = name.strip_prefix("tests.walk.") {
+                    assert_eq!(type_name, std::any::type_name::<u64>());
+                    assert_eq!(size, size_of::<u64>());
+                    indices.push(index.parse::<usize>().expect("index"));
+                }
+            });
+            // Entries are inserted in order, so a consistent snapshot has a prefix of them
+            indices.sort_unstable();
+            assert!(indices.iter().enumerate().all(|(i, index)| i == *index));
+            assert!(indices.len() >= seen);
+            seen = indices.len();
+        }
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+        SharedDictionary::default()
+            .remove_within(target as *const u64 as *const u8, size_of::<u64>());
+    }
+
+    #[derive(Default)]
+    struct NamedLockTest {
+        /// Whether the worker could take the lock right away
+        conditional: std::sync::atomic::AtomicBool,
+        tried: std::sync::atomic::AtomicBool,
+        acquired: std::sync::atomic::AtomicBool,
+    }
+
+    unsafe impl ShmemSafe for NamedLockTest {}
+
+    /// Tranche requested when pgextkit was preloaded, as `Handle::request_lwlocks` does
+    fn preloaded_tranche() -> crate::lwlock::NamedLwLockTranche {
+        crate::lwlock::NamedLwLockTranche::new(cstr_core::cstr!("pgextkit_disabled_workers"), 1)
+    }
+
+    /// Takes the lock of the preloaded tranche, found by its name
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_named_lock(_arg: pg_sys::Datum) {
+        let test = SharedDictionary::default()
+            .get::<NamedLockTest>("tests.named_lock")
+            .expect("named lock");
+        let lock = preloaded_tranche().get(0);
+        let conditional =
+            unsafe { pg_sys::LWLockConditionalAcquire(lock, pg_sys::LWLockMode_LW_EXCLUSIVE) };
+        test.conditional.store(conditional, Ordering::SeqCst);
+        test.tried.store(true, Ordering::SeqCst);
+        if !conditional {
+            unsafe { pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_EXCLUSIVE) };
+        }
+        test.acquired.store(true, Ordering::SeqCst);
+        unsafe { pg_sys::LWLockRelease(lock) };
+    }
+
+    #[pg_test]
+    fn test_requested_lwlock_shared_by_backends() {
+        let test = shared("tests.named_lock", NamedLockTest::default());
+        let lock = preloaded_tranche().get(0);
+        unsafe { pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_EXCLUSIVE) };
+        let worker = start_worker("pgextkit_test_named_lock", 0);
+        for _ in 0..100 {
+            if test.tried.load(Ordering::SeqCst) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        std::thread::sleep(Duration::from_millis(200));
+        let blocked =
+            !test.conditional.load(Ordering::SeqCst) && !test.acquired.load(Ordering::SeqCst);
+        unsafe { pg_sys::LWLockRelease(lock) };
+        assert!(
+            blocked,
+            "the worker didn't wait for the lock held by this backend"
+        );
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+        assert!(test.acquired.load(Ordering::SeqCst));
+    }
+
+    #[pg_test]
+    fn test_init_only_advisory() {
+        // plpgsql exports no pgextkit_deinit
+        let init_only = unsafe { libloading::Library::new(pkglib("plpgsql")) }.expect("plpgsql");
+        let advised = |acquired_resources| {
+            captured_messages(pg_sys::LOG, || {
+                crate::ext::check_deinit(&init_only, "init_only", acquired_resources)
+            })
+        };
+        let advisory = advised(true);
+        assert_eq!(advisory.len(), 1);
+        assert!(advisory[0].starts_with("init_only allocates shared memory"));
+        // Nothing to clean up without resources
+        assert!(advised(false).is_empty());
+        // pgextkit's test library has a deinit
+        let with_deinit =
+            unsafe { libloading::Library::new(pkglib("pgextkit")) }.expect("pgextkit");
+        assert!(captured_messages(pg_sys::LOG, || {
+            crate::ext::check_deinit(&with_deinit, "with_deinit", true)
+        })
+        .is_empty());
+    }
+
+    const CAS_INCREMENTS: u64 = 1_000;
+
+    /// Increments the number in `tests.cas` with compare_and_set
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_cas(_arg: pg_sys::Datum) {
+        let s = std::pin::Pin::into_inner(
+            SharedDictionary::default()
+                .get_mut::<PgDynamicLwLock<heapless::String<32>>>("tests.cas")
+                .expect("cas"),
+        );
+        for _ in 0..CAS_INCREMENTS {
+            loop {
+                let current = s.share().to_string();
+                let next = (current.parse::<u64>().expect("number") + 1).to_string();
+                if s.compare_and_set(&current, &next) {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[pg_test]
+    fn test_compare_and_set_concurrently() {
+        let s = shared(
+            "tests.cas",
+            PgDynamicLwLock::new("tests.cas", heapless::String::<32>::from("0")),
+        );
+        let workers = (0..CONTENDERS)
+            .map(|_| start_worker("pgextkit_test_cas", 0))
+            .collect::<Vec<_>>();
+        for worker in &workers {
+            assert!(worker.wait_for_shutdown(Duration::from_secs(60)));
+        }
+        // Every increment succeeded exactly once
+        assert_eq!(
+            s.share().as_str(),
+            (CONTENDERS as u64 * CAS_INCREMENTS).to_string()
+        );
+        assert!(!s.compare_and_set("0", "1"));
+        // Too long to fit
+        let current = s.share().to_string();
+        assert!(!s.compare_and_set(&current, &"9".repeat(33)));
+    }
+
+    #[pg_test]
+    fn test_allocator_stats_fragmentation() {
+        const BLOCK: usize = 64 * 1024;
+        let stats = || {
+            Spi::get_two::<i64, i64>(
+                "SELECT free, largest_free FROM pgextkit.shmem_allocator_stats()",
+            )
+        };
+        let handle = dynamic_handle("fragment_test");
+        let blocks = (0..8)
+            .map(|_| try_allocate(&handle, BLOCK, align_of::<usize>()) as *mut u8)
+            .collect::<Vec<_>>();
+        assert!(blocks.iter().all(|block| !block.is_null()));
+        let (free_before, _) = stats();
+        let layout =
+            std::alloc::Layout::from_size_align(BLOCK, align_of::<usize>()).expect("layout");
+        let release = |block| {
+            crate::ext::allocations::Allocations::default().free_one(
+                "fragment_test",
+                block,
+                layout,
+                false,
+            )
+        };
+        // Every other block, so that the freed ones aren't contiguous
+        for block in blocks.iter().step_by(2) {
+            release(*block);
+        }
+        let (free, largest_free) = stats();
+        assert_eq!(free, free_before.map(|free| free + 4 * BLOCK as i64));
+        assert!(largest_free.is_some());
+        assert!(
+            largest_free < free,
+            "{:?} isn't below {:?}",
+            largest_free,
+            free
+        );
+        for block in blocks.iter().skip(1).step_by(2) {
+            release(*block);
+        }
+    }
+
+    #[pg_test]
+    fn test_unload_all_deinitializes_dependents_first() {
+        let _control_files = InstalledControlFiles::new(&[
+            (
+                "deinit_order_a--1.0.control",
+                "module_pathname = '$libdir/pgextkit'\n",
+            ),
+            (
+                "deinit_order_b--1.0.control",
+                "module_pathname = '$libdir/pgextkit'\nrequires = 'deinit_order_a'\n",
+            ),
+        ]);
+        // Loaded before the extension it requires, so the load order alone would
+        // deinitialize A first
+        for name in ["deinit_order_b", "deinit_order_a"] {
+            assert_eq!(
+                Spi::get_one::<String>(&format!("SELECT pgextkit.load('{}')", name)).as_deref(),
+                Some("loaded")
+            );
+        }
+        DEINIT_ORDER
+            .lock()
+            .expect("can't lock deinit order")
+            .clear();
+        Spi::run("SELECT * FROM pgextkit.unload_all()");
+        assert_eq!(
+            *DEINIT_ORDER.lock().expect("can't lock deinit order"),
+            vec![
+                vec!["deinit_order_b".to_string(), "deinit_order_a".to_string()],
+                vec!["deinit_order_a".to_string()],
+            ]
+        );
+    }
+
+    type TestQueue = crate::queue::SharedQueue<u32, 2>;
+
+    /// Pops an item off `tests.queue` after a second
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_slow_consumer(_arg: pg_sys::Datum) {
+        let queue = std::pin::Pin::into_inner(
+            SharedDictionary::default()
+                .get_mut::<TestQueue>("tests.queue")
+                .expect("queue"),
+        );
+        std::thread::sleep(Duration::from_secs(1));
+        assert_eq!(queue.pop(), Some(1));
+    }
+
+    #[pg_test]
+    fn test_queue_backpressure() {
+        let queue = shared("tests.queue", TestQueue::new("tests.queue"));
+        assert!(queue.push(1).is_ok());
+        assert!(queue.push(2).is_ok());
+        // Nobody makes room
+        let timed_out = queue.push_blocking(3, Duration::from_millis(100));
+        assert_eq!(timed_out.map_err(|err| err.0), Err(3));
+        let worker = start_worker("pgextkit_test_slow_consumer", 0);
+        let started = std::time::Instant::now();
+        assert!(queue.push_blocking(3, Duration::from_secs(10)).is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(500));
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(3));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[repr(C)]
+    pub struct AdderService {
+        add: extern "C" fn(i64, i64) -> i64,
+    }
+
+    unsafe impl crate::service::ServiceVtable for AdderService {}
+
+    extern "C" fn add(a: i64, b: i64) -> i64 {
+        a + b
+    }
+
+    #[no_mangle]
+    pub static PGEXTKIT_TEST_ADDER: AdderService = AdderService { add };
+
+    /// Calls the `tests.adder` service, storing the result in `tests.adder_result`
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_call_service(_arg: pg_sys::Datum) {
+        let adder = crate::service::lookup_service::<AdderService>("tests.adder").expect("service");
+        SharedDictionary::default()
+            .get::<std::sync::atomic::AtomicI64>("tests.adder_result")
+            .expect("result")
+            .store((adder.add)(2, 3), Ordering::SeqCst);
+    }
+
+    #[pg_test]
+    fn test_service_called_from_another_backend() {
+        // As Handle::register_service does
+        shared(
+            &crate::service::service_key("tests.adder"),
+            crate::service::ServiceEntry::<AdderService>::new(
+                "$libdir/pgextkit",
+                "PGEXTKIT_TEST_ADDER",
+            ),
+        );
+        let result = shared("tests.adder_result", std::sync::atomic::AtomicI64::new(0));
+        let worker = start_worker("pgextkit_test_call_service", 0);
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+        assert_eq!(result.load(Ordering::SeqCst), 5);
+        // Looking it up as another vtable type doesn't find it
+        #[repr(C)]
+        struct OtherService {
+            call: extern "C" fn(),
+        }
+        unsafe impl crate::service::ServiceVtable for OtherService {}
+        assert!(crate::service::lookup_service::<OtherService>("tests.adder").is_none());
+    }
+
+    #[pg_test]
+    fn test_load_prefers_installed_version() {
+        let _control_files = InstalledControlFiles::new(&[
+            (
+                "pick_installed.control",
+                "default_version = '1.0'\nmodule_pathname = '$libdir/pgextkit'\n",
+            ),
+            (
+                "pick_installed--1.0.control",
+                "module_pathname = '$libdir/pgextkit'\n",
+            ),
+            (
+                "pick_installed--2.0.0-longest.control",
+                "module_pathname = '$libdir/pgextkit'\n",
+            ),
+            ("pick_installed--1.0.sql", ""),
+        ]);
+        let picked = || {
+            crate::ext::find_matching_control_file("pick_installed", None)
+                .expect("control file")
+                .version
+        };
+        // Not installed yet, the longest name wins
+        assert_eq!(picked(), "2.0.0-longest");
+        Spi::run("CREATE EXTENSION pick_installed VERSION '1.0'");
+        assert_eq!(picked(), "1.0");
+    }
+
+    #[pg_test]
+    fn test_pgextkit_version() {
+        assert_eq!(
+            Spi::get_two::<i32, i32>("SELECT * FROM pgextkit.pgextkit_version()"),
+            (
+                Some(crate::VERSION as i32),
+                Some(size_of::<crate::Magic>() as i32)
+            )
+        );
+    }
+
+    /// Attaches signal handlers to the latch of `tests.signal_latch` twice, storing how
+    /// many times it got registered in `tests.signal_registrations`
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_attach_twice(_arg: pg_sys::Datum) {
+        use crate::latch::SignalWakeFlags;
+        let dict = SharedDictionary::default();
+        let latch = std::pin::Pin::into_inner(
+            dict.get_mut::<SharedLatch>("tests.signal_latch")
+                .expect("latch"),
+        )
+        .own()
+        .expect("owned latch");
+        latch.attach_signal_handlers(SignalWakeFlags::SIGHUP);
+        latch.attach_signal_handlers(SignalWakeFlags::SIGTERM);
+        dict.get::<AtomicUsize>("tests.signal_registrations")
+            .expect("registrations")
+            .store(latch.signal_registrations(), Ordering::SeqCst);
+    }
+
+    #[pg_test]
+    fn test_attach_signal_handlers_twice() {
+        shared("tests.signal_latch", SharedLatch::new());
+        let registrations = shared("tests.signal_registrations", AtomicUsize::new(0));
+        let worker = start_worker("pgextkit_test_attach_twice", 0);
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+        assert_eq!(registrations.load(Ordering::SeqCst), 1);
+    }
+
+    /// Runs `SELECT 1` through `WorkerSpi`, storing what it returned
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_worker_spi(_arg: pg_sys::Datum) {
+        use pgx::bgworkers::BackgroundWorker;
+        BackgroundWorker::connect_worker_to_spi(None, None);
+        let dict = SharedDictionary::default();
+        let latch = std::pin::Pin::into_inner(
+            dict.get_mut::<SharedLatch>("tests.spi_latch")
+                .expect("latch"),
+        )
+        .own()
+        .expect("owned latch");
+        let result = crate::worker::WorkerSpi::new(&latch)
+            .query_one::<i32>("SELECT 1")
+            .expect("query")
+            .expect("row");
+        dict.get::<AtomicU64>("tests.spi_result")
+            .expect("result")
+            .store(result as u64, Ordering::SeqCst);
+    }
+
+    #[pg_test]
+    fn test_worker_spi_select() {
+        shared("tests.spi_latch", SharedLatch::new());
+        let result = shared("tests.spi_result", AtomicU64::new(0));
+        let worker = start_worker("pgextkit_test_worker_spi", 0);
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+        assert_eq!(result.load(Ordering::SeqCst), 1);
+    }
+
+    static UNDERSIZED_CALLED: std::sync::atomic::AtomicBool =
+        std::sync::atomic::AtomicBool::new(false);
+
+    extern "C" fn record_undersized(_mem: *mut c_void, _payload: *const c_void, _found: bool) {
+        UNDERSIZED_CALLED.store(true, Ordering::SeqCst);
+    }
+
+    /// An allocation larger than what's left of shared memory must fail before its
+    /// callback runs against memory that isn't there
+    #[pg_test]
+    fn test_undersized_allocation_skips_callback() {
+        let (message, _) = caught_error(|| unsafe {
+            crate::ext::init_allocation(
+                cstr_core::cstr!("pgextkit_tests_undersized"),
+                "pgextkit_tests",
+                usize::MAX / 2,
+                record_undersized,
+                std::ptr::null(),
+            )
+        })
+        .expect("allocation should fail");
+        assert!(message.contains("shared memory"), "{}", message);
+        assert!(!UNDERSIZED_CALLED.load(Ordering::SeqCst));
+    }
+
+    /// Extensions are notified of a database once, however many scans find it
+    #[pg_test]
+    fn test_new_databases_notified_once() {
+        use crate::ext::workers::take_new;
+        let scan = |databases: &[&str]| {
+            databases
+                .iter()
+                .map(|database| database.to_string())
+                .collect::<Vec<_>>()
+        };
+        let mut known = std::collections::HashSet::new();
+        assert_eq!(
+            take_new(&mut known, &scan(&["postgres", "one"])),
+            scan(&["postgres", "one"])
+        );
+        assert_eq!(
+            take_new(&mut known, &scan(&["postgres", "one", "two"])),
+            scan(&["two"])
+        );
+        assert!(take_new(&mut known, &scan(&["postgres", "one", "two"])).is_empty());
+        // Dropped and created again, as the master worker forgets dropped databases
+        let live = scan(&["postgres", "two"]);
+        known.retain(|database| live.contains(database));
+        assert!(take_new(&mut known, &live).is_empty());
+        assert_eq!(
+            take_new(&mut known, &scan(&["postgres", "one", "two"])),
+            scan(&["one"])
+        );
+    }
+
+    const FALSE_SHARING_INCREMENTS: u64 = 10_000_000;
+
+    /// Counters incremented by two workers each, either next to each other or on their
+    /// own cache lines
+    struct FalseSharing {
+        packed: [AtomicU64; 2],
+        aligned: [crate::types::CacheAligned<AtomicU64>; 2],
+        /// Time both workers of either layout took, in microseconds
+        elapsed_us: [AtomicU64; 2],
+        start: crate::barrier::SharedBarrier,
+    }
+
+    unsafe impl ShmemSafe for FalseSharing {}
+
+    /// Increments one counter of `tests.false_sharing`, the argument tells which and
+    /// whether to use the aligned ones
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_false_sharing(arg: pg_sys::Datum) {
+        let counters = SharedDictionary::default()
+            .get::<FalseSharing>("tests.false_sharing")
+            .expect("false sharing");
+        let index = arg.value() % 2;
+        let aligned = arg.value() / 2;
+        let counter = if aligned == 1 {
+            &*counters.aligned[index]
+        } else {
+            &counters.packed[index]
+        };
+        counters.start.wait(2);
+        let start = std::time::Instant::now();
+        for _ in 0..FALSE_SHARING_INCREMENTS {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        counters.elapsed_us[aligned]
+            .fetch_max(start.elapsed().as_micros() as u64, Ordering::SeqCst);
+    }
+
+    #[pg_test]
+    fn test_cache_aligned_bench() {
+        let counters = shared(
+            "tests.false_sharing",
+            FalseSharing {
+                packed: Default::default(),
+                aligned: Default::default(),
+                elapsed_us: Default::default(),
+                start: crate::barrier::SharedBarrier::new(),
+            },
+        );
+        for aligned in 0..2 {
+            let workers = (0..2)
+                .map(|index| start_worker("pgextkit_test_false_sharing", aligned * 2 + index))
+                .collect::<Vec<_>>();
+            for worker in &workers {
+                assert!(worker.wait_for_shutdown(Duration::from_secs(60)));
+            }
+        }
+        for index in 0..2 {
+            assert_eq!(
+                counters.packed[index].load(Ordering::SeqCst),
+                FALSE_SHARING_INCREMENTS
+            );
+            assert_eq!(
+                counters.aligned[index].load(Ordering::SeqCst),
+                FALSE_SHARING_INCREMENTS
+            );
+        }
+        pgx::notice!(
+            "{} concurrent increments of two counters: {}us next to each other, {}us cache aligned",
+            FALSE_SHARING_INCREMENTS,
+            counters.elapsed_us[0].load(Ordering::SeqCst),
+            counters.elapsed_us[1].load(Ordering::SeqCst)
+        );
+    }
+
+    #[pg_test]
+    fn test_with_exclusive_releases_on_panic() {
+        let lock = shared(
+            "tests.with_exclusive",
+            PgDynamicLwLock::new("tests.with_exclusive", 1),
+        );
+        let raw = lock.raw();
+        let (message, _) = caught_error(|| {
+            lock.with_exclusive(|value| {
+                *value = 2;
+                panic!("closure failed");
+            })
+        })
+        .expect("closure should panic");
+        assert!(message.contains("closure failed"), "{}", message);
+        assert!(!unsafe { pg_sys::LWLockHeldByMe(raw) });
+        // Can be acquired again
+        assert_eq!(lock.with_exclusive(|value| std::mem::replace(value, 3)), 2);
+        assert_eq!(lock.with_share(|value| *value), 3);
+    }
+
+    struct PerDatabase {
+        /// Values of this backend's database and of `template1`
+        ours: AtomicU64,
+        theirs: AtomicU64,
+        /// What the worker in `template1` found before and after inserting `theirs`
+        found_before: AtomicU64,
+        found_after: AtomicU64,
+    }
+
+    unsafe impl ShmemSafe for PerDatabase {}
+
+    /// Looks up `tests.per_database` from `template1`, then inserts its own value there
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_per_database(_arg: pg_sys::Datum) {
+        use pgx::bgworkers::BackgroundWorker;
+        BackgroundWorker::connect_worker_to_spi(Some("template1"), None);
+        let mut dict = SharedDictionary::default();
+        let per_database = dict
+            .get::<PerDatabase>("tests.per_database")
+            .expect("per database");
+        let found = |dict: &SharedDictionary| {
+            dict.get_for_database::<AtomicU64>("tests.per_database")
+                .map_or(0, |value| value.load(Ordering::SeqCst))
+        };
+        per_database
+            .found_before
+            .store(found(&dict), Ordering::SeqCst);
+        dict.insert_for_database(
+            "tests.per_database",
+            &per_database.theirs as *const AtomicU64 as *mut AtomicU64,
+        );
+        per_database
+            .found_after
+            .store(found(&dict), Ordering::SeqCst);
+    }
+
+    #[pg_test]
+    fn test_dictionary_entries_per_database() {
+        let per_database = shared(
+            "tests.per_database",
+            PerDatabase {
+                ours: AtomicU64::new(1),
+                theirs: AtomicU64::new(2),
+                found_before: Default::default(),
+                found_after: Default::default(),
+            },
+        );
+        SharedDictionary::default().insert_for_database(
+            "tests.per_database",
+            &mut per_database.ours as *mut AtomicU64,
+        );
+        let worker = start_worker("pgextkit_test_per_database", 0);
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+        // Nothing under that name in template1 until the worker inserted it
+        assert_eq!(per_database.found_before.load(Ordering::SeqCst), 0);
+        assert_eq!(per_database.found_after.load(Ordering::SeqCst), 2);
+        // Which didn't replace this database's entry
+        assert_eq!(
+            SharedDictionary::default()
+                .get_for_database::<AtomicU64>("tests.per_database")
+                .map(|value| value.load(Ordering::SeqCst)),
+            Some(1)
+        );
+    }
+
+    struct Wakeup {
+        latch: SharedLatch,
+        /// How long the owner waited, in milliseconds
+        waited_ms: AtomicU64,
+    }
+
+    unsafe impl SyncMut for Wakeup {}
+    unsafe impl ShmemSafe for Wakeup {}
+
+    /// Owns the latch of `tests.wakeup` and waits on it for up to a minute
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_wakeup(_arg: pg_sys::Datum) {
+        let wakeup = std::pin::Pin::into_inner(
+            SharedDictionary::default()
+                .get_mut::<Wakeup>("tests.wakeup")
+                .expect("wakeup"),
+        );
+        let latch = wakeup.latch.own().expect("latch");
+        let start = std::time::Instant::now();
+        latch.wait(Some(Duration::from_secs(60)));
+        wakeup
+            .waited_ms
+            .store(start.elapsed().as_millis() as u64, Ordering::SeqCst);
+    }
+
+    #[pg_test]
+    fn test_set_wakes_up_owner() {
+        let wakeup = shared(
+            "tests.wakeup",
+            Wakeup {
+                latch: SharedLatch::new(),
+                waited_ms: Default::default(),
+            },
+        );
+        let worker = start_worker("pgextkit_test_wakeup", 0);
+        for _ in 0..100 {
+            if wakeup.latch.owner_pid().is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(wakeup.latch.owner_pid(), worker.pid());
+        wakeup.latch.set_and_wake_up();
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+        assert!(wakeup.waited_ms.load(Ordering::SeqCst) < 10_000);
+    }
+
+    #[pg_test]
+    fn test_worker_name_template() {
+        use crate::ext::workers::expand_worker_name;
+        let expand =
+            |template| expand_worker_name(template, "postgres", "alice", "example", "1.2.0");
+        assert_eq!(expand("{{USER}}@{{DATABASE}}"), "alice@postgres");
+        assert_eq!(
+            expand("{{EXTENSION}} {{VERSION}} ({{USER}}@{{DATABASE}})"),
+            "example 1.2.0 (alice@postgres)"
+        );
+        // Unknown and unterminated tokens are left as-is
+        assert_eq!(expand("{{HOST}} {{USER}}"), "{{HOST}} alice");
+        assert_eq!(expand("{{USER}} {{DATA"), "alice {{DATA");
+    }
+
+    #[pg_test]
+    fn test_shmem_stats_in_init() {
+        let _control_files = InstalledControlFiles::new(&[
+            (
+                "shmem_stats.control",
+                "default_version = '1.0'\nmodule_pathname = '$libdir/pgextkit'\n",
+            ),
+            ("shmem_stats--1.0.sql", ""),
+        ]);
+        Spi::run("CREATE EXTENSION shmem_stats");
+        assert_eq!(
+            Spi::get_one::<String>("SELECT pgextkit.load('shmem_stats')").as_deref(),
+            Some("loaded")
+        );
+        let (total, free) = SHMEM_STATS_IN_INIT
+            .lock()
+            .expect("can't lock shmem stats")
+            .expect("pgextkit_init didn't run");
+        assert_eq!(
+            Spi::get_one::<i64>("SELECT total FROM pgextkit.shmem_allocator_stats()"),
+            Some(total as i64)
+        );
+        assert!(free > 0);
+        assert!(free <= total);
+    }
+
+    #[pg_test]
+    fn test_dictionary_spills_into_overflow() {
+        let value = shared("tests.spill", 0u64);
+        let mut dict = SharedDictionary::default();
+        let spilled = 10;
+        let count = SharedDictionary::max_entries() - dict.len() + spilled;
+        for i in 0..count {
+            dict.insert(&format!("tests.spill.{}", i), value as *mut u64);
+        }
+        assert!(dict.overflow_len() >= spilled);
+        for i in 0..count {
+            assert!(
+                dict.get::<u64>(&format!("tests.spill.{}", i)).is_some(),
+                "tests.spill.{} is missing",
+                i
+            );
+        }
+        let found = dict
+            .keys()
+            .filter(|name| name.starts_with("tests.spill."))
+            .count();
+        assert_eq!(found, count);
+        // Removed from either table (along with `tests.spill` itself), making room again
+        let removed = dict.remove_within(value as *const u64 as *const u8, size_of::<u64>());
+        assert_eq!(removed.len(), count + 1);
+    }
+
+    #[pg_test]
+    fn test_wait_event_set_latch_and_timer() {
+        use crate::latch::{wait_event_set, WaitEvents, WakeReason};
+        let latch = shared("tests.event_set", SharedLatch::new())
+            .own()
+            .expect("latch");
+        latch.reset();
+        let wait = |timeout| {
+            let start = std::time::Instant::now();
+            let reason = wait_event_set(WaitEvents::new().latch(&latch).timeout(timeout));
+            (reason, start.elapsed())
+        };
+        let (reason, elapsed) = wait(Duration::from_millis(50));
+        assert_eq!(reason, WakeReason::Timeout);
+        assert!(elapsed >= Duration::from_millis(50), "{:?}", elapsed);
+        // Sub-millisecond timeouts are waited for too
+        assert_eq!(wait(Duration::from_micros(500)).0, WakeReason::Timeout);
+        latch.set_and_wake_up();
+        let (reason, elapsed) = wait(Duration::from_secs(60));
+        assert_eq!(reason, WakeReason::Latch(0));
+        assert!(elapsed < Duration::from_secs(10), "{:?}", elapsed);
+        // The latch was reset, so the timer fires again
+        assert_eq!(wait(Duration::from_millis(1)).0, WakeReason::Timeout);
+    }
+
+    #[pg_test]
+    fn test_malformed_control_file_is_reported() {
+        let _control_files = InstalledControlFiles::new(&[(
+            "malformed.control",
+            "comment = 'no default version'\nmodule_pathname = '$libdir/pgextkit'\n",
+        )]);
+        let (version, error) = Spi::get_two::<String, String>(
+            "SELECT version, error FROM pgextkit.discoverable_extensions() WHERE name = 'malformed'",
+        );
+        assert_eq!(version, None);
+        let error = error.expect("malformed control file isn't reported");
+        assert!(error.contains("can't get default_version"), "{}", error);
+    }
+
+    #[pg_test]
+    fn test_locks_with_the_same_name_share_a_tranche() {
+        let locks = shared(
+            "tests.same_tranche",
+            std::array::from_fn::<_, 100, _>(|_| PgDynamicLwLock::new("tests.same_tranche", 0)),
+        );
+        let other = shared(
+            "tests.other_tranche",
+            PgDynamicLwLock::new("tests.other_tranche", 0),
+        );
+        let tranche = |lock: &PgDynamicLwLock<i32>| unsafe { (*lock.raw()).tranche };
+        let first = tranche(&locks[0]);
+        assert!(locks.iter().all(|lock| tranche(lock) == first));
+        assert_ne!(tranche(other), first);
+    }
+
+    /// Payloads of the prioritized allocations, in the order they were initialized
+    static INITIALIZED_ALLOCATIONS: std::sync::Mutex<Vec<usize>> = std::sync::Mutex::new(vec![]);
+
+    extern "C" fn record_initialization(_mem: *mut c_void, payload: *const c_void, _found: bool) {
+        INITIALIZED_ALLOCATIONS
+            .lock()
+            .expect("can't lock initialized allocations")
+            .push(payload as usize);
+    }
+
+    #[pg_test]
+    fn test_allocations_initialized_by_priority() {
+        let handle = Handle::make_static("prioritized".to_string(), "1.0".to_string(), "pgextkit");
+        for (id, priority) in [(1, 0), (2, 10), (3, 0), (4, -5), (5, 10)] {
+            (handle.allocate_shmem_prioritized)(
+                &handle,
+                size_of::<u64>(),
+                align_of::<u64>(),
+                priority,
+                record_initialization,
+                id as *const c_void,
+            );
+        }
+        unsafe { crate::ext::init_allocations() };
+        assert_eq!(
+            *INITIALIZED_ALLOCATIONS
+                .lock()
+                .expect("can't lock initialized allocations"),
+            vec![2, 5, 1, 3, 4]
+        );
+    }
+
+    /// Records an error, as a failing worker would
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_worker_error(_arg: pg_sys::Datum) {
+        crate::worker_errors::record("tests_failing", "can't reach the upstream server");
+    }
+
+    #[pg_test]
+    fn test_worker_error_shown_from_sql() {
+        let worker = start_worker("pgextkit_test_worker_error", 0);
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+        let (database, message) = Spi::get_two::<String, String>(
+            "SELECT database, message FROM pgextkit.worker_errors() WHERE extension = 'tests_failing'",
+        );
+        // Not started by pgextkit, so its database isn't known
+        assert_eq!(database.as_deref(), Some(""));
+        assert_eq!(message.as_deref(), Some("can't reach the upstream server"));
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT \"when\" <= clock_timestamp() FROM pgextkit.worker_errors() WHERE extension = 'tests_failing'"
+            ),
+            Some(true)
+        );
+    }
+
+    #[pg_test]
+    fn test_unload_keeps_no_probed_library() {
+        let _control_files = InstalledControlFiles::new(&[
+            (
+                "probed.control",
+                "default_version = '1.0'\nmodule_pathname = '$libdir/pgextkit'\n",
+            ),
+            ("probed--1.0.sql", ""),
+        ]);
+        Spi::run("CREATE EXTENSION probed");
+        let probed = crate::ext::probed_libraries();
+        for _ in 0..3 {
+            assert_eq!(
+                Spi::get_one::<String>("SELECT pgextkit.load('probed')").as_deref(),
+                Some("loaded")
+            );
+            assert_eq!(
+                Spi::get_one::<String>("SELECT pgextkit.unload('probed')").as_deref(),
+                Some("unloaded")
+            );
+        }
+        assert_eq!(crate::ext::probed_libraries(), probed);
+    }
+
+    #[pg_test]
+    fn test_entries_of_type() {
+        shared("tests.typed.latch_1", SharedLatch::new());
+        shared("tests.typed.latch_2", SharedLatch::new());
+        shared(
+            "tests.typed.lock",
+            PgDynamicLwLock::new("tests.typed.lock", 0),
+        );
+        let dict = SharedDictionary::default();
+        let typed = |names: &mut dyn Iterator<Item = String>| {
+            let mut names = names
+                .filter(|name| name.starts_with("tests.typed."))
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        assert_eq!(
+            typed(&mut dict.keys()),
+            vec![
+                "tests.typed.latch_1",
+                "tests.typed.latch_2",
+                "tests.typed.lock"
+            ]
+        );
+        let mut latches = dict
+            .entries_of_type::<SharedLatch>()
+            .filter(|(name, _)| name.starts_with("tests.typed."))
+            .collect::<Vec<_>>();
+        // Usable as latches
+        for (_, latch) in latches.iter_mut() {
+            latch.set_and_wake_up();
+        }
+        assert_eq!(
+            typed(&mut latches.into_iter().map(|(name, _)| name)),
+            vec!["tests.typed.latch_1", "tests.typed.latch_2"]
+        );
+    }
+
+    /// Exits with an error shortly after starting, so that it's restarted
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_crash(_arg: pg_sys::Datum) {
+        std::thread::sleep(Duration::from_millis(100));
+        unsafe { pg_sys::proc_exit(1) };
+    }
+
+    #[pg_test]
+    fn test_crashing_worker_is_quarantined() {
+        let mut bgw = test_worker("pgextkit_test_crash", 0);
+        bgw.bgw_restart_time = 0;
+        let mut handle = std::ptr::null_mut();
+        assert!(
+            unsafe { pg_sys::RegisterDynamicBackgroundWorker(&mut bgw, &mut handle) },
+            "no background worker slots available"
+        );
+        let databases = std::collections::HashMap::from([("crashing".to_string(), unsafe {
+            WorkerHandle::from_raw(handle)
+        })]);
+        let mut restarts = std::collections::HashMap::new();
+        // Checked as often as the master worker would, more often than the worker restarts
+        let quarantined = (0..300).any(|_| {
+            let crashing = crate::ext::workers::watch_restarts(&databases, &mut restarts, 2);
+            !crashing.is_empty() || {
+                std::thread::sleep(Duration::from_millis(20));
+                false
+            }
+        });
+        let worker = &databases["crashing"];
+        worker.terminate();
+        assert!(quarantined, "the crashing worker wasn't quarantined");
+        assert!(restarts["crashing"].1.len() > 2);
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+    }
+
+    /// Layout of `tests.migrating` in version 1.0 of its extension
+    struct CounterV1 {
+        count: u32,
+        _flags: u32,
+    }
+
+    unsafe impl ShmemSafe for CounterV1 {}
+
+    /// Layout since 2.0, which fits in the memory of the previous one
+    struct CounterV2 {
+        count: u64,
+    }
+
+    unsafe impl ShmemSafe for CounterV2 {}
+
+    /// Migrates `tests.migrating` from 1.0, other entries are reinitialized
+    #[no_mangle]
+    pub extern "C" fn pgextkit_migrate_shmem(
+        name: *const std::ffi::c_char,
+        old_version: *const std::ffi::c_char,
+        ptr: *mut c_void,
+    ) -> bool {
+        let (name, old_version) = unsafe {
+            (
+                std::ffi::CStr::from_ptr(name),
+                std::ffi::CStr::from_ptr(old_version),
+            )
+        };
+        if name.to_bytes() != b"tests.migrating" || old_version.to_bytes() != b"1.0" {
+            return false;
+        }
+        unsafe {
+            let count = (*(ptr as *const CounterV1)).count;
+            (ptr as *mut CounterV2).write(CounterV2 {
+                count: count as u64,
+            });
+        }
+        true
+    }
+
+    #[pg_test]
+    fn test_upgrade_migrates_shmem() {
+        let handle = dynamic_handle("migrating");
+        let dict = SharedDictionary::default();
+        let upgrade = |name: &str, init: u64| unsafe {
+            let old = shared(
+                &format!("{}.v1", name),
+                CounterV1 {
+                    count: 5,
+                    _flags: 0,
+                },
+            ) as *mut CounterV1;
+            SharedDictionary::default().insert_versioned(name, old, "1.0");
+            let new = shared(&format!("{}.v2", name), CounterV2 { count: 0 }) as *mut CounterV2;
+            let value = crate::register_versioned(
+                name,
+                "2.0",
+                handle.library_name,
+                handle.migrate_shmem,
+                new,
+                false,
+                || CounterV2 { count: init },
+            );
+            (value == old as *mut CounterV2, (*value).count)
+        };
+        // Migrated in place, the entry keeps pointing to the old memory
+        assert_eq!(upgrade("tests.migrating", 1), (true, 5));
+        assert_eq!(dict.version("tests.migrating").as_deref(), Some("2.0"));
+        assert_eq!(
+            dict.get::<CounterV2>("tests.migrating")
+                .map(|value| value.count),
+            Some(5)
+        );
+        // Not migrated, so reinitialized in the new memory
+        assert_eq!(upgrade("tests.reinitialized", 7), (false, 7));
+        assert_eq!(dict.version("tests.reinitialized").as_deref(), Some("2.0"));
+    }
+
+    #[pg_test]
+    fn test_wait_for_startup_cancelled() {
+        let mut bgw = test_worker("pgextkit_test_crash", 0);
+        let mut handle = std::ptr::null_mut();
+        assert!(unsafe { pg_sys::RegisterDynamicBackgroundWorker(&mut bgw, &mut handle) });
+        let worker = unsafe { WorkerHandle::from_raw(handle) };
+        let polls = std::cell::Cell::new(0);
+        let started = std::time::Instant::now();
+        // As if SIGTERM was received while the worker is yet to start
+        let pid = worker.wait_for_startup(Duration::from_secs(3600), || {
+            polls.set(polls.get() + 1);
+            true
+        });
+        assert!(started.elapsed() < Duration::from_secs(10));
+        // Cancellation is only checked while the worker hasn't started
+        if polls.get() > 0 {
+            assert_eq!(pid, None);
+        }
+        worker.terminate();
+    }
+
+    #[derive(Default)]
+    struct Rendezvous {
+        barrier: crate::barrier::SharedBarrier,
+        arrived: AtomicUsize,
+        last: AtomicUsize,
+        passed: AtomicUsize,
+    }
+
+    unsafe impl SyncMut for Rendezvous {}
+    unsafe impl ShmemSafe for Rendezvous {}
+
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_rendezvous(_arg: pg_sys::Datum) {
+        let rendezvous = SharedDictionary::default()
+            .get::<Rendezvous>("tests.rendezvous")
+            .expect("rendezvous");
+        rendezvous.arrived.fetch_add(1, Ordering::SeqCst);
+        if rendezvous.barrier.wait(3) {
+            rendezvous.last.fetch_add(1, Ordering::SeqCst);
+        }
+        rendezvous.passed.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[pg_test]
+    fn test_barrier_releases_three_participants() {
+        let rendezvous = shared("tests.rendezvous", Rendezvous::default());
+        let workers = [
+            start_worker("pgextkit_test_rendezvous", 0),
+            start_worker("pgextkit_test_rendezvous", 1),
+        ];
+        for _ in 0..100 {
+            if rendezvous.arrived.load(Ordering::SeqCst) == 2 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(rendezvous.arrived.load(Ordering::SeqCst), 2);
+        // Two out of three participants aren't enough
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(rendezvous.passed.load(Ordering::SeqCst), 0);
+        let last = rendezvous.barrier.wait(3);
+        for worker in &workers {
+            assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+        }
+        assert_eq!(rendezvous.passed.load(Ordering::SeqCst), 2);
+        assert_eq!(rendezvous.last.load(Ordering::SeqCst) + last as usize, 1);
+    }
+
+    /// Waits to be terminated, for up to a minute
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_idle(_arg: pg_sys::Datum) {
+        use pgx::bgworkers::{BackgroundWorker, SignalWakeFlags};
+        BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGTERM);
+        BackgroundWorker::wait_latch(Some(Duration::from_secs(60)));
+    }
+
+    #[pg_test]
+    fn test_failed_load_leaves_no_state() {
+        let _control_files = InstalledControlFiles::new(&[
+            (
+                "fails_midway.control",
+                "default_version = '1.0'\nmodule_pathname = '$libdir/pgextkit'\n",
+            ),
+            ("fails_midway--1.0.sql", ""),
+        ]);
+        Spi::run("CREATE EXTENSION fails_midway");
+        let (message, _) = caught_error(|| {
+            Spi::get_one::<String>("SELECT pgextkit.load('fails_midway')");
+        })
+        .expect("load didn't fail");
+        assert_eq!(message, "fails_midway failed to initialize");
+        // The allocation is freed along with the entry pointing to it
+        assert!(SharedDictionary::default()
+            .get::<u64>("tests.fails_midway")
+            .is_none());
+        assert!(crate::ext::handles::WorkerHandles::default()
+            .of("fails_midway")
+            .is_empty());
+        assert!(!crate::ext::registry::Registry::default().contains("fails_midway", "1.0"));
+        let running = || {
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM pg_stat_activity WHERE backend_type = 'pgextkit_test_idle'",
+            )
+        };
+        for _ in 0..100 {
+            if running() == Some(0) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(running(), Some(0));
+    }
+
+    #[cfg(debug_assertions)]
+    #[pg_test]
+    fn test_insert_rejects_backend_local_memory() {
+        let mut local = Box::new(0u64);
+        let local = &mut *local as *mut u64;
+        let (message, _) = caught_error(|| {
+            SharedDictionary::default().insert("tests.backend_local", local);
+        })
+        .expect("backend-local memory was inserted");
+        assert_eq!(
+            message,
+            format!(
+                "tests.backend_local ({:p}) isn't in shared memory, it can't be shared with other backends",
+                local
+            )
+        );
+        assert!(SharedDictionary::default()
+            .get::<u64>("tests.backend_local")
+            .is_none());
+        // Shared memory is accepted
+        shared("tests.shared_memory", 0u64);
+    }
+
+    #[pg_test]
+    fn test_refresh_swaps_to_the_installed_version() {
+        // Version 2.0 comes with a library of its own
+        let library = pkglib("pgextkit_refreshing");
+        std::fs::copy(pkglib("pgextkit"), &library).expect("can't copy library");
+        let _control_files = InstalledControlFiles::new(&[
+            (
+                "refreshing.control",
+                "default_version = '1.0'\nmodule_pathname = '$libdir/pgextkit'\n",
+            ),
+            (
+                "refreshing--2.0.control",
+                "module_pathname = '$libdir/pgextkit_refreshing'\n",
+            ),
+            ("refreshing--1.0.sql", ""),
+            ("refreshing--1.0--2.0.sql", ""),
+        ]);
+        Spi::run("CREATE EXTENSION refreshing VERSION '1.0'");
+        assert_eq!(
+            Spi::get_one::<String>("SELECT pgextkit.load('refreshing')").as_deref(),
+            Some("loaded")
+        );
+        let refresh = || {
+            Spi::get_two::<String, String>(
+                "SELECT installed_version, status FROM pgextkit.refresh() WHERE name = 'refreshing'",
+            )
+        };
+        // In sync with the catalog
+        assert_eq!(refresh(), (None, None));
+        Spi::run("ALTER EXTENSION refreshing UPDATE TO '2.0'");
+        assert_eq!(
+            refresh(),
+            (Some("2.0".to_string()), Some("loaded".to_string()))
+        );
+        let registry = crate::ext::registry::Registry::default();
+        assert!(registry.contains("refreshing", "2.0"));
+        assert!(!registry.contains("refreshing", "1.0"));
+        let maps = std::fs::read_to_string("/proc/self/maps").expect("can't read mappings");
+        assert!(maps.contains(&library), "{} isn't loaded", library);
+        // Nothing left to do
+        assert_eq!(refresh(), (None, None));
+        let _ = std::fs::remove_file(&library);
+    }
+
+    #[pg_test]
+    fn test_log_level_quiets_load_messages() {
+        Spi::run("SET log_min_messages = debug1");
+        let load_from_path = |path: &str, name: &str| {
+            Spi::get_one::<String>(&format!(
+                "SELECT pgextkit.load_from_path('{}', '{}', '1.0')",
+                path, name
+            ))
+        };
+        let loaded = |level: u32, name: &str| {
+            captured_messages(level, || {
+                assert_eq!(
+                    load_from_path(&pkglib("pgextkit"), name).as_deref(),
+                    Some("loaded")
+                );
+            })
+            .iter()
+            .any(|message| message.starts_with("Loaded pgextkit library"))
+        };
+        assert!(loaded(pg_sys::DEBUG1, "log_level_debug1"));
+        reload_setting("pgextkit.log_level", "debug2");
+        assert!(!loaded(pg_sys::DEBUG1, "log_level_debug2"));
+        // Warnings are still logged
+        let dir = directory_with(&[("corrupt.so", "not a library")]);
+        let path = dir.join("corrupt.so").to_string_lossy().to_string();
+        let warnings = captured_warnings(|| {
+            assert_eq!(
+                load_from_path(&path, "log_level_corrupt").as_deref(),
+                Some("incompatible")
+            );
+        });
+        assert!(
+            warnings
+                .iter()
+                .any(|warning| warning.starts_with(&format!("Can't validate {}", path))),
+            "{:?}",
+            warnings
+        );
+        reload_setting("pgextkit.log_level", "log");
+        assert!(loaded(pg_sys::LOG, "log_level_log"));
+        reload_setting("pgextkit.log_level", "debug1");
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    /// Beats until terminated, for up to a minute
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_beating(_arg: pg_sys::Datum) {
+        use pgx::bgworkers::{BackgroundWorker, SignalWakeFlags};
+        BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGTERM);
+        for _ in 0..600 {
+            crate::heartbeat::beat();
+            if !BackgroundWorker::wait_latch(Some(Duration::from_millis(100))) {
+                break;
+            }
+        }
+    }
+
+    #[pg_test]
+    fn test_wait_workers_ready() {
+        let _control_files = InstalledControlFiles::new(&[
+            (
+                "ready.control",
+                "default_version = '1.0'\nmodule_pathname = '$libdir/pgextkit'\n",
+            ),
+            ("ready--1.0.sql", ""),
+        ]);
+        Spi::run("CREATE EXTENSION ready");
+        // Nothing is ready before the extension is loaded
+        assert_eq!(
+            Spi::get_one::<bool>("SELECT pgextkit.wait_workers_ready('ready', 100)"),
+            Some(false)
+        );
+        assert_eq!(
+            Spi::get_one::<String>("SELECT pgextkit.load('ready')").as_deref(),
+            Some("loaded")
+        );
+        let started = std::time::Instant::now();
+        assert_eq!(
+            Spi::get_one::<bool>("SELECT pgextkit.wait_workers_ready('ready', 10000)"),
+            Some(true)
+        );
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(
+            Spi::get_one::<String>("SELECT pgextkit.unload('ready')").as_deref(),
+            Some("unloaded")
+        );
+    }
+
+    struct LazyLocal {
+        local: crate::db::DatabaseLocal<u32, 4>,
+        /// Number of times a slot was constructed
+        constructed: AtomicUsize,
+    }
+
+    unsafe impl SyncMut for LazyLocal {}
+    unsafe impl ShmemSafe for LazyLocal {}
+
+    impl LazyLocal {
+        fn for_my_database(&mut self, value: u32) -> u32 {
+            let constructed = &self.constructed;
+            *std::pin::Pin::new(&mut self.local)
+                .try_for_my_database(|| {
+                    constructed.fetch_add(1, Ordering::SeqCst);
+                    Ok::<_, ()>(value)
+                })
+                .expect("infallible")
+        }
+    }
+
+    /// Uses the slot of `template1` of `tests.lazy_local` twice
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_lazy_local(_arg: pg_sys::Datum) {
+        use pgx::bgworkers::BackgroundWorker;
+        BackgroundWorker::connect_worker_to_spi(Some("template1"), None);
+        let lazy = std::pin::Pin::into_inner(
+            SharedDictionary::default()
+                .get_mut::<LazyLocal>("tests.lazy_local")
+                .expect("lazy local"),
+        );
+        assert_eq!(lazy.for_my_database(2), 2);
+        assert_eq!(lazy.for_my_database(3), 2);
+    }
+
+    #[pg_test]
+    fn test_lazy_database_local() {
+        let lazy = shared(
+            "tests.lazy_local",
+            LazyLocal {
+                local: crate::db::DatabaseLocal::lazy(),
+                constructed: AtomicUsize::new(0),
+            },
+        );
+        // A failed construction doesn't claim a slot
+        assert_eq!(
+            std::pin::Pin::new(&mut lazy.local)
+                .try_for_my_database(|| Err("can't construct"))
+                .err(),
+            Some("can't construct")
+        );
+        assert_eq!(std::pin::Pin::new(&lazy.local).slot_index(), None);
+        assert_eq!(lazy.constructed.load(Ordering::SeqCst), 0);
+        // Constructed on first use only
+        assert_eq!(lazy.for_my_database(1), 1);
+        assert_eq!(lazy.for_my_database(4), 1);
+        assert_eq!(lazy.constructed.load(Ordering::SeqCst), 1);
+        assert_eq!(*std::pin::Pin::new(&mut lazy.local).for_my_database(), 1);
+        // Then once for another database
+        let worker = start_worker("pgextkit_test_lazy_local", 0);
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+        assert_eq!(lazy.constructed.load(Ordering::SeqCst), 2);
+        assert_eq!(std::pin::Pin::new(&lazy.local).slot_index(), Some(0));
+    }
+
+    const CONFIG_READERS: usize = 2;
+    const CONFIG_VERSIONS: u64 = 10_000;
+
+    struct ConfigSwap {
+        config: crate::config::SharedConfig<[u64; 16]>,
+        stop: std::sync::atomic::AtomicBool,
+        reads: AtomicU64,
+        /// Reads that saw parts of different versions, or a version older than one read before
+        torn: AtomicU64,
+    }
+
+    unsafe impl SyncMut for ConfigSwap {}
+    unsafe impl ShmemSafe for ConfigSwap {}
+
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_config_reader(_arg: pg_sys::Datum) {
+        let swap = SharedDictionary::default()
+            .get::<ConfigSwap>("tests.config_swap")
+            .expect("config swap");
+        let mut last = 0;
+        while !swap.stop.load(Ordering::SeqCst) {
+            let value = swap.config.read();
+            if value.iter().any(|part| *part != value[0]) || value[0] < last {
+                swap.torn.fetch_add(1, Ordering::SeqCst);
+            }
+            last = value[0];
+            drop(value);
+            swap.reads.fetch_add(1, Ordering::SeqCst);
+            pgx::check_for_interrupts!();
+        }
+    }
+
+    #[pg_test]
+    fn test_config_swap_under_concurrent_reads() {
+        let swap = shared(
+            "tests.config_swap",
+            ConfigSwap {
+                config: crate::config::SharedConfig::new("tests.config_swap", [0; 16]),
+                stop: Default::default(),
+                reads: AtomicU64::new(0),
+                torn: AtomicU64::new(0),
+            },
+        );
+        let readers = (0..CONFIG_READERS)
+            .map(|i| start_worker("pgextkit_test_config_reader", i as i64))
+            .collect::<Vec<_>>();
+        for _ in 0..100 {
+            if swap.reads.load(Ordering::SeqCst) > 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        for version in 1..=CONFIG_VERSIONS {
+            swap.config.publish([version; 16]);
+        }
+        swap.stop.store(true, Ordering::SeqCst);
+        for reader in &readers {
+            assert!(reader.wait_for_shutdown(Duration::from_secs(10)));
+        }
+        assert!(swap.reads.load(Ordering::SeqCst) > 0);
+        assert_eq!(swap.torn.load(Ordering::SeqCst), 0);
+        assert_eq!(swap.config.version(), CONFIG_VERSIONS);
+        assert_eq!(*swap.config.read(), [CONFIG_VERSIONS; 16]);
+    }
+
+    struct Tagged(u64);
+
+    unsafe impl SyncMut for Tagged {}
+    unsafe impl ShmemSafe for Tagged {}
+
+    struct Mistagged(#[allow(dead_code)] u64);
+
+    unsafe impl SyncMut for Mistagged {}
+    unsafe impl ShmemSafe for Mistagged {}
+
+    #[pg_test]
+    fn test_get_mut_or_err() {
+        use crate::shmem::DictionaryError;
+        shared("tests.tagged", Tagged(1));
+        let dict = SharedDictionary::default();
+        assert_eq!(
+            dict.get_mut_or_err::<Tagged>("tests.tagged")
+                .map(|tagged| tagged.0),
+            Ok(1)
+        );
+        let err = dict
+            .get_mut_or_err::<Mistagged>("tests.tagged")
+            .err()
+            .expect("an entry of another type was returned");
+        assert_eq!(
+            err,
+            DictionaryError::WrongType {
+                name: "tests.tagged".to_string(),
+                expected: std::any::type_name::<Mistagged>().to_string(),
+                found: std::any::type_name::<Tagged>().to_string(),
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "shared dictionary entry tests.tagged is a {}, not a {}",
+                std::any::type_name::<Tagged>(),
+                std::any::type_name::<Mistagged>()
+            )
+        );
+        let err = dict
+            .get_mut_or_err::<Tagged>("tests.missing")
+            .err()
+            .expect("a missing entry was returned");
+        assert_eq!(err, DictionaryError::NotFound("tests.missing".to_string()));
+        assert_eq!(
+            err.to_string(),
+            "no shared dictionary entry named tests.missing"
+        );
+    }
+
+    #[pg_test]
+    fn test_global_dictionary_attached_once() {
+        let value = shared("tests.global_dictionary_value", 1u64) as *mut u64;
+        let global = SharedDictionary::global();
+        let addin_shmem_init_lock: *mut pg_sys::LWLock =
+            unsafe { &mut (*pg_sys::MainLWLockArray.add(21)).lock };
+        // Attaching again would wait for the lock this backend holds
+        unsafe { pg_sys::LWLockAcquire(addin_shmem_init_lock, pg_sys::LWLockMode_LW_EXCLUSIVE) };
+        let again = SharedDictionary::global();
+        SharedDictionary::default().insert("tests.global_dictionary", value);
+        unsafe { pg_sys::LWLockRelease(addin_shmem_init_lock) };
+        assert!(std::ptr::eq(global, again));
+        assert_eq!(
+            global
+                .get::<u64>("tests.global_dictionary")
+                .map(|value| *value),
+            Some(1)
+        );
+    }
+
+    /// Whether `pgextkit_can_unload` vetoes unloading in this backend
+    static VETO_UNLOAD: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+    #[no_mangle]
+    pub extern "C" fn pgextkit_can_unload() -> bool {
+        !VETO_UNLOAD.load(Ordering::SeqCst)
+    }
+
+    #[pg_test]
+    fn test_unload_vetoed() {
+        let _control_files = InstalledControlFiles::new(&[
+            (
+                "vetoed.control",
+                "default_version = '1.0'\nmodule_pathname = '$libdir/pgextkit'\n",
+            ),
+            ("vetoed--1.0.sql", ""),
+        ]);
+        Spi::run("CREATE EXTENSION vetoed");
+        assert_eq!(
+            Spi::get_one::<String>("SELECT pgextkit.load('vetoed')").as_deref(),
+            Some("loaded")
+        );
+        let ready = || Spi::get_one::<bool>("SELECT pgextkit.wait_workers_ready('vetoed', 10000)");
+        assert_eq!(ready(), Some(true));
+        VETO_UNLOAD.store(true, Ordering::SeqCst);
+        assert_eq!(
+            Spi::get_one::<String>("SELECT pgextkit.unload('vetoed')").as_deref(),
+            Some("busy")
+        );
+        // Left loaded, with its worker running
+        assert!(crate::ext::registry::Registry::default().contains("vetoed", "1.0"));
+        assert_eq!(ready(), Some(true));
+        VETO_UNLOAD.store(false, Ordering::SeqCst);
+        assert_eq!(
+            Spi::get_one::<String>("SELECT pgextkit.unload('vetoed')").as_deref(),
+            Some("unloaded")
+        );
+    }
+
+    #[pg_test]
+    fn test_shared_str_of_1_kib() {
+        use crate::types::{CapacityError, SharedStr};
+        let text = "é".repeat(512);
+        assert_eq!(text.len(), 1024);
+        let lock = shared(
+            "tests.shared_str",
+            PgDynamicLwLock::new("tests.shared_str", SharedStr::<1024>::new()),
+        );
+        lock.exclusive().set_str(&text).expect("1 KiB doesn't fit");
+        let read = || {
+            SharedDictionary::default()
+                .get::<PgDynamicLwLock<SharedStr<1024>>>("tests.shared_str")
+                .map(|lock| lock.share().to_string())
+        };
+        assert_eq!(read(), Some(text.clone()));
+        // Too long by a byte, the contents are left unchanged
+        assert_eq!(
+            lock.exclusive().set_str(&format!("{}a", text)),
+            Err(CapacityError {
+                len: 1025,
+                capacity: 1024
+            })
+        );
+        assert_eq!(read(), Some(text));
+    }
+
+    struct HeldLock {
+        lock: PgDynamicLwLock<u64>,
+        held: std::sync::atomic::AtomicBool,
+    }
+
+    unsafe impl SyncMut for HeldLock {}
+    unsafe impl ShmemSafe for HeldLock {}
+
+    /// Holds `tests.held_lock` for 300ms
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_hold_lock(_arg: pg_sys::Datum) {
+        let held = std::pin::Pin::into_inner(
+            SharedDictionary::default()
+                .get_mut::<HeldLock>("tests.held_lock")
+                .expect("held lock"),
+        );
+        let mut guard = held.lock.exclusive();
+        held.held.store(true, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(300));
+        *guard += 1;
+    }
+
+    #[pg_test]
+    fn test_lock_wait_recorded() {
+        let held = shared(
+            "tests.held_lock",
+            HeldLock {
+                lock: PgDynamicLwLock::new("tests.held_lock", 0),
+                held: Default::default(),
+            },
+        );
+        Spi::run("SET pgextkit.lock_stats = on");
+        let worker = start_worker("pgextkit_test_hold_lock", 0);
+        for _ in 0..100 {
+            if held.held.load(Ordering::SeqCst) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(
+            held.held.load(Ordering::SeqCst),
+            "worker didn't take the lock"
+        );
+        // Waits for the worker to release it
+        assert_eq!(*held.lock.exclusive(), 1);
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+        let (count, max_wait_us) = Spi::get_two::<i64, i64>(
+            "SELECT count, max_wait_us FROM pgextkit.lock_wait_stats() WHERE lock_name = 'tests.held_lock'",
+        );
+        assert!(count.unwrap_or_default() >= 1, "{:?}", count);
+        assert!(max_wait_us.unwrap_or_default() > 0, "{:?}", max_wait_us);
+    }
+
+    #[pg_test]
+    fn test_repeated_catalog_scans_leak_nothing() {
+        let warnings = captured_warnings(|| {
+            for _ in 0..10 {
+                // Committing a subtransaction warns about the resources it leaked
+                let (context, owner) =
+                    unsafe { (pg_sys::CurrentMemoryContext, pg_sys::CurrentResourceOwner) };
+                unsafe { pg_sys::BeginInternalSubTransaction(std::ptr::null()) };
+                assert!(!crate::ext::get_extensions().is_empty());
+                assert!(crate::ext::workers::get_databases()
+                    .iter()
+                    .any(|database| database == "postgres"));
+                unsafe {
+                    pg_sys::ReleaseCurrentSubTransaction();
+                    pg_sys::MemoryContextSwitchTo(context);
+                    pg_sys::CurrentResourceOwner = owner;
+                }
+            }
+        });
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[pg_test]
+    fn test_rpgffi_char_round_trip() {
+        use crate::types::{RpgffiChar128, RpgffiChar96};
+        assert_eq!(String::from(&RpgffiChar128::from("hello")), "hello");
+        assert_eq!(String::from(&RpgffiChar96::from("hello")), "hello");
+        // Read up to the first NUL
+        assert_eq!(String::from(&RpgffiChar128::from("ab\0cd")), "ab");
+        // Or to the end, when it's full
+        let full = "é".repeat(48);
+        assert_eq!(String::from(&RpgffiChar96::from(full.as_str())), full);
+        assert_eq!(
+            String::from(&RpgffiChar96::from(format!("{}a", full).as_str())),
+            full
+        );
+        // Invalid UTF-8 is replaced
+        let mut chars = RpgffiChar128::from("a b");
+        chars.0[1] = 0xff_u8 as std::ffi::c_char;
+        assert_eq!(String::from(&chars), "a\u{FFFD}b");
+    }
+
+    #[pg_test]
+    fn test_unload_frees_shared_memory() {
+        let _control_files = InstalledControlFiles::new(&[
+            (
+                "churning.control",
+                "default_version = '1.0'\nmodule_pathname = '$libdir/pgextkit'\n",
+            ),
+            ("churning--1.0.sql", ""),
+        ]);
+        Spi::run("CREATE EXTENSION churning");
+        let used = || {
+            Spi::get_one::<i64>(
+                "SELECT used FROM pgextkit.shmem_usage() WHERE extension = 'churning'",
+            )
+        };
+        // The quota only fits one load's allocation, so it would run out if unloading
+        // didn't free it
+        for _ in 0..10 {
+            assert_eq!(
+                Spi::get_one::<String>("SELECT pgextkit.load('churning')").as_deref(),
+                Some("loaded")
+            );
+            assert_eq!(used(), Some(CHURNING_SIZE as i64));
+            assert!(SharedDictionary::default()
+                .get::<u64>("tests.churning")
+                .is_some());
+            assert_eq!(
+                Spi::get_one::<String>("SELECT pgextkit.unload('churning')").as_deref(),
+                Some("unloaded")
+            );
+            assert_eq!(used(), Some(0));
+            assert!(SharedDictionary::default()
+                .get::<u64>("tests.churning")
+                .is_none());
+        }
+    }
+
+    #[pg_test]
+    fn test_get_text_of_unused_database_local() {
+        type Text = heapless::String<96>;
+        let local = shared(
+            "tests.text_local",
+            crate::db::DatabaseLocal::<PgDynamicLwLock<Text>>::new(|| {
+                PgDynamicLwLock::new("tests.text_local", Text::from("hello"))
+            }),
+        );
+        let get = || {
+            Spi::get_one::<String>("SELECT pgextkit.shared_dictionary_get_text('tests.text_local')")
+        };
+        // Reading doesn't claim a slot for this database
+        assert_eq!(get(), None);
+        assert_eq!(std::pin::Pin::new(&*local).slot_index(), None);
+        std::pin::Pin::new(&mut *local).for_my_database();
+        assert_eq!(get().as_deref(), Some("hello"));
+    }
+
+    #[pg_test]
+    fn test_unload_library_loaded_from_path() {
+        let load = || {
+            Spi::get_one::<String>(&format!(
+                "SELECT pgextkit.load_from_path('{}', 'from_path', '1.0')",
+                pkglib("pgextkit")
+            ))
+        };
+        let unload = || Spi::get_one::<String>("SELECT pgextkit.unload('from_path')");
+        for _ in 0..2 {
+            assert_eq!(load().as_deref(), Some("loaded"));
+            assert_eq!(load().as_deref(), Some("already_loaded"));
+            assert_eq!(
+                Spi::get_one::<String>(
+                    "SELECT version FROM pgextkit.loaded_extensions() WHERE name = 'from_path'"
+                )
+                .as_deref(),
+                Some("1.0")
+            );
+            assert_eq!(unload().as_deref(), Some("unloaded"));
+            assert!(!crate::ext::registry::Registry::default().contains("from_path", "1.0"));
+        }
+        assert_eq!(unload().as_deref(), Some("not_found"));
+    }
+
+    #[pg_test]
+    fn test_dropped_databases_are_forgotten() {
+        psql("CREATE DATABASE pgextkit_test_dropped_database");
+        let listed = || {
+            Spi::get_one::<bool>(
+                "SELECT 'pgextkit_test_dropped_database' IN (SELECT pgextkit.active_database_workers())",
+            )
+            .unwrap_or_default()
+        };
+        let within = |f: &dyn Fn() -> bool| {
+            (0..100).any(|_| {
+                f() || {
+                    std::thread::sleep(Duration::from_millis(100));
+                    false
+                }
+            })
+        };
+        assert!(within(&listed));
+        let dropped = unsafe {
+            pg_sys::get_database_oid(
+                cstr_core::cstr!("pgextkit_test_dropped_database").as_ptr(),
+                false,
+            )
+        };
+
+        // A worker some extension started in it
+        let worker = start_worker("pgextkit_test_idle", 0);
+        crate::ext::handles::WorkerHandles::default().restore("dropping", dropped, worker);
+
+        // And the only slot of a database-local value, claimed by it
+        let mut local = Box::pin(crate::db::DatabaseLocal::<u32, 1>::lazy());
+        let database = unsafe { pg_sys::MyDatabaseId };
+        unsafe { pg_sys::MyDatabaseId = dropped };
+        let claimed = local
+            .as_mut()
+            .try_for_my_database(|| Ok::<_, ()>(1))
+            .map(|value| *value);
+        unsafe { pg_sys::MyDatabaseId = database };
+        assert_eq!(claimed, Ok(1));
+
+        psql("DROP DATABASE pgextkit_test_dropped_database WITH (FORCE)");
+        let forgotten = || {
+            worker.is_stopped()
+                && crate::ext::handles::WorkerHandles::default()
+                    .of("dropping")
+                    .is_empty()
+        };
+        assert!(within(&forgotten), "the worker wasn't stopped");
+        assert!(within(&|| !listed()));
+
+        let value = local
+            .as_mut()
+            .try_for_my_database(|| Ok::<_, ()>(2))
+            .map(|value| *value);
+        assert_eq!(value, Ok(2));
+        assert_eq!(local.as_ref().slot_index(), Some(0));
+    }
+
+    /// Sets the latch of `test_wait_any_woken_by_second_latch` shortly after starting
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn pgextkit_test_set_latch(_arg: pg_sys::Datum) {
+        std::thread::sleep(Duration::from_millis(100));
+        if let Some(latch) =
+            SharedDictionary::default().get_mut::<SharedLatch>("tests.wait_any_second")
+        {
+            latch.get_mut().set_and_wake_up();
+        }
+    }
+
+    #[pg_test]
+    fn test_wait_any_woken_by_second_latch() {
+        use crate::latch::{OwnedLatch, WakeReason};
+        let first = shared("tests.wait_any_first", SharedLatch::new())
+            .own()
+            .expect("latch");
+        let second = shared("tests.wait_any_second", SharedLatch::new())
+            .own()
+            .expect("latch");
+        first.reset();
+        second.reset();
+        let wait = |timeout| {
+            let start = std::time::Instant::now();
+            let reason = OwnedLatch::wait_any(&[&first, &second], Some(timeout));
+            (reason, start.elapsed())
+        };
+        assert_eq!(wait(Duration::from_millis(10)).0, WakeReason::Timeout);
+
+        // Set by another process while waiting
+        let worker = start_worker("pgextkit_test_set_latch", 0);
+        let (reason, elapsed) = wait(Duration::from_secs(60));
+        assert_eq!(reason, WakeReason::Latch(1));
+        assert!(elapsed < Duration::from_secs(10), "{:?}", elapsed);
+        assert!(worker.wait_for_shutdown(Duration::from_secs(10)));
+        // It was reset, so the timer fires again
+        assert_eq!(wait(Duration::from_millis(10)).0, WakeReason::Timeout);
+    }
+}
 
 #[cfg(all(feature = "extension", test))]
 pub mod pg_test {
@@ -141,7 +4308,10 @@ pub mod pg_test {
     }
 
     pub fn postgresql_conf_options() -> Vec<&'static str> {
-        // return any postgresql.conf settings that are required for your tests
-        vec![]
+        vec![
+            "shared_preload_libraries = 'pgextkit'",
+            "pgextkit.max_overflow_shmem_size = '64MB'",
+            "pgextkit.max_dictionary_entries = 10000",
+        ]
     }
 }
@@ -0,0 +1,118 @@
+//! Time spent waiting for [`PgDynamicLwLock`](crate::lwlock::PgDynamicLwLock)s
+//!
+//! Only acquisitions that had to wait are recorded, and only while `pgextkit.lock_stats`
+//! is on, so uncontended locks don't pay for it. `pgextkit.lock_wait_stats()` shows
+//! the waits by lock name.
+use crate::shmem::{compare, make_hashkey, TruncatingFrom};
+use cstr_core::cstr;
+use pgx::pg_sys;
+use std::ffi::{c_void, CStr};
+use std::mem::size_of;
+use std::time::Duration;
+
+const MAX_LOCKS: usize = 1024;
+
+type Key = heapless::String<64>;
+
+#[repr(C)]
+struct Entry {
+    // Key must be the first field of the hash table entry
+    name: Key,
+    count: u64,
+    total_wait_us: u64,
+    max_wait_us: u64,
+}
+
+fn htab() -> *mut pg_sys::HTAB {
+    let addin_shmem_init_lock: *mut pg_sys::LWLock =
+        unsafe { &mut (*pg_sys::MainLWLockArray.add(21)).lock };
+    let mut ctl = unsafe { std::mem::zeroed::<pg_sys::HASHCTL>() };
+    ctl.keysize = size_of::<Key>();
+    ctl.entrysize = size_of::<Entry>();
+    ctl.hash = Some(make_hashkey);
+    ctl.match_ = Some(compare);
+    unsafe {
+        pg_sys::LWLockAcquire(addin_shmem_init_lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+        let htab = pg_sys::ShmemInitHash(
+            cstr!("pgextkit_lock_stats").as_ptr(),
+            MAX_LOCKS as _,
+            MAX_LOCKS as _,
+            &mut ctl,
+            (pg_sys::HASH_ELEM | pg_sys::HASH_FUNCTION | pg_sys::HASH_COMPARE) as _,
+        );
+        pg_sys::LWLockRelease(addin_shmem_init_lock);
+        htab
+    }
+}
+
+fn lock() -> *mut pg_sys::LWLock {
+    unsafe { &mut (*pg_sys::GetNamedLWLockTranche(cstr!("pgextkit_lock_stats").as_ptr())).lock }
+}
+
+/// Whether `pgextkit.lock_stats` is on (it's unknown unless pgextkit is preloaded)
+pub(crate) fn enabled() -> bool {
+    let setting =
+        unsafe { pg_sys::GetConfigOption(cstr!("pgextkit.lock_stats").as_ptr(), true, false) };
+    !setting.is_null() && unsafe { CStr::from_ptr(setting) }.to_bytes() == b"on"
+}
+
+/// Records that acquiring the lock named `name` took `wait`
+pub(crate) fn record(name: &CStr, wait: Duration) {
+    let name = Key::truncating_from(name.to_string_lossy());
+    let wait_us = wait.as_micros().min(u64::MAX as u128) as u64;
+    let htab = htab();
+    let lock = lock();
+    unsafe {
+        pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+        let mut found = false;
+        let entry = pg_sys::hash_search(
+            htab,
+            &name as *const _ as *const c_void,
+            pg_sys::HASHACTION_HASH_ENTER_NULL,
+            &mut found,
+        ) as *mut Entry;
+        if !entry.is_null() {
+            if !found {
+                std::ptr::addr_of_mut!((*entry).count).write(0);
+                std::ptr::addr_of_mut!((*entry).total_wait_us).write(0);
+                std::ptr::addr_of_mut!((*entry).max_wait_us).write(0);
+            }
+            (*entry).count += 1;
+            (*entry).total_wait_us = (*entry).total_wait_us.saturating_add(wait_us);
+            (*entry).max_wait_us = (*entry).max_wait_us.max(wait_us);
+        }
+        pg_sys::LWLockRelease(lock);
+    }
+}
+
+/// Names, number of waits, total and longest wait (in microseconds) of every lock
+#[cfg(feature = "extension")]
+pub(crate) fn entries() -> Vec<(String, u64, u64, u64)> {
+    let htab = htab();
+    let lock = lock();
+    let mut result = vec![];
+    unsafe {
+        pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_SHARED);
+        let mut status = std::mem::zeroed::<pg_sys::HASH_SEQ_STATUS>();
+        pg_sys::hash_seq_init(&mut status, htab);
+        loop {
+            let entry = pg_sys::hash_seq_search(&mut status) as *const Entry;
+            if entry.is_null() {
+                break;
+            }
+            result.push((
+                (*entry).name.to_string(),
+                (*entry).count,
+                (*entry).total_wait_us,
+                (*entry).max_wait_us,
+            ));
+        }
+        pg_sys::LWLockRelease(lock);
+    }
+    result
+}
+
+#[cfg(feature = "extension")]
+pub(crate) fn size() -> usize {
+    unsafe { pg_sys::hash_estimate_size(MAX_LOCKS as _, size_of::<Entry>()) }
+}
@@ -0,0 +1,62 @@
+use crate::shmem::TruncatingFrom;
+use crate::types::{SharedStr, ShmemSafe};
+use pgx::{ereport, PgLogLevel, PgSqlErrorCode};
+
+/// Emits log messages attributed to an extension
+///
+/// Every message is prefixed with `[<extension> <version>]`. Unlike [`Handle`](crate::Handle),
+/// a logger can be stored, for example in the argument of a background worker
+/// (see [`Handle::register_bgworker_with_arg`](crate::Handle::register_bgworker_with_arg)).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Logger {
+    name: SharedStr<64>,
+    version: SharedStr<64>,
+}
+
+unsafe impl ShmemSafe for Logger {}
+
+impl Logger {
+    pub fn new(name: &str, version: &str) -> Self {
+        Self {
+            name: SharedStr::truncating_from(name),
+            version: SharedStr::truncating_from(version),
+        }
+    }
+
+    fn format(&self, message: &str) -> String {
+        format!("[{} {}] {}", self.name, self.version, message)
+    }
+
+    pub fn log<S: AsRef<str>>(&self, message: S) {
+        ereport!(
+            PgLogLevel::LOG,
+            PgSqlErrorCode::ERRCODE_SUCCESSFUL_COMPLETION,
+            self.format(message.as_ref()).as_str()
+        );
+    }
+
+    pub fn warn<S: AsRef<str>>(&self, message: S) {
+        ereport!(
+            PgLogLevel::WARNING,
+            PgSqlErrorCode::ERRCODE_WARNING,
+            self.format(message.as_ref()).as_str()
+        );
+    }
+
+    /// Logs a warning and records it as the last error of the extension's worker in the
+    /// current database, shown by `pgextkit.worker_errors()`
+    pub fn worker_error<S: AsRef<str>>(&self, message: S) {
+        self.warn(message.as_ref());
+        crate::worker_errors::record(&self.name, message);
+    }
+
+    /// Raises an error, aborting the current transaction
+    pub fn error<S: AsRef<str>>(&self, message: S) -> ! {
+        ereport!(
+            PgLogLevel::ERROR,
+            PgSqlErrorCode::ERRCODE_INTERNAL_ERROR,
+            self.format(message.as_ref()).as_str()
+        );
+        unreachable!("ereport(ERROR) returned")
+    }
+}
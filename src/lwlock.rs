@@ -1,54 +1,116 @@
-use crate::types::SyncMut;
+use crate::lock_stats;
+use crate::types::{ShmemSafe, SyncMut};
 use once_cell::sync::OnceCell;
 use pgx::pg_sys;
+use std::collections::{HashMap, HashSet};
 use std::ffi::{CStr, CString};
 use std::fmt;
-use std::mem::MaybeUninit;
+use std::mem::{ManuallyDrop, MaybeUninit};
 use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::time::Instant;
 
 type TrancheId = std::ffi::c_int;
 
+/// Maximum length of a lock's name, longer names are truncated
+const MAX_NAME_LEN: usize = 63;
+
+/// Tranche IDs allocated by this process, by tranche name
+///
+/// Locks sharing a name share a tranche, so creating many locks doesn't use up tranche
+/// IDs (nor grow every backend's table of tranche names).
+static TRANCHE_IDS: OnceCell<Mutex<HashMap<&'static CStr, TrancheId>>> = OnceCell::new();
+
+/// Names of the locks used by this process
+///
+/// Postgres keeps pointers to tranche names, while the names of the locks are in shared
+/// memory, so each of them is copied once per process.
+static NAMES: OnceCell<Mutex<HashSet<&'static CStr>>> = OnceCell::new();
+
+/// This process' copy of `name`
+fn interned(name: &CStr) -> &'static CStr {
+    let mut names = NAMES
+        .get_or_init(|| Mutex::new(HashSet::new()))
+        .lock()
+        .expect("can't lock names");
+    if let Some(name) = names.get(name) {
+        return name;
+    }
+    let name: &'static CStr = Box::leak(CString::from(name).into_boxed_c_str());
+    names.insert(name);
+    name
+}
+
+/// Tranche ID for locks named `name`, allocating it on first use in this process
+fn tranche_id(name: &'static CStr) -> TrancheId {
+    let mut tranche_ids = TRANCHE_IDS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .expect("can't lock tranche IDs");
+    if let Some(tranche_id) = tranche_ids.get(name) {
+        return *tranche_id;
+    }
+    let tranche_id = unsafe { pg_sys::LWLockNewTrancheId() };
+    // Postgres doesn't check the counter, once it wraps around IDs are no longer valid
+    if tranche_id < 0 {
+        // Unwinding with the guard held would poison the mutex for every later lock
+        drop(tranche_ids);
+        pgx::error!(
+            "can't allocate an LWLock tranche for {}: tranche IDs are exhausted",
+            name.to_string_lossy()
+        );
+    }
+    tranche_ids.insert(name, tranche_id);
+    tranche_id
+}
+
 pub struct PgDynamicLwLock<T> {
     lock: OnceCell<(TrancheId, pg_sys::LWLock)>,
     data: T,
-    name: &'static CStr,
+    /// NUL-terminated name, stored inline so that it is valid in every backend
+    name: [u8; MAX_NAME_LEN + 1],
 }
 
-unsafe impl<T> SyncMut for PgDynamicLwLock<T> {}
+unsafe impl<T: ShmemSafe> SyncMut for PgDynamicLwLock<T> {}
+unsafe impl<T: ShmemSafe> ShmemSafe for PgDynamicLwLock<T> {}
 
 impl<T> fmt::Debug for PgDynamicLwLock<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_fmt(format_args!(
             "PgInnerDynamicLwLock({})",
-            self.name.to_string_lossy()
+            self.name().to_string_lossy()
         ))
     }
 }
 
 impl<T> PgDynamicLwLock<T> {
+    /// Creates a lock named `name`, truncated to 63 bytes
     pub fn new(name: &str, data: T) -> Self {
-        let name = unsafe {
-            CStr::from_ptr(
-                Box::leak(
-                    CString::new(name)
-                        .expect("CString::new failed")
-                        .into_boxed_c_str(),
-                )
-                .as_ptr(),
-            )
-        };
+        let mut len = name.len().min(MAX_NAME_LEN);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        let name = CString::new(&name[..len]).expect("CString::new failed");
+        let mut inline = [0; MAX_NAME_LEN + 1];
+        inline[..len].copy_from_slice(name.as_bytes());
 
         PgDynamicLwLock {
             data,
-            name,
+            name: inline,
             lock: OnceCell::new(),
         }
     }
 
+    fn name(&self) -> &CStr {
+        // The last byte is always NUL
+        unsafe { CStr::from_ptr(self.name.as_ptr() as *const std::ffi::c_char) }
+    }
+
     fn get_lock(&self) -> &(TrancheId, pg_sys::LWLock) {
         self.lock.get_or_init(|| {
-            let tranche_id = unsafe { pg_sys::LWLockNewTrancheId() };
-            unsafe { pg_sys::LWLockRegisterTranche(tranche_id, self.name.as_ptr()) }
+            let name = interned(self.name());
+            let tranche_id = tranche_id(name);
+            unsafe { pg_sys::LWLockRegisterTranche(tranche_id, name.as_ptr()) }
             let mut lock = MaybeUninit::<pg_sys::LWLock>::zeroed();
             unsafe { pg_sys::LWLockInitialize(lock.as_mut_ptr(), tranche_id) }
             (tranche_id, unsafe { lock.assume_init() })
@@ -57,16 +119,33 @@ impl<T> PgDynamicLwLock<T> {
 
     fn register(&self) -> *const pg_sys::LWLock {
         let (tranche_id, lock) = self.get_lock();
-        unsafe { pg_sys::LWLockRegisterTranche(*tranche_id, self.name.as_ptr()) }
+        unsafe { pg_sys::LWLockRegisterTranche(*tranche_id, interned(self.name()).as_ptr()) }
         lock as *const _
     }
 
+    /// Acquires the lock, recording how long it waited if `pgextkit.lock_stats` is on
+    fn acquire(&self, lock: *const pg_sys::LWLock, mode: pg_sys::LWLockMode) {
+        let lock = lock as *mut _;
+        unsafe {
+            // Uncontended acquisitions don't wait, so they aren't timed
+            if pg_sys::LWLockConditionalAcquire(lock, mode) {
+                return;
+            }
+            if !lock_stats::enabled() {
+                pg_sys::LWLockAcquire(lock, mode);
+                return;
+            }
+            let started = Instant::now();
+            pg_sys::LWLockAcquire(lock, mode);
+            lock_stats::record(self.name(), started.elapsed());
+        }
+    }
+
     /// Obtain a shared lock (which comes with `&T` access)
     pub fn share(&self) -> PgDynamicLwLockShareGuard<T> {
         let lock = self.register();
+        self.acquire(lock, pg_sys::LWLockMode_LW_SHARED);
         unsafe {
-            pg_sys::LWLockAcquire(lock as *mut _, pg_sys::LWLockMode_LW_SHARED);
-
             PgDynamicLwLockShareGuard {
                 data: &self.data,
                 lock: lock as *mut _,
@@ -76,15 +155,91 @@ impl<T> PgDynamicLwLock<T> {
 
     pub fn exclusive(&mut self) -> PgDynamicLwLockExclusiveGuard<T> {
         let lock = self.register();
+        self.acquire(lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
         unsafe {
-            pg_sys::LWLockAcquire(lock as *mut _, pg_sys::LWLockMode_LW_EXCLUSIVE);
-
             PgDynamicLwLockExclusiveGuard {
                 data: &mut self.data,
                 lock: lock as *mut _,
             }
         }
     }
+
+    /// Calls `f` with shared access to the data, holding the lock for its duration only
+    ///
+    /// Prefer it to [`PgDynamicLwLock::share`], as the lock can't be accidentally held
+    /// across unrelated work (such as SPI calls) or early returns.
+    pub fn with_share<R, F: FnOnce(&T) -> R>(&self, f: F) -> R {
+        let guard = self.share();
+        f(&guard)
+    }
+
+    /// Calls `f` with exclusive access to the data, holding the lock for its duration only
+    ///
+    /// Prefer it to [`PgDynamicLwLock::exclusive`], as the lock can't be accidentally held
+    /// across unrelated work (such as SPI calls) or early returns.
+    pub fn with_exclusive<R, F: FnOnce(&mut T) -> R>(&mut self, f: F) -> R {
+        let mut guard = self.exclusive();
+        f(&mut guard)
+    }
+
+    /// The underlying LWLock, for tests to check how it's held
+    #[cfg(all(feature = "extension", any(test, feature = "pg_test")))]
+    pub(crate) fn raw(&self) -> *mut pg_sys::LWLock {
+        self.register() as *mut _
+    }
+}
+
+impl<const N: usize> PgDynamicLwLock<heapless::String<N>> {
+    /// Replaces the string with `new` if it currently is `expected`, atomically
+    ///
+    /// Returns `false` if the string is something else, or if `new` doesn't fit.
+    pub fn compare_and_set(&mut self, expected: &str, new: &str) -> bool {
+        if new.len() > N {
+            return false;
+        }
+        let mut s = self.exclusive();
+        if s.as_str() != expected {
+            return false;
+        }
+        s.clear();
+        s.push_str(new).expect("checked above");
+        true
+    }
+}
+
+/// Tranche of locks requested with [`Handle::request_lwlocks`](crate::Handle::request_lwlocks)
+///
+/// The locks are allocated by Postgres during startup, so unlike [`PgDynamicLwLock`] they
+/// are shared by all backends under the same tranche name.
+#[derive(Debug, Clone, Copy)]
+pub struct NamedLwLockTranche {
+    name: &'static CStr,
+    count: usize,
+}
+
+impl NamedLwLockTranche {
+    pub(crate) fn new(name: &'static CStr, count: usize) -> Self {
+        Self { name, count }
+    }
+
+    pub fn name(&self) -> &'static CStr {
+        self.name
+    }
+
+    /// Locks of the tranche, only available once the server has started
+    pub fn locks(&self) -> &'static [pg_sys::LWLockPadded] {
+        unsafe {
+            std::slice::from_raw_parts(
+                pg_sys::GetNamedLWLockTranche(self.name.as_ptr()),
+                self.count,
+            )
+        }
+    }
+
+    /// `i`-th lock of the tranche
+    pub fn get(&self, i: usize) -> *mut pg_sys::LWLock {
+        &self.locks()[i].lock as *const _ as *mut _
+    }
 }
 
 pub struct PgDynamicLwLockShareGuard<'a, T> {
@@ -113,6 +268,34 @@ pub struct PgDynamicLwLockExclusiveGuard<'a, T> {
     lock: *mut pg_sys::LWLock,
 }
 
+impl<'a, T> PgDynamicLwLockExclusiveGuard<'a, T> {
+    /// Turn this guard into a shared one, letting other readers in
+    ///
+    /// Postgres LWLocks can't be downgraded atomically, so the exclusive lock is released
+    /// and a shared lock is acquired afterwards. Another writer may acquire the lock
+    /// in between, so the data should not be assumed unchanged after downgrading.
+    ///
+    /// The downgraded guard is read-only:
+    ///
+    /// ```compile_fail
+    /// fn write(lock: &mut pgextkit::lwlock::PgDynamicLwLock<u32>) {
+    ///     let mut guard = lock.exclusive().downgrade();
+    ///     *guard = 1;
+    /// }
+    /// ```
+    pub fn downgrade(self) -> PgDynamicLwLockShareGuard<'a, T> {
+        let this = ManuallyDrop::new(self);
+        let lock = this.lock;
+        // Safety: `this` is never dropped or used again, so the reference is moved out exactly once
+        let data: &'a T = unsafe { std::ptr::read(&this.data) };
+        unsafe {
+            pg_sys::LWLockRelease(lock);
+            pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_SHARED);
+        }
+        PgDynamicLwLockShareGuard { data, lock }
+    }
+}
+
 impl<T> Deref for PgDynamicLwLockExclusiveGuard<'_, T> {
     type Target = T;
 
@@ -134,3 +317,80 @@ impl<T> Drop for PgDynamicLwLockExclusiveGuard<'_, T> {
         }
     }
 }
+
+/// Condition variable to be used alongside [`PgDynamicLwLock`]
+///
+/// Allows a backend to atomically release the lock and sleep until another backend
+/// signals that the protected state has changed.
+pub struct PgConditionVariable {
+    cv: pg_sys::ConditionVariable,
+}
+
+unsafe impl SyncMut for PgConditionVariable {}
+unsafe impl ShmemSafe for PgConditionVariable {}
+
+impl fmt::Debug for PgConditionVariable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PgConditionVariable")
+    }
+}
+
+impl PgConditionVariable {
+    pub fn new() -> Self {
+        let mut cv = MaybeUninit::<pg_sys::ConditionVariable>::zeroed();
+        unsafe { pg_sys::ConditionVariableInit(cv.as_mut_ptr()) }
+        Self {
+            cv: unsafe { cv.assume_init() },
+        }
+    }
+
+    fn as_ptr(&self) -> *mut pg_sys::ConditionVariable {
+        &self.cv as *const _ as *mut _
+    }
+
+    /// Sleep while `condition` returns `true`
+    ///
+    /// The lock is released while sleeping and is reacquired before `condition`
+    /// is checked again.
+    pub fn wait_while<'a, T, F: FnMut(&mut T) -> bool>(
+        &self,
+        mut guard: PgDynamicLwLockExclusiveGuard<'a, T>,
+        mut condition: F,
+    ) -> PgDynamicLwLockExclusiveGuard<'a, T> {
+        while condition(&mut *guard) {
+            // Sleeping processes interrupts, so it may raise an error while the lock isn't
+            // held: the guard is taken apart meanwhile, so that it can't release it again
+            let released = ManuallyDrop::new(guard);
+            let lock = released.lock;
+            // Safety: `released` is never dropped or used again
+            let data: &'a mut T = unsafe { std::ptr::read(&released.data) };
+            unsafe {
+                // Preparing to sleep before releasing the lock ensures that a notification
+                // sent in between is not lost
+                pg_sys::ConditionVariablePrepareToSleep(self.as_ptr());
+                pg_sys::LWLockRelease(lock);
+                pg_sys::ConditionVariableSleep(self.as_ptr(), pg_sys::PG_WAIT_EXTENSION);
+                pg_sys::ConditionVariableCancelSleep();
+                pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+            }
+            guard = PgDynamicLwLockExclusiveGuard { data, lock };
+        }
+        guard
+    }
+
+    /// Wake up one of the waiting backends
+    pub fn notify_one(&self) {
+        unsafe { pg_sys::ConditionVariableSignal(self.as_ptr()) }
+    }
+
+    /// Wake up all waiting backends
+    pub fn notify_all(&self) {
+        unsafe { pg_sys::ConditionVariableBroadcast(self.as_ptr()) }
+    }
+}
+
+impl Default for PgConditionVariable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,279 @@
+//! Overflow pool of shared memory, backed by DSM segments
+//!
+//! Once the arena of `pgextkit.shmem_size` is used up, memory allocated by extensions
+//! comes from DSM segments created on demand, up to `pgextkit.overflow_shmem_size`, which
+//! can be raised with a reload.
+//!
+//! Allocations are shared as raw pointers (through the
+//! [`SharedDictionary`](crate::shmem::SharedDictionary)), so every segment has to be mapped
+//! at the same address in every backend. The postmaster reserves (without backing it with
+//! memory) an address range of `pgextkit.max_overflow_shmem_size` at startup, which
+//! backends inherit, and segments are mapped one after the other within it. Backends map
+//! the segments created by others when they look entries up in the dictionary.
+//!
+//! Segments are mapped by name, so this needs `dynamic_shared_memory_type = posix`.
+use cstr_core::cstr;
+use once_cell::sync::OnceCell;
+use pgx::pg_sys;
+use std::cell::UnsafeCell;
+use std::ffi::{c_void, CString};
+use std::mem::size_of;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Segments are created at least this large, and grow along with the pool
+#[cfg(feature = "extension")]
+const MIN_SEGMENT_SIZE: usize = 1024 * 1024;
+
+const MAX_SEGMENTS: usize = 64;
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Segment {
+    handle: pg_sys::dsm_handle,
+    size: usize,
+}
+
+#[repr(C)]
+struct OverflowPool {
+    /// Start of the address range reserved by the postmaster, zero without one
+    base: usize,
+    reserved: usize,
+    /// Segments mapped one after the other from `base`, only written holding the lock
+    segments: UnsafeCell<[Segment; MAX_SEGMENTS]>,
+    /// Number of `segments` in use
+    segment_count: AtomicUsize,
+    /// Bytes of the range backed by segments
+    mapped: AtomicUsize,
+    /// Bytes of the range handed out
+    used: AtomicUsize,
+}
+
+/// Address range reserved by the postmaster (start and size), inherited by backends
+static RESERVED: OnceCell<(usize, usize)> = OnceCell::new();
+
+/// Segments mapped by this process
+static MAPPED_SEGMENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Attaches to the pool's header, once per backend
+///
+/// The postmaster attaches every time, as shared memory is created anew when it restarts
+/// after a crash.
+fn pool() -> &'static OverflowPool {
+    static POOL: OnceCell<usize> = OnceCell::new();
+    if unsafe { !pg_sys::IsUnderPostmaster } {
+        return unsafe { &*attach() };
+    }
+    unsafe { &*(*POOL.get_or_init(|| attach() as usize) as *const OverflowPool) }
+}
+
+fn attach() -> *const OverflowPool {
+    let addin_shmem_init_lock: *mut pg_sys::LWLock =
+        unsafe { &mut (*pg_sys::MainLWLockArray.add(21)).lock };
+    unsafe {
+        pg_sys::LWLockAcquire(addin_shmem_init_lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+        let mut found = false;
+        let ptr = pg_sys::ShmemInitStruct(
+            cstr!("pgextkit_overflow_pool").as_ptr(),
+            size_of::<OverflowPool>(),
+            &mut found,
+        ) as *mut OverflowPool;
+        if !found {
+            let (base, reserved) = RESERVED.get().copied().unwrap_or_default();
+            ptr.write(OverflowPool {
+                base,
+                reserved,
+                segments: UnsafeCell::new([Segment { handle: 0, size: 0 }; MAX_SEGMENTS]),
+                segment_count: AtomicUsize::new(0),
+                mapped: AtomicUsize::new(0),
+                used: AtomicUsize::new(0),
+            });
+        }
+        pg_sys::LWLockRelease(addin_shmem_init_lock);
+        ptr
+    }
+}
+
+/// Whether `ptr` points into the pool's address range
+pub(crate) fn contains<T>(ptr: *const T) -> bool {
+    let pool = pool();
+    let ptr = ptr as usize;
+    pool.base != 0 && ptr >= pool.base && ptr < pool.base + pool.mapped.load(Ordering::SeqCst)
+}
+
+/// Maps the segments other backends created since this backend last looked
+pub(crate) fn map_segments() {
+    let pool = pool();
+    let count = pool.segment_count.load(Ordering::SeqCst);
+    let mapped = MAPPED_SEGMENTS.load(Ordering::SeqCst);
+    if mapped >= count {
+        return;
+    }
+    let segments = unsafe { &*pool.segments.get() };
+    let mut offset: usize = segments[..mapped].iter().map(|segment| segment.size).sum();
+    for segment in &segments[mapped..count] {
+        map(pool.base + offset, segment);
+        offset += segment.size;
+    }
+    MAPPED_SEGMENTS.store(count, Ordering::SeqCst);
+}
+
+/// Maps `segment` at `address`, replacing the reservation there
+fn map(address: usize, segment: &Segment) {
+    let name =
+        CString::new(format!("/PostgreSQL.{}", segment.handle)).expect("CString::new failed");
+    unsafe {
+        let fd = libc::shm_open(name.as_ptr(), libc::O_RDWR, 0);
+        if fd < 0 {
+            pgx::error!(
+                "can't open pgextkit's overflow shared memory segment {}: {}",
+                segment.handle,
+                std::io::Error::last_os_error()
+            );
+        }
+        let ptr = libc::mmap(
+            address as *mut c_void,
+            segment.size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_FIXED,
+            fd,
+            0,
+        );
+        let err = std::io::Error::last_os_error();
+        libc::close(fd);
+        if ptr == libc::MAP_FAILED {
+            pgx::error!(
+                "can't map pgextkit's overflow shared memory segment {}: {}",
+                segment.handle,
+                err
+            );
+        }
+    }
+}
+
+/// Reserves `size` bytes of address space for the pool, must be called by the postmaster
+/// before shared memory is created
+#[cfg(feature = "extension")]
+pub(crate) fn reserve(size: usize) {
+    if size == 0 {
+        return;
+    }
+    if unsafe { pg_sys::dynamic_shared_memory_type } != pg_sys::DSM_IMPL_POSIX as i32 {
+        pgx::warning!(
+            "pgextkit.max_overflow_shmem_size requires dynamic_shared_memory_type = posix, not reserving any"
+        );
+        return;
+    }
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            size,
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        pgx::warning!(
+            "Can't reserve {} bytes of address space for pgextkit.max_overflow_shmem_size: {}",
+            size,
+            std::io::Error::last_os_error()
+        );
+        return;
+    }
+    let _ = RESERVED.set((ptr as usize, size));
+}
+
+#[cfg(feature = "extension")]
+fn lock() -> *mut pg_sys::LWLock {
+    unsafe { &mut (*pg_sys::GetNamedLWLockTranche(cstr!("pgextkit_overflow_pool").as_ptr())).lock }
+}
+
+/// Allocates `layout` from the pool, creating segments as needed as long as no more than
+/// `limit` bytes are handed out in total
+///
+/// Returns null if the allocation doesn't fit.
+#[cfg(feature = "extension")]
+pub(crate) fn allocate(layout: std::alloc::Layout, limit: usize) -> *mut u8 {
+    let pool = pool();
+    if pool.base == 0 {
+        return std::ptr::null_mut();
+    }
+    let lock = lock();
+    unsafe {
+        pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+    }
+    // The allocation may start in a segment another backend created
+    map_segments();
+    let used = pool.used.load(Ordering::SeqCst);
+    let start = (used + layout.align() - 1) & !(layout.align() - 1);
+    let end = start + layout.size();
+    let fits = end <= limit.min(pool.reserved)
+        && (end <= pool.mapped.load(Ordering::SeqCst) || grow(pool, end));
+    if fits {
+        pool.used.store(end, Ordering::SeqCst);
+    }
+    unsafe {
+        pg_sys::LWLockRelease(lock);
+    }
+    if fits {
+        (pool.base + start) as *mut u8
+    } else {
+        std::ptr::null_mut()
+    }
+}
+
+/// Creates a segment after the mapped ones so that the pool extends to at least `end`,
+/// the lock must be held
+#[cfg(feature = "extension")]
+fn grow(pool: &OverflowPool, end: usize) -> bool {
+    let count = pool.segment_count.load(Ordering::SeqCst);
+    let mapped = pool.mapped.load(Ordering::SeqCst);
+    if count == MAX_SEGMENTS {
+        return false;
+    }
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    // Growing geometrically keeps the number of segments low
+    let size = (end - mapped).max(mapped).max(MIN_SEGMENT_SIZE);
+    let size = ((size + page_size - 1) & !(page_size - 1)).min(pool.reserved - mapped);
+    if mapped + size < end {
+        return false;
+    }
+    let segment = unsafe { pg_sys::dsm_create(size, pg_sys::DSM_CREATE_NULL_IF_MAXSEGMENTS as _) };
+    if segment.is_null() {
+        return false;
+    }
+    let segment = unsafe {
+        // Pinned segments stay around until the server shuts down, even once detached
+        pg_sys::dsm_pin_segment(segment);
+        let handle = pg_sys::dsm_segment_handle(segment);
+        pg_sys::dsm_detach(segment);
+        Segment { handle, size }
+    };
+    map(pool.base + mapped, &segment);
+    unsafe {
+        (*pool.segments.get())[count] = segment;
+    }
+    pool.mapped.store(mapped + size, Ordering::SeqCst);
+    pool.segment_count.store(count + 1, Ordering::SeqCst);
+    MAPPED_SEGMENTS.store(count + 1, Ordering::SeqCst);
+    true
+}
+
+/// Gives back `size` bytes allocated at `ptr`
+///
+/// The pool only hands out memory past its last allocation, so it can only be reused if
+/// nothing was allocated after it in the meantime (such as when a failed load is undone).
+#[cfg(feature = "extension")]
+pub(crate) fn release(ptr: *mut u8, size: usize) {
+    let pool = pool();
+    let start = ptr as usize - pool.base;
+    let _ = pool
+        .used
+        .compare_exchange(start + size, start, Ordering::SeqCst, Ordering::SeqCst);
+}
+
+#[cfg(feature = "extension")]
+pub(crate) fn size() -> usize {
+    size_of::<OverflowPool>()
+}
@@ -0,0 +1,146 @@
+use crate::latch::{SharedLatch, MAX_WAIT_MS};
+use crate::lwlock::PgDynamicLwLock;
+use crate::types::{ShmemSafe, SyncMut};
+use pgx::check_for_interrupts;
+use pgx::pg_sys;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Maximum number of producers waiting for room at once, others fall back to polling
+const MAX_WAITING_PRODUCERS: usize = 64;
+
+/// How often producers that couldn't register as waiting check for room
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Error returned by [`SharedQueue::push_blocking`] when the queue stayed full,
+/// handing the item back
+pub struct TimedOut<T>(pub T);
+
+impl<T> fmt::Debug for TimedOut<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TimedOut")
+    }
+}
+
+impl<T> fmt::Display for TimedOut<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("timed out waiting for room in the queue")
+    }
+}
+
+struct State<T, const CAP: usize> {
+    items: heapless::Deque<T, CAP>,
+    /// Latches of producers waiting for room
+    waiting: heapless::Vec<*mut pg_sys::Latch, MAX_WAITING_PRODUCERS>,
+}
+
+/// Bounded queue of items handed over to the backend that owns its latch
+///
+/// Any backend can push items, which are popped by the backend that owns the queue's
+/// latch (see [`SharedQueue::latch`]). Producers finding the queue full can wait for the
+/// consumer to make room with [`SharedQueue::push_blocking`].
+pub struct SharedQueue<T, const CAP: usize> {
+    state: PgDynamicLwLock<State<T, CAP>>,
+    latch: SharedLatch,
+}
+
+unsafe impl<T: ShmemSafe, const CAP: usize> SyncMut for SharedQueue<T, CAP> {}
+unsafe impl<T: ShmemSafe, const CAP: usize> ShmemSafe for SharedQueue<T, CAP> {}
+
+impl<T, const CAP: usize> SharedQueue<T, CAP> {
+    pub fn new(name: &str) -> Self {
+        Self {
+            state: PgDynamicLwLock::new(
+                name,
+                State {
+                    items: heapless::Deque::new(),
+                    waiting: heapless::Vec::new(),
+                },
+            ),
+            latch: SharedLatch::new(),
+        }
+    }
+
+    /// Latch that is set when an item is pushed, to be owned by the consuming backend
+    pub fn latch(&mut self) -> &mut SharedLatch {
+        &mut self.latch
+    }
+
+    /// Pushes the item, handing it back if the queue is full
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        self.state.exclusive().items.push_back(item)?;
+        self.latch.set_and_wake_up();
+        Ok(())
+    }
+
+    /// Pushes the item, waiting for up to `timeout` for room if the queue is full
+    pub fn push_blocking(&mut self, item: T, timeout: Duration) -> Result<(), TimedOut<T>> {
+        let deadline = Instant::now().checked_add(timeout);
+        let my_latch = unsafe { pg_sys::MyLatch };
+        let mut item = item;
+        let result = loop {
+            let registered = {
+                let mut state = self.state.exclusive();
+                match state.items.push_back(item) {
+                    Ok(()) => break Ok(()),
+                    Err(returned) => item = returned,
+                }
+                state.waiting.contains(&my_latch) || state.waiting.push(my_latch).is_ok()
+            };
+
+            let remaining = deadline.map_or(Duration::MAX, |deadline| {
+                deadline.saturating_duration_since(Instant::now())
+            });
+            if remaining == Duration::ZERO {
+                break Err(TimedOut(item));
+            }
+            let wait = if registered {
+                remaining
+            } else {
+                remaining.min(POLL_INTERVAL)
+            };
+            let rc = unsafe {
+                let rc = pg_sys::WaitLatch(
+                    my_latch,
+                    (pg_sys::WL_LATCH_SET | pg_sys::WL_TIMEOUT | pg_sys::WL_POSTMASTER_DEATH) as _,
+                    wait.as_millis().min(MAX_WAIT_MS) as _,
+                    pg_sys::PG_WAIT_EXTENSION,
+                );
+                pg_sys::ResetLatch(my_latch);
+                rc
+            };
+            check_for_interrupts!();
+            if rc as u32 & pg_sys::WL_POSTMASTER_DEATH != 0 {
+                break Err(TimedOut(item));
+            }
+        };
+        self.state
+            .exclusive()
+            .waiting
+            .retain(|latch| *latch != my_latch);
+        if result.is_ok() {
+            self.latch.set_and_wake_up();
+        }
+        result
+    }
+
+    /// Takes the next item off the queue, waking up producers waiting for room
+    pub fn pop(&mut self) -> Option<T> {
+        let mut state = self.state.exclusive();
+        let item = state.items.pop_front();
+        if item.is_some() {
+            for latch in state.waiting.iter() {
+                unsafe { pg_sys::SetLatch(*latch) }
+            }
+        }
+        item
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.share().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,64 @@
+//! Discovery of services exposed by extensions to each other
+//!
+//! Trait objects can't be shared between separately compiled libraries, so a service is a
+//! `#[repr(C)]` table of `extern "C"` functions exported by the providing library as a
+//! `#[no_mangle]` static. The dictionary only records which library and symbol it is, and
+//! every backend resolves it in its own address space when looking it up.
+use crate::shmem::{SharedDictionary, TruncatingFrom};
+use crate::types::ShmemSafe;
+use pgx::pg_sys;
+use std::ffi::CString;
+use std::marker::PhantomData;
+
+/// Table of `extern "C"` functions (and other FFI-safe data) provided by an extension
+///
+/// # Safety
+///
+/// The type must be `#[repr(C)]` and its layout must be identical in the providing
+/// and in the consuming extension.
+pub unsafe trait ServiceVtable: Sync + 'static {}
+
+/// Dictionary entry of a registered service
+///
+/// The vtable type is part of the entry's type, so looking a service up with the wrong
+/// vtable type doesn't find it.
+pub(crate) struct ServiceEntry<V> {
+    library: heapless::String<256>,
+    symbol: heapless::String<64>,
+    _vtable: PhantomData<fn() -> V>,
+}
+
+unsafe impl<V> ShmemSafe for ServiceEntry<V> {}
+
+impl<V: ServiceVtable> ServiceEntry<V> {
+    pub(crate) fn new(library: &str, symbol: &str) -> Self {
+        Self {
+            library: heapless::String::truncating_from(library),
+            symbol: heapless::String::truncating_from(symbol),
+            _vtable: PhantomData,
+        }
+    }
+}
+
+pub(crate) fn service_key(name: &str) -> String {
+    format!("pgextkit.service.{}", name)
+}
+
+/// Finds the service registered under `name` with
+/// [`Handle::register_service`](crate::Handle::register_service)
+///
+/// The providing library gets loaded into the current backend if it isn't yet.
+pub fn lookup_service<V: ServiceVtable>(name: &str) -> Option<&'static V> {
+    let entry = SharedDictionary::default().get::<ServiceEntry<V>>(&service_key(name))?;
+    let library = CString::new(entry.library.as_str()).ok()?;
+    let symbol = CString::new(entry.symbol.as_str()).ok()?;
+    let vtable = unsafe {
+        pg_sys::load_external_function(
+            library.as_ptr(),
+            symbol.as_ptr(),
+            false,
+            std::ptr::null_mut(),
+        )
+    }?;
+    Some(unsafe { &*(vtable as *const () as *const V) })
+}
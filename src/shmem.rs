@@ -1,23 +1,80 @@
-use crate::types::SyncMut;
+use crate::types::{SharedStr, ShmemSafe, SyncMut};
 use cstr_core::cstr;
-use heapless::FnvIndexMap;
+use once_cell::sync::OnceCell;
 use pgx::prelude::*;
+use std::ffi::{c_void, CStr};
+use std::mem::size_of;
+use std::ops::Deref;
 use std::pin::Pin;
+use std::ptr::addr_of_mut;
 
-const MAX_ATTACHMENTS: usize = 8192;
+pub(crate) const DEFAULT_MAX_ATTACHMENTS: usize = 8192;
 
+pub(crate) const DEFAULT_OVERFLOW_ENTRIES: usize = 1024;
+
+/// Number of partitions (each with its own lock) of the dictionary, must be a power of two
+pub(crate) const DICTIONARY_PARTITIONS: usize = 16;
+
+type Key = heapless::String<96>;
+
+#[repr(C)]
 pub struct Entry {
+    // Key must be the first field of the hash table entry
+    name: Key,
     type_name: heapless::String<96>,
     ptr: *mut (),
+    size: usize,
+    /// Version of the extension that inserted the entry, empty if unknown
+    version: heapless::String<32>,
+}
+
+/// Error returned by [`SharedDictionary::get_mut_or_err`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DictionaryError {
+    /// There is no entry under the name
+    NotFound(String),
+    /// The entry was inserted with another type
+    WrongType {
+        name: String,
+        expected: String,
+        found: String,
+    },
+}
+
+impl std::fmt::Display for DictionaryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DictionaryError::NotFound(name) => {
+                write!(f, "no shared dictionary entry named {}", name)
+            }
+            DictionaryError::WrongType {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "shared dictionary entry {} is a {}, not a {}",
+                name, found, expected
+            ),
+        }
+    }
 }
 
-pub type Map = FnvIndexMap<heapless::String<96>, Entry, MAX_ATTACHMENTS>;
+impl std::error::Error for DictionaryError {}
 
+/// Dictionary of named objects in shared memory, shared by all extensions
+///
+/// Shared hash tables can't grow, so once its main table is full, entries spill into a
+/// smaller overflow table (sized by `pgextkit.dictionary_overflow_entries`). Looking up
+/// entries that aren't in the main table takes a second hash lookup, so if the overflow
+/// is used, consider raising `pgextkit.max_dictionary_entries`.
+#[derive(Clone, Copy)]
 pub struct SharedDictionary {
-    map: *mut Map,
+    htab: *mut pg_sys::HTAB,
+    overflow: *mut pg_sys::HTAB,
 }
 
-trait TruncatingFrom {
+pub(crate) trait TruncatingFrom {
     fn truncating_from<S: AsRef<str>>(s: S) -> Self;
 }
 
@@ -41,96 +98,587 @@ impl<const N: usize> TruncatingFrom for heapless::String<N> {
     }
 }
 
+impl<const N: usize> TruncatingFrom for SharedStr<N> {
+    fn truncating_from<S: AsRef<str>>(s: S) -> Self {
+        let truncated = heapless::String::<N>::truncating_from(s);
+        Self::try_from(truncated.as_str()).expect("truncated to the capacity")
+    }
+}
+
+/// Function hashing the dictionary's keys (`heapless::String<96>`)
+pub(crate) type KeyHasher = unsafe extern "C" fn(key: *const c_void, keysize: pg_sys::Size) -> u32;
+
+/// Hashes the key with FNV-1a, so that the hash is the same in every library
+/// that embeds its own copy of pgextkit
+pub(crate) unsafe extern "C" fn make_hashkey(key: *const c_void, _keysize: pg_sys::Size) -> u32 {
+    let key = &*(key as *const Key);
+    key.as_bytes().iter().fold(0x811c9dc5, |hash: u32, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+pub(crate) unsafe extern "C" fn compare(
+    key1: *const c_void,
+    key2: *const c_void,
+    _keysize: pg_sys::Size,
+) -> std::ffi::c_int {
+    let (key1, key2) = (&*(key1 as *const Key), &*(key2 as *const Key));
+    if key1 == key2 {
+        0
+    } else {
+        1
+    }
+}
+
+/// Same as [`SharedDictionary::global`]
 impl Default for SharedDictionary {
     fn default() -> Self {
+        *Self::global()
+    }
+}
+
+impl SharedDictionary {
+    /// Dictionary hashing keys with the default hasher, attached to once per backend
+    ///
+    /// The postmaster attaches every time, as shared memory is created anew (possibly at
+    /// another address) when it restarts after a crash.
+    pub fn global() -> &'static SharedDictionary {
+        static GLOBAL: OnceCell<usize> = OnceCell::new();
+        // The postmaster leaks a copy every time, which only happens while it creates
+        // shared memory
+        let attach =
+            || Box::leak(Box::new(Self::with_hasher(make_hashkey))) as *const Self as usize;
+        if unsafe { !pg_sys::IsUnderPostmaster } {
+            return unsafe { &*(attach() as *const Self) };
+        }
+        unsafe { &*(*GLOBAL.get_or_init(attach) as *const Self) }
+    }
+
+    /// Attaches to the dictionary, hashing keys with `hasher`
+    ///
+    /// The dictionary is shared by all extensions, so every backend and every extension
+    /// must use the same hasher, otherwise entries inserted by others can't be found.
+    pub(crate) fn with_hasher(hasher: KeyHasher) -> Self {
         let addin_shmem_init_lock: *mut pg_sys::LWLock =
             unsafe { &mut (*pg_sys::MainLWLockArray.add(21)).lock };
         unsafe {
             pg_sys::LWLockAcquire(addin_shmem_init_lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
         }
 
-        let mut found = false;
-        let map = unsafe {
-            pg_sys::ShmemInitStruct(
-                cstr!("pgextkit_shared_dictionary").as_ptr(),
-                Self::size(),
-                &mut found as *mut _,
+        let htab = Self::init_hash(
+            cstr!("pgextkit_shared_dictionary"),
+            Self::max_entries(),
+            hasher,
+        );
+        // Shares the partition locks with the main table, as it's hashed the same way
+        let overflow = Self::init_hash(
+            cstr!("pgextkit_shared_dictionary_overflow"),
+            Self::overflow_entries(),
+            hasher,
+        );
+
+        unsafe {
+            pg_sys::LWLockRelease(addin_shmem_init_lock);
+        }
+
+        Self { htab, overflow }
+    }
+
+    fn init_hash(name: &CStr, entries: usize, hasher: KeyHasher) -> *mut pg_sys::HTAB {
+        let mut ctl = unsafe { std::mem::zeroed::<pg_sys::HASHCTL>() };
+        ctl.keysize = size_of::<Key>();
+        ctl.entrysize = size_of::<Entry>();
+        ctl.hash = Some(hasher);
+        ctl.match_ = Some(compare);
+        ctl.num_partitions = DICTIONARY_PARTITIONS as _;
+
+        // Without a fixed size, a full table would take what's left of shared memory
+        // rather than spilling into the overflow table
+        unsafe {
+            pg_sys::ShmemInitHash(
+                name.as_ptr(),
+                entries as _,
+                entries as _,
+                &mut ctl,
+                (pg_sys::HASH_ELEM
+                    | pg_sys::HASH_FUNCTION
+                    | pg_sys::HASH_COMPARE
+                    | pg_sys::HASH_PARTITION
+                    | pg_sys::HASH_FIXED_SIZE) as _,
             )
-        } as *mut _;
+        }
+    }
 
-        if !found {
-            unsafe {
-                *map = FnvIndexMap::new();
+    /// Locks of all partitions
+    fn locks() -> impl Iterator<Item = *mut pg_sys::LWLock> {
+        let tranche =
+            unsafe { pg_sys::GetNamedLWLockTranche(cstr!("pgextkit_shared_dictionary").as_ptr()) };
+        (0..DICTIONARY_PARTITIONS).map(move |i| unsafe { &mut (*tranche.add(i)).lock as *mut _ })
+    }
+
+    /// Lock of the partition the key's hash belongs to
+    fn partition_lock(hashcode: u32) -> *mut pg_sys::LWLock {
+        let tranche =
+            unsafe { pg_sys::GetNamedLWLockTranche(cstr!("pgextkit_shared_dictionary").as_ptr()) };
+        unsafe { &mut (*tranche.add(hashcode as usize % DICTIONARY_PARTITIONS)).lock }
+    }
+
+    fn hash(&self, key: &Key) -> u32 {
+        unsafe { pg_sys::get_hash_value(self.htab, key as *const _ as *const c_void) }
+    }
+
+    fn search_table(
+        htab: *mut pg_sys::HTAB,
+        key: &Key,
+        hashcode: u32,
+        action: pg_sys::HASHACTION,
+    ) -> *mut Entry {
+        unsafe {
+            pg_sys::hash_search_with_hash_value(
+                htab,
+                key as *const _ as *const c_void,
+                hashcode,
+                action,
+                std::ptr::null_mut(),
+            ) as *mut Entry
+        }
+    }
+
+    /// Finds the entry in the main or in the overflow table, the partition must be locked
+    fn search(&self, key: &Key, hashcode: u32) -> *mut Entry {
+        // The entry may point to overflow memory allocated by another backend
+        crate::overflow::map_segments();
+        match Self::search_table(self.htab, key, hashcode, pg_sys::HASHACTION_HASH_FIND) {
+            entry if entry.is_null() => {
+                Self::search_table(self.overflow, key, hashcode, pg_sys::HASHACTION_HASH_FIND)
             }
+            entry => entry,
         }
+    }
 
-        unsafe {
-            pg_sys::LWLockRelease(addin_shmem_init_lock);
+    /// Finds or creates the entry, spilling into the overflow table once the main one
+    /// is full, the partition must be locked exclusively
+    ///
+    /// Returns null if both tables are full.
+    fn enter(&self, key: &Key, hashcode: u32) -> *mut Entry {
+        let entry = Self::search_table(self.overflow, key, hashcode, pg_sys::HASHACTION_HASH_FIND);
+        if !entry.is_null() {
+            return entry;
         }
+        match Self::search_table(self.htab, key, hashcode, pg_sys::HASHACTION_HASH_ENTER_NULL) {
+            entry if entry.is_null() => Self::search_table(
+                self.overflow,
+                key,
+                hashcode,
+                pg_sys::HASHACTION_HASH_ENTER_NULL,
+            ),
+            entry => entry,
+        }
+    }
 
-        Self { map }
+    /// Registers `value` under `name`
+    ///
+    /// `value` must point to shared memory, other backends would read garbage otherwise.
+    /// Debug builds panic if it doesn't. For the same reason, `T` must be [`ShmemSafe`].
+    pub fn insert<T: Unpin + ShmemSafe>(&mut self, name: &str, value: *mut T) {
+        self.insert_versioned(name, value, "")
     }
-}
 
-impl SharedDictionary {
-    pub fn insert<T: Unpin>(&mut self, name: &str, value: *mut T) {
-        let lock = unsafe {
-            &mut (*pg_sys::GetNamedLWLockTranche(cstr!("pgextkit_shared_dictionary").as_ptr())).lock
-        };
+    /// Like [`SharedDictionary::insert`], tagging the entry with the version of the
+    /// extension whose layout of `T` it uses
+    pub fn insert_versioned<T: Unpin + ShmemSafe>(
+        &mut self,
+        name: &str,
+        value: *mut T,
+        version: &str,
+    ) {
+        debug_assert!(
+            unsafe { pg_sys::ShmemAddrIsValid(value as *const c_void) }
+                || crate::overflow::contains(value),
+            "{} ({:p}) isn't in shared memory, it can't be shared with other backends",
+            name,
+            value
+        );
+        let name = Key::truncating_from(name);
+        let hashcode = self.hash(&name);
+        let lock = Self::partition_lock(hashcode);
         unsafe {
             pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
         }
-        let name = heapless::String::truncating_from(name);
+        let entry = self.enter(&name, hashcode);
+        if !entry.is_null() {
+            // The key is already copied into the entry by `hash_search`
+            unsafe {
+                addr_of_mut!((*entry).type_name)
+                    .write(heapless::String::truncating_from(std::any::type_name::<T>()));
+                addr_of_mut!((*entry).ptr).write(value as *mut _);
+                addr_of_mut!((*entry).size).write(size_of::<T>());
+                addr_of_mut!((*entry).version).write(heapless::String::truncating_from(version));
+            }
+        }
         unsafe {
-            let _ = (*self.map).insert(
-                name,
-                Entry {
-                    type_name: heapless::String::truncating_from(std::any::type_name::<T>()),
-                    ptr: value as *mut _,
-                },
+            pg_sys::LWLockRelease(lock);
+        }
+        if entry.is_null() {
+            pgx::warning!(
+                "pgextkit shared dictionary is full, can't insert {} (consider raising pgextkit.max_dictionary_entries or pgextkit.dictionary_overflow_entries)",
+                name
             );
         }
+    }
+
+    /// Finds the entry, returning its pointer and type name
+    fn find(&self, name: &str) -> Option<(*mut (), heapless::String<96>)> {
+        let name = Key::truncating_from(name);
+        let hashcode = self.hash(&name);
+        let lock = Self::partition_lock(hashcode);
+        unsafe {
+            pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_SHARED);
+        }
+        let entry = self.search(&name, hashcode);
+        let result = if entry.is_null() {
+            None
+        } else {
+            Some(unsafe { ((*entry).ptr, (*entry).type_name.clone()) })
+        };
+
         unsafe {
             pg_sys::LWLockRelease(lock);
         }
+
+        result
     }
 
-    fn internal_get<T>(&self, name: &str) -> Option<*mut T> {
-        let lock = unsafe {
-            &mut (*pg_sys::GetNamedLWLockTranche(cstr!("pgextkit_shared_dictionary").as_ptr())).lock
-        };
+    /// Gets the entry, keeping its partition locked (in shared mode) until the guard is dropped,
+    /// so that the entry can't be removed while it's in use
+    ///
+    /// LWLocks are not meant to be held for long and are not re-entrant: don't insert into
+    /// the dictionary while holding the guard.
+    pub fn get_locked<T: Unpin + ShmemSafe>(&self, name: &str) -> Option<DictEntryGuard<T>> {
+        let name = Key::truncating_from(name);
+        let hashcode = self.hash(&name);
+        let lock = Self::partition_lock(hashcode);
         unsafe {
             pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_SHARED);
         }
-        let name = heapless::String::truncating_from(name);
-        let result = unsafe { (*self.map).get(&name) }.map(|entry| entry.ptr as *mut T);
+        let entry = self.search(&name, hashcode);
+        if entry.is_null() {
+            unsafe {
+                pg_sys::LWLockRelease(lock);
+            }
+            return None;
+        }
+        Some(DictEntryGuard {
+            data: unsafe { &*((*entry).ptr as *const T) },
+            lock,
+        })
+    }
 
+    fn internal_get<T>(&self, name: &str) -> Option<*mut T> {
+        self.find(name).map(|(ptr, _)| ptr as *mut T)
+    }
+
+    /// Pointer and version tag of the entry
+    pub(crate) fn find_versioned(&self, name: &str) -> Option<(*mut (), String)> {
+        let name = Key::truncating_from(name);
+        let hashcode = self.hash(&name);
+        let lock = Self::partition_lock(hashcode);
+        unsafe {
+            pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_SHARED);
+        }
+        let entry = self.search(&name, hashcode);
+        let result = if entry.is_null() {
+            None
+        } else {
+            Some(unsafe { ((*entry).ptr, (*entry).version.to_string()) })
+        };
         unsafe {
             pg_sys::LWLockRelease(lock);
         }
-
         result
     }
 
+    /// Version of the extension the entry was inserted by, see
+    /// [`SharedDictionary::insert_versioned`]
+    pub fn version(&self, name: &str) -> Option<String> {
+        self.find_versioned(name)
+            .map(|(_, version)| version)
+            .filter(|version| !version.is_empty())
+    }
+
+    /// Name of the type the entry was inserted with
+    pub fn type_name(&self, name: &str) -> Option<String> {
+        self.find(name).map(|(_, type_name)| type_name.to_string())
+    }
+
+    /// Checks whether the entry's type name matches `T`
+    pub fn is_of_type<T>(&self, name: &str) -> Option<bool> {
+        self.find(name).map(|(_, type_name)| {
+            type_name == heapless::String::<96>::truncating_from(std::any::type_name::<T>())
+        })
+    }
+
     pub fn get_mut<T: Unpin + SyncMut>(&self, name: &str) -> Option<Pin<&'static mut T>> {
         self.internal_get(name)
             .map(|ptr| Pin::new(unsafe { &mut *ptr }))
     }
 
-    pub fn get<T: Unpin>(&self, name: &str) -> Option<Pin<&'static T>> {
+    /// Like [`SharedDictionary::get_mut`], checking the type the entry was inserted with
+    /// and telling a missing entry apart from one of another type
+    pub fn get_mut_or_err<T: Unpin + SyncMut>(
+        &self,
+        name: &str,
+    ) -> Result<Pin<&'static mut T>, DictionaryError> {
+        let (ptr, type_name) = self
+            .find(name)
+            .ok_or_else(|| DictionaryError::NotFound(name.to_string()))?;
+        let expected = heapless::String::<96>::truncating_from(std::any::type_name::<T>());
+        if type_name != expected {
+            return Err(DictionaryError::WrongType {
+                name: name.to_string(),
+                expected: expected.to_string(),
+                found: type_name.to_string(),
+            });
+        }
+        Ok(Pin::new(unsafe { &mut *(ptr as *mut T) }))
+    }
+
+    pub fn get<T: Unpin + ShmemSafe>(&self, name: &str) -> Option<Pin<&'static T>> {
         self.internal_get(name)
             .map(|ptr| Pin::new(unsafe { &*ptr }))
     }
 
-    pub fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+    /// Runs `f` on the entry, returns `None` if there is no such entry
+    pub fn update<T: Unpin + SyncMut, R, F: FnOnce(&mut T) -> R>(
+        &self,
+        name: &str,
+        f: F,
+    ) -> Option<R> {
+        self.get_mut::<T>(name).map(|entry| f(entry.get_mut()))
+    }
+
+    /// Key `name` is stored under in the current database's namespace
+    fn database_key(name: &str) -> String {
+        format!("pgextkit.db.{}.{}", unsafe { pg_sys::MyDatabaseId }, name)
+    }
+
+    /// Like [`SharedDictionary::insert`], but the entry is only visible from the current database
+    ///
+    /// Unlike [`DatabaseLocal`](crate::db::DatabaseLocal), nothing is preallocated for
+    /// databases that never use the entry.
+    pub fn insert_for_database<T: Unpin + ShmemSafe>(&mut self, name: &str, value: *mut T) {
+        self.insert(&Self::database_key(name), value)
+    }
+
+    /// Gets an entry inserted with [`SharedDictionary::insert_for_database`] in the current database
+    pub fn get_for_database<T: Unpin + ShmemSafe>(&self, name: &str) -> Option<Pin<&'static T>> {
+        self.get(&Self::database_key(name))
+    }
+
+    pub fn get_mut_for_database<T: Unpin + SyncMut>(
+        &self,
+        name: &str,
+    ) -> Option<Pin<&'static mut T>> {
+        self.get_mut(&Self::database_key(name))
+    }
+
+    /// Iterates over names, type names and sizes of the entries
+    pub fn entries(&self) -> impl Iterator<Item = (String, String, usize)> {
+        let mut result = vec![];
+        self.for_each(|name, type_name, size| {
+            // Entries can be removed once the lock is released, so the strings are copied
+            result.push((name.to_string(), type_name.to_string(), size));
+        });
+        result.into_iter()
+    }
+
+    /// Removes the entries pointing into `len` bytes at `start`, returning their names
+    ///
+    /// Used to forget the entries of a failed extension load once its shared memory is
+    /// freed, entries are otherwise never removed.
+    pub(crate) fn remove_within(&mut self, start: *const u8, len: usize) -> Vec<String> {
+        let range = start as usize..start as usize + len;
+        let mut names = vec![];
+        self.walk(|entry| {
+            if range.contains(&(entry.ptr as usize)) {
+                names.push(entry.name.clone());
+            }
+        });
+        for name in names.iter() {
+            let hashcode = self.hash(name);
+            let lock = Self::partition_lock(hashcode);
+            unsafe {
+                pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+            }
+            for htab in [self.htab, self.overflow] {
+                Self::search_table(htab, name, hashcode, pg_sys::HASHACTION_HASH_REMOVE);
+            }
+            unsafe {
+                pg_sys::LWLockRelease(lock);
+            }
+        }
+        names.into_iter().map(|name| name.to_string()).collect()
+    }
+
+    /// Calls `f` with the name, type name and size of every entry
+    ///
+    /// All partitions are locked for the duration of the walk, so `f` sees a consistent
+    /// snapshot of the dictionary. It must not access the dictionary itself.
+    pub fn for_each<F: FnMut(&str, &str, usize)>(&self, mut f: F) {
+        self.walk(|entry| f(entry.name.as_str(), entry.type_name.as_str(), entry.size))
+    }
+
+    /// Names of all entries
+    pub fn keys(&self) -> impl Iterator<Item = String> {
+        let mut result = vec![];
+        self.walk(|entry| result.push(entry.name.to_string()));
+        result.into_iter()
+    }
+
+    /// Names and values of all entries inserted with type `T`, such as every
+    /// [`SharedLatch`](crate::latch::SharedLatch)
+    ///
+    /// The entries are collected while the dictionary is locked, the values are accessed
+    /// after it's unlocked (like with [`SharedDictionary::get_mut`]).
+    pub fn entries_of_type<T: Unpin + SyncMut>(
+        &self,
+    ) -> impl Iterator<Item = (String, Pin<&'static mut T>)> {
+        let type_name = heapless::String::<96>::truncating_from(std::any::type_name::<T>());
+        crate::overflow::map_segments();
+        let mut result = vec![];
+        self.walk(|entry| {
+            if entry.type_name == type_name {
+                result.push((entry.name.to_string(), entry.ptr as *mut T));
+            }
+        });
+        result
+            .into_iter()
+            .map(|(name, ptr)| (name, Pin::new(unsafe { &mut *ptr })))
+    }
+
+    /// Calls `f` with every entry of both tables, holding the locks of all partitions
+    fn walk<F: FnMut(&Entry)>(&self, mut f: F) {
+        /// Terminates the scan, even if `f` panics
+        struct Scan {
+            status: pg_sys::HASH_SEQ_STATUS,
+            finished: bool,
+        }
+
+        impl Drop for Scan {
+            fn drop(&mut self) {
+                if !self.finished {
+                    unsafe { pg_sys::hash_seq_term(&mut self.status) }
+                }
+            }
+        }
+
+        /// Releases the locks once both tables were walked, even if `f` panics
+        struct Locks;
+
+        impl Drop for Locks {
+            fn drop(&mut self) {
+                for lock in SharedDictionary::locks() {
+                    unsafe { pg_sys::LWLockRelease(lock) }
+                }
+            }
+        }
+
         unsafe {
-            (*self.map)
-                .iter()
-                .map(|(name, entry)| (name.as_str(), entry.type_name.as_str()))
+            // Partition locks are always taken in the same order to avoid deadlocks
+            for lock in Self::locks() {
+                pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_SHARED);
+            }
+            let _locks = Locks;
+            for htab in [self.htab, self.overflow] {
+                let mut scan = Scan {
+                    status: std::mem::zeroed::<pg_sys::HASH_SEQ_STATUS>(),
+                    finished: false,
+                };
+                pg_sys::hash_seq_init(&mut scan.status, htab);
+                loop {
+                    let entry = pg_sys::hash_seq_search(&mut scan.status) as *const Entry;
+                    if entry.is_null() {
+                        // hash_seq_search terminates the scan once it's exhausted
+                        scan.finished = true;
+                        break;
+                    }
+                    f(&*entry);
+                }
+            }
+        }
+    }
+
+    /// Number of entries in the dictionary
+    pub fn len(&self) -> usize {
+        unsafe {
+            (pg_sys::hash_get_num_entries(self.htab) + pg_sys::hash_get_num_entries(self.overflow))
+                as usize
         }
     }
 
+    /// Number of entries that spilled into the overflow table
+    pub fn overflow_len(&self) -> usize {
+        unsafe { pg_sys::hash_get_num_entries(self.overflow) as usize }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Maximum number of entries of the main table, as configured by
+    /// `pgextkit.max_dictionary_entries`
+    pub fn max_entries() -> usize {
+        Self::entries_setting(cstr!("pgextkit.max_dictionary_entries"))
+            .map(usize::next_power_of_two)
+            .unwrap_or(DEFAULT_MAX_ATTACHMENTS)
+    }
+
+    /// Maximum number of entries of the overflow table, as configured by
+    /// `pgextkit.dictionary_overflow_entries`
+    pub fn overflow_entries() -> usize {
+        Self::entries_setting(cstr!("pgextkit.dictionary_overflow_entries"))
+            .map(usize::next_power_of_two)
+            .unwrap_or(DEFAULT_OVERFLOW_ENTRIES)
+    }
+
+    fn entries_setting(name: &CStr) -> Option<usize> {
+        let setting = unsafe { pg_sys::GetConfigOption(name.as_ptr(), true, false) };
+        if setting.is_null() {
+            return None;
+        }
+        unsafe { CStr::from_ptr(setting) }
+            .to_str()
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .filter(|n| *n > 0)
+    }
+
     pub fn size() -> usize {
-        std::mem::size_of::<Map>()
+        unsafe {
+            pg_sys::hash_estimate_size(Self::max_entries() as _, size_of::<Entry>())
+                + pg_sys::hash_estimate_size(Self::overflow_entries() as _, size_of::<Entry>())
+        }
+    }
+}
+
+/// Entry of the [`SharedDictionary`] that can't be removed while the guard is held
+pub struct DictEntryGuard<'a, T> {
+    data: &'a T,
+    lock: *mut pg_sys::LWLock,
+}
+
+impl<T> Deref for DictEntryGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.data
+    }
+}
+
+impl<T> Drop for DictEntryGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            pg_sys::LWLockRelease(self.lock);
+        }
     }
 }
@@ -0,0 +1,90 @@
+use crate::types::{ShmemSafe, SyncMut};
+use pgx::pg_sys;
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+#[cfg(debug_assertions)]
+use std::time::{Duration, Instant};
+
+/// Longest a spinlock is expected to be held for, longer holds are logged in debug builds
+#[cfg(debug_assertions)]
+const MAX_HOLD: Duration = Duration::from_millis(10);
+
+/// Spinlock for guarding very short critical sections, such as updating a few fields
+///
+/// Unlike [`PgDynamicLwLock`](crate::lwlock::PgDynamicLwLock), waiting backends busy-loop,
+/// so nothing but a handful of instructions should be executed while holding it: no
+/// allocation, no elog and no other locks.
+pub struct SharedSpinLock<T> {
+    lock: pg_sys::slock_t,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SharedSpinLock<T> {}
+unsafe impl<T: ShmemSafe> SyncMut for SharedSpinLock<T> {}
+unsafe impl<T: ShmemSafe> ShmemSafe for SharedSpinLock<T> {}
+
+impl<T> fmt::Debug for SharedSpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedSpinLock")
+    }
+}
+
+impl<T> SharedSpinLock<T> {
+    pub fn new(data: T) -> Self {
+        let mut lock = pg_sys::slock_t::default();
+        unsafe { pg_sys::pgx_SpinLockInit(&mut lock) }
+        Self {
+            lock,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    fn as_ptr(&self) -> *mut pg_sys::slock_t {
+        &self.lock as *const _ as *mut _
+    }
+
+    pub fn lock(&self) -> SharedSpinLockGuard<T> {
+        unsafe { pg_sys::pgx_SpinLockAcquire(self.as_ptr()) }
+        SharedSpinLockGuard {
+            lock: self,
+            #[cfg(debug_assertions)]
+            acquired: Instant::now(),
+            _not_send: PhantomData,
+        }
+    }
+}
+
+/// Guard of a [`SharedSpinLock`], it can't be sent to another thread
+pub struct SharedSpinLockGuard<'a, T> {
+    lock: &'a SharedSpinLock<T>,
+    #[cfg(debug_assertions)]
+    acquired: Instant,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Deref for SharedSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SharedSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SharedSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { pg_sys::pgx_SpinLockRelease(self.lock.as_ptr()) }
+        // Only reported once released, as nothing may be logged while holding it
+        #[cfg(debug_assertions)]
+        if self.acquired.elapsed() >= MAX_HOLD {
+            pgx::warning!("spinlock held for {:?}", self.acquired.elapsed());
+        }
+    }
+}
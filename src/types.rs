@@ -1,3 +1,4 @@
+use std::ops::{Deref, DerefMut};
 use std::os::raw::c_char;
 
 pub(crate) struct RpgffiChar128(pub(crate) [c_char; 128]);
@@ -12,6 +13,12 @@ impl<'a> From<&'a str> for RpgffiChar128 {
     }
 }
 
+impl<'a> From<&'a RpgffiChar128> for String {
+    fn from(chars: &RpgffiChar128) -> Self {
+        string_from_chars(&chars.0)
+    }
+}
+
 pub(crate) struct RpgffiChar96(pub(crate) [c_char; 96]);
 
 impl<'a> From<&'a str> for RpgffiChar96 {
@@ -24,9 +31,346 @@ impl<'a> From<&'a str> for RpgffiChar96 {
     }
 }
 
+impl<'a> From<&'a RpgffiChar96> for String {
+    fn from(chars: &RpgffiChar96) -> Self {
+        string_from_chars(&chars.0)
+    }
+}
+
+/// Reads characters up to the first NUL (or the end), replacing invalid UTF-8
+fn string_from_chars(chars: &[c_char]) -> String {
+    let bytes = chars
+        .iter()
+        .take_while(|c| **c != 0)
+        .map(|c| *c as u8)
+        .collect::<Vec<_>>();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
 /// Marker that indicates that the type can be safely mutated across multiple threads of execution
 ///
+/// It allows getting `Pin<&'static mut T>` out of the
+/// [`SharedDictionary`](crate::shmem::SharedDictionary), which every backend can do at once.
+///
 /// # Safety
 ///
-/// Unsafe if the type is not in fact safely mutable as intended.
+/// Implementors must synchronize all mutation themselves (with a lock, atomics or by
+/// only letting the owning backend mutate), and must only hold data that is valid in every
+/// backend, see [`ShmemSafe`]. Containers of user data should only be `SyncMut` when
+/// the data is `ShmemSafe`.
 pub unsafe trait SyncMut {}
+
+/// Marker of types whose values are valid in every backend when placed in shared memory
+///
+/// Types owning heap memory (such as `String`, `Vec` or `Box`) are not: their pointers
+/// point into the memory of the backend that allocated them. Use fixed-capacity types
+/// like `heapless::String` instead. Locks and other containers of this crate are only
+/// [`SyncMut`] when their data is `ShmemSafe`, so that mistake is caught at compile time:
+///
+/// ```compile_fail
+/// struct Named {
+///     name: String,
+/// }
+///
+/// fn assert_sync_mut<T: pgextkit::types::SyncMut>() {}
+/// assert_sync_mut::<pgextkit::lwlock::PgDynamicLwLock<Named>>();
+/// ```
+///
+/// Implement it for your own types with [`shmem_safe!`](crate::shmem_safe), which checks
+/// every field.
+///
+/// # Safety
+///
+/// The type must not contain pointers (or references) to memory local to a backend.
+pub unsafe trait ShmemSafe {}
+
+macro_rules! impl_shmem_safe {
+    ($($t:ty),* $(,)?) => {
+        $(unsafe impl ShmemSafe for $t {})*
+    };
+}
+
+impl_shmem_safe!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64,
+    std::sync::atomic::AtomicBool,
+    std::sync::atomic::AtomicU8,
+    std::sync::atomic::AtomicU16,
+    std::sync::atomic::AtomicU32,
+    std::sync::atomic::AtomicU64,
+    std::sync::atomic::AtomicUsize,
+    std::sync::atomic::AtomicI8,
+    std::sync::atomic::AtomicI16,
+    std::sync::atomic::AtomicI32,
+    std::sync::atomic::AtomicI64,
+    std::sync::atomic::AtomicIsize,
+);
+
+unsafe impl<T: ShmemSafe, const N: usize> ShmemSafe for [T; N] {}
+unsafe impl<T: ShmemSafe> ShmemSafe for Option<T> {}
+unsafe impl<T: ?Sized> ShmemSafe for std::marker::PhantomData<T> {}
+unsafe impl<A: ShmemSafe, B: ShmemSafe> ShmemSafe for (A, B) {}
+unsafe impl<A: ShmemSafe, B: ShmemSafe, C: ShmemSafe> ShmemSafe for (A, B, C) {}
+unsafe impl<const N: usize> ShmemSafe for heapless::String<N> {}
+unsafe impl<T: ShmemSafe, const N: usize> ShmemSafe for heapless::Vec<T, N> {}
+unsafe impl<T: ShmemSafe, const N: usize> ShmemSafe for heapless::Deque<T, N> {}
+unsafe impl<K: ShmemSafe, V: ShmemSafe, const N: usize> ShmemSafe
+    for heapless::FnvIndexMap<K, V, N>
+{
+}
+unsafe impl<T: ShmemSafe, const N: usize> ShmemSafe for heapless::FnvIndexSet<T, N> {}
+
+/// Declares a struct and implements [`ShmemSafe`] for it, checking at compile time that
+/// all of its fields are `ShmemSafe`
+///
+/// ```ignore
+/// pgextkit::shmem_safe! {
+///     pub struct Counters {
+///         pub hits: u64,
+///         pub last_key: heapless::String<64>,
+///     }
+/// }
+/// ```
+///
+/// A field such as `String` is rejected:
+///
+/// ```compile_fail
+/// pgextkit::shmem_safe! {
+///     pub struct Named {
+///         pub name: String,
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! shmem_safe {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$field_meta:meta])* $field_vis:vis $field:ident : $ty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $($(#[$field_meta])* $field_vis $field: $ty),*
+        }
+
+        const _: () = {
+            fn assert_shmem_safe<T: $crate::types::ShmemSafe>() {}
+            #[allow(dead_code)]
+            fn assert_fields() {
+                $(assert_shmem_safe::<$ty>();)*
+            }
+        };
+
+        unsafe impl $crate::types::ShmemSafe for $name {}
+    };
+}
+
+/// Aligns the value to its own cache line, so that frequently updated shared state
+/// doesn't slow down backends accessing its neighbours (false sharing)
+///
+/// Shared memory allocated through `Handle` respects the alignment.
+#[repr(align(64))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheAligned<T>(pub T);
+
+impl<T> CacheAligned<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for CacheAligned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for CacheAligned<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+unsafe impl<T: SyncMut> SyncMut for CacheAligned<T> {}
+unsafe impl<T: ShmemSafe> ShmemSafe for CacheAligned<T> {}
+
+/// Error returned when data doesn't fit into a [`SharedBytes`] or a [`SharedStr`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapacityError {
+    pub len: usize,
+    pub capacity: usize,
+}
+
+impl std::fmt::Display for CapacityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} bytes don't fit into a buffer of {} bytes",
+            self.len, self.capacity
+        )
+    }
+}
+
+impl std::error::Error for CapacityError {}
+
+/// Fixed-capacity byte buffer to be placed in shared memory, of any size
+///
+/// Like any other data, it must be guarded (for example, by a
+/// [`PgDynamicLwLock`](crate::lwlock::PgDynamicLwLock)) to be mutated by several backends.
+/// It isn't [`SyncMut`] for that reason: its length and contents are written separately,
+/// so a backend reading while another one writes could see a length that doesn't match
+/// the contents (and, for a [`SharedStr`], invalid UTF-8). Being [`ShmemSafe`], it can
+/// be put in a lock, which is `SyncMut`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SharedBytes<const N: usize> {
+    len: usize,
+    data: [u8; N],
+}
+
+unsafe impl<const N: usize> ShmemSafe for SharedBytes<N> {}
+
+impl<const N: usize> Default for SharedBytes<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> std::fmt::Debug for SharedBytes<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.as_bytes()).finish()
+    }
+}
+
+impl<const N: usize> SharedBytes<N> {
+    pub const fn new() -> Self {
+        Self {
+            len: 0,
+            data: [0; N],
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    /// Replaces the contents, leaving them unchanged if `bytes` doesn't fit
+    pub fn set_bytes(&mut self, bytes: &[u8]) -> Result<(), CapacityError> {
+        if bytes.len() > N {
+            return Err(CapacityError {
+                len: bytes.len(),
+                capacity: N,
+            });
+        }
+        self.data[..bytes.len()].copy_from_slice(bytes);
+        self.len = bytes.len();
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+/// Fixed-capacity string to be placed in shared memory, of any size
+///
+/// See [`SharedBytes`], including why it isn't [`SyncMut`].
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct SharedStr<const N: usize>(SharedBytes<N>);
+
+unsafe impl<const N: usize> ShmemSafe for SharedStr<N> {}
+
+impl<const N: usize> std::fmt::Debug for SharedStr<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl<const N: usize> std::fmt::Display for SharedStr<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<const N: usize> SharedStr<N> {
+    pub const fn new() -> Self {
+        Self(SharedBytes::new())
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever set from a `str`
+        unsafe { std::str::from_utf8_unchecked(self.0.as_bytes()) }
+    }
+
+    /// Replaces the contents, leaving them unchanged if `s` doesn't fit
+    pub fn set_str(&mut self, s: &str) -> Result<(), CapacityError> {
+        self.0.set_bytes(s.as_bytes())
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<const N: usize> TryFrom<&str> for SharedStr<N> {
+    type Error = CapacityError;
+
+    fn try_from(s: &str) -> Result<Self, CapacityError> {
+        let mut result = Self::new();
+        result.set_str(s)?;
+        Ok(result)
+    }
+}
+
+impl<const N: usize> Deref for SharedStr<N> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
@@ -0,0 +1,135 @@
+use crate::latch::OwnedLatch;
+use pgx::bgworkers::{BackgroundWorker, SignalWakeFlags};
+use pgx::{pg_sys, FromDatum, IntoDatum, PgSqlErrorCode, PgTryBuilder, Spi};
+use std::fmt;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+
+/// Database and user a background worker started by pgextkit should connect as
+///
+/// It is passed to the worker in `bgw_extra` as `<username length>:<username><database>`,
+/// so that names containing any characters survive the round trip.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerContext {
+    pub database: String,
+    pub username: String,
+}
+
+impl WorkerContext {
+    pub fn new<D: Into<String>, U: Into<String>>(database: D, username: U) -> Self {
+        Self {
+            database: database.into(),
+            username: username.into(),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        format!("{}:{}{}", self.username.len(), self.username, self.database)
+    }
+
+    pub fn decode(s: &str) -> Option<Self> {
+        let (len, rest) = s.split_once(':')?;
+        let len = len.parse::<usize>().ok()?;
+        if !rest.is_char_boundary(len) {
+            return None;
+        }
+        let (username, database) = rest.split_at(len);
+        Some(Self::new(database, username))
+    }
+
+    /// Context of the current background worker, if it was started by pgextkit
+    pub fn from_extra() -> Option<Self> {
+        Self::decode(BackgroundWorker::get_extra())
+    }
+}
+
+/// Error returned by [`WorkerSpi`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpiError {
+    /// SIGTERM was received, the worker should exit
+    Interrupted,
+    /// The transaction kept failing because of serialization failures or deadlocks
+    RetriesExhausted,
+}
+
+impl fmt::Display for SpiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpiError::Interrupted => f.write_str("interrupted by SIGTERM"),
+            SpiError::RetriesExhausted => f.write_str("transaction retries exhausted"),
+        }
+    }
+}
+
+impl std::error::Error for SpiError {}
+
+/// Runs SPI queries from a background worker, each in its own transaction
+///
+/// Transactions failing because of a serialization failure or a deadlock are retried.
+/// SIGTERM is checked for (without consuming it) through the worker's owned latch before
+/// every attempt, so the worker's main loop still sees it. The worker must already be
+/// connected with `BackgroundWorker::connect_worker_to_spi`.
+pub struct WorkerSpi<'a> {
+    latch: &'a OwnedLatch,
+    max_retries: u32,
+    retry_delay: Duration,
+}
+
+impl<'a> WorkerSpi<'a> {
+    pub fn new(latch: &'a OwnedLatch) -> Self {
+        Self {
+            latch,
+            max_retries: 3,
+            retry_delay: Duration::from_millis(100),
+        }
+    }
+
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn with_retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Runs `f` in a transaction, retrying it on serialization failures and deadlocks
+    pub fn transaction<R, F: FnMut() -> R>(&self, mut f: F) -> Result<R, SpiError> {
+        for attempt in 0..=self.max_retries {
+            if self.latch.signal_pending(SignalWakeFlags::SIGTERM) {
+                return Err(SpiError::Interrupted);
+            }
+            if attempt > 0 {
+                self.latch.wait(Some(self.retry_delay));
+                if self.latch.signal_pending(SignalWakeFlags::SIGTERM) {
+                    return Err(SpiError::Interrupted);
+                }
+            }
+            let abort = |_| {
+                unsafe { pg_sys::AbortCurrentTransaction() };
+                None
+            };
+            let result = PgTryBuilder::new(AssertUnwindSafe(|| {
+                Some(BackgroundWorker::transaction(AssertUnwindSafe(&mut f)))
+            }))
+            .catch_when(PgSqlErrorCode::ERRCODE_T_R_SERIALIZATION_FAILURE, abort)
+            .catch_when(PgSqlErrorCode::ERRCODE_T_R_DEADLOCK_DETECTED, abort)
+            .execute();
+            if let Some(result) = result {
+                return Ok(result);
+            }
+        }
+        Err(SpiError::RetriesExhausted)
+    }
+
+    /// Runs the query and returns the first column of its first row
+    pub fn query_one<T: FromDatum + IntoDatum>(&self, query: &str) -> Result<Option<T>, SpiError> {
+        self.transaction(|| Spi::get_one::<T>(query))
+    }
+
+    /// Runs the statement, discarding its results
+    pub fn execute(&self, query: &str) -> Result<(), SpiError> {
+        self.transaction(|| Spi::run(query))
+    }
+}
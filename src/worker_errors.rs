@@ -0,0 +1,120 @@
+//! Last error of background workers
+//!
+//! Workers call [`record`] when they fail, and `pgextkit.worker_errors()` shows the last
+//! error of every extension in every database, so failures are visible from SQL.
+use crate::shmem::{compare, make_hashkey, TruncatingFrom};
+use crate::worker::WorkerContext;
+use cstr_core::cstr;
+use pgx::pg_sys;
+use std::ffi::c_void;
+use std::mem::size_of;
+
+const MAX_ERRORS: usize = 1024;
+
+type Key = heapless::String<96>;
+
+#[repr(C)]
+struct Entry {
+    // Key must be the first field of the hash table entry
+    key: Key,
+    extension: heapless::String<64>,
+    database: heapless::String<64>,
+    message: heapless::String<256>,
+    when: pg_sys::TimestampTz,
+}
+
+fn htab() -> *mut pg_sys::HTAB {
+    let addin_shmem_init_lock: *mut pg_sys::LWLock =
+        unsafe { &mut (*pg_sys::MainLWLockArray.add(21)).lock };
+    let mut ctl = unsafe { std::mem::zeroed::<pg_sys::HASHCTL>() };
+    ctl.keysize = size_of::<Key>();
+    ctl.entrysize = size_of::<Entry>();
+    ctl.hash = Some(make_hashkey);
+    ctl.match_ = Some(compare);
+    unsafe {
+        pg_sys::LWLockAcquire(addin_shmem_init_lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+        let htab = pg_sys::ShmemInitHash(
+            cstr!("pgextkit_worker_errors").as_ptr(),
+            MAX_ERRORS as _,
+            MAX_ERRORS as _,
+            &mut ctl,
+            (pg_sys::HASH_ELEM | pg_sys::HASH_FUNCTION | pg_sys::HASH_COMPARE) as _,
+        );
+        pg_sys::LWLockRelease(addin_shmem_init_lock);
+        htab
+    }
+}
+
+fn lock() -> *mut pg_sys::LWLock {
+    unsafe { &mut (*pg_sys::GetNamedLWLockTranche(cstr!("pgextkit_worker_errors").as_ptr())).lock }
+}
+
+/// Records `message` as the last error of the extension's worker in the current database
+///
+/// The database is only known in workers started by pgextkit, others are recorded
+/// without one.
+pub fn record<S: AsRef<str>>(extension: &str, message: S) {
+    let database = WorkerContext::from_extra()
+        .map(|context| context.database)
+        .unwrap_or_default();
+    record_in(extension, &database, message.as_ref());
+}
+
+/// Records `message` as the last error of the extension's worker in `database`
+pub(crate) fn record_in(extension: &str, database: &str, message: &str) {
+    let key = Key::truncating_from(format!("{}/{}", extension, database));
+    let htab = htab();
+    let lock = lock();
+    unsafe {
+        pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+        let entry = pg_sys::hash_search(
+            htab,
+            &key as *const _ as *const c_void,
+            pg_sys::HASHACTION_HASH_ENTER_NULL,
+            std::ptr::null_mut(),
+        ) as *mut Entry;
+        if !entry.is_null() {
+            // The key is already copied into the entry by `hash_search`
+            std::ptr::addr_of_mut!((*entry).extension)
+                .write(heapless::String::truncating_from(extension));
+            std::ptr::addr_of_mut!((*entry).database)
+                .write(heapless::String::truncating_from(database));
+            std::ptr::addr_of_mut!((*entry).message)
+                .write(heapless::String::truncating_from(message));
+            (*entry).when = pg_sys::GetCurrentTimestamp();
+        }
+        pg_sys::LWLockRelease(lock);
+    }
+}
+
+/// Extensions, databases, messages and times of the last errors of all workers
+#[cfg(feature = "extension")]
+pub(crate) fn entries() -> Vec<(String, String, String, pg_sys::TimestampTz)> {
+    let htab = htab();
+    let lock = lock();
+    let mut result = vec![];
+    unsafe {
+        pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode_LW_SHARED);
+        let mut status = std::mem::zeroed::<pg_sys::HASH_SEQ_STATUS>();
+        pg_sys::hash_seq_init(&mut status, htab);
+        loop {
+            let entry = pg_sys::hash_seq_search(&mut status) as *const Entry;
+            if entry.is_null() {
+                break;
+            }
+            result.push((
+                (*entry).extension.to_string(),
+                (*entry).database.to_string(),
+                (*entry).message.to_string(),
+                (*entry).when,
+            ));
+        }
+        pg_sys::LWLockRelease(lock);
+    }
+    result
+}
+
+#[cfg(feature = "extension")]
+pub(crate) fn size() -> usize {
+    unsafe { pg_sys::hash_estimate_size(MAX_ERRORS as _, size_of::<Entry>()) }
+}